@@ -0,0 +1,11 @@
+-- only the first destination of each call forward is kept
+ALTER TABLE call_forward ADD COLUMN to_extension TEXT;
+
+UPDATE call_forward SET to_extension = (
+	SELECT to_extension FROM call_forward_destination
+	WHERE call_forward_destination.fwd_id = call_forward.fwd_id
+	ORDER BY position
+	LIMIT 1
+);
+
+DROP TABLE call_forward_destination;
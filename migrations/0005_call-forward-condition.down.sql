@@ -0,0 +1,4 @@
+DELETE FROM call_forward WHERE condition <> 'always';
+
+ALTER TABLE call_forward DROP COLUMN no_answer_timeout;
+ALTER TABLE call_forward DROP COLUMN condition;
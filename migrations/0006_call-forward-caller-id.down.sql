@@ -0,0 +1,3 @@
+-- without their patterns, these call forwards would apply to every caller
+DELETE FROM call_forward WHERE fwd_id IN (SELECT fwd_id FROM call_forward_caller_id);
+DROP TABLE call_forward_caller_id;
@@ -0,0 +1,9 @@
+-- Call forwards may only apply to some callers.
+-- A call forward without any pattern applies to every caller.
+CREATE TABLE call_forward_caller_id (
+	fwd_id integer NOT NULL REFERENCES call_forward(fwd_id) ON UPDATE CASCADE ON DELETE CASCADE,
+	pattern TEXT NOT NULL CHECK (pattern <> ''),
+	-- if true, every caller id starting with pattern matches
+	is_prefix BOOLEAN NOT NULL,
+	PRIMARY KEY (fwd_id, pattern, is_prefix)
+);
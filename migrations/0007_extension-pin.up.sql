@@ -0,0 +1,10 @@
+-- PINs for managing the call forwards of an extension over the phone
+CREATE TABLE extension_pin (
+	extension TEXT PRIMARY KEY,
+	-- argon2 hash in PHC string format
+	pin_hash TEXT NOT NULL,
+	-- wrong PINs entered since the last correct one
+	failed_attempts integer NOT NULL DEFAULT 0,
+	-- the PIN is not accepted until then, even if correct
+	locked_until TIMESTAMPTZ
+);
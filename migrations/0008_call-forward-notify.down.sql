@@ -0,0 +1,6 @@
+DROP TRIGGER call_forward_caller_id_notify ON call_forward_caller_id;
+DROP TRIGGER call_forward_schedule_window_notify ON call_forward_schedule_window;
+DROP TRIGGER call_forward_destination_notify ON call_forward_destination;
+DROP TRIGGER map_call_forward_context_notify ON map_call_forward_context;
+DROP TRIGGER call_forward_notify ON call_forward;
+DROP FUNCTION notify_call_forward_changed();
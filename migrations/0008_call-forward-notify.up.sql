@@ -0,0 +1,23 @@
+-- tell every running instance that its cached call forwards are outdated
+CREATE FUNCTION notify_call_forward_changed() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('call_forward_changed', '');
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER call_forward_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON call_forward
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
+CREATE TRIGGER map_call_forward_context_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON map_call_forward_context
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
+CREATE TRIGGER call_forward_destination_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON call_forward_destination
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
+CREATE TRIGGER call_forward_schedule_window_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON call_forward_schedule_window
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
+CREATE TRIGGER call_forward_caller_id_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON call_forward_caller_id
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
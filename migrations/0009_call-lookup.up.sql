@@ -0,0 +1,16 @@
+-- every lookup made by asterisk, so that we can tell why a call went where it went
+CREATE TABLE call_lookup (
+	lookup_id bigserial PRIMARY KEY,
+	looked_up_at TIMESTAMPTZ NOT NULL,
+	-- NULL if asterisk did not know the caller
+	caller_id TEXT,
+	dialed_extension TEXT NOT NULL,
+	context TEXT NOT NULL,
+	-- the extensions the call went to, joined by '&'. Empty if the call was not put through again
+	destination TEXT NOT NULL,
+	-- the call forward applied to the dialed extension. NULL if the call was not forwarded
+	fwd_id integer REFERENCES call_forward(fwd_id) ON UPDATE CASCADE ON DELETE SET NULL
+);
+CREATE INDEX call_lookup_dialed_extension ON call_lookup (dialed_extension, looked_up_at);
+CREATE INDEX call_lookup_fwd_id ON call_lookup (fwd_id);
+CREATE INDEX call_lookup_looked_up_at ON call_lookup (looked_up_at);
@@ -0,0 +1,3 @@
+DROP INDEX call_forward_valid_until;
+ALTER TABLE call_forward DROP COLUMN valid_until;
+ALTER TABLE call_forward DROP COLUMN valid_from;
@@ -0,0 +1,5 @@
+-- call forwards are only applied between valid_from and valid_until, e.g. during a vacation
+ALTER TABLE call_forward ADD COLUMN valid_from TIMESTAMPTZ;
+ALTER TABLE call_forward ADD COLUMN valid_until TIMESTAMPTZ;
+ALTER TABLE call_forward ADD CONSTRAINT call_forward_validity CHECK (valid_from < valid_until);
+CREATE INDEX call_forward_valid_until ON call_forward (valid_until);
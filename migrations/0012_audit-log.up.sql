@@ -0,0 +1,17 @@
+-- every change to a call forward, so that we can tell who changed what and when
+CREATE TABLE audit_log (
+	audit_id bigserial PRIMARY KEY,
+	changed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	-- the LDAP uid of the user, phone:<extension> for feature codes, remote:<extension> for remote
+	-- access or asterconf itself
+	actor TEXT NOT NULL,
+	action TEXT NOT NULL,
+	-- no reference, the entries outlive their call forward
+	fwd_id integer NOT NULL,
+	-- the call forward before and after the change. NULL if it did not exist
+	before JSONB,
+	after JSONB
+);
+CREATE INDEX audit_log_changed_at ON audit_log (changed_at);
+CREATE INDEX audit_log_actor ON audit_log (actor, changed_at);
+CREATE INDEX audit_log_fwd_id ON audit_log (fwd_id);
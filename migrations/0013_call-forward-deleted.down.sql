@@ -0,0 +1,4 @@
+DROP INDEX call_forward_deleted_at;
+-- without the column they would be active again
+DELETE FROM call_forward WHERE deleted_at IS NOT NULL;
+ALTER TABLE call_forward DROP COLUMN deleted_at;
@@ -0,0 +1,2 @@
+-- counts the changes of a call forward, so that an edit of an outdated state is rejected
+ALTER TABLE call_forward ADD COLUMN version integer NOT NULL DEFAULT 1;
@@ -0,0 +1,4 @@
+DROP TRIGGER context_notify ON context;
+DROP TRIGGER extension_notify ON extension;
+DROP TABLE context;
+DROP TABLE extension;
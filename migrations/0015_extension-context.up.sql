@@ -0,0 +1,22 @@
+-- the known extensions and contexts, managed on the admin page.
+-- The lists in the config file are only copied into these tables while both are empty
+CREATE TABLE extension (
+	extension TEXT PRIMARY KEY,
+	-- human readable name, displayed in the GUI
+	name TEXT NOT NULL,
+	-- the LDAP uid of the user who may set the PIN for remote access to this extension
+	owner TEXT
+);
+CREATE TABLE context (
+	asterisk_name TEXT PRIMARY KEY,
+	display_name TEXT NOT NULL
+);
+
+-- call forwards are read with the names of their extensions and contexts,
+-- so every running instance reloads these along with its cached call forwards
+CREATE TRIGGER extension_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON extension
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
+CREATE TRIGGER context_notify
+    AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON context
+    FOR EACH STATEMENT EXECUTE FUNCTION notify_call_forward_changed();
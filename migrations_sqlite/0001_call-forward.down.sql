@@ -0,0 +1,3 @@
+-- Tearing down the DB in version 0.1.0
+DROP TABLE call_forward;
+
@@ -0,0 +1,7 @@
+-- the same schema as in migrations/, for a single SQLite file
+
+CREATE TABLE call_forward (
+	fwd_id INTEGER PRIMARY KEY AUTOINCREMENT,
+	from_extension TEXT NOT NULL,
+	to_extension TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+DROP TABLE map_call_forward_context;
+
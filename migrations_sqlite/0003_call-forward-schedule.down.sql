@@ -0,0 +1,3 @@
+DROP TABLE call_forward_schedule_window;
+
+ALTER TABLE call_forward DROP COLUMN schedule_timezone;
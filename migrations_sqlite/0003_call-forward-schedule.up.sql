@@ -0,0 +1,13 @@
+-- Call forwards may only be active during some weekly recurring windows.
+-- A call forward without any window is always active.
+ALTER TABLE call_forward ADD COLUMN schedule_timezone TEXT;
+
+-- weekdays are counted from monday (0) to sunday (6)
+CREATE TABLE call_forward_schedule_window (
+	fwd_id integer NOT NULL REFERENCES call_forward(fwd_id) ON UPDATE CASCADE ON DELETE CASCADE,
+	first_weekday smallint NOT NULL CHECK (first_weekday BETWEEN 0 AND 6),
+	last_weekday smallint NOT NULL CHECK (last_weekday BETWEEN 0 AND 6),
+	start_time TIME NOT NULL,
+	end_time TIME NOT NULL,
+	CHECK (start_time < end_time)
+);
@@ -0,0 +1,13 @@
+-- A call forward may ring multiple destinations in parallel.
+-- position keeps the order in which the destinations were entered.
+CREATE TABLE call_forward_destination (
+	fwd_id integer NOT NULL REFERENCES call_forward(fwd_id) ON UPDATE CASCADE ON DELETE CASCADE,
+	position integer NOT NULL,
+	to_extension TEXT NOT NULL,
+	PRIMARY KEY (fwd_id, position)
+);
+
+INSERT INTO call_forward_destination (fwd_id, position, to_extension)
+	SELECT fwd_id, 0, to_extension FROM call_forward;
+
+ALTER TABLE call_forward DROP COLUMN to_extension;
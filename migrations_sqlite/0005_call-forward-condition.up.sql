@@ -0,0 +1,6 @@
+-- Call forwards may only apply when the original destination is busy, does not answer or is unavailable.
+-- no_answer_timeout (in seconds) is only set for no_answer call forwards.
+ALTER TABLE call_forward ADD COLUMN condition TEXT NOT NULL DEFAULT 'always'
+	CHECK (condition IN ('always', 'busy', 'no_answer', 'unavailable'));
+ALTER TABLE call_forward ADD COLUMN no_answer_timeout integer
+	CHECK (no_answer_timeout > 0);
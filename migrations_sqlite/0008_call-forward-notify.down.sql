@@ -0,0 +1 @@
+-- nothing was set up
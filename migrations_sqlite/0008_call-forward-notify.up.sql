@@ -0,0 +1,2 @@
+-- SQLite cannot notify other connections of changes.
+-- The call forward cache watches PRAGMA data_version instead, so there is nothing to set up.
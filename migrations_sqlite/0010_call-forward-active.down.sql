@@ -0,0 +1 @@
+ALTER TABLE call_forward DROP COLUMN active;
@@ -0,0 +1,2 @@
+-- inactive call forwards are kept with their id and history, but never applied
+ALTER TABLE call_forward ADD COLUMN active BOOLEAN NOT NULL DEFAULT TRUE;
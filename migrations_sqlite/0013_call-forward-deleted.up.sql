@@ -0,0 +1,3 @@
+-- deleted call forwards are kept until deleted_forward_retention_days have passed, so they can be restored
+ALTER TABLE call_forward ADD COLUMN deleted_at DATETIME;
+CREATE INDEX call_forward_deleted_at ON call_forward (deleted_at);
@@ -0,0 +1,2 @@
+DROP TABLE context;
+DROP TABLE extension;
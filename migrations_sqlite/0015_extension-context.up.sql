@@ -0,0 +1,13 @@
+-- the known extensions and contexts, managed on the admin page.
+-- The lists in the config file are only copied into these tables while both are empty
+CREATE TABLE extension (
+	extension TEXT PRIMARY KEY,
+	-- human readable name, displayed in the GUI
+	name TEXT NOT NULL,
+	-- the LDAP uid of the user who may set the PIN for remote access to this extension
+	owner TEXT
+);
+CREATE TABLE context (
+	asterisk_name TEXT PRIMARY KEY,
+	display_name TEXT NOT NULL
+);
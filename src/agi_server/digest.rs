@@ -0,0 +1,286 @@
+//! Authenticate asterisk before answering its AGI requests
+//!
+//! We send a fresh nonce and ask asterisk to compute a digest over it and the secret in
+//! `BLAZING_AGI_DIGEST_SECRET`. Every nonce is accepted at most once and only for a short time,
+//! so an answer seen on the wire cannot be replayed.
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use blazing_agi::{
+    command::{AGIResponse, GetFullVariable, Verbose},
+    connection::Connection,
+    handler::AGIHandler,
+    AGIError, AGIRequest,
+};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::{event, Level};
+
+use crate::metrics::METRICS;
+
+/// How long asterisk has to answer a nonce
+const NONCE_LIFETIME: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+enum DigestError {
+    DecodeError,
+}
+impl Display for DigestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::DecodeError => {
+                write!(f, "The returned digest was not decodable as u8")
+            }
+        }
+    }
+}
+impl std::error::Error for DigestError {}
+
+/// How asterisk proves that it knows the secret
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DigestAlgorithm {
+    /// `${HMAC(sha256,secret,nonce)}`
+    HmacSha256,
+    /// `${SHA256(secret:nonce)}`, for asterisk without the `HMAC` function
+    Sha256,
+    /// `${SHA1(secret:nonce)}`. Only kept for existing setups
+    Sha1,
+}
+impl DigestAlgorithm {
+    /// The expression asterisk evaluates to answer `nonce`
+    fn expression(&self, nonce: &str) -> String {
+        match self {
+            Self::HmacSha256 => {
+                format!("${{HMAC(sha256,${{BLAZING_AGI_DIGEST_SECRET}},{nonce})}}")
+            }
+            Self::Sha256 => format!("${{SHA256(${{BLAZING_AGI_DIGEST_SECRET}}:{nonce})}}"),
+            Self::Sha1 => format!("${{SHA1(${{BLAZING_AGI_DIGEST_SECRET}}:{nonce})}}"),
+        }
+    }
+
+    /// Is `digest` the answer to `nonce` with `secret`?
+    ///
+    /// Compares in constant time.
+    fn verify(&self, secret: &str, nonce: &str, digest: &[u8]) -> bool {
+        match self {
+            Self::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                    .expect("HMAC takes keys of any length");
+                mac.update(nonce.as_bytes());
+                mac.verify_slice(digest).is_ok()
+            }
+            Self::Sha256 => Sha256::digest(format!("{secret}:{nonce}"))
+                .as_slice()
+                .ct_eq(digest)
+                .into(),
+            Self::Sha1 => Sha1::digest(format!("{secret}:{nonce}"))
+                .as_slice()
+                .ct_eq(digest)
+                .into(),
+        }
+    }
+}
+
+fn create_nonce() -> String {
+    let mut raw_bytes = [0_u8; 20];
+    let now_in_secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Should be after the epoch");
+    // 8 bytes against reuse
+    raw_bytes[0..=7].clone_from_slice(&now_in_secs.as_secs().to_le_bytes());
+    // 4 bytes against reuse
+    raw_bytes[8..=11].clone_from_slice(&now_in_secs.subsec_millis().to_le_bytes());
+    // 8 bytes against predictability
+    rand::rngs::ThreadRng::default().fill(&mut raw_bytes[12..=19]);
+    hex::encode(raw_bytes)
+}
+
+/// The nonces handed out and not yet answered
+#[derive(Debug)]
+struct NonceCache {
+    lifetime: Duration,
+    issued: Mutex<HashMap<String, Instant>>,
+}
+impl NonceCache {
+    fn new(lifetime: Duration) -> Self {
+        NonceCache {
+            lifetime,
+            issued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A nonce that was never handed out before
+    fn issue(&self) -> String {
+        let mut issued = self.issued.lock().expect("nonce cache lock poisoned");
+        let now = Instant::now();
+        // forget the nonces that were never answered
+        issued.retain(|_, at| now.duration_since(*at) <= self.lifetime);
+        loop {
+            let nonce = create_nonce();
+            if !issued.contains_key(&nonce) {
+                issued.insert(nonce.clone(), now);
+                return nonce;
+            };
+        }
+    }
+
+    /// Was `nonce` handed out within the lifetime and not used yet?
+    ///
+    /// The nonce cannot be used again afterwards.
+    fn consume(&self, nonce: &str) -> bool {
+        self.issued
+            .lock()
+            .expect("nonce cache lock poisoned")
+            .remove(nonce)
+            .is_some_and(|at| at.elapsed() <= self.lifetime)
+    }
+}
+
+/// Only lets requests through if asterisk knows one of `secrets`.
+///
+/// Several secrets are accepted at once, so that they can be rotated without downtime.
+#[derive(Clone)]
+pub(super) struct DigestOverAGI {
+    algorithm: DigestAlgorithm,
+    secrets: Arc<Vec<String>>,
+    nonces: Arc<NonceCache>,
+}
+impl std::fmt::Debug for DigestOverAGI {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DigestOverAGI")
+            .field("algorithm", &self.algorithm)
+            .field("secrets", &"[redacted]")
+            .field("nonces", &self.nonces)
+            .finish()
+    }
+}
+impl DigestOverAGI {
+    pub fn new(algorithm: DigestAlgorithm, secrets: Vec<String>) -> Self {
+        Self {
+            algorithm,
+            secrets: Arc::new(secrets),
+            nonces: Arc::new(NonceCache::new(NONCE_LIFETIME)),
+        }
+    }
+
+    /// Does `digest` answer `nonce` with any of our secrets?
+    fn accepts(&self, nonce: &str, digest: &[u8]) -> bool {
+        self.secrets
+            .iter()
+            .any(|secret| self.algorithm.verify(secret, nonce, digest))
+    }
+}
+#[async_trait]
+impl AGIHandler for DigestOverAGI {
+    // Note: this handler does not care about the request.
+    // It simply ignores it and does the AGI digest.
+    // This handler effectively works as a layer later)
+    #[tracing::instrument(name="DigestOverAGI::handle",skip(self, connection), level=Level::DEBUG)]
+    async fn handle(&self, connection: &mut Connection, _: &AGIRequest) -> Result<(), AGIError> {
+        let nonce = self.nonces.issue();
+        let digest_response = connection
+            .send_command(GetFullVariable::new(self.algorithm.expression(&nonce)))
+            .await?;
+        let AGIResponse::Ok(inner_response) = digest_response else {
+            return Err(AGIError::Not200(digest_response.into()));
+        };
+        let Some(digest_as_str) = inner_response.value else {
+            return Err(AGIError::ClientSideError(
+                "The client did not actually send operational data".to_string(),
+            ));
+        };
+        // used up even if the answer is wrong, so every nonce gets exactly one try
+        let fresh = self.nonces.consume(&nonce);
+        let digest = hex::decode(digest_as_str.trim()).map_err(|_| {
+            METRICS.digest_failures.inc();
+            AGIError::InnerError(Box::new(DigestError::DecodeError))
+        })?;
+        if !fresh {
+            METRICS.digest_failures.inc();
+            event!(Level::WARN, "AGI client answered an expired nonce.");
+            return Err(AGIError::ClientSideError(
+                "The Client answered too late.".to_string(),
+            ));
+        };
+        if !self.accepts(&nonce, &digest) {
+            METRICS.digest_failures.inc();
+            // never log the expected digest, it would let anyone reading the logs in
+            event!(Level::WARN, "AGI client supplied a wrong digest.");
+            connection
+                .send_command(Verbose::new("Unauthenticated: Wrong Digest.".to_string()))
+                .await?;
+            return Err(AGIError::ClientSideError(
+                "The Client supplied the wrong digest data.".to_string(),
+            ));
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{DigestAlgorithm, DigestOverAGI, NonceCache};
+
+    #[test]
+    fn known_digests() {
+        // HMAC-SHA256 test case 2 from RFC 4231
+        let hmac = hex::decode("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+            .unwrap();
+        assert!(DigestAlgorithm::HmacSha256.verify("Jefe", "what do ya want for nothing?", &hmac));
+        assert!(!DigestAlgorithm::HmacSha256.verify("jefe", "what do ya want for nothing?", &hmac));
+        // SHA256("secret:nonce")
+        let sha256 =
+            hex::decode("299621064c68edb7df58e24dfcf7bebbaf7cd786ee38dcef2fe749381af5e433")
+                .unwrap();
+        assert!(DigestAlgorithm::Sha256.verify("secret", "nonce", &sha256));
+        assert!(!DigestAlgorithm::Sha256.verify("secret", "nonce", &sha256[..4]));
+        // SHA1("secret:nonce")
+        let sha1 = hex::decode("f2018eb111fc52d73f4c06618655ca848003652f").unwrap();
+        assert!(DigestAlgorithm::Sha1.verify("secret", "nonce", &sha1));
+        assert!(!DigestAlgorithm::Sha1.verify("secret", "other", &sha1));
+    }
+
+    #[test]
+    fn rotating_secrets() {
+        let auth = DigestOverAGI::new(
+            DigestAlgorithm::HmacSha256,
+            vec!["old".to_string(), "new".to_string()],
+        );
+        let answer = |secret: &str| {
+            use hmac::Mac;
+            let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+            mac.update(b"nonce");
+            mac.finalize().into_bytes().to_vec()
+        };
+        assert!(auth.accepts("nonce", &answer("old")));
+        assert!(auth.accepts("nonce", &answer("new")));
+        assert!(!auth.accepts("nonce", &answer("other")));
+    }
+
+    #[test]
+    fn nonces_are_used_once() {
+        let cache = NonceCache::new(Duration::from_secs(30));
+        let nonce = cache.issue();
+        assert_ne!(cache.issue(), nonce);
+        assert!(cache.consume(&nonce));
+        assert!(!cache.consume(&nonce));
+        assert!(!cache.consume("never-issued"));
+
+        let cache = NonceCache::new(Duration::ZERO);
+        let nonce = cache.issue();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(!cache.consume(&nonce));
+    }
+}
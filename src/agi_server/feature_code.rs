@@ -0,0 +1,432 @@
+//! Set and clear call forwards from the phone with feature codes
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use blazing_agi::{
+    command::{AGIResponse, Answer, RawCommand},
+    connection::Connection,
+    handler::AGIHandler,
+    AGIError, AGIRequest,
+};
+use tracing::{event, Level};
+
+use crate::{
+    db::{apply_call_forward_changes, get_call_forwards_from_startpoint, DBError},
+    types::{CallForward, Config, ForwardChange, ForwardCondition, HasId, NoId},
+};
+
+use super::{applicable_call_forward, caller_id_of};
+
+/// played before collecting the destination
+const PROMPT_SOUND: &str = "vm-enter-num-to-call";
+/// played after a call forward was set
+const SET_SOUND: &str = "call-fwd-unconditional";
+/// played after the call forwards were cleared and when reading back without a call forward
+const CLEARED_SOUND: &str = "call-fwd-cancelled";
+/// played when the feature code could not be carried out
+const ERROR_SOUND: &str = "an-error-has-occured";
+
+/// How long to wait for digits to be entered, in milliseconds
+const GET_DATA_TIMEOUT: u32 = 10000;
+/// The most digits a destination may have
+const GET_DATA_MAX_DIGITS: u32 = 20;
+
+/// The feature codes understood by [`HandleFeatureCode`]
+#[derive(Debug, PartialEq)]
+pub(super) enum FeatureCode {
+    /// `*21` - forward all calls to a destination entered via DTMF
+    Set,
+    /// `#21` - remove the call forward
+    Clear,
+    /// `*#21` - read back the current destination
+    Read,
+}
+impl FeatureCode {
+    fn parse(code: &str) -> Option<FeatureCode> {
+        match code {
+            "*21" => Some(Self::Set),
+            "#21" => Some(Self::Clear),
+            "*#21" => Some(Self::Read),
+            _ => None,
+        }
+    }
+}
+
+/// Is `fwd` a call forward managed by feature codes?
+///
+/// These are the active call forwards that forward every call from every caller at any time.
+/// They may still have been created in the web UI.
+/// Call forwards with a schedule or a validity, e.g. for office hours or a vacation, and switched
+/// off call forwards are planned in the web UI and left alone.
+fn is_managed_by_feature_code(fwd: &CallForward<HasId>) -> bool {
+    fwd.active
+        && fwd.condition == ForwardCondition::Always
+        && fwd.caller_ids.is_empty()
+        && fwd.schedule.is_none()
+        && fwd.valid_from.is_none()
+        && fwd.valid_until.is_none()
+}
+
+/// The changes removing all call forwards from `extension` managed by feature codes
+async fn removals<'a>(
+    config: &'a Config,
+    extension: &str,
+) -> Result<Vec<ForwardChange<'a>>, DBError> {
+    Ok(get_call_forwards_from_startpoint(
+        config,
+        &crate::types::Extension::create_from_name(config, extension.to_string()),
+    )
+    .await?
+    .into_iter()
+    .filter(is_managed_by_feature_code)
+    .map(ForwardChange::Remove)
+    .collect())
+}
+
+/// Remove all call forwards from `extension` managed by feature codes.
+///
+/// Returns the number of removed call forwards.
+async fn clear_call_forwards(
+    config: &Config,
+    extension: &str,
+    actor: &str,
+) -> Result<usize, DBError> {
+    let changes = removals(config, extension).await?;
+    apply_call_forward_changes(config, &changes, actor, true).await?;
+    Ok(changes.len())
+}
+
+/// Forward all calls to `extension` in every context to `destination`.
+///
+/// Replaces the call forwards from `extension` managed by feature codes, in one transaction: if
+/// the new call forward overlaps or forms a loop with the remaining ones, nothing is changed.
+async fn set_call_forward<'a>(
+    config: &'a Config,
+    extension: &str,
+    destination: &str,
+    actor: &str,
+) -> Result<CallForward<'a, HasId>, DBError> {
+    let forward = CallForward::<NoId>::new(
+        config,
+        extension.to_string(),
+        vec![destination.to_string()],
+        config
+            .registry
+            .contexts()
+            .into_iter()
+            .map(|x| x.asterisk_name.clone())
+            .collect(),
+    )?;
+    let mut changes = removals(config, extension).await?;
+    changes.push(ForwardChange::Add(forward));
+    Ok(apply_call_forward_changes(config, &changes, actor, true)
+        .await?
+        .pop()
+        .expect("the call forward was added"))
+}
+
+/// The route handler for feature_code
+///
+/// The caller id of the call is the extension whose call forward is changed.
+#[derive(Debug)]
+pub(super) struct HandleFeatureCode {
+    config: Arc<Config>,
+}
+impl HandleFeatureCode {
+    pub fn new(config: Arc<Config>) -> Self {
+        HandleFeatureCode { config }
+    }
+}
+
+/// Play `sound` to the caller, ignoring any DTMF entered meanwhile
+pub(super) async fn play(connection: &mut Connection, sound: &str) -> Result<(), AGIError> {
+    match connection
+        .send_command(RawCommand::new(format!("STREAM FILE {sound} \"\"")))
+        .await?
+    {
+        AGIResponse::Ok(_) => Ok(()),
+        m => Err(AGIError::Not200(m.into())),
+    }
+}
+
+/// Carry out `feature_code` for `extension`, reading back the call forward in `context_name`.
+///
+/// Changes are recorded in the audit log as made by `actor`.
+pub(super) async fn carry_out(
+    connection: &mut Connection,
+    config: &Config,
+    extension: &str,
+    context_name: &str,
+    feature_code: &FeatureCode,
+    actor: &str,
+) -> Result<(), AGIError> {
+    match feature_code {
+        FeatureCode::Set => {
+            let destination = get_digits(connection, PROMPT_SOUND, GET_DATA_MAX_DIGITS).await?;
+            if destination.is_empty() || !destination.chars().all(|c| c.is_ascii_digit()) {
+                event!(
+                    Level::INFO,
+                    "{extension} did not enter a destination for a call forward."
+                );
+                play(connection, ERROR_SOUND).await?;
+                return Ok(());
+            };
+            match set_call_forward(config, extension, &destination, actor).await {
+                Ok(fwd) => {
+                    event!(
+                        Level::INFO,
+                        "{extension} set call forward {} to {destination} from the phone.",
+                        fwd.fwd_id
+                    );
+                    play(connection, SET_SOUND).await?;
+                    let _ = connection
+                        .send_command(RawCommand::new(format!("SAY DIGITS {destination} \"\"")))
+                        .await?;
+                }
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "{extension} could not set a call forward to {destination} from the phone: {e}"
+                    );
+                    play(connection, ERROR_SOUND).await?;
+                }
+            };
+        }
+        FeatureCode::Clear => {
+            match clear_call_forwards(config, extension, actor).await {
+                Ok(removed) => {
+                    event!(
+                        Level::INFO,
+                        "{extension} removed {removed} call forwards from the phone."
+                    );
+                    play(connection, CLEARED_SOUND).await?;
+                }
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "{extension} could not remove call forwards from the phone: {e}"
+                    );
+                    play(connection, ERROR_SOUND).await?;
+                }
+            };
+        }
+        FeatureCode::Read => {
+            let current = applicable_call_forward(
+                config,
+                extension,
+                context_name,
+                &ForwardCondition::Always,
+                None,
+            )
+            .await
+            .map_err(|e| AGIError::InnerError(Box::new(e)))?;
+            match current {
+                Some(fwd) => {
+                    play(connection, SET_SOUND).await?;
+                    for dest in fwd.destination_extensions() {
+                        let _ = connection
+                            .send_command(RawCommand::new(format!("SAY DIGITS {dest} \"\"")))
+                            .await?;
+                    }
+                }
+                None => {
+                    play(connection, CLEARED_SOUND).await?;
+                }
+            };
+        }
+    };
+    Ok(())
+}
+
+/// Play `sound` and collect up to `max_digits` DTMF digits, terminated by `#`.
+///
+/// Returns an empty string if nothing was entered before the timeout.
+pub(super) async fn get_digits(
+    connection: &mut Connection,
+    sound: &str,
+    max_digits: u32,
+) -> Result<String, AGIError> {
+    match connection
+        .send_command(RawCommand::new(format!(
+            "GET DATA {sound} {GET_DATA_TIMEOUT} {max_digits}"
+        )))
+        .await?
+    {
+        // asterisk returns -1 if the channel hung up
+        AGIResponse::Ok(inner) if inner.result.starts_with('-') => {
+            Err(AGIError::ClientSideError("GET DATA failed.".to_string()))
+        }
+        AGIResponse::Ok(inner) => Ok(inner.result),
+        m => Err(AGIError::Not200(m.into())),
+    }
+}
+
+#[async_trait]
+impl AGIHandler for HandleFeatureCode {
+    async fn handle(
+        &self,
+        connection: &mut Connection,
+        request: &AGIRequest,
+    ) -> Result<(), AGIError> {
+        let dump = &request.variables;
+        let code = dump
+            .custom_args
+            .get(&1)
+            .ok_or(AGIError::NotEnoughCustomVariables(0, 2))?;
+        let context_name = dump
+            .custom_args
+            .get(&2)
+            .ok_or(AGIError::NotEnoughCustomVariables(1, 2))?;
+        let Some(feature_code) = FeatureCode::parse(code) else {
+            return Err(AGIError::ClientSideError(format!(
+                "Unknown feature code {code}."
+            )));
+        };
+        match connection.send_command(Answer::new()).await? {
+            AGIResponse::Ok(_) => {}
+            m => return Err(AGIError::Not200(m.into())),
+        };
+
+        let Some(extension) = caller_id_of(request)
+            .filter(|x| self.config.registry.has_extension(x))
+            .map(|x| x.to_string())
+        else {
+            event!(
+                Level::WARN,
+                "Feature code {code} used by unknown caller {}.",
+                dump.callerid
+            );
+            play(connection, ERROR_SOUND).await?;
+            return Ok(());
+        };
+
+        carry_out(
+            connection,
+            &self.config,
+            &extension,
+            context_name,
+            &feature_code,
+            &format!("phone:{extension}"),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::PgPool;
+
+    use super::{clear_call_forwards, set_call_forward, FeatureCode};
+    use crate::{
+        db::{get_call_forwards_from_startpoint, new_call_forward},
+        types::{CallForward, Config, NoId, Schedule, ScheduleWindow},
+    };
+
+    #[test]
+    fn parse_feature_codes() {
+        assert_eq!(FeatureCode::parse("*21"), Some(FeatureCode::Set));
+        assert_eq!(FeatureCode::parse("#21"), Some(FeatureCode::Clear));
+        assert_eq!(FeatureCode::parse("*#21"), Some(FeatureCode::Read));
+        assert_eq!(FeatureCode::parse("*22"), None);
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward")))]
+    async fn set_and_clear(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let from = crate::types::Extension::create_from_name(&config, "702".to_string());
+
+        // replaces the unconditional call forward from 702, but not the one for when it is busy
+        let fwd = set_call_forward(&config, "702", "0151234", "phone:702").await?;
+        assert_eq!(fwd.destination_extensions(), vec!["0151234"]);
+        assert_eq!(fwd.in_contexts.len(), config.registry.contexts().len());
+        let res = get_call_forwards_from_startpoint(&config, &from).await?;
+        assert_eq!(res.len(), 2);
+
+        assert_eq!(clear_call_forwards(&config, "702", "phone:702").await?, 1);
+        let res = get_call_forwards_from_startpoint(&config, &from).await?;
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].destination_extensions(), vec!["something-external"]);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward")))]
+    async fn clear_keeps_planned(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let from = crate::types::Extension::create_from_name(&config, "703".to_string());
+
+        let scheduled = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["704".to_string()],
+            vec!["from_internal".to_string()],
+        )?
+        .set_schedule(Some(Schedule {
+            timezone: chrono_tz::Tz::UTC,
+            windows: vec![ScheduleWindow::default()],
+        }));
+        new_call_forward(&config, scheduled, "jdoe").await?;
+        let switched_off = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["704".to_string()],
+            vec!["from_sales".to_string()],
+        )?
+        .set_active(false);
+        new_call_forward(&config, switched_off, "jdoe").await?;
+
+        // only the unconditional call forward to 702 is removed
+        assert_eq!(clear_call_forwards(&config, "703", "phone:703").await?, 1);
+        let res = get_call_forwards_from_startpoint(&config, &from).await?;
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().any(|x| x.schedule.is_some()));
+        assert!(res.iter().any(|x| !x.active));
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward")))]
+    async fn set_overlapping_keeps_existing(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let from = crate::types::Extension::create_from_name(&config, "704".to_string());
+
+        let now = chrono::Utc::now();
+        let vacation = CallForward::<NoId>::new(
+            &config,
+            "704".to_string(),
+            vec!["0151234".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_validity(None, Some(now + chrono::Duration::days(7)));
+        new_call_forward(&config, vacation, "jdoe").await?;
+
+        // the new call forward would overlap the vacation in from_external
+        assert!(set_call_forward(&config, "704", "703", "phone:704")
+            .await
+            .is_err());
+        let res = get_call_forwards_from_startpoint(&config, &from).await?;
+        assert_eq!(res.len(), 2);
+        assert!(res
+            .iter()
+            .any(|x| x.destination_extensions() == vec!["something-external"]));
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward")))]
+    async fn set_cyclic_keeps_existing(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let from = crate::types::Extension::create_from_name(&config, "703".to_string());
+
+        // 703 cannot be forwarded to itself, so its call forward to 702 stays
+        assert!(set_call_forward(&config, "703", "703", "phone:703")
+            .await
+            .is_err());
+        let res = get_call_forwards_from_startpoint(&config, &from).await?;
+        assert_eq!(res.len(), 1);
+        Ok(())
+    }
+}
@@ -1,135 +1,348 @@
-use std::{fmt::Display, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
 use async_trait::async_trait;
 use blazing_agi::{
-    command::{AGIResponse, GetFullVariable, SetVariable, Verbose},
-    connection::Connection,
-    handler::AGIHandler,
-    router::Router,
-    serve::serve,
-    AGIError, AGIRequest,
+    command::SetVariable, connection::Connection, handler::AGIHandler, router::Router,
+    serve::serve, AGIError, AGIRequest,
 };
 use blazing_agi_macros::layer_before;
+use chrono::Utc;
 use rand::Rng;
-use sha1::{Digest, Sha1};
-use tokio::net::TcpListener;
-use tracing::{event, Level};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+};
+use tracing::{event, Instrument, Level};
+
+mod digest;
+mod feature_code;
+mod peer;
+mod remote;
+
+pub use digest::DigestAlgorithm;
+use digest::DigestOverAGI;
+pub use peer::AgiPeer;
 
 use crate::{
-    db::get_call_forwards_from_startpoint,
-    types::{Config, Extension},
+    db::{
+        delete_call_forwards_deleted_before, delete_call_forwards_expired_before,
+        delete_call_lookups_before, insert_call_lookup, DBError,
+    },
+    metrics::METRICS,
+    types::{CallForward, CallLookup, Config, ForwardCondition, HasId, Validity},
 };
 
-#[derive(Debug, Clone)]
-enum SHA1DigestError {
-    DecodeError,
+/// How many call lookups may wait to be logged before new ones are dropped
+const LOOKUP_QUEUE_SIZE: usize = 1000;
+/// How often old call lookups, expired call forwards and deleted call forwards are removed
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+/// How long the routers of a replaced config still take connections, which were accepted just
+/// before the config changed
+const ROUTER_RETIREMENT_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+/// How long to wait before accepting again after accepting an AGI connection failed
+const ACCEPT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// The route handler for call_forward
+
+#[derive(Debug)]
+struct HandleCallForward {
+    config: Arc<Config>,
+}
+impl HandleCallForward {
+    pub fn new(config: Arc<Config>) -> Self {
+        HandleCallForward { config }
+    }
+}
+/// The final destination of a call after following all applicable call forwards
+#[derive(Debug, PartialEq)]
+enum Resolution {
+    /// No call forward applies to the dialed extension
+    NotForwarded,
+    /// The call is forwarded (possibly over several call forwards) to these extensions,
+    /// starting with the call forward with this id
+    Forwarded(i32, Vec<String>),
+    /// The call forwards form a loop
+    Cycle,
 }
-impl Display for SHA1DigestError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl Resolution {
+    /// The result label in the call forward metrics
+    fn metric_label(&self) -> &'static str {
         match self {
-            Self::DecodeError => {
-                write!(f, "The returned digest was not decodable as u8")
-            }
+            Self::NotForwarded => "not_forwarded",
+            Self::Forwarded(_, _) => "forwarded",
+            Self::Cycle => "cycle",
         }
     }
 }
-impl std::error::Error for SHA1DigestError {}
 
-fn create_nonce() -> String {
-    let mut raw_bytes = [0_u8; 20];
-    // let mut raw_bytes: Vec<u8> = Vec::with_capacity(20);
-    let now_in_secs = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Should be after the epoch");
-    // 8 bytes against reuse
-    raw_bytes[0..=7].clone_from_slice(&now_in_secs.as_secs().to_le_bytes());
-    // 4 bytes against reuse
-    raw_bytes[8..=11].clone_from_slice(&now_in_secs.subsec_millis().to_le_bytes());
-    // 8 bytes against predictability
-    rand::rngs::ThreadRng::default().fill(&mut raw_bytes[12..=19]);
-    hex::encode(raw_bytes)
-}
+/// Get the call forward from `extension` with the same kind of condition as `condition`
+/// which applies right now in the context `context_name` to calls from `caller_id`.
+///
+/// If several call forwards apply, an exact caller id match takes precedence over prefix matches,
+/// longer prefixes over shorter ones, and any match over call forwards for every caller.
+async fn applicable_call_forward<'a>(
+    config: &'a Config,
+    extension: &str,
+    context_name: &str,
+    condition: &ForwardCondition,
+    caller_id: Option<&str>,
+) -> Result<Option<CallForward<'a, HasId>>, DBError> {
+    let call_forwards_from_src = config
+        .forward_cache
+        .call_forwards_from(config, extension, context_name)
+        .await?;
 
-#[derive(Clone, Debug)]
-struct SHA1DigestOverAGI {
-    secret: String,
+    // select the most specific call_forward which has the relevant context set,
+    // whose schedule is currently active and which applies to the caller
+    let mut best: Option<(usize, CallForward<'a, HasId>)> = None;
+    for fwd in call_forwards_from_src.into_iter().filter(|fwd| {
+        if !fwd.condition.same_kind(condition) {
+            return false;
+        };
+        if !fwd
+            .in_contexts
+            .iter()
+            .any(|&x| x.asterisk_name == *context_name)
+        {
+            return false;
+        };
+        let now = Utc::now();
+        if !fwd.is_active_at(&now) {
+            let reason = if !fwd.active {
+                "switched off"
+            } else {
+                match fwd.validity_at(&now) {
+                    Validity::Upcoming => "not valid yet",
+                    Validity::Expired => "expired",
+                    Validity::Valid => "outside of its schedule",
+                }
+            };
+            event!(
+                Level::DEBUG,
+                "Call forward {} from {extension} is {reason}.",
+                fwd.fwd_id
+            );
+            return false;
+        };
+        true
+    }) {
+        let Some(specificity) = fwd.caller_id_specificity(caller_id) else {
+            continue;
+        };
+        if best.as_ref().is_none_or(|(x, _)| specificity > *x) {
+            best = Some((specificity, fwd));
+        };
+    }
+    Ok(best.map(|(_, fwd)| fwd))
 }
-impl SHA1DigestOverAGI {
-    pub fn new<S: AsRef<str>>(secret: S) -> Self {
-        Self {
-            secret: secret.as_ref().to_string(),
+
+/// Follow the call forwards starting at `initial_dest` in the context `context_name`.
+///
+/// The first call forward has to match `condition`. Every destination of a call forward is then
+/// followed on its own through unconditional call forwards,
+/// up to `config.max_forward_depth` call forwards in a row.
+/// `caller_id` stays the same over all call forwards.
+async fn resolve_call_forward(
+    config: &Config,
+    initial_dest: &str,
+    context_name: &str,
+    condition: &ForwardCondition,
+    caller_id: Option<&str>,
+) -> Result<Resolution, DBError> {
+    // the extensions we still need to follow, together with the extensions forwarded to them
+    let mut pending = vec![(initial_dest.to_string(), vec![])];
+    let mut final_dests = vec![];
+    // the call forward applied to the dialed extension itself
+    let mut first_fwd_id = None;
+    while let Some((current, mut path)) = pending.pop() {
+        let next_forward = if path.len() < config.max_forward_depth {
+            // conditions only apply to the dialed extension itself
+            let this_condition = if path.is_empty() {
+                condition
+            } else {
+                &ForwardCondition::Always
+            };
+            applicable_call_forward(config, &current, context_name, this_condition, caller_id)
+                .await?
+        } else {
+            event!(
+                Level::DEBUG,
+                "Stopped following call forwards from {initial_dest} at {current} after {} call forwards.",
+                config.max_forward_depth
+            );
+            None
+        };
+        let Some(fwd) = next_forward else {
+            if !final_dests.contains(&current) {
+                final_dests.push(current);
+            };
+            continue;
+        };
+        event!(
+            Level::DEBUG,
+            "Call to {current} is forwarded to {:?} by call forward {}.",
+            fwd.destination_extensions(),
+            fwd.fwd_id
+        );
+        if path.is_empty() {
+            first_fwd_id = Some(fwd.fwd_id.into());
+        };
+        path.push(current);
+        // push in reverse, so that the destinations are followed in the order they were entered
+        for dest in fwd.to.iter().rev() {
+            if path.contains(&dest.extension) {
+                return Ok(Resolution::Cycle);
+            };
+            pending.push((dest.extension.clone(), path.clone()));
         }
     }
-}
-#[async_trait]
-impl AGIHandler for SHA1DigestOverAGI {
-    // Note: this handler does not care about the request.
-    // It simply ignores it and does the AGI digest.
-    // This handler effectively works as a layer later)
-    #[tracing::instrument(name="SHA1DigestOverAGI::handle",skip(self, connection), level=Level::DEBUG)]
-    async fn handle(&self, connection: &mut Connection, _: &AGIRequest) -> Result<(), AGIError> {
-        let nonce = create_nonce();
-        let mut hasher = Sha1::new();
-        hasher.update(self.secret.as_bytes());
-        hasher.update(":".as_bytes());
-        hasher.update(nonce.as_bytes());
-        let expected_digest: [u8; 20] = hasher.finalize().into();
-        let digest_response = connection
-            .send_command(GetFullVariable::new(format!(
-                "${{SHA1(${{BLAZING_AGI_DIGEST_SECRET}}:{})}}",
-                nonce
-            )))
-            .await?;
-        match digest_response {
-            AGIResponse::Ok(inner_response) => {
-                if let Some(digest_as_str) = inner_response.value {
-                    if expected_digest
-                        != *hex::decode(&digest_as_str).map_err(|_| {
-                            AGIError::InnerError(Box::new(SHA1DigestError::DecodeError))
-                        })?
-                    {
-                        event!(
-                            Level::WARN,
-                            "Expected Digest {}, got {}. Nonce is {}",
-                            hex::encode(expected_digest),
-                            digest_as_str,
-                            nonce
-                        );
-                        connection
-                            .send_command(Verbose::new(
-                                "Unauthenticated: Wrong Digest.".to_string(),
-                            ))
-                            .await?;
-                        Err(AGIError::ClientSideError(
-                            "The Client supplied the wrong digest data.".to_string(),
-                        ))
-                    } else {
-                        Ok(())
-                    }
-                } else {
-                    Err(AGIError::ClientSideError(
-                        "The client did not actually send operational data".to_string(),
-                    ))
-                }
-            }
-            m => {
-                return Err(AGIError::Not200(m.into()));
-            }
+
+    match first_fwd_id {
+        Some(fwd_id) if final_dests != [initial_dest] => {
+            Ok(Resolution::Forwarded(fwd_id, final_dests))
         }
+        _ => Ok(Resolution::NotForwarded),
     }
 }
 
-/// The route handler for call_forward
+/// The caller id of the call, if asterisk knows it
+fn caller_id_of(request: &AGIRequest) -> Option<&str> {
+    match request.variables.callerid.as_str() {
+        "" | "unknown" => None,
+        x => Some(x),
+    }
+}
 
+/// The call lookups waiting to be logged by [`run_lookup_writer`], shared by all clones of a
+/// [`Config`]
 #[derive(Debug)]
-struct HandleCallForward {
-    config: Arc<Config>,
+pub struct LookupQueue {
+    sender: mpsc::Sender<CallLookup>,
+    /// taken by [`run_lookup_writer`]
+    receiver: Mutex<Option<mpsc::Receiver<CallLookup>>>,
 }
-impl HandleCallForward {
-    pub fn new(config: Arc<Config>) -> Self {
-        HandleCallForward { config }
+impl Default for LookupQueue {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel(LOOKUP_QUEUE_SIZE);
+        LookupQueue {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+}
+
+/// Log `lookup` in the DB without delaying the call.
+///
+/// If [`LOOKUP_QUEUE_SIZE`] lookups are waiting already, e.g. because the DB is unreachable, it is
+/// dropped and only counted.
+fn log_call_lookup(config: &Config, lookup: CallLookup) {
+    if let Err(e) = config.lookup_queue.sender.try_send(lookup) {
+        METRICS.dropped_call_lookups.inc();
+        event!(Level::DEBUG, "Dropped a call lookup: {e}");
+    };
+}
+
+/// Write the call lookups passed to [`log_call_lookup`] to the DB, one after the other.
+///
+/// Only one of these may run, later ones return at once. This never returns otherwise.
+pub async fn run_lookup_writer(config: watch::Receiver<Arc<Config>>) {
+    let receiver = config
+        .borrow()
+        .lookup_queue
+        .receiver
+        .lock()
+        .expect("lookup queue lock poisoned")
+        .take();
+    let Some(mut lookups) = receiver else {
+        return;
+    };
+    while let Some(lookup) = lookups.recv().await {
+        let config = config.borrow().clone();
+        if let Err(e) = insert_call_lookup(&config, &lookup).await {
+            event!(
+                Level::WARN,
+                "Could not log the lookup for {}: {e}",
+                lookup.dialed_extension
+            );
+        };
+    }
+}
+
+/// Delete the call lookups older than `config.lookup_retention` once an hour. This never returns.
+pub async fn run_lookup_purge(config: watch::Receiver<Arc<Config>>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let config = config.borrow().clone();
+        match delete_call_lookups_before(&config, Utc::now() - config.lookup_retention).await {
+            Ok(0) => {}
+            Ok(x) => event!(Level::INFO, "Deleted {x} old call lookups."),
+            Err(e) => event!(Level::WARN, "Could not delete old call lookups: {e}"),
+        };
+    }
+}
+
+/// Delete the call forwards that expired more than `config.expired_forward_retention` ago once an
+/// hour. This never returns.
+///
+/// Until then, they are kept so users can see what was set and set it up again.
+pub async fn run_expired_forward_purge(config: watch::Receiver<Arc<Config>>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let config = config.borrow().clone();
+        match delete_call_forwards_expired_before(
+            &config,
+            Utc::now() - config.expired_forward_retention,
+        )
+        .await
+        {
+            Ok(0) => {}
+            Ok(x) => event!(Level::INFO, "Deleted {x} expired call forwards."),
+            Err(e) => event!(Level::WARN, "Could not delete expired call forwards: {e}"),
+        };
+    }
+}
+
+/// Remove the call forwards deleted more than `config.deleted_forward_retention` ago for good once
+/// an hour. This never returns.
+///
+/// Until then, they can be restored.
+pub async fn run_deleted_forward_purge(config: watch::Receiver<Arc<Config>>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let config = config.borrow().clone();
+        match delete_call_forwards_deleted_before(
+            &config,
+            Utc::now() - config.deleted_forward_retention,
+        )
+        .await
+        {
+            Ok(0) => {}
+            Ok(x) => event!(Level::INFO, "Removed {x} deleted call forwards for good."),
+            Err(e) => event!(Level::WARN, "Could not remove deleted call forwards: {e}"),
+        };
     }
 }
+
+/// The string passed to Dial() in asterisk to ring all `extensions` in parallel, each prefixed
+/// with `technology` if there is one
+fn dial_string(technology: Option<&str>, extensions: &[String]) -> String {
+    extensions
+        .iter()
+        .map(|x| match technology {
+            Some(tech) => format!("{tech}/{x}"),
+            None => x.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
 #[async_trait::async_trait]
 impl AGIHandler for HandleCallForward {
     async fn handle(
@@ -147,60 +360,457 @@ impl AGIHandler for HandleCallForward {
             .get(&2)
             .ok_or(AGIError::NotEnoughCustomVariables(1, 2))?;
 
-        let call_forwards_from_src = get_call_forwards_from_startpoint(
+        let caller_id = caller_id_of(request);
+        let looked_up_at = Utc::now();
+
+        let resolution = resolve_call_forward(
             &self.config,
-            &Extension::create_from_name(&self.config, initial_dest.to_string()),
+            initial_dest,
+            context_name,
+            &ForwardCondition::Always,
+            caller_id,
         )
         .await
         .map_err(|e| AGIError::InnerError(Box::new(e)))?;
 
-        // select the first call_forward which has the relevant context set
-        // and use its destination
-        for fwd in call_forwards_from_src.iter() {
-            if fwd
-                .in_contexts
-                .iter()
-                .any(|&x| x.asterisk_name == *context_name)
-            {
+        METRICS
+            .call_forward_results
+            .with_label_values(&["call_forward", resolution.metric_label()])
+            .inc();
+        // how long to ring the dialed extension before the no-answer call forward applies
+        let mut no_answer_timeout = None;
+        let (fwd_id, final_dests) = match resolution {
+            Resolution::Forwarded(fwd_id, dests) => {
+                event!(Level::INFO, "Call to {initial_dest} forwarded to {dests:?}");
+                (Some(fwd_id), dests)
+            }
+            Resolution::NotForwarded => {
+                // do not set a call forward, since no context matches
+                // instead repeat the initial destination as the final destination
                 event!(
                     Level::INFO,
-                    "Call to {initial_dest} forwarded to {}",
-                    fwd.to.extension
+                    "Call to {initial_dest} did not need forwarding."
                 );
-                connection
-                    .send_command(SetVariable::new(
-                        "CALL_FORWARDED_TO".to_string(),
-                        fwd.to.extension.to_string(),
-                    ))
-                    .await?;
-                return Ok(());
-            } else {
-                continue;
-            };
-        }
-        // do not set a call forward, since no context matches
-        // instead repeat the initial destination as the final destination
-        event!(
-            Level::INFO,
-            "Call to {initial_dest} did not need forwarding."
+                no_answer_timeout = applicable_call_forward(
+                    &self.config,
+                    initial_dest,
+                    context_name,
+                    &ForwardCondition::NoAnswer(0),
+                    caller_id,
+                )
+                .await
+                .map_err(|e| AGIError::InnerError(Box::new(e)))?
+                .and_then(|fwd| fwd.condition.no_answer_timeout());
+                (None, vec![initial_dest.to_string()])
+            }
+            Resolution::Cycle => {
+                event!(
+                    Level::WARN,
+                    "Call forwards from {initial_dest} form a loop in context {context_name}. Not forwarding the call."
+                );
+                (None, vec![initial_dest.to_string()])
+            }
+        };
+        log_call_lookup(
+            &self.config,
+            CallLookup {
+                looked_up_at,
+                caller_id: caller_id.map(str::to_string),
+                dialed_extension: initial_dest.to_string(),
+                context: context_name.to_string(),
+                destinations: final_dests.clone(),
+                fwd_id,
+            },
+        );
+        connection
+            .send_command(SetVariable::new(
+                "CALL_FORWARDED_TO".to_string(),
+                dial_string(self.config.dial_technology.as_deref(), &final_dests),
+            ))
+            .await?;
+        connection
+            .send_command(SetVariable::new(
+                "CALL_FORWARDED_TO_EXTENSIONS".to_string(),
+                final_dests.join("&"),
+            ))
+            .await?;
+        connection
+            .send_command(SetVariable::new(
+                "CALL_FORWARD_TIMEOUT".to_string(),
+                no_answer_timeout.map(|x| x.to_string()).unwrap_or_default(),
+            ))
+            .await?;
+        // lets the dialplan tell apart answers from outdated call forwards while the DB is down
+        connection
+            .send_command(SetVariable::new(
+                "CALL_FORWARD_DEGRADED".to_string(),
+                if self.config.forward_cache.is_degraded() {
+                    "1".to_string()
+                } else {
+                    "0".to_string()
+                },
+            ))
+            .await?;
+        return Ok(());
+    }
+}
+
+/// The route handler for call_forward_after_dial
+///
+/// Applies the conditional call forwards after Dial() returned without the call being answered.
+#[derive(Debug)]
+struct HandleCallForwardAfterDial {
+    config: Arc<Config>,
+}
+impl HandleCallForwardAfterDial {
+    pub fn new(config: Arc<Config>) -> Self {
+        HandleCallForwardAfterDial { config }
+    }
+}
+
+#[async_trait::async_trait]
+impl AGIHandler for HandleCallForwardAfterDial {
+    async fn handle(
+        &self,
+        connection: &mut Connection,
+        request: &AGIRequest,
+    ) -> Result<(), AGIError> {
+        let dump = &request.variables;
+        let dialed = dump
+            .custom_args
+            .get(&1)
+            .ok_or(AGIError::NotEnoughCustomVariables(0, 3))?;
+        let context_name = dump
+            .custom_args
+            .get(&2)
+            .ok_or(AGIError::NotEnoughCustomVariables(1, 3))?;
+        let dial_status = dump
+            .custom_args
+            .get(&3)
+            .ok_or(AGIError::NotEnoughCustomVariables(2, 3))?;
+
+        let looked_up_at = Utc::now();
+        let resolution = match ForwardCondition::from_dial_status(dial_status) {
+            Some(condition) => resolve_call_forward(
+                &self.config,
+                dialed,
+                context_name,
+                &condition,
+                caller_id_of(request),
+            )
+            .await
+            .map_err(|e| AGIError::InnerError(Box::new(e)))?,
+            None => Resolution::NotForwarded,
+        };
+
+        METRICS
+            .call_forward_results
+            .with_label_values(&["call_forward_after_dial", resolution.metric_label()])
+            .inc();
+        // an empty destination tells the dialplan to not dial again
+        let (fwd_id, final_dests) = match resolution {
+            Resolution::Forwarded(fwd_id, dests) => {
+                event!(
+                    Level::INFO,
+                    "Call to {dialed} forwarded to {dests:?} after dial status {dial_status}"
+                );
+                (Some(fwd_id), dests)
+            }
+            Resolution::NotForwarded => {
+                event!(
+                    Level::INFO,
+                    "Call to {dialed} is not forwarded after dial status {dial_status}."
+                );
+                (None, vec![])
+            }
+            Resolution::Cycle => {
+                event!(
+                    Level::WARN,
+                    "Call forwards from {dialed} form a loop in context {context_name} after dial status {dial_status}. Not forwarding the call."
+                );
+                (None, vec![])
+            }
+        };
+        log_call_lookup(
+            &self.config,
+            CallLookup {
+                looked_up_at,
+                caller_id: caller_id_of(request).map(str::to_string),
+                dialed_extension: dialed.to_string(),
+                context: context_name.to_string(),
+                destinations: final_dests.clone(),
+                fwd_id,
+            },
         );
         connection
             .send_command(SetVariable::new(
                 "CALL_FORWARDED_TO".to_string(),
-                initial_dest.to_string(),
+                dial_string(self.config.dial_technology.as_deref(), &final_dests),
+            ))
+            .await?;
+        connection
+            .send_command(SetVariable::new(
+                "CALL_FORWARDED_TO_EXTENSIONS".to_string(),
+                final_dests.join("&"),
             ))
             .await?;
         return Ok(());
     }
 }
 
-pub async fn run_agi_server(config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
-    let agi_listener = TcpListener::bind(config.agi_bind_string.clone()).await?;
-    let router = Router::new()
-        .route("/call_forward", HandleCallForward::new(config.clone()))
-        .layer(layer_before!(SHA1DigestOverAGI::new(
-            config.agi_digest_secret.clone()
-        )));
+/// Answers the requests of `peer` to the route `route` with `inner`.
+///
+/// Refuses contexts the peer may not use, logs the peer and counts and times the requests.
+#[derive(Debug)]
+struct PeerRoute<H> {
+    peer: Arc<AgiPeer>,
+    route: &'static str,
+    /// the position of the context in the custom arguments of this route
+    context_arg: u8,
+    inner: H,
+}
+impl<H: AGIHandler> PeerRoute<H> {
+    fn new(peer: Arc<AgiPeer>, route: &'static str, context_arg: u8, inner: H) -> Self {
+        PeerRoute {
+            peer,
+            route,
+            context_arg,
+            inner,
+        }
+    }
+}
+
+#[async_trait]
+impl<H: AGIHandler> AGIHandler for PeerRoute<H> {
+    async fn handle(
+        &self,
+        connection: &mut Connection,
+        request: &AGIRequest,
+    ) -> Result<(), AGIError> {
+        let peer = &self.peer.name;
+        METRICS
+            .agi_requests
+            .with_label_values(&[self.route, peer])
+            .inc();
+        METRICS
+            .agi_last_request
+            .with_label_values(&[peer])
+            .set(Utc::now().timestamp_millis() as f64 / 1000.0);
+        event!(Level::INFO, "AGI request to {} from {peer}.", self.route);
+        // a missing context is reported by the handler itself
+        if let Some(context_name) = request.variables.custom_args.get(&self.context_arg) {
+            if !self.peer.allows_context(context_name) {
+                event!(
+                    Level::WARN,
+                    "{peer} requested {} in context {context_name}, which it may not use.",
+                    self.route
+                );
+                METRICS.agi_errors.with_label_values(&[self.route]).inc();
+                return Err(AGIError::ClientSideError(format!(
+                    "The context {context_name} is not allowed."
+                )));
+            };
+        };
+        let timer = METRICS
+            .agi_request_duration
+            .with_label_values(&[self.route])
+            .start_timer();
+        let res = self
+            .inner
+            .handle(connection, request)
+            .instrument(tracing::info_span!("agi_peer", peer = %peer))
+            .await;
+        timer.observe_duration();
+        if res.is_err() {
+            METRICS.agi_errors.with_label_values(&[self.route]).inc();
+        };
+        res
+    }
+}
+
+/// The routes served to `peer`, behind its digest and below `/token`
+fn peer_router(config: &Arc<Config>, peer: &Arc<AgiPeer>, token: &str) -> Router {
+    Router::new()
+        .route(
+            &format!("/{token}/call_forward"),
+            PeerRoute::new(
+                peer.clone(),
+                "call_forward",
+                2,
+                HandleCallForward::new(config.clone()),
+            ),
+        )
+        .route(
+            &format!("/{token}/call_forward_after_dial"),
+            PeerRoute::new(
+                peer.clone(),
+                "call_forward_after_dial",
+                2,
+                HandleCallForwardAfterDial::new(config.clone()),
+            ),
+        )
+        .route(
+            &format!("/{token}/feature_code"),
+            PeerRoute::new(
+                peer.clone(),
+                "feature_code",
+                2,
+                feature_code::HandleFeatureCode::new(config.clone()),
+            ),
+        )
+        .route(
+            &format!("/{token}/remote_call_forward"),
+            PeerRoute::new(
+                peer.clone(),
+                "remote_call_forward",
+                1,
+                remote::HandleRemoteCallForward::new(config.clone()),
+            ),
+        )
+        .layer(layer_before!(DigestOverAGI::new(
+            config.agi_digest_algorithm,
+            peer.secrets.clone()
+        )))
+}
+
+/// Add the segment `token` to the front of the path requested in `line`, if it is the
+/// `agi_request` line of the header.
+fn add_token(line: &str, token: &str) -> Option<String> {
+    let url = line.strip_prefix("agi_request:")?;
+    let host_start = url.find("://")? + 3;
+    let path_start = url[host_start..]
+        .find(['/', '?', ' ', '\r', '\n'])
+        .map_or(url.len(), |x| host_start + x);
+    Some(format!(
+        "agi_request:{}/{token}{}",
+        &url[..path_start],
+        &url[path_start..]
+    ))
+}
+
+/// Pass the connection from a peer on to the router of that peer.
+///
+/// The router only answers requests below `token`, which is added to the requested path here, so
+/// that its loopback port is of no use to anyone else.
+async fn forward_connection(inbound: TcpStream, backend: SocketAddr, token: Arc<str>) {
+    let mut outbound = match TcpStream::connect(backend).await {
+        Ok(x) => x,
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "Could not reach the AGI router at {backend}: {e}"
+            );
+            return;
+        }
+    };
+    // the header with the request ends with an empty line
+    let mut inbound = BufReader::new(inbound);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match inbound.read_line(&mut line).await {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) => {
+                event!(Level::DEBUG, "AGI connection ended with an error: {e}");
+                return;
+            }
+        };
+        let forwarded = add_token(&line, &token).unwrap_or_else(|| line.clone());
+        if let Err(e) = outbound.write_all(forwarded.as_bytes()).await {
+            event!(Level::DEBUG, "AGI connection ended with an error: {e}");
+            return;
+        };
+        if line.trim().is_empty() {
+            break;
+        };
+    }
+    if let Err(e) = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await {
+        event!(Level::DEBUG, "AGI connection ended with an error: {e}");
+    };
+}
+
+/// The routers for the peers of one config, each on its own loopback port
+struct PeerRouters {
+    backends: Vec<(Arc<AgiPeer>, SocketAddr)>,
+    /// the first segment of every path the routers answer, which only [`forward_connection`] knows
+    token: Arc<str>,
+    tasks: Vec<tokio::task::JoinHandle<()>>,
+}
+impl PeerRouters {
+    async fn start(config: &Arc<Config>) -> Result<Self, std::io::Error> {
+        let mut raw_token = [0_u8; 16];
+        rand::rngs::ThreadRng::default().fill(&mut raw_token[..]);
+        let token: Arc<str> = hex::encode(raw_token).into();
+        let mut backends = vec![];
+        let mut tasks = vec![];
+        for peer in &config.agi_peers {
+            let peer = Arc::new(peer.clone());
+            // kept, so that the router can take up the same port again after it failed
+            let peer_listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+            peer_listener.set_nonblocking(true)?;
+            backends.push((peer.clone(), peer_listener.local_addr()?));
+            let config = config.clone();
+            let token = token.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    let listener = match peer_listener.try_clone().and_then(TcpListener::from_std) {
+                        Ok(x) => x,
+                        Err(e) => {
+                            event!(
+                                Level::ERROR,
+                                "The AGI router for {} stopped: {e}",
+                                peer.name
+                            );
+                            return;
+                        }
+                    };
+                    // blazing_agi gives up on the first connection it could not accept
+                    if let Err(e) = serve(listener, peer_router(&config, &peer, &token)).await {
+                        event!(
+                            Level::WARN,
+                            "The AGI router for {} failed and is restarted: {e}",
+                            peer.name
+                        );
+                    };
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                }
+            }));
+        }
+        Ok(PeerRouters {
+            backends,
+            token,
+            tasks,
+        })
+    }
+
+    /// Stop taking new connections after [`ROUTER_RETIREMENT_DELAY`].
+    ///
+    /// Calls that reached a router before are answered to the end.
+    fn retire(self) {
+        tokio::spawn(async move {
+            tokio::time::sleep(ROUTER_RETIREMENT_DELAY).await;
+            for task in self.tasks {
+                task.abort();
+            }
+        });
+    }
+}
+
+/// Accept AGI connections from the configured peers only.
+///
+/// blazing_agi does not tell handlers where a connection came from, so every peer gets its own
+/// router on a loopback port. We accept the connections, drop those from unknown addresses before
+/// speaking AGI and forward the rest to the router of their peer. The routers only answer paths
+/// with a secret token in front, which is added on the way, so that connecting to their ports
+/// directly gets around none of this.
+/// When the config changes, new connections go to new routers, while the old ones finish their calls.
+pub async fn run_agi_server(
+    mut config: watch::Receiver<Arc<Config>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let current = config.borrow_and_update().clone();
+    let agi_listener = TcpListener::bind(current.agi_bind_string.clone()).await?;
+    let mut routers = PeerRouters::start(&current).await?;
 
     event!(
         Level::INFO,
@@ -209,6 +819,304 @@ pub async fn run_agi_server(config: Arc<Config>) -> Result<(), Box<dyn std::erro
             .local_addr()
             .expect("Should be able to get local addr")
     );
-    serve(agi_listener, router).await?;
-    Ok(())
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = agi_listener.accept() => match accepted {
+                Ok(x) => x,
+                Err(e) => {
+                    // e.g. out of file descriptors, which may pass once calls end
+                    event!(Level::WARN, "Could not accept an AGI connection: {e}");
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            Ok(()) = config.changed() => {
+                let current = config.borrow_and_update().clone();
+                match PeerRouters::start(&current).await {
+                    Ok(x) => {
+                        std::mem::replace(&mut routers, x).retire();
+                        event!(Level::INFO, "AGI routers follow the new config.");
+                    }
+                    Err(e) => event!(
+                        Level::ERROR,
+                        "Could not start the AGI routers for the new config, keeping the old ones: {e}"
+                    ),
+                };
+                continue;
+            }
+        };
+        let Some((peer, backend)) = routers
+            .backends
+            .iter()
+            .find(|(peer, _)| peer.has_address(&remote.ip()))
+        else {
+            METRICS.agi_refused_connections.inc();
+            event!(
+                Level::WARN,
+                "Refused AGI connection from {remote}, which belongs to no peer."
+            );
+            continue;
+        };
+        event!(
+            Level::DEBUG,
+            "AGI connection from {remote} belongs to {}.",
+            peer.name
+        );
+        tokio::spawn(forward_connection(stream, *backend, routers.token.clone()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::PgPool;
+
+    use super::{
+        add_token, applicable_call_forward, dial_string, log_call_lookup, resolve_call_forward,
+        run_lookup_writer, Resolution, LOOKUP_QUEUE_SIZE,
+    };
+    use crate::{
+        metrics::METRICS,
+        types::{CallLookup, Config, ForwardCondition},
+    };
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward_chain")))]
+    async fn follow_chain(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.max_forward_depth = 5;
+
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::Forwarded(1, vec!["0151234".to_string()]));
+        // the second call forward is not active in from_internal
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_internal",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::Forwarded(1, vec!["704".to_string()]));
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_sales",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::NotForwarded);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward_chain")))]
+    async fn follow_chain_up_to_max_depth(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.max_forward_depth = 1;
+
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::Forwarded(1, vec!["704".to_string()]));
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward_chain")))]
+    async fn follow_every_destination(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.max_forward_depth = 5;
+
+        let res = resolve_call_forward(
+            &config,
+            "706",
+            "from_external",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            res,
+            Resolution::Forwarded(5, vec!["0151234".to_string(), "707".to_string()])
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward_chain")))]
+    async fn detect_cycle(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.max_forward_depth = 5;
+
+        let res = resolve_call_forward(
+            &config,
+            "703",
+            "from_internal",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::Cycle);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward_conditional")))]
+    async fn follow_conditional_forwards(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.max_forward_depth = 5;
+
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::Always,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::NotForwarded);
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::Busy,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::Forwarded(1, vec!["0151234".to_string()]));
+        // the destination of a conditional forward is followed through unconditional forwards
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::NoAnswer(0),
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::Forwarded(2, vec!["0160000".to_string()]));
+        let res = resolve_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::Unavailable,
+            None,
+        )
+        .await?;
+        assert_eq!(res, Resolution::NotForwarded);
+
+        let fwd = applicable_call_forward(
+            &config,
+            "702",
+            "from_external",
+            &ForwardCondition::NoAnswer(0),
+            None,
+        )
+        .await?;
+        assert_eq!(fwd.and_then(|x| x.condition.no_answer_timeout()), Some(20));
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures(path = "../fixtures", scripts("call_forward_caller_id")))]
+    async fn caller_id_precedence(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.max_forward_depth = 5;
+
+        for (caller_id, fwd_id, expected) in [
+            (None, 1, "701"),
+            (Some("0170123"), 1, "701"),
+            // the longest matching prefix wins
+            (Some("0151999"), 2, "704"),
+            (Some("0151199"), 3, "0160000"),
+            // an exact match beats every prefix
+            (Some("0151123"), 4, "703"),
+        ] {
+            let res = resolve_call_forward(
+                &config,
+                "702",
+                "from_external",
+                &ForwardCondition::Always,
+                caller_id,
+            )
+            .await?;
+            assert_eq!(
+                res,
+                Resolution::Forwarded(fwd_id, vec![expected.to_string()]),
+                "caller id {caller_id:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dial_string_with_technology() {
+        let extensions = vec!["702".to_string(), "704".to_string()];
+        assert_eq!(dial_string(None, &extensions), "702&704");
+        assert_eq!(
+            dial_string(Some("PJSIP"), &extensions),
+            "PJSIP/702&PJSIP/704"
+        );
+    }
+
+    #[test]
+    fn token_in_front_of_the_path() {
+        assert_eq!(
+            add_token("agi_request: agi://10.0.0.1:4573/call_forward?x=1\n", "abc").as_deref(),
+            Some("agi_request: agi://10.0.0.1:4573/abc/call_forward?x=1\n")
+        );
+        assert_eq!(
+            add_token("agi_request: agi://10.0.0.1 \n", "abc").as_deref(),
+            Some("agi_request: agi://10.0.0.1/abc \n")
+        );
+        assert_eq!(add_token("agi_channel: SIP/701-0001\n", "abc"), None);
+    }
+
+    #[sqlx::test]
+    async fn bounded_lookup_queue(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let lookup = CallLookup {
+            looked_up_at: chrono::Utc::now(),
+            caller_id: None,
+            dialed_extension: "702".to_string(),
+            context: "from_external".to_string(),
+            destinations: vec![],
+            fwd_id: None,
+        };
+
+        // without a writer, the queue fills up and further lookups are dropped
+        let dropped = METRICS.dropped_call_lookups.get();
+        for _ in 0..LOOKUP_QUEUE_SIZE + 5 {
+            log_call_lookup(&config, lookup.clone());
+        }
+        assert!(METRICS.dropped_call_lookups.get() >= dropped + 5);
+
+        let (_sender, configs) = tokio::sync::watch::channel(std::sync::Arc::new(config));
+        tokio::spawn(run_lookup_writer(configs.clone()));
+        let config = configs.borrow().clone();
+        for _ in 0..100 {
+            if crate::db::get_call_lookups(&config, "702", 2000)
+                .await?
+                .len()
+                == LOOKUP_QUEUE_SIZE
+            {
+                return Ok(());
+            };
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("the queued call lookups were not written");
+    }
 }
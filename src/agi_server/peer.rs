@@ -0,0 +1,220 @@
+//! The asterisk servers allowed to make AGI requests
+use std::{collections::HashSet, fmt::Display, net::IpAddr, str::FromStr};
+
+use crate::registry::Registry;
+
+/// A single IP address or a network in CIDR notation, like `10.0.0.0/24`
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddressRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+impl AddressRange {
+    /// Does this range contain `addr`?
+    ///
+    /// IPv4 addresses mapped into IPv6 (`::ffff:10.0.0.1`) are treated as IPv4 addresses.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+impl FromStr for AddressRange {
+    type Err = PeerConfigError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PeerConfigError::InvalidAddress(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let network = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(max_len);
+        if prefix_len > max_len {
+            return Err(invalid());
+        };
+        Ok(AddressRange {
+            network,
+            prefix_len,
+        })
+    }
+}
+impl Display for AddressRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerConfigError {
+    InvalidAddress(String),
+    NoSecret(String),
+}
+impl Display for PeerConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress(x) => {
+                write!(
+                    f,
+                    "{x} is neither an IP address nor a network in CIDR notation"
+                )
+            }
+            Self::NoSecret(x) => {
+                write!(f, "The AGI peer {x} has no digest secret")
+            }
+        }
+    }
+}
+impl std::error::Error for PeerConfigError {}
+
+/// An asterisk server that may make AGI requests
+#[derive(Clone)]
+pub struct AgiPeer {
+    /// shown in the logs
+    pub(crate) name: String,
+    /// connections from these addresses belong to this peer
+    addresses: Vec<AddressRange>,
+    /// the secrets accepted in the digest of this peer
+    pub(crate) secrets: Vec<String>,
+    /// the contexts this peer may look up. All contexts if None
+    allowed_contexts: Option<HashSet<String>>,
+}
+impl std::fmt::Debug for AgiPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AgiPeer")
+            .field("name", &self.name)
+            .field("addresses", &self.addresses)
+            .field("secrets", &"[redacted]")
+            .field("allowed_contexts", &self.allowed_contexts)
+            .finish()
+    }
+}
+impl AgiPeer {
+    /// Check the config of a peer.
+    ///
+    /// The contexts are managed on the admin page, so `allowed_contexts` can only be checked once
+    /// they are loaded, see [`Self::unknown_contexts`].
+    pub fn new(
+        name: String,
+        addresses: &[String],
+        secrets: Vec<String>,
+        allowed_contexts: Option<Vec<String>>,
+    ) -> Result<AgiPeer, PeerConfigError> {
+        if secrets.is_empty() {
+            return Err(PeerConfigError::NoSecret(name));
+        };
+        let addresses = addresses
+            .iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<AddressRange>, _>>()?;
+        Ok(AgiPeer {
+            name,
+            addresses,
+            secrets,
+            allowed_contexts: allowed_contexts.map(|x| x.into_iter().collect()),
+        })
+    }
+
+    /// Does the connection from `addr` belong to this peer?
+    pub fn has_address(&self, addr: &IpAddr) -> bool {
+        self.addresses.iter().any(|range| range.contains(addr))
+    }
+
+    /// The contexts this peer is allowed, but which are not in `registry`
+    pub fn unknown_contexts<'a>(&'a self, registry: &Registry) -> Vec<&'a str> {
+        let mut unknown = self
+            .allowed_contexts
+            .iter()
+            .flatten()
+            .filter(|x| registry.context(x).is_none())
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        unknown.sort();
+        unknown
+    }
+
+    /// May this peer look up call forwards in `context_name`?
+    pub fn allows_context(&self, context_name: &str) -> bool {
+        self.allowed_contexts
+            .as_ref()
+            .is_none_or(|x| x.contains(context_name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{AddressRange, AgiPeer};
+    use crate::{registry::Registry, types::Context};
+
+    #[test]
+    fn address_ranges() {
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        let range = "10.0.0.0/24".parse::<AddressRange>().unwrap();
+        assert!(range.contains(&ip("10.0.0.17")));
+        assert!(range.contains(&ip("::ffff:10.0.0.17")));
+        assert!(!range.contains(&ip("10.0.1.17")));
+        assert!(!range.contains(&ip("fe80::1")));
+
+        let single = "192.168.1.5".parse::<AddressRange>().unwrap();
+        assert!(single.contains(&ip("192.168.1.5")));
+        assert!(!single.contains(&ip("192.168.1.6")));
+
+        let everything = "0.0.0.0/0".parse::<AddressRange>().unwrap();
+        assert!(everything.contains(&ip("203.0.113.1")));
+
+        let v6 = "2001:db8::/32".parse::<AddressRange>().unwrap();
+        assert!(v6.contains(&ip("2001:db8::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+
+        assert!("10.0.0.0/33".parse::<AddressRange>().is_err());
+        assert!("asterisk.example.com".parse::<AddressRange>().is_err());
+    }
+
+    #[test]
+    fn peer_contexts() {
+        let registry = Registry::new(
+            vec![],
+            vec![Context {
+                asterisk_name: "from_internal".to_string(),
+                display_name: "Intern".to_string(),
+            }],
+        );
+        let peer = AgiPeer::new(
+            "pbx1".to_string(),
+            &["10.0.0.1".to_string()],
+            vec!["secret".to_string()],
+            Some(vec!["from_internal".to_string()]),
+        )
+        .unwrap();
+        assert!(peer.allows_context("from_internal"));
+        assert!(!peer.allows_context("from_external"));
+        assert!(peer.has_address(&"10.0.0.1".parse().unwrap()));
+        assert!(!peer.has_address(&"10.0.0.2".parse().unwrap()));
+        assert!(peer.unknown_contexts(&registry).is_empty());
+
+        // the context may still be added on the admin page
+        let peer = AgiPeer::new(
+            "pbx2".to_string(),
+            &[],
+            vec!["secret".to_string()],
+            Some(vec!["from_external".to_string()]),
+        )
+        .unwrap();
+        assert_eq!(peer.unknown_contexts(&registry), vec!["from_external"]);
+        assert!(AgiPeer::new("pbx3".to_string(), &[], vec![], None).is_err());
+    }
+}
@@ -0,0 +1,138 @@
+//! Manage the call forward of an extension from outside, protected by a PIN
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use blazing_agi::{
+    command::{AGIResponse, Answer},
+    connection::Connection,
+    handler::AGIHandler,
+    AGIError, AGIRequest,
+};
+use tracing::{event, Level};
+
+use crate::{
+    pin::{check_pin, PinCheck, MAX_PIN_LENGTH},
+    types::Config,
+};
+
+use super::feature_code::{carry_out, get_digits, play, FeatureCode};
+
+/// asks for the extension
+const EXTENSION_SOUND: &str = "agent-user";
+/// asks for the PIN
+const PIN_SOUND: &str = "agent-pass";
+/// played after a wrong extension or PIN
+const INCORRECT_SOUND: &str = "auth-incorrect";
+/// the menu. This is not part of the asterisk sounds and has to be recorded:
+/// "Press 1 to set your call forward, 2 to remove it and 3 to hear it."
+const MENU_SOUND: &str = "asterconf-remote-menu";
+/// played before hanging up
+const GOODBYE_SOUND: &str = "vm-goodbye";
+
+/// How often the caller may try to log in during one call
+const LOGIN_TRIES_PER_CALL: u32 = 3;
+
+/// The route handler for remote_call_forward
+///
+/// The caller enters an extension and its PIN and may then change the call forward of that
+/// extension like with feature codes.
+#[derive(Debug)]
+pub(super) struct HandleRemoteCallForward {
+    config: Arc<Config>,
+}
+impl HandleRemoteCallForward {
+    pub fn new(config: Arc<Config>) -> Self {
+        HandleRemoteCallForward { config }
+    }
+
+    /// Ask for an extension and its PIN until they match.
+    ///
+    /// Returns None if the caller did not log in within [`LOGIN_TRIES_PER_CALL`] tries.
+    async fn login(&self, connection: &mut Connection) -> Result<Option<String>, AGIError> {
+        for _ in 0..LOGIN_TRIES_PER_CALL {
+            let extension = get_digits(connection, EXTENSION_SOUND, 20).await?;
+            if extension.is_empty() {
+                return Ok(None);
+            };
+            let pin = get_digits(connection, PIN_SOUND, MAX_PIN_LENGTH as u32).await?;
+            // unknown extensions have no PIN, and check_pin takes about as long for them as for
+            // a wrong PIN, so that callers cannot find out which extensions exist
+            let check = check_pin(&self.config, &extension, &pin)
+                .await
+                .map_err(|e| AGIError::InnerError(Box::new(e)))?;
+            match check {
+                PinCheck::Correct => {
+                    event!(Level::INFO, "Remote login for {extension} succeeded.");
+                    return Ok(Some(extension));
+                }
+                PinCheck::Wrong => {
+                    event!(Level::WARN, "Remote login for {extension} with wrong PIN.");
+                }
+                PinCheck::Locked => {
+                    event!(
+                        Level::WARN,
+                        "Remote login for {extension} while it is locked out."
+                    );
+                }
+                PinCheck::NotSet => {
+                    event!(
+                        Level::WARN,
+                        "Remote login for {extension}, which has no PIN."
+                    );
+                }
+            };
+            // do not tell the caller why the login failed
+            play(connection, INCORRECT_SOUND).await?;
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+impl AGIHandler for HandleRemoteCallForward {
+    async fn handle(
+        &self,
+        connection: &mut Connection,
+        request: &AGIRequest,
+    ) -> Result<(), AGIError> {
+        let context_name = request
+            .variables
+            .custom_args
+            .get(&1)
+            .ok_or(AGIError::NotEnoughCustomVariables(0, 1))?;
+        match connection.send_command(Answer::new()).await? {
+            AGIResponse::Ok(_) => {}
+            m => return Err(AGIError::Not200(m.into())),
+        };
+
+        let Some(extension) = self.login(connection).await? else {
+            play(connection, GOODBYE_SOUND).await?;
+            return Ok(());
+        };
+
+        loop {
+            let choice = get_digits(connection, MENU_SOUND, 1).await?;
+            let feature_code = match choice.as_str() {
+                "1" => FeatureCode::Set,
+                "2" => FeatureCode::Clear,
+                "3" => FeatureCode::Read,
+                "" => {
+                    play(connection, GOODBYE_SOUND).await?;
+                    return Ok(());
+                }
+                _ => {
+                    continue;
+                }
+            };
+            carry_out(
+                connection,
+                &self.config,
+                &extension,
+                context_name,
+                &feature_code,
+                &format!("remote:{extension}"),
+            )
+            .await?;
+        }
+    }
+}
@@ -0,0 +1,644 @@
+//! Exporting all call forwards to a file and importing them again
+//!
+//! A file holds one [`ForwardRecord`] per call forward, as a JSON array or as CSV with a header.
+//! In CSV, the entries of lists are separated by semicolons.
+//!
+//! An import compares the file with the stored call forwards: entries with the id of a stored
+//! call forward change it, all others are added. With `replace`, the call forwards missing from
+//! the file are deleted as well. All changes are made in one transaction, so that either all or
+//! none of them are made, and a dry run makes them without committing.
+use std::{collections::HashSet, fmt::Display};
+
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::{apply_call_forward_changes, forward_conflict_message, get_all_call_forwards, DBError},
+    forward_input::ForwardRecord,
+    types::{CallForward, Config, ForwardChange, IdState},
+};
+
+/// The formats call forwards can be exported to and imported from
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Csv,
+    Json,
+}
+impl FileFormat {
+    /// The format of the file `name`, judging from its extension
+    pub(crate) fn from_file_name(name: &str) -> Option<FileFormat> {
+        let (_, extension) = name.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+        }
+    }
+
+    pub(crate) fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Json => "application/json",
+        }
+    }
+}
+
+/// A [`ForwardRecord`] as a row of a CSV file, with its lists joined by semicolons
+#[derive(Debug, Serialize, Deserialize)]
+struct CsvRow {
+    #[serde(default)]
+    id: Option<i32>,
+    from: String,
+    to: String,
+    contexts: String,
+    #[serde(default)]
+    condition: Option<String>,
+    #[serde(default)]
+    no_answer_timeout: Option<u32>,
+    #[serde(default)]
+    caller_ids: String,
+    #[serde(default)]
+    schedule_timezone: Option<String>,
+    #[serde(default)]
+    windows: String,
+    #[serde(default)]
+    valid_from: Option<String>,
+    #[serde(default)]
+    valid_until: Option<String>,
+    #[serde(default)]
+    active: Option<bool>,
+    #[serde(default)]
+    version: Option<i32>,
+}
+impl From<ForwardRecord> for CsvRow {
+    fn from(record: ForwardRecord) -> Self {
+        CsvRow {
+            id: record.id,
+            from: record.from,
+            to: record.to.join(";"),
+            contexts: record.contexts.join(";"),
+            condition: Some(record.condition),
+            no_answer_timeout: record.no_answer_timeout,
+            caller_ids: record.caller_ids.join(";"),
+            schedule_timezone: record.schedule_timezone,
+            windows: record.windows.join(";"),
+            valid_from: record.valid_from,
+            valid_until: record.valid_until,
+            active: Some(record.active),
+            version: record.version,
+        }
+    }
+}
+impl From<CsvRow> for ForwardRecord {
+    fn from(row: CsvRow) -> Self {
+        let split = |x: &str| {
+            x.split(';')
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        // empty cells are read as empty strings
+        let non_empty = |x: Option<String>| x.filter(|x| !x.trim().is_empty());
+        ForwardRecord {
+            id: row.id,
+            from: row.from,
+            to: split(&row.to),
+            contexts: split(&row.contexts),
+            condition: non_empty(row.condition).unwrap_or_else(|| "always".to_string()),
+            no_answer_timeout: row.no_answer_timeout,
+            caller_ids: split(&row.caller_ids),
+            schedule_timezone: non_empty(row.schedule_timezone),
+            windows: split(&row.windows),
+            valid_from: non_empty(row.valid_from),
+            valid_until: non_empty(row.valid_until),
+            active: row.active.unwrap_or(true),
+            version: row.version,
+        }
+    }
+}
+
+/// Write `forwards` to a file in `format`
+pub(crate) fn export(
+    forwards: &[CallForward<'_, crate::types::HasId>],
+    format: FileFormat,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let records = forwards.iter().map(ForwardRecord::from);
+    match format {
+        FileFormat::Json => Ok(serde_json::to_string_pretty(&records.collect::<Vec<_>>())?),
+        FileFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for record in records {
+                writer.serialize(CsvRow::from(record))?;
+            }
+            Ok(String::from_utf8(writer.into_inner()?)?)
+        }
+    }
+}
+
+/// Read the call forwards from a file in `format`
+///
+/// Returns the message to display to the user if the file cannot be read.
+pub(crate) fn parse(content: &str, format: FileFormat) -> Result<Vec<ForwardRecord>, String> {
+    match format {
+        FileFormat::Json => serde_json::from_str(content)
+            .map_err(|e| format!("Die Datei ist kein gültiges JSON mit Weiterleitungen: {e}")),
+        FileFormat::Csv => csv::Reader::from_reader(content.as_bytes())
+            .deserialize::<CsvRow>()
+            .enumerate()
+            .map(|(idx, row)| {
+                row.map(ForwardRecord::from).map_err(|e| {
+                    format!(
+                        "Eintrag {}: Die Zeile ist keine gültige Weiterleitung: {e}",
+                        idx + 1
+                    )
+                })
+            })
+            .collect(),
+    }
+}
+
+/// An entry of an imported file that cannot be imported
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ImportProblem {
+    /// counted from 1. None if the problem is not caused by a single entry
+    pub(crate) entry: Option<usize>,
+    pub(crate) message: String,
+}
+impl Display for ImportProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.entry {
+            Some(x) => write!(f, "Eintrag {x}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+impl ImportProblem {
+    fn general(message: String) -> Self {
+        ImportProblem {
+            entry: None,
+            message,
+        }
+    }
+}
+
+/// The changes an import makes
+#[derive(Debug)]
+pub(crate) struct ImportPlan<'a> {
+    /// deletions first, then updates, then additions
+    pub(crate) changes: Vec<ForwardChange<'a>>,
+    /// the entry each change comes from, counted from 1. None for deletions
+    entries: Vec<Option<usize>>,
+    /// the number of entries that match the stored call forward
+    pub(crate) unchanged: usize,
+    /// the stored call forwards the plan was made for, see [`stored_versions`]
+    pub(crate) stored_versions: String,
+}
+
+/// The ids and versions of `forwards`, as `id:version` separated by commas.
+///
+/// They change with every change to the call forwards, so an import previewed for them is only
+/// applied as long as they are the same.
+pub(crate) fn stored_versions(forwards: &[CallForward<'_, crate::types::HasId>]) -> String {
+    let mut versions = forwards
+        .iter()
+        .map(|x| (i32::from(x.fwd_id), x.version))
+        .collect::<Vec<_>>();
+    versions.sort_unstable();
+    versions
+        .iter()
+        .map(|(id, version)| format!("{id}:{version}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// One line of the difference an import makes, as shown to users
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DiffLine {
+    /// `+`, `~` or `-`
+    pub(crate) sign: char,
+    pub(crate) id: Option<i32>,
+    pub(crate) before: Option<String>,
+    pub(crate) after: Option<String>,
+}
+
+/// `forward` with its contexts, caller ids and windows sorted, so that it can be compared with
+/// others regardless of the order these were entered or stored in
+fn normalized<S: IdState>(mut forward: CallForward<'_, S>) -> CallForward<'_, S> {
+    forward
+        .in_contexts
+        .sort_by(|x, y| x.asterisk_name.cmp(&y.asterisk_name));
+    forward.caller_ids.sort_by_key(|x| x.to_string());
+    if let Some(schedule) = forward.schedule.as_mut() {
+        schedule
+            .windows
+            .sort_by_key(|x| (x.first_day_index(), x.last_day_index(), x.start, x.end));
+    };
+    forward
+}
+
+/// A call forward in one line, as shown to users in `timezone`
+fn describe<S: IdState>(forward: &CallForward<'_, S>, timezone: &Tz) -> String {
+    let mut text = format!(
+        "{} -> {} in {}, {}",
+        forward.from.extension,
+        forward.destination_extensions().join(", "),
+        forward
+            .in_contexts
+            .iter()
+            .map(|x| x.asterisk_name.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+        forward.condition,
+    );
+    if !forward.caller_ids.is_empty() {
+        text.push_str(&format!(", von {}", forward.caller_ids_string()));
+    };
+    if let Some(schedule) = &forward.schedule {
+        text.push_str(&format!(", {schedule}"));
+    };
+    if forward.valid_from.is_some() || forward.valid_until.is_some() {
+        text.push_str(&format!(", gültig {}", forward.validity_in(timezone)));
+    };
+    if !forward.active {
+        text.push_str(", ausgeschaltet");
+    };
+    text
+}
+
+impl<'a> ImportPlan<'a> {
+    /// Compare `records` with the `current` call forwards
+    ///
+    /// Returns all entries that are not valid, with the reason.
+    pub(crate) fn new(
+        config: &'a Config,
+        current: Vec<CallForward<'a, crate::types::HasId>>,
+        records: &[ForwardRecord],
+        replace: bool,
+    ) -> Result<ImportPlan<'a>, Vec<ImportProblem>> {
+        let stored_versions = stored_versions(&current);
+        let current: Vec<_> = current.into_iter().map(normalized).collect();
+        let mut problems = vec![];
+        let mut seen_ids = HashSet::new();
+        let mut additions = vec![];
+        let mut updates = vec![];
+        let mut unchanged = 0;
+        for (idx, record) in records.iter().enumerate() {
+            let entry = idx + 1;
+            let stored = record
+                .id
+                .and_then(|id| current.iter().find(|x| i32::from(x.fwd_id) == id));
+            // exports contain the expired call forwards that are still kept
+            let checked = record.to_input().and_then(|x| match stored {
+                Some(before) => x.check_change(config, before),
+                None => x.check(config),
+            });
+            let forward = match checked {
+                Ok(x) => normalized(x),
+                Err(message) => {
+                    problems.push(ImportProblem {
+                        entry: Some(entry),
+                        message,
+                    });
+                    continue;
+                }
+            };
+            let Some(before) = stored else {
+                additions.push((entry, ForwardChange::Add(forward)));
+                continue;
+            };
+            let id = i32::from(before.fwd_id);
+            if !seen_ids.insert(id) {
+                problems.push(ImportProblem {
+                    entry: Some(entry),
+                    message: format!("Die Weiterleitung {id} kommt mehrfach vor."),
+                });
+                continue;
+            };
+            let after = forward.set_id(id).set_version(before.version);
+            if after == *before {
+                unchanged += 1;
+            } else {
+                updates.push((
+                    entry,
+                    ForwardChange::Update {
+                        before: Box::new(before.clone()),
+                        after: Box::new(after),
+                    },
+                ));
+            };
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        };
+
+        let mut changes = vec![];
+        let mut entries = vec![];
+        if replace {
+            for forward in current {
+                if !seen_ids.contains(&i32::from(forward.fwd_id)) {
+                    changes.push(ForwardChange::Remove(forward));
+                    entries.push(None);
+                };
+            }
+        };
+        for (entry, change) in updates.into_iter().chain(additions) {
+            changes.push(change);
+            entries.push(Some(entry));
+        }
+        Ok(ImportPlan {
+            changes,
+            entries,
+            unchanged,
+            stored_versions,
+        })
+    }
+
+    /// The changes as shown to users in `timezone`
+    pub(crate) fn diff(&self, timezone: &Tz) -> Vec<DiffLine> {
+        self.changes
+            .iter()
+            .map(|change| match change {
+                ForwardChange::Add(forward) => DiffLine {
+                    sign: '+',
+                    id: None,
+                    before: None,
+                    after: Some(describe(forward, timezone)),
+                },
+                ForwardChange::Update { before, after } => DiffLine {
+                    sign: '~',
+                    id: Some(before.fwd_id.into()),
+                    before: Some(describe(before, timezone)),
+                    after: Some(describe(after, timezone)),
+                },
+                ForwardChange::Remove(forward) => DiffLine {
+                    sign: '-',
+                    id: Some(forward.fwd_id.into()),
+                    before: Some(describe(forward, timezone)),
+                    after: None,
+                },
+            })
+            .collect()
+    }
+}
+
+/// The message about a change that could not be made, as shown to users
+fn change_problem(error: &DBError) -> String {
+    if let Some(message) = forward_conflict_message(error) {
+        return message;
+    };
+    match error {
+        DBError::StaleCallForward(x) | DBError::CannotSelectCallForward(x) => format!(
+            "Die Weiterleitung {x} wurde in der Zwischenzeit geändert. Bitte erneut versuchen."
+        ),
+        e => e.to_string(),
+    }
+}
+
+/// Import `records` into the stored call forwards, as made by `actor`.
+///
+/// Unless `commit` is set, this is a dry run which checks all changes without making them.
+/// If `previewed` is given, nothing is changed unless the stored call forwards are still the
+/// ones a dry run was made for (see [`ImportPlan::stored_versions`]).
+/// Returns the planned changes, or why they cannot be made. Then, no change is made.
+pub(crate) async fn import<'a>(
+    config: &'a Config,
+    records: &[ForwardRecord],
+    replace: bool,
+    commit: bool,
+    actor: &str,
+    previewed: Option<&str>,
+) -> Result<ImportPlan<'a>, Vec<ImportProblem>> {
+    let current = get_all_call_forwards(config)
+        .await
+        .map_err(|e| vec![ImportProblem::general(e.to_string())])?;
+    if previewed.is_some_and(|x| x != stored_versions(&current)) {
+        return Err(vec![ImportProblem::general(
+            "Die Weiterleitungen wurden seit der Vorschau geändert. Bitte die Datei erneut prüfen."
+                .to_string(),
+        )]);
+    };
+    let plan = ImportPlan::new(config, current, records, replace)?;
+    match apply_call_forward_changes(config, &plan.changes, actor, commit).await {
+        Ok(_) => Ok(plan),
+        Err(DBError::CannotApplyChange(idx, e)) => {
+            let message = change_problem(&e);
+            let problem = match (plan.entries.get(idx).copied().flatten(), &plan.changes[idx]) {
+                (Some(entry), _) => ImportProblem {
+                    entry: Some(entry),
+                    message,
+                },
+                (None, ForwardChange::Remove(forward)) => ImportProblem::general(format!(
+                    "Die Weiterleitung {} kann nicht gelöscht werden: {message}",
+                    forward.fwd_id
+                )),
+                (None, _) => ImportProblem::general(message),
+            };
+            Err(vec![problem])
+        }
+        Err(e) => Err(vec![ImportProblem::general(e.to_string())]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::PgPool;
+
+    use super::{export, import, parse, FileFormat};
+    use crate::{
+        db::{get_all_call_forwards, new_call_forward},
+        types::{CallForward, Config, NoId},
+    };
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn export_and_import(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let before = get_all_call_forwards(&config).await?;
+
+        for format in [FileFormat::Csv, FileFormat::Json] {
+            let file = export(&before, format)?;
+            let records = parse(&file, format)?;
+            assert_eq!(records.len(), before.len());
+            // importing an export changes nothing
+            let plan = import(&config, &records, true, true, "test", None)
+                .await
+                .map_err(|x| format!("{x:?}"))?;
+            assert!(plan.changes.is_empty());
+            assert_eq!(plan.unchanged, before.len());
+        }
+
+        // fwd 4 is missing, fwd 3 goes elsewhere and a new one is added
+        let file = "id,from,to,contexts,condition,caller_ids\n\
+            1,702,something-external,from_external;from_internal,busy,\n\
+            2,702,704,from_external;from_internal,,\n\
+            3,703,704,from_external,,\n\
+            ,704,702;703,from_sales,,0171*\n";
+        let records = parse(file, FileFormat::Csv)?;
+        let plan = import(&config, &records, true, false, "test", None)
+            .await
+            .map_err(|x| format!("{x:?}"))?;
+        let signs: Vec<char> = plan
+            .diff(&config.default_timezone)
+            .iter()
+            .map(|x| x.sign)
+            .collect();
+        assert_eq!(signs, vec!['-', '~', '+']);
+        // a dry run changes nothing
+        assert_eq!(get_all_call_forwards(&config).await?, before);
+
+        import(&config, &records, true, true, "test", None)
+            .await
+            .map_err(|x| format!("{x:?}"))?;
+        let after = get_all_call_forwards(&config).await?;
+        assert_eq!(after.len(), 4);
+        assert!(after.iter().all(|x| i32::from(x.fwd_id) != 4));
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn import_expired(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let expired = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["704".to_string()],
+            vec!["from_sales".to_string()],
+        )?
+        .set_validity(None, Some(chrono::Utc::now() - chrono::Duration::days(1)));
+        new_call_forward(&config, expired, "jdoe").await?;
+        let before = get_all_call_forwards(&config).await?;
+        assert_eq!(before.len(), 5);
+
+        // expired call forwards are kept for a while, and exported along with the others
+        let file = export(&before, FileFormat::Json)?;
+        let mut records = parse(&file, FileFormat::Json)?;
+        let plan = import(&config, &records, true, true, "test", None)
+            .await
+            .map_err(|x| format!("{x:?}"))?;
+        assert!(plan.changes.is_empty());
+        assert_eq!(plan.unchanged, 5);
+
+        // they can be switched off, but not added again
+        records[4].active = false;
+        let plan = import(&config, &records, true, false, "test", None)
+            .await
+            .map_err(|x| format!("{x:?}"))?;
+        assert_eq!(plan.changes.len(), 1);
+        records[4].id = None;
+        let problems = import(&config, &records, false, false, "test", None)
+            .await
+            .expect_err("expired");
+        assert_eq!(problems[0].entry, Some(5));
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn import_changed_since_preview(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let file = export(&get_all_call_forwards(&config).await?, FileFormat::Json)?;
+        let records = parse(&file, FileFormat::Json)?;
+        let preview = import(&config, &records, true, false, "test", None)
+            .await
+            .map_err(|x| format!("{x:?}"))?;
+        assert!(preview.changes.is_empty());
+
+        // a call forward added after the preview is not removed without being shown
+        let added = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["704".to_string()],
+            vec!["from_sales".to_string()],
+        )?;
+        new_call_forward(&config, added, "jdoe").await?;
+        let before = get_all_call_forwards(&config).await?;
+        let problems = import(
+            &config,
+            &records,
+            true,
+            true,
+            "test",
+            Some(&preview.stored_versions),
+        )
+        .await
+        .expect_err("changed since the preview");
+        assert_eq!(problems[0].entry, None);
+        assert_eq!(get_all_call_forwards(&config).await?, before);
+
+        // a new preview shows the removal and can be applied
+        let preview = import(&config, &records, true, false, "test", None)
+            .await
+            .map_err(|x| format!("{x:?}"))?;
+        assert_eq!(preview.changes.len(), 1);
+        import(
+            &config,
+            &records,
+            true,
+            true,
+            "test",
+            Some(&preview.stored_versions),
+        )
+        .await
+        .map_err(|x| format!("{x:?}"))?;
+        assert_eq!(
+            get_all_call_forwards(&config).await?.len(),
+            before.len() - 1
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn import_all_or_nothing(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let before = get_all_call_forwards(&config).await?;
+
+        // the first entry is fine, the second one is in an unknown context
+        let file = r#"[
+            {"from": "703", "to": ["704"], "contexts": ["from_internal"]},
+            {"from": "704", "to": ["702"], "contexts": ["nope"]}
+        ]"#;
+        let problems = import(
+            &config,
+            &parse(file, FileFormat::Json)?,
+            false,
+            true,
+            "test",
+            None,
+        )
+        .await
+        .expect_err("unknown context");
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].entry, Some(2));
+
+        // the second entry overlaps the first one
+        let file = r#"[
+            {"from": "703", "to": ["704"], "contexts": ["from_internal"]},
+            {"from": "703", "to": ["702"], "contexts": ["from_internal"]}
+        ]"#;
+        let problems = import(
+            &config,
+            &parse(file, FileFormat::Json)?,
+            false,
+            true,
+            "test",
+            None,
+        )
+        .await
+        .expect_err("overlapping call forwards");
+        assert_eq!(problems[0].entry, Some(2));
+        assert_eq!(get_all_call_forwards(&config).await?, before);
+        Ok(())
+    }
+}
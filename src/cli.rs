@@ -0,0 +1,575 @@
+//! The command line of asterconf
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    bulk::{self, FileFormat},
+    db::{
+        apply_call_forward_changes, delete_call_forward_by_id, get_all_call_forwards,
+        get_call_forward_by_id, get_call_forwards_from_startpoint, new_call_forward, DBError,
+    },
+    forward_input::{ForwardInput, ForwardRecord, WindowInput},
+    reload::LogFilterHandle,
+    storage::query,
+    types::{Config, Extension, ForwardChange},
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Call forwards for asterisk, set in a web interface and answered over AGI"
+)]
+pub struct Cli {
+    /// The config file to read
+    #[arg(long, global = true, default_value = crate::types::DEFAULT_CONFIG_PATH)]
+    pub config: PathBuf,
+    /// The directory to write the daily log files to
+    #[arg(long, global = true, default_value = "/var/log/asterconf")]
+    pub log_dir: PathBuf,
+    /// What to do. Serves everything if not given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// parsed once on start, the size does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Run the servers
+    Serve {
+        /// Which servers to run
+        #[arg(value_enum, default_value_t = Part::Both)]
+        part: Part,
+    },
+    /// Check the config file and the connections to the DB and to LDAP, without starting anything
+    CheckConfig,
+    /// List, add, change and delete call forwards
+    Forward {
+        #[command(subcommand)]
+        action: ForwardAction,
+    },
+}
+
+/// What to do with call forwards.
+///
+/// Changes are checked like in the web interface and recorded in the audit log as made by
+/// `cli:<user>`.
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+pub enum ForwardAction {
+    /// Show the call forwards
+    List {
+        /// Only show the call forwards for this extension
+        #[arg(long)]
+        from: Option<String>,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Add a call forward
+    Add {
+        /// The extension whose calls are forwarded
+        #[arg(long)]
+        from: String,
+        #[command(flatten)]
+        fields: ForwardFields,
+        /// Store it switched off
+        #[arg(long)]
+        inactive: bool,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete a call forward. It can be restored in the web interface for a while
+    Rm { id: i32 },
+    /// Write all call forwards to a file, or to stdout
+    Export {
+        /// The format to write. By default, judged from the name of the output file or JSON
+        #[arg(long, value_enum)]
+        format: Option<FileFormat>,
+        /// The file to write to instead of stdout
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Show what importing a file of call forwards changes, and make the changes with --apply
+    Import {
+        /// A file as written by export. Entries with the id of a stored call forward change it,
+        /// all others are added
+        file: PathBuf,
+        /// The format of the file, if its name does not end in .csv or .json
+        #[arg(long, value_enum)]
+        format: Option<FileFormat>,
+        /// Also delete the call forwards which are not in the file
+        #[arg(long)]
+        replace: bool,
+        /// Make the changes, all of them or none. Without this, nothing is changed
+        #[arg(long)]
+        apply: bool,
+    },
+    /// Change a call forward. Everything not given stays as it is
+    Set {
+        id: i32,
+        /// The extension whose calls are forwarded
+        #[arg(long)]
+        from: Option<String>,
+        #[command(flatten)]
+        fields: ForwardFields,
+        /// Remove the schedule, so that the call forward applies at any time
+        #[arg(long, conflicts_with = "windows")]
+        no_schedule: bool,
+        /// Switch the call forward on or off
+        #[arg(long)]
+        active: Option<bool>,
+        /// Print JSON instead of a table
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// The settings of a call forward which can be given when adding or changing it
+#[derive(Args, Debug, Clone, Default, PartialEq)]
+pub struct ForwardFields {
+    /// An extension to forward to. Given several times, all of them ring at once
+    #[arg(long)]
+    to: Vec<String>,
+    /// The asterisk name of a context the call forward applies in. Can be given several times
+    #[arg(long = "context")]
+    contexts: Vec<String>,
+    /// When to forward: always, busy, no_answer or unavailable
+    #[arg(long)]
+    condition: Option<String>,
+    /// Seconds to wait for an answer before forwarding, with the no_answer condition
+    #[arg(long)]
+    no_answer_timeout: Option<String>,
+    /// Only forward calls from these caller ids, separated by commas. A trailing * matches all
+    /// caller ids starting with it. An empty value forwards calls from everyone
+    #[arg(long)]
+    caller_ids: Option<String>,
+    /// Only forward within this window, like "Mon-Fri 08:00-17:00". Can be given several times
+    #[arg(long = "window", value_parser = WindowInput::parse)]
+    windows: Vec<WindowInput>,
+    /// The timezone of the windows, the default timezone if not given
+    #[arg(long)]
+    timezone: Option<String>,
+    /// Do not forward before this, like 2024-12-24T18:00 in the default timezone. An empty value
+    /// removes the limit
+    #[arg(long)]
+    valid_from: Option<String>,
+    /// Stop forwarding at this point in time. An empty value removes the limit
+    #[arg(long)]
+    valid_until: Option<String>,
+}
+impl ForwardFields {
+    /// Replace what was given in `input`
+    fn apply_to(self, input: &mut ForwardInput) {
+        if !self.to.is_empty() {
+            input.to = self.to;
+        };
+        if !self.contexts.is_empty() {
+            input.contexts = self.contexts;
+        };
+        if !self.windows.is_empty() {
+            input.windows = self.windows;
+        };
+        let fields = [
+            (self.condition, &mut input.condition),
+            (self.no_answer_timeout, &mut input.no_answer_timeout),
+            (self.caller_ids, &mut input.caller_ids),
+            (self.timezone, &mut input.schedule_timezone),
+            (self.valid_from, &mut input.valid_from),
+            (self.valid_until, &mut input.valid_until),
+        ];
+        for (given, field) in fields {
+            if given.is_some() {
+                *field = given;
+            };
+        }
+    }
+}
+
+/// The parts of asterconf that can run on their own
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Part {
+    /// The AGI server answering asterisk, with the cleanup of old data
+    Agi,
+    /// The web interface
+    Web,
+    /// The AGI server and the web interface
+    Both,
+}
+impl Part {
+    pub fn runs_agi(&self) -> bool {
+        matches!(self, Self::Agi | Self::Both)
+    }
+
+    pub fn runs_web(&self) -> bool {
+        matches!(self, Self::Web | Self::Both)
+    }
+}
+
+/// Check the config at `config_path`, print what was found and fail if anything is wrong
+pub async fn check_config(config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = match Config::create_from(config_path).await {
+        Ok(x) => {
+            println!("config file {}: ok", config_path.display());
+            x
+        }
+        Err(e) => {
+            println!("config file {}: {e}", config_path.display());
+            return Err("the config file is not valid".into());
+        }
+    };
+    let mut ok = true;
+    // reading the config does not fail while postgres is down, so that calls can be answered from the snapshot
+    match query("SELECT 1").execute(&config.pool).await {
+        Ok(_) => println!("DB: ok"),
+        Err(e) => {
+            println!("DB: {e}");
+            ok = false;
+        }
+    };
+    match config.ldap_config.check_connection().await {
+        Ok(()) => println!("LDAP: ok"),
+        Err(e) => {
+            println!("LDAP: {e}");
+            ok = false;
+        }
+    };
+    if !ok {
+        return Err("not all checks passed".into());
+    };
+    Ok(())
+}
+
+/// Run `action` on the call forwards in the DB of the config at `config_path`
+pub async fn forward(
+    config_path: &Path,
+    action: ForwardAction,
+    log_filter: &LogFilterHandle,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::create_from(config_path).await?;
+    log_filter.reload(EnvFilter::new(&config.log_filter))?;
+    // what is valid depends on the extensions and contexts in the DB, not in the config file
+    crate::registry::load(&config).await?;
+    let actor = format!(
+        "cli:{}",
+        std::env::var("USER").unwrap_or_else(|_| "unknown".to_string())
+    );
+
+    match action {
+        ForwardAction::List { from, json } => {
+            let forwards = match from {
+                None => get_all_call_forwards(&config).await?,
+                Some(from) => {
+                    let from = Extension::create_from_name(&config, from);
+                    get_call_forwards_from_startpoint(&config, &from).await?
+                }
+            };
+            let records: Vec<ForwardRecord> = forwards.iter().map(ForwardRecord::from).collect();
+            print_forwards(&records, json)?;
+        }
+        ForwardAction::Add {
+            from,
+            fields,
+            inactive,
+            json,
+        } => {
+            let mut input = ForwardInput {
+                from,
+                active: !inactive,
+                ..Default::default()
+            };
+            fields.apply_to(&mut input);
+            let forward = input.check(&config).map_err(invalid_input)?;
+            let forward = new_call_forward(&config, forward, &actor).await?;
+            info!(
+                "{actor} Inserted a new call forward: {}->{:?}@{:?}",
+                forward.from.extension,
+                forward.destination_extensions(),
+                forward.in_contexts
+            );
+            print_forwards(&[ForwardRecord::from(&forward)], json)?;
+        }
+        ForwardAction::Rm { id } => {
+            // deleting is silent about call forwards that do not exist
+            get_call_forward_by_id(&config, id).await?;
+            delete_call_forward_by_id(&config, id, &actor).await?;
+            info!("{actor} Deleted call forward {id}.");
+            println!("Deleted call forward {id}.");
+        }
+        ForwardAction::Export { format, output } => {
+            let format = format
+                .or_else(|| {
+                    output
+                        .as_ref()
+                        .and_then(|x| FileFormat::from_file_name(&x.to_string_lossy()))
+                })
+                .unwrap_or(FileFormat::Json);
+            let file = bulk::export(&get_all_call_forwards(&config).await?, format)?;
+            match output {
+                None => print!("{file}"),
+                Some(path) => std::fs::write(path, file)?,
+            };
+        }
+        ForwardAction::Import {
+            file,
+            format,
+            replace,
+            apply,
+        } => {
+            let Some(format) =
+                format.or_else(|| FileFormat::from_file_name(&file.to_string_lossy()))
+            else {
+                return Err("give the format of the file with --format".into());
+            };
+            let content = std::fs::read_to_string(&file)?;
+            let records = bulk::parse(&content, format).map_err(invalid_input)?;
+            let plan = match bulk::import(&config, &records, replace, apply, &actor, None).await {
+                Ok(x) => x,
+                Err(problems) => {
+                    for problem in problems {
+                        eprintln!("{problem}");
+                    }
+                    return Err("the file cannot be imported, nothing was changed".into());
+                }
+            };
+            for line in plan.diff(&config.default_timezone) {
+                let id = line.id.map(|x| format!("{x}: ")).unwrap_or_default();
+                match (line.before, line.after) {
+                    (Some(before), Some(after)) => {
+                        println!("{} {id}{before}\n  -> {after}", line.sign)
+                    }
+                    (before, after) => {
+                        println!("{} {id}{}", line.sign, before.or(after).unwrap_or_default())
+                    }
+                };
+            }
+            println!(
+                "{} changes, {} call forwards stay as they are.",
+                plan.changes.len(),
+                plan.unchanged
+            );
+            if apply {
+                info!(
+                    "{actor} Imported {} changes to the call forwards from {}.",
+                    plan.changes.len(),
+                    file.display()
+                );
+                println!("All changes were made.");
+            } else {
+                println!("Nothing was changed. Run again with --apply to make the changes.");
+            };
+        }
+        ForwardAction::Set {
+            id,
+            from,
+            fields,
+            no_schedule,
+            active,
+            json,
+        } => {
+            let current = get_call_forward_by_id(&config, id).await?;
+            let mut input = ForwardInput::from_forward(&current);
+            if let Some(from) = from {
+                input.from = from;
+            };
+            if no_schedule {
+                input.windows = vec![];
+            };
+            fields.apply_to(&mut input);
+            if let Some(active) = active {
+                input.active = active;
+            };
+            let forward = input
+                .check_change(&config, &current)
+                .map_err(invalid_input)?
+                .set_id(id)
+                .set_version(current.version);
+            if forward != current {
+                // one transaction, which switches the call forward off first and on last
+                let change = ForwardChange::Update {
+                    before: Box::new(current.clone()),
+                    after: Box::new(forward.clone()),
+                };
+                apply_call_forward_changes(&config, &[change], &actor, true)
+                    .await
+                    .map_err(|e| match e {
+                        DBError::CannotApplyChange(_, e) => *e,
+                        e => e,
+                    })?;
+                info!(
+                    "{actor} Updated a call forward. Is now: {}->{:?}@{:?}, {}.",
+                    forward.from.extension,
+                    forward.destination_extensions(),
+                    forward.in_contexts,
+                    if forward.active { "on" } else { "off" }
+                );
+            };
+            let forward = get_call_forward_by_id(&config, id).await?;
+            print_forwards(&[ForwardRecord::from(&forward)], json)?;
+        }
+    };
+    Ok(())
+}
+
+/// Print the message about `input` not being valid and turn it into the error to exit with
+fn invalid_input(message: String) -> Box<dyn std::error::Error> {
+    eprintln!("{message}");
+    "the call forward is not valid".into()
+}
+
+/// Print `records` as a table, or as JSON if `json` is set
+fn print_forwards(records: &[ForwardRecord], json: bool) -> Result<(), serde_json::Error> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(records)?);
+        return Ok(());
+    };
+    let header = [
+        "ID",
+        "FROM",
+        "TO",
+        "CONTEXTS",
+        "CONDITION",
+        "CALLERS",
+        "SCHEDULE",
+        "VALIDITY",
+        "ACTIVE",
+    ];
+    let rows: Vec<[String; 9]> = records.iter().map(table_row).collect();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let print_row = |cells: Vec<&str>| {
+        let line = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    };
+    print_row(header.to_vec());
+    for row in &rows {
+        print_row(row.iter().map(String::as_str).collect());
+    }
+    Ok(())
+}
+
+/// The cells of `record` in a table of call forwards
+fn table_row(record: &ForwardRecord) -> [String; 9] {
+    let or_dash = |x: String| if x.is_empty() { "-".to_string() } else { x };
+    let condition = match record.no_answer_timeout {
+        Some(timeout) => format!("{} {timeout}s", record.condition),
+        None => record.condition.clone(),
+    };
+    let schedule = match &record.schedule_timezone {
+        Some(timezone) => format!("{} ({timezone})", record.windows.join(", ")),
+        None => String::new(),
+    };
+    let validity = match (&record.valid_from, &record.valid_until) {
+        (None, None) => String::new(),
+        (from, until) => format!(
+            "{}..{}",
+            from.as_deref().unwrap_or_default(),
+            until.as_deref().unwrap_or_default()
+        ),
+    };
+    [
+        record.id.map(|x| x.to_string()).unwrap_or_default(),
+        record.from.clone(),
+        record.to.join(","),
+        record.contexts.join(","),
+        condition,
+        or_dash(record.caller_ids.join(",")),
+        or_dash(schedule),
+        or_dash(validity),
+        if record.active { "yes" } else { "no" }.to_string(),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::{Cli, Command, ForwardAction, Part};
+
+    #[test]
+    fn parse_subcommands() {
+        let cli = Cli::parse_from(["asterconf"]);
+        assert_eq!(cli.command, None);
+        assert_eq!(cli.config.to_str(), Some("/etc/asterconf/config.yaml"));
+
+        let cli = Cli::parse_from(["asterconf", "serve", "agi", "--config", "/tmp/a.yaml"]);
+        assert_eq!(cli.command, Some(Command::Serve { part: Part::Agi }));
+        assert_eq!(cli.config.to_str(), Some("/tmp/a.yaml"));
+
+        let cli = Cli::parse_from(["asterconf", "--log-dir", "/tmp/log", "serve"]);
+        assert_eq!(cli.command, Some(Command::Serve { part: Part::Both }));
+        assert_eq!(cli.log_dir.to_str(), Some("/tmp/log"));
+
+        let cli = Cli::parse_from(["asterconf", "check-config"]);
+        assert_eq!(cli.command, Some(Command::CheckConfig));
+        assert!(Cli::try_parse_from(["asterconf", "serve", "sip"]).is_err());
+    }
+
+    #[test]
+    fn parse_forward_subcommands() {
+        let cli = Cli::parse_from(["asterconf", "forward", "list", "--json"]);
+        assert_eq!(
+            cli.command,
+            Some(Command::Forward {
+                action: ForwardAction::List {
+                    from: None,
+                    json: true
+                }
+            })
+        );
+
+        let cli = Cli::parse_from([
+            "asterconf",
+            "forward",
+            "add",
+            "--from",
+            "702",
+            "--to",
+            "704",
+            "--to",
+            "0171234",
+            "--context",
+            "from_internal",
+            "--window",
+            "Mon-Fri 08:00-17:00",
+        ]);
+        let Some(Command::Forward {
+            action: ForwardAction::Add { from, fields, .. },
+        }) = cli.command
+        else {
+            panic!("not parsed as forward add: {:?}", cli.command);
+        };
+        assert_eq!(from, "702");
+        assert_eq!(fields.to, vec!["704", "0171234"]);
+        assert_eq!(fields.contexts, vec!["from_internal"]);
+        assert_eq!(fields.windows.len(), 1);
+
+        let cli = Cli::parse_from(["asterconf", "forward", "set", "3", "--active", "false"]);
+        let Some(Command::Forward {
+            action: ForwardAction::Set { id, active, .. },
+        }) = cli.command
+        else {
+            panic!("not parsed as forward set: {:?}", cli.command);
+        };
+        assert_eq!((id, active), (3, Some(false)));
+
+        assert!(Cli::try_parse_from(["asterconf", "forward", "add", "--to", "704"]).is_err());
+        assert!(Cli::try_parse_from(["asterconf", "forward", "rm"]).is_err());
+        assert!(
+            Cli::try_parse_from(["asterconf", "forward", "set", "3", "--window", "Mon-Fri",])
+                .is_err()
+        );
+    }
+}
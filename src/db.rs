@@ -1,11 +1,20 @@
-use std::error::Error;
 /// Functions for reading and writing into the DB
 use std::fmt::Display;
+use std::future::Future;
+use std::{collections::HashMap, error::Error};
 
-use sqlx::{postgres::PgRow, Row};
+use chrono::{DateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
 use tracing::Level;
 
-use crate::types::{CallForward, Config, Context, Extension, HasId, NoId};
+use crate::cache::CachedCallForward;
+use crate::metrics::time_db_query;
+use crate::storage::{query, DbRow, DbTransaction};
+use crate::types::{
+    AuditAction, AuditEntry, CallForward, CallLookup, CallerIdPattern, Config, Context,
+    DeletedCallForward, Extension, ExtensionPin, ForwardChange, ForwardCondition, ForwardUsage,
+    HasId, IdState, NoId, Schedule, ScheduleWindow, SYSTEM_ACTOR,
+};
 
 #[derive(Debug, PartialEq)]
 pub enum DBError {
@@ -22,6 +31,51 @@ pub enum DBError {
     CannotSelectContexts(i32),
     CannotDeleteContextMapping(String, i32),
     OverlappingCallForwards(Extension, Context),
+    CannotSelectScheduleWindows,
+    CannotInsertScheduleWindow(i32),
+    CannotDeleteScheduleWindows(i32),
+    CannotUpdateSchedule(i32),
+    InvalidScheduleTimezone(String),
+    InvalidScheduleWindow(i32),
+    CyclicCallForwards(Extension, Context),
+    NoDestination(Extension),
+    InvalidDestination(String),
+    CannotInsertDestination(String, i32),
+    CannotDeleteDestinations(i32),
+    InvalidCondition(i32),
+    CannotUpdateCondition(i32),
+    CannotSelectCallerIds,
+    CannotInsertCallerId(i32),
+    CannotDeleteCallerIds(i32),
+    CannotSelectPin(String),
+    CannotSetPin(String),
+    CannotUpdatePinAttempts(String),
+    CannotDeletePin(String),
+    CannotInsertCallLookup(String),
+    CannotSelectCallLookups(String),
+    CannotSelectForwardUsage,
+    CannotDeleteCallLookups,
+    CannotUpdateActive(i32),
+    CannotUpdateValidity(i32),
+    CannotDeleteExpiredCallForwards,
+    CannotRestoreCallForward(i32),
+    CannotPurgeDeletedCallForwards,
+    ConcurrentChange,
+    StaleCallForward(i32),
+    CannotInsertAuditEntry(i32),
+    CannotSelectAuditEntries,
+    InvalidAuditEntry(i64),
+    CannotSelectExtensions,
+    CannotSelectKnownContexts,
+    CannotSeedExtensionsAndContexts,
+    ExtensionExists(String),
+    ExtensionDoesNotExist(String),
+    CannotChangeExtension(String),
+    ContextExists(String),
+    ContextInUse(String),
+    CannotChangeContext(String),
+    /// the change with this index failed, so none was made
+    CannotApplyChange(usize, Box<DBError>),
 }
 impl Display for DBError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -71,117 +125,689 @@ impl Display for DBError {
                     "There is already a call forward from {exten} active in context {context}."
                 )
             }
+            Self::CannotSelectScheduleWindows => {
+                write!(f, "Unable to select schedule windows")
+            }
+            Self::CannotInsertScheduleWindow(x) => {
+                write!(f, "Unable to insert a schedule window for call forward {x}")
+            }
+            Self::CannotDeleteScheduleWindows(x) => {
+                write!(
+                    f,
+                    "Unable to delete the schedule windows of call forward {x}"
+                )
+            }
+            Self::CannotUpdateSchedule(x) => {
+                write!(f, "Unable to update the schedule of call forward {x}")
+            }
+            Self::InvalidScheduleTimezone(x) => {
+                write!(f, "The schedule timezone {x} is not a valid timezone")
+            }
+            Self::InvalidScheduleWindow(x) => {
+                write!(f, "Call forward {x} has an invalid schedule window")
+            }
+            Self::CyclicCallForwards(exten, context) => {
+                write!(
+                    f,
+                    "A call forward from {exten} would create a loop in context {context}."
+                )
+            }
+            Self::NoDestination(x) => {
+                write!(f, "The call forward from {x} has no destination")
+            }
+            Self::InvalidDestination(x) => {
+                write!(f, "{x} is not a valid destination")
+            }
+            Self::CannotInsertDestination(x, y) => {
+                write!(f, "Unable to insert destination {x} for call forward {y}")
+            }
+            Self::CannotDeleteDestinations(x) => {
+                write!(f, "Unable to delete the destinations of call forward {x}")
+            }
+            Self::InvalidCondition(x) => {
+                write!(f, "Call forward {x} has an invalid condition")
+            }
+            Self::CannotUpdateCondition(x) => {
+                write!(f, "Unable to update the condition of call forward {x}")
+            }
+            Self::CannotSelectCallerIds => {
+                write!(f, "Unable to select caller id patterns")
+            }
+            Self::CannotInsertCallerId(x) => {
+                write!(
+                    f,
+                    "Unable to insert a caller id pattern for call forward {x}"
+                )
+            }
+            Self::CannotDeleteCallerIds(x) => {
+                write!(
+                    f,
+                    "Unable to delete the caller id patterns of call forward {x}"
+                )
+            }
+            Self::CannotSelectPin(x) => {
+                write!(f, "Unable to select the PIN of extension {x}")
+            }
+            Self::CannotSetPin(x) => {
+                write!(f, "Unable to set the PIN of extension {x}")
+            }
+            Self::CannotUpdatePinAttempts(x) => {
+                write!(
+                    f,
+                    "Unable to update the failed PIN attempts of extension {x}"
+                )
+            }
+            Self::CannotDeletePin(x) => {
+                write!(f, "Unable to delete the PIN of extension {x}")
+            }
+            Self::CannotInsertCallLookup(x) => {
+                write!(f, "Unable to log a call lookup for extension {x}")
+            }
+            Self::CannotSelectCallLookups(x) => {
+                write!(f, "Unable to select the call lookups for extension {x}")
+            }
+            Self::CannotSelectForwardUsage => {
+                write!(f, "Unable to select how often call forwards were applied")
+            }
+            Self::CannotDeleteCallLookups => {
+                write!(f, "Unable to delete old call lookups")
+            }
+            Self::CannotUpdateActive(x) => {
+                write!(f, "Unable to switch call forward {x} on or off")
+            }
+            Self::CannotUpdateValidity(x) => {
+                write!(f, "Unable to update the validity of call forward {x}")
+            }
+            Self::CannotDeleteExpiredCallForwards => {
+                write!(f, "Unable to delete expired call forwards")
+            }
+            Self::CannotRestoreCallForward(x) => {
+                write!(f, "Unable to restore the call forward with id {x}")
+            }
+            Self::CannotPurgeDeletedCallForwards => {
+                write!(f, "Unable to remove deleted call forwards for good")
+            }
+            Self::ConcurrentChange => {
+                write!(f, "The call forwards were changed concurrently")
+            }
+            Self::CannotApplyChange(x, e) => {
+                write!(f, "Unable to make change {x}, so no change was made: {e}")
+            }
+            Self::StaleCallForward(x) => {
+                write!(
+                    f,
+                    "The call forward with id {x} was changed in the meantime"
+                )
+            }
+            Self::CannotInsertAuditEntry(x) => {
+                write!(f, "Unable to record the change of call forward {x}")
+            }
+            Self::CannotSelectAuditEntries => {
+                write!(f, "Unable to select the changes of call forwards")
+            }
+            Self::InvalidAuditEntry(x) => {
+                write!(f, "The change {x} of a call forward cannot be read")
+            }
+            Self::CannotSelectExtensions => {
+                write!(f, "Unable to select the known extensions")
+            }
+            Self::CannotSelectKnownContexts => {
+                write!(f, "Unable to select the known contexts")
+            }
+            Self::CannotSeedExtensionsAndContexts => {
+                write!(
+                    f,
+                    "Unable to copy the extensions and contexts from the config file"
+                )
+            }
+            Self::ExtensionExists(x) => {
+                write!(f, "The extension {x} already exists")
+            }
+            Self::ExtensionDoesNotExist(x) => {
+                write!(f, "The extension {x} does not exist")
+            }
+            Self::CannotChangeExtension(x) => {
+                write!(f, "Unable to change the extension {x}")
+            }
+            Self::ContextExists(x) => {
+                write!(f, "The context {x} already exists")
+            }
+            Self::ContextInUse(x) => {
+                write!(f, "The context {x} is used by call forwards")
+            }
+            Self::CannotChangeContext(x) => {
+                write!(f, "Unable to change the context {x}")
+            }
         }
     }
 }
 impl Error for DBError {}
 
+/// How often a change is tried while it clashes with concurrent changes
+const SERIALIZABLE_ATTEMPTS: usize = 5;
+
+/// `otherwise`, unless `e` is a clash with a concurrent serializable transaction
+fn conflict_or(e: sqlx::Error, otherwise: DBError) -> DBError {
+    match e.as_database_error().and_then(|x| x.code()).as_deref() {
+        // postgres: serialization_failure or deadlock_detected,
+        // SQLite: SQLITE_BUSY or SQLITE_BUSY_SNAPSHOT, when another connection wrote first
+        Some("40001" | "40P01" | "5" | "517") => DBError::ConcurrentChange,
+        _ => otherwise,
+    }
+}
+
+/// Start a serializable transaction.
+///
+/// Call forwards are checked for overlaps and loops inside these, so two concurrent changes can
+/// not both pass the checks: one of them fails with DBError::ConcurrentChange and is tried again
+/// by [`retry_on_conflict`], when it sees the other change.
+/// SQLite transactions are always serializable, since only one connection may write at a time.
+async fn begin_serializable(config: &Config) -> Result<DbTransaction, DBError> {
+    let mut tx = config
+        .pool
+        .begin()
+        .await
+        .map_err(|_| DBError::CannotStartTransaction)?;
+    if let DbTransaction::Postgres(_) = tx {
+        query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+            .execute(&mut tx)
+            .await
+            .map_err(|_| DBError::CannotStartTransaction)?;
+    };
+    Ok(tx)
+}
+
+/// Run `attempt` again while it fails with DBError::ConcurrentChange, at most
+/// SERIALIZABLE_ATTEMPTS times in total
+async fn retry_on_conflict<T, F, Fut>(mut attempt: F) -> Result<T, DBError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DBError>>,
+{
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(DBError::ConcurrentChange) if attempts < SERIALIZABLE_ATTEMPTS => attempts += 1,
+            res => return res,
+        }
+    }
+}
+
+/// Return DBError::OverlappingCallForwards or DBError::CyclicCallForwards if `forward` would
+/// overlap or form a loop with the call forwards in the DB, apart from the one with id `ignore`.
+async fn check_call_forward<'a, S: IdState>(
+    config: &'a Config,
+    conn: &mut DbTransaction,
+    forward: &CallForward<'a, S>,
+    ignore: Option<i32>,
+) -> Result<(), DBError> {
+    let existing = select_all_call_forwards(config, conn).await?;
+    if let Some(overlap) = find_overlap(forward, &existing, ignore) {
+        return Err(DBError::OverlappingCallForwards(
+            forward.from.clone(),
+            overlap.clone(),
+        ));
+    };
+    if let Some(ctx) = find_cycle(forward, &existing, ignore) {
+        return Err(DBError::CyclicCallForwards(
+            forward.from.clone(),
+            ctx.clone(),
+        ));
+    };
+    Ok(())
+}
+
+/// Find a context in which `forward` would close a loop of call forwards.
+///
+/// `existing` are all call forwards currently set. The call forward with id `ignore` is skipped,
+/// because it is about to be replaced by `forward`.
+/// Schedules and caller ids are ignored here: a loop that is only active some of the time is still
+/// a loop.
+/// Conditional call forwards are only applied to the dialed extension and never followed further,
+/// so only unconditional call forwards can continue a loop. Switched off and expired call forwards
+/// are never followed either.
+pub(crate) fn find_cycle<'a, S: IdState>(
+    forward: &CallForward<'a, S>,
+    existing: &[CallForward<'a, HasId>],
+    ignore: Option<i32>,
+) -> Option<&'a Context> {
+    for &ctx in forward.in_contexts.iter() {
+        let mut pending = forward.destination_extensions();
+        let mut seen = vec![];
+        while let Some(current) = pending.pop() {
+            if current == forward.from.extension {
+                return Some(ctx);
+            };
+            // we are in a loop that does not contain forward.from
+            if seen.contains(&current) {
+                continue;
+            };
+            seen.push(current);
+            // with caller ids or validity windows, several call forwards can start here
+            let next = existing.iter().filter(|fwd| {
+                Some(Into::<i32>::into(fwd.fwd_id)) != ignore
+                    && fwd.active
+                    && !fwd.is_expired()
+                    && fwd.condition == ForwardCondition::Always
+                    && fwd.from.extension == current
+                    && fwd.in_contexts.contains(&ctx)
+            });
+            for fwd in next {
+                pending.extend(fwd.destination_extensions());
+            }
+        }
+    }
+    None
+}
+
+/// Find a context in which `forward` overlaps a call forward in `existing`.
+///
+/// Two call forwards overlap if they start at the same extension, have the same kind of condition,
+/// apply to the same callers, can be valid at the same time and share a context.
+/// Only active call forwards that have not expired can overlap. The call forward with id `ignore`
+/// is skipped, because it is `forward` itself.
+pub(crate) fn find_overlap<'a, S: IdState>(
+    forward: &CallForward<'a, S>,
+    existing: &[CallForward<'a, HasId>],
+    ignore: Option<i32>,
+) -> Option<&'a Context> {
+    if !forward.active || forward.is_expired() {
+        return None;
+    };
+    existing
+        .iter()
+        .filter(|fwd| {
+            Some(Into::<i32>::into(fwd.fwd_id)) != ignore
+                && fwd.active
+                && !fwd.is_expired()
+                && fwd.validity_overlaps(forward)
+                && fwd.from.extension == forward.from.extension
+                && fwd.condition.same_kind(&forward.condition)
+                && fwd.caller_ids_overlap(forward)
+        })
+        .find_map(|fwd| {
+            fwd.in_contexts
+                .iter()
+                .find(|ctx| forward.in_contexts.contains(ctx))
+                .copied()
+        })
+}
+
+/// What users are told when a call forward cannot be saved because it clashes with the others,
+/// as found by [`find_overlap`] and [`find_cycle`].
+///
+/// Returns None if `e` is not such a clash.
+pub(crate) fn forward_conflict_message(e: &DBError) -> Option<String> {
+    match e {
+        DBError::OverlappingCallForwards(x, y) => Some(format!(
+            "Anrufe an die Nummer {x} werden bereits weitergeleitet wenn sie von {y} kommen."
+        )),
+        DBError::CyclicCallForwards(x, y) => Some(format!(
+            "Diese Weiterleitung würde Anrufe an {x} im Kreis weiterleiten wenn sie von {y} kommen."
+        )),
+        _ => None,
+    }
+}
+
 /// Set or Update a call forward.
 ///
 /// Note that call forwards contain data for the Contexts in which they are relevant
-/// No two call forwards from the same extension can be applicable in the same context.
+/// No two call forwards from the same extension with the same kind of condition can be applicable
+/// in the same context to the same callers.
 /// This function returns DBError::OverlappingCallForwards if this happens.
 ///
+/// Call forwards may not form a loop in any context.
+/// This function returns DBError::CyclicCallForwards if this happens.
+///
 /// If there is no conflicting context, this function may create another call forward from the same
 /// Extension that already has another (in other contexts)
+///
+/// The new call forward is recorded in the audit log as created by `actor`.
 #[tracing::instrument(level=Level::DEBUG,skip_all,err)]
 pub async fn new_call_forward<'a>(
     config: &Config,
     new_forward: CallForward<'a, NoId>,
+    actor: &str,
 ) -> Result<CallForward<'a, HasId>, DBError> {
-    let existing_forwards = get_call_forwards_from_startpoint(config, &new_forward.from).await?;
-    for fwd in existing_forwards {
-        if let Some(overlap) = fwd.intersecting_contexts(&new_forward).next() {
-            return Err(DBError::OverlappingCallForwards(
-                fwd.from.clone(),
-                (*overlap).clone(),
-            ));
-        };
-    }
+    let _timer = time_db_query("new_call_forward");
+    retry_on_conflict(|| try_new_call_forward(config, new_forward.clone(), actor)).await
+}
 
-    // The good case: there are no overlapping call forwards with new_forward
-    let mut tx = config
-        .pool
-        .begin()
+/// One attempt of [`new_call_forward`]
+async fn try_new_call_forward<'a>(
+    config: &Config,
+    new_forward: CallForward<'a, NoId>,
+    actor: &str,
+) -> Result<CallForward<'a, HasId>, DBError> {
+    let mut tx = begin_serializable(config).await?;
+    let inserted = insert_call_forward(config, &mut tx, new_forward, actor).await?;
+    tx.commit()
         .await
-        .map_err(|_| DBError::CannotStartTransaction)?;
-    let new_id_result = sqlx::query(
-        "INSERT INTO call_forward (from_extension, to_extension) VALUES ($1, $2) RETURNING fwd_id",
+        .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
+    Ok(inserted)
+}
+
+/// Check `new_forward` and insert it in `tx`, recording it in the audit log as created by `actor`
+async fn insert_call_forward<'a>(
+    config: &Config,
+    tx: &mut DbTransaction,
+    new_forward: CallForward<'a, NoId>,
+    actor: &str,
+) -> Result<CallForward<'a, HasId>, DBError> {
+    check_call_forward(config, tx, &new_forward, None).await?;
+
+    // The good case: there are no overlapping or cyclic call forwards with new_forward
+    let new_id_result = query(
+        "INSERT INTO call_forward (from_extension, schedule_timezone, condition, no_answer_timeout, active, valid_from, valid_until) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING fwd_id",
     )
     .bind(&new_forward.from.extension)
-    .bind(&new_forward.to.extension)
+    .bind(new_forward.schedule.as_ref().map(|s| s.timezone.name()))
+    .bind(new_forward.condition.kind())
+    .bind(new_forward.condition.no_answer_timeout().map(|x| x as i32))
+    .bind(new_forward.active)
+    .bind(new_forward.valid_from)
+    .bind(new_forward.valid_until)
     .fetch_one(&mut *tx)
     .await
-    .map_err(|_| DBError::CannotInsertCallForward)?;
+    .map_err(|e| conflict_or(e, DBError::CannotInsertCallForward))?;
 
     let new_id: i32 = new_id_result.get("fwd_id");
 
     for ctx in new_forward.in_contexts.iter() {
-        sqlx::query("INSERT INTO map_call_forward_context (fwd_id, context) VALUES ($1, $2)")
+        query("INSERT INTO map_call_forward_context (fwd_id, context) VALUES ($1, $2)")
             .bind(new_id)
             .bind(&ctx.asterisk_name)
             .execute(&mut *tx)
             .await
-            .map_err(|_| DBError::CannotInsertContextMapping(ctx.asterisk_name.clone(), new_id))?;
+            .map_err(|e| {
+                conflict_or(
+                    e,
+                    DBError::CannotInsertContextMapping(ctx.asterisk_name.clone(), new_id),
+                )
+            })?;
     }
-    tx.commit()
+    insert_destinations(tx, new_id, &new_forward.to).await?;
+    insert_schedule_windows(tx, new_id, &new_forward.schedule).await?;
+    insert_caller_ids(tx, new_id, &new_forward.caller_ids).await?;
+    let inserted = new_forward.set_id(new_id);
+    insert_audit_entry(
+        tx,
+        actor,
+        AuditAction::Create,
+        new_id,
+        None,
+        Some(&inserted),
+    )
+    .await?;
+    Ok(inserted)
+}
+
+/// Record that `actor` changed the call forward `fwd_id` from `before` to `after`
+async fn insert_audit_entry(
+    conn: &mut DbTransaction,
+    actor: &str,
+    action: AuditAction,
+    fwd_id: i32,
+    before: Option<&CallForward<'_, HasId>>,
+    after: Option<&CallForward<'_, HasId>>,
+) -> Result<(), DBError> {
+    let as_json = |fwd: Option<&CallForward<HasId>>| {
+        fwd.map(|x| serde_json::to_string(&CachedCallForward::from_call_forward(x)))
+            .transpose()
+            .map_err(|_| DBError::CannotInsertAuditEntry(fwd_id))
+    };
+    query(
+        "INSERT INTO audit_log (changed_at, actor, action, fwd_id, before, after) VALUES ($1, $2, $3, $4, $5::jsonb, $6::jsonb)",
+    )
+    .sqlite(
+        "INSERT INTO audit_log (changed_at, actor, action, fwd_id, before, after) VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(Utc::now())
+    .bind(actor)
+    .bind(action.kind())
+    .bind(fwd_id)
+    .bind(as_json(before)?)
+    .bind(as_json(after)?)
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| conflict_or(e, DBError::CannotInsertAuditEntry(fwd_id)))?;
+    Ok(())
+}
+
+/// Insert the destinations `to` for the call forward `fwd_id`, keeping their order
+async fn insert_destinations(
+    conn: &mut DbTransaction,
+    fwd_id: i32,
+    to: &[Extension],
+) -> Result<(), DBError> {
+    for (position, dest) in to.iter().enumerate() {
+        query("INSERT INTO call_forward_destination (fwd_id, position, to_extension) VALUES ($1, $2, $3)")
+            .bind(fwd_id)
+            .bind(position as i32)
+            .bind(&dest.extension)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| conflict_or(e, DBError::CannotInsertDestination(dest.extension.clone(), fwd_id)))?;
+    }
+    Ok(())
+}
+
+/// Insert the windows of `schedule` for the call forward `fwd_id`
+async fn insert_schedule_windows(
+    conn: &mut DbTransaction,
+    fwd_id: i32,
+    schedule: &Option<Schedule>,
+) -> Result<(), DBError> {
+    let Some(schedule) = schedule else {
+        return Ok(());
+    };
+    for window in schedule.windows.iter() {
+        query("INSERT INTO call_forward_schedule_window (fwd_id, first_weekday, last_weekday, start_time, end_time) VALUES ($1, $2, $3, $4, $5)")
+            .bind(fwd_id)
+            .bind(window.first_day_index() as i16)
+            .bind(window.last_day_index() as i16)
+            .bind(window.start)
+            .bind(window.end)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| conflict_or(e, DBError::CannotInsertScheduleWindow(fwd_id)))?;
+    }
+    Ok(())
+}
+
+/// Insert the caller id patterns for the call forward `fwd_id`
+async fn insert_caller_ids(
+    conn: &mut DbTransaction,
+    fwd_id: i32,
+    caller_ids: &[CallerIdPattern],
+) -> Result<(), DBError> {
+    for caller_id in caller_ids {
+        query(
+            "INSERT INTO call_forward_caller_id (fwd_id, pattern, is_prefix) VALUES ($1, $2, $3)",
+        )
+        .bind(fwd_id)
+        .bind(caller_id.pattern())
+        .bind(caller_id.is_prefix())
+        .execute(&mut *conn)
         .await
-        .map_err(|_| DBError::CannotCommitTransaction)?;
-    Ok(new_forward.set_id(new_id))
+        .map_err(|e| conflict_or(e, DBError::CannotInsertCallerId(fwd_id)))?;
+    }
+    Ok(())
 }
 
 fn convert_to_call_forwards(
     config: &Config,
-    call_forwards: Vec<PgRow>,
-) -> Result<Vec<CallForward<HasId>>, DBError> {
+    call_forwards: Vec<DbRow>,
+) -> Result<Vec<CallForward<'_, HasId>>, DBError> {
     let mut result: Vec<CallForward<HasId>> = vec![];
+    // every row contains one context and one destination,
+    // so we see every context and every destination multiple times
     'row: for row in call_forwards {
         let fwd_id: i32 = row.get("fwd_id");
         let from_extension: String = row.get("from_extension");
         let to_extension: String = row.get("to_extension");
         let context: String = row.get("context");
         for fwd in result.iter_mut() {
-            if fwd.to.extension == to_extension && fwd.from.extension == from_extension {
+            if Into::<i32>::into(fwd.fwd_id) == fwd_id {
                 let context_as_object = Context::create_from_name(config, &context);
                 match context_as_object {
                     None => {
                         return Err(DBError::ContextDoesNotExist(context));
                     }
                     Some(x) => {
-                        fwd.in_contexts.push(x);
-                        continue 'row;
+                        if !fwd.in_contexts.contains(&x) {
+                            fwd.in_contexts.push(x);
+                        };
                     }
                 };
+                if !fwd.to.iter().any(|x| x.extension == to_extension) {
+                    fwd.to
+                        .push(Extension::create_from_name(config, to_extension));
+                };
+                continue 'row;
             };
         }
-        // this destination has no call forward already set
+        // this call forward was not seen yet
         // so we add a new call forward into result
-        result.push(CallForward::<HasId>::new(
-            config,
-            from_extension,
-            to_extension,
-            vec![context],
-            fwd_id,
-        )?);
+        let condition = ForwardCondition::from_kind(
+            row.get("condition"),
+            row.get::<Option<i32>, &str>("no_answer_timeout")
+                .map(|x| x as u32),
+        )
+        .ok_or(DBError::InvalidCondition(fwd_id))?;
+        let schedule = match row.get::<Option<String>, &str>("schedule_timezone") {
+            None => None,
+            Some(tz_name) => Some(Schedule {
+                timezone: tz_name
+                    .parse::<Tz>()
+                    .map_err(|_| DBError::InvalidScheduleTimezone(tz_name.clone()))?,
+                windows: vec![],
+            }),
+        };
+        result.push(
+            CallForward::<HasId>::new(
+                config,
+                from_extension,
+                vec![to_extension],
+                vec![context],
+                fwd_id,
+            )?
+            .set_schedule(schedule)
+            .set_condition(condition)
+            .set_active(row.get("active"))
+            .set_validity(row.get("valid_from"), row.get("valid_until"))
+            .set_version(row.get("version")),
+        );
     }
     Ok(result)
 }
 
-/// Get all call forwards that start at `startpoint`
+/// Add the windows in `windows` to the schedules of the matching call forwards.
+///
+/// Call forwards that end up without any window are always active, so their schedule is removed.
+fn attach_schedule_windows(
+    forwards: &mut [CallForward<HasId>],
+    windows: Vec<DbRow>,
+) -> Result<(), DBError> {
+    for row in windows {
+        let fwd_id: i32 = row.get("fwd_id");
+        let Some(fwd) = forwards
+            .iter_mut()
+            .find(|fwd| Into::<i32>::into(fwd.fwd_id) == fwd_id)
+        else {
+            continue;
+        };
+        let first_day = Weekday::try_from(row.get::<i16, &str>("first_weekday") as u8)
+            .map_err(|_| DBError::InvalidScheduleWindow(fwd_id))?;
+        let last_day = Weekday::try_from(row.get::<i16, &str>("last_weekday") as u8)
+            .map_err(|_| DBError::InvalidScheduleWindow(fwd_id))?;
+        let window = ScheduleWindow::new(
+            first_day,
+            last_day,
+            row.get::<NaiveTime, &str>("start_time"),
+            row.get::<NaiveTime, &str>("end_time"),
+        )
+        .ok_or(DBError::InvalidScheduleWindow(fwd_id))?;
+        match fwd.schedule.as_mut() {
+            Some(schedule) => schedule.windows.push(window),
+            // windows without a timezone cannot be interpreted
+            None => return Err(DBError::InvalidScheduleWindow(fwd_id)),
+        };
+    }
+    for fwd in forwards.iter_mut() {
+        if fwd.schedule.as_ref().is_some_and(|s| s.windows.is_empty()) {
+            fwd.schedule = None;
+        };
+    }
+    Ok(())
+}
+
+/// Add the caller id patterns in `caller_ids` to the matching call forwards.
+fn attach_caller_ids(forwards: &mut [CallForward<HasId>], caller_ids: Vec<DbRow>) {
+    for row in caller_ids {
+        let fwd_id: i32 = row.get("fwd_id");
+        let Some(fwd) = forwards
+            .iter_mut()
+            .find(|fwd| Into::<i32>::into(fwd.fwd_id) == fwd_id)
+        else {
+            continue;
+        };
+        let pattern: String = row.get("pattern");
+        if row.get::<bool, &str>("is_prefix") {
+            fwd.caller_ids.push(CallerIdPattern::Prefix(pattern));
+        } else {
+            fwd.caller_ids.push(CallerIdPattern::Exact(pattern));
+        };
+    }
+}
+
+/// Get all call forwards that are not deleted
 #[tracing::instrument(level=Level::DEBUG,skip(config),err)]
 pub async fn get_all_call_forwards<'a>(
     config: &'a Config,
 ) -> Result<Vec<CallForward<'a, HasId>>, DBError> {
-    let call_forwards = sqlx::query(
-        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward.to_extension, map_call_forward_context.context
+    let _timer = time_db_query("get_all_call_forwards");
+    let mut tx = config
+        .pool
+        .begin()
+        .await
+        .map_err(|_| DBError::CannotSelectCallForwards)?;
+    select_all_call_forwards(config, &mut tx).await
+}
+
+/// Get all call forwards that are not deleted, as seen by `conn`
+async fn select_all_call_forwards<'a>(
+    config: &'a Config,
+    conn: &mut DbTransaction,
+) -> Result<Vec<CallForward<'a, HasId>>, DBError> {
+    let call_forwards = query(
+        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward_destination.to_extension, call_forward.schedule_timezone, call_forward.condition, call_forward.no_answer_timeout, call_forward.active, call_forward.valid_from, call_forward.valid_until, call_forward.version, map_call_forward_context.context
             FROM call_forward
          INNER JOIN map_call_forward_context
-            ON map_call_forward_context.fwd_id = call_forward.fwd_id"
+            ON map_call_forward_context.fwd_id = call_forward.fwd_id
+         INNER JOIN call_forward_destination
+            ON call_forward_destination.fwd_id = call_forward.fwd_id
+         WHERE call_forward.deleted_at IS NULL
+         ORDER BY call_forward.fwd_id, call_forward_destination.position"
     )
-        .fetch_all(&config.pool)
+        .fetch_all(&mut *conn)
         .await
-        .map_err(|_| DBError::CannotSelectCallForwards)?;
-    convert_to_call_forwards(config, call_forwards)
+        .map_err(|e| conflict_or(e, DBError::CannotSelectCallForwards))?;
+    let mut forwards = convert_to_call_forwards(config, call_forwards)?;
+    let windows = query(
+        "SELECT fwd_id, first_weekday, last_weekday, start_time, end_time FROM call_forward_schedule_window",
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| conflict_or(e, DBError::CannotSelectScheduleWindows))?;
+    attach_schedule_windows(&mut forwards, windows)?;
+    let caller_ids =
+        query("SELECT fwd_id, pattern, is_prefix FROM call_forward_caller_id ORDER BY pattern")
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(|e| conflict_or(e, DBError::CannotSelectCallerIds))?;
+    attach_caller_ids(&mut forwards, caller_ids);
+    Ok(forwards)
 }
 
 /// Get all call forwards that start at `startpoint`
@@ -190,39 +816,105 @@ pub async fn get_call_forwards_from_startpoint<'a>(
     config: &'a Config,
     startpoint: &Extension,
 ) -> Result<Vec<CallForward<'a, HasId>>, DBError> {
-    let call_forwards = sqlx::query(
-        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward.to_extension, map_call_forward_context.context
+    let _timer = time_db_query("get_call_forwards_from_startpoint");
+    let call_forwards = query(
+        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward_destination.to_extension, call_forward.schedule_timezone, call_forward.condition, call_forward.no_answer_timeout, call_forward.active, call_forward.valid_from, call_forward.valid_until, call_forward.version, map_call_forward_context.context
             FROM call_forward
          INNER JOIN map_call_forward_context
             ON map_call_forward_context.fwd_id = call_forward.fwd_id
-         WHERE from_extension = $1"
+         INNER JOIN call_forward_destination
+            ON call_forward_destination.fwd_id = call_forward.fwd_id
+         WHERE from_extension = $1 AND call_forward.deleted_at IS NULL
+         ORDER BY call_forward.fwd_id, call_forward_destination.position"
     )
         .bind(startpoint.extension.clone())
         .fetch_all(&config.pool)
         .await
         .map_err(|_| DBError::CannotSelectCallForwards)?;
-    convert_to_call_forwards(config, call_forwards)
+    let mut forwards = convert_to_call_forwards(config, call_forwards)?;
+    let windows = query(
+        "SELECT call_forward_schedule_window.fwd_id, first_weekday, last_weekday, start_time, end_time
+            FROM call_forward_schedule_window
+         INNER JOIN call_forward
+            ON call_forward.fwd_id = call_forward_schedule_window.fwd_id
+         WHERE call_forward.from_extension = $1",
+    )
+    .bind(startpoint.extension.clone())
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectScheduleWindows)?;
+    attach_schedule_windows(&mut forwards, windows)?;
+    let caller_ids = query(
+        "SELECT call_forward_caller_id.fwd_id, pattern, is_prefix
+            FROM call_forward_caller_id
+         INNER JOIN call_forward
+            ON call_forward.fwd_id = call_forward_caller_id.fwd_id
+         WHERE call_forward.from_extension = $1
+         ORDER BY pattern",
+    )
+    .bind(startpoint.extension.clone())
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectCallerIds)?;
+    attach_caller_ids(&mut forwards, caller_ids);
+    Ok(forwards)
 }
 
-/// Get call forward with a specific id
+/// Get call forward with a specific id, unless it was deleted
 #[tracing::instrument(level=Level::DEBUG,skip(config),err)]
 pub async fn get_call_forward_by_id<'a>(
     config: &'a Config,
     fwdid: i32,
 ) -> Result<CallForward<'a, HasId>, DBError> {
-    let call_forwards = sqlx::query(
-        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward.to_extension, map_call_forward_context.context
+    let _timer = time_db_query("get_call_forward_by_id");
+    let mut tx = config
+        .pool
+        .begin()
+        .await
+        .map_err(|_| DBError::CannotSelectCallForward(fwdid))?;
+    select_call_forward_by_id(config, &mut tx, fwdid, false).await
+}
+
+/// Get the call forward `fwdid` as seen by `conn`, if it is deleted exactly when `deleted` is set
+async fn select_call_forward_by_id<'a>(
+    config: &'a Config,
+    conn: &mut DbTransaction,
+    fwdid: i32,
+    deleted: bool,
+) -> Result<CallForward<'a, HasId>, DBError> {
+    let call_forwards = query(
+        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward_destination.to_extension, call_forward.schedule_timezone, call_forward.condition, call_forward.no_answer_timeout, call_forward.active, call_forward.valid_from, call_forward.valid_until, call_forward.version, map_call_forward_context.context
             FROM call_forward
          INNER JOIN map_call_forward_context
             ON map_call_forward_context.fwd_id = call_forward.fwd_id
+         INNER JOIN call_forward_destination
+            ON call_forward_destination.fwd_id = call_forward.fwd_id
         WHERE
-            call_forward.fwd_id = $1"
+            call_forward.fwd_id = $1 AND (call_forward.deleted_at IS NOT NULL) = $2
+        ORDER BY call_forward_destination.position"
     )
         .bind(fwdid)
-        .fetch_all(&config.pool)
+        .bind(deleted)
+        .fetch_all(&mut *conn)
         .await
-        .map_err(|_| DBError::CannotSelectCallForwards)?;
-    let forwards = convert_to_call_forwards(config, call_forwards)?;
+        .map_err(|e| conflict_or(e, DBError::CannotSelectCallForwards))?;
+    let mut forwards = convert_to_call_forwards(config, call_forwards)?;
+    let windows = query(
+        "SELECT fwd_id, first_weekday, last_weekday, start_time, end_time FROM call_forward_schedule_window WHERE fwd_id = $1",
+    )
+    .bind(fwdid)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| conflict_or(e, DBError::CannotSelectScheduleWindows))?;
+    attach_schedule_windows(&mut forwards, windows)?;
+    let caller_ids = query(
+        "SELECT fwd_id, pattern, is_prefix FROM call_forward_caller_id WHERE fwd_id = $1 ORDER BY pattern",
+    )
+    .bind(fwdid)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| conflict_or(e, DBError::CannotSelectCallerIds))?;
+    attach_caller_ids(&mut forwards, caller_ids);
     if forwards.len() == 1 {
         Ok(forwards
             .into_iter()
@@ -233,76 +925,222 @@ pub async fn get_call_forward_by_id<'a>(
     }
 }
 
-/// Remove a given call forward
+/// Delete a given call forward, recording in the audit log that `actor` deleted it.
+///
+/// It is only marked as deleted, so it can be restored with [`restore_call_forward`] until
+/// [`delete_call_forwards_deleted_before`] removes it for good.
+/// Call forwards that do not exist (anymore) are silently ignored.
 #[tracing::instrument(level=Level::DEBUG,skip(config),err)]
-pub async fn delete_call_forward_by_id<'a>(config: &'a Config, fwd_id: i32) -> Result<(), DBError> {
-    let mut tx = config
-        .pool
-        .begin()
+pub async fn delete_call_forward_by_id<'a>(
+    config: &'a Config,
+    fwd_id: i32,
+    actor: &str,
+) -> Result<(), DBError> {
+    let _timer = time_db_query("delete_call_forward_by_id");
+    retry_on_conflict(|| try_delete_call_forward_by_id(config, fwd_id, actor)).await
+}
+
+/// One attempt of [`delete_call_forward_by_id`]
+async fn try_delete_call_forward_by_id(
+    config: &Config,
+    fwd_id: i32,
+    actor: &str,
+) -> Result<(), DBError> {
+    let mut tx = begin_serializable(config).await?;
+    mark_call_forward_deleted(config, &mut tx, fwd_id, None, actor).await?;
+    tx.commit()
         .await
-        .map_err(|_| DBError::CannotStartTransaction)?;
-    sqlx::query("DELETE FROM call_forward WHERE fwd_id = $1")
+        .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
+    Ok(())
+}
+
+/// Mark the call forward `fwd_id` as deleted in `tx`, recording in the audit log that `actor`
+/// deleted it.
+///
+/// It is read in `tx`, so that the audit log shows what was actually deleted. If `version` is given
+/// and the call forward was changed since, this returns DBError::StaleCallForward.
+/// Nothing is recorded if it was deleted already.
+async fn mark_call_forward_deleted(
+    config: &Config,
+    tx: &mut DbTransaction,
+    fwd_id: i32,
+    version: Option<i32>,
+    actor: &str,
+) -> Result<(), DBError> {
+    let before = match select_call_forward_by_id(config, tx, fwd_id, false).await {
+        Ok(x) => x,
+        // someone else was faster and already recorded it
+        Err(DBError::CannotSelectCallForward(_)) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if version.is_some_and(|x| x != before.version) {
+        return Err(DBError::StaleCallForward(fwd_id));
+    };
+    query("UPDATE call_forward SET deleted_at = $2 WHERE fwd_id = $1 AND deleted_at IS NULL")
         .bind(fwd_id)
+        .bind(Utc::now())
         .execute(&mut *tx)
         .await
-        .map_err(|_| DBError::CannotDeleteCallForward)?;
+        .map_err(|e| conflict_or(e, DBError::CannotDeleteCallForward))?;
+    insert_audit_entry(tx, actor, AuditAction::Delete, fwd_id, Some(&before), None).await
+}
+
+/// Update a call forward, recording in the audit log that `actor` changed it.
+///
+/// This does not switch the call forward on or off, see [`set_call_forward_active`].
+/// If the call forward was changed since `forward` was read, i.e. its version differs, this
+/// function returns DBError::StaleCallForward and changes nothing.
+/// Like [`new_call_forward`], this function returns DBError::OverlappingCallForwards or
+/// DBError::CyclicCallForwards if the updated call forward would overlap or form a loop with the
+/// other call forwards.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn update_call_forward<'a>(
+    config: &'a Config,
+    forward: &CallForward<'a, HasId>,
+    actor: &str,
+) -> Result<(), DBError> {
+    let _timer = time_db_query("update_call_forward");
+    retry_on_conflict(|| try_update_call_forward(config, forward, actor)).await
+}
+
+/// One attempt of [`update_call_forward`]
+async fn try_update_call_forward<'a>(
+    config: &'a Config,
+    forward: &CallForward<'a, HasId>,
+    actor: &str,
+) -> Result<(), DBError> {
+    let mut tx = begin_serializable(config).await?;
+    change_call_forward(config, &mut tx, forward, actor).await?;
     tx.commit()
         .await
-        .map_err(|_| DBError::CannotCommitTransaction)?;
+        .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
     Ok(())
 }
 
-#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
-pub async fn update_call_forward<'a>(
+/// Check `forward` and update it in `tx`, recording in the audit log that `actor` changed it
+async fn change_call_forward<'a>(
     config: &'a Config,
+    tx: &mut DbTransaction,
     forward: &CallForward<'a, HasId>,
+    actor: &str,
 ) -> Result<(), DBError> {
+    // this also makes sure the call forward actually exists
+    let before = select_call_forward_by_id(config, tx, forward.fwd_id.into(), false).await?;
+    if before.version != forward.version {
+        return Err(DBError::StaleCallForward(forward.fwd_id.into()));
+    };
+    let after = forward
+        .clone()
+        .set_active(before.active)
+        .set_version(before.version + 1);
+    check_call_forward(config, tx, &after, Some(forward.fwd_id.into())).await?;
+
     // update the contexts
     //  get the contexts currently in the DB
     //  calculate the diff
     //  apply the diff
-    let mut tx = config
-        .pool
-        .begin()
+
+    // Update the source and count the change
+    query("UPDATE call_forward SET from_extension = $1, version = version + 1 WHERE fwd_id = $2")
+        .bind(&forward.from.extension)
+        .bind(Into::<i32>::into(forward.fwd_id))
+        .execute(&mut *tx)
         .await
-        .map_err(|_| DBError::CannotStartTransaction)?;
+        .map_err(|e| conflict_or(e, DBError::CannotUpdateCallForwardDestination))?;
 
-    // make sure the call forward actually exists
-    let res = sqlx::query("SELECT COUNT(*) AS count FROM call_forward WHERE fwd_id = $1")
+    // Replace the targets
+    query("DELETE FROM call_forward_destination WHERE fwd_id = $1")
         .bind(Into::<i32>::into(forward.fwd_id))
-        .fetch_one(&mut *tx)
+        .execute(&mut *tx)
         .await
-        .map_err(|_| DBError::CannotSelectCallForward(Into::<i32>::into(forward.fwd_id)))?;
-    if res.get::<i64, &str>("count") != 1 {
-        return Err(DBError::CannotSelectCallForward(Into::<i32>::into(
-            forward.fwd_id,
-        )));
-    }
+        .map_err(|e| {
+            conflict_or(
+                e,
+                DBError::CannotDeleteDestinations(Into::<i32>::into(forward.fwd_id)),
+            )
+        })?;
+    insert_destinations(tx, Into::<i32>::into(forward.fwd_id), &forward.to).await?;
 
-    // Update the source
-    sqlx::query("UPDATE call_forward SET from_extension = $1 WHERE fwd_id = $2")
-        .bind(&forward.from.extension)
+    // Update the condition
+    query("UPDATE call_forward SET condition = $1, no_answer_timeout = $2 WHERE fwd_id = $3")
+        .bind(forward.condition.kind())
+        .bind(forward.condition.no_answer_timeout().map(|x| x as i32))
         .bind(Into::<i32>::into(forward.fwd_id))
         .execute(&mut *tx)
         .await
-        .map_err(|_| DBError::CannotUpdateCallForwardDestination)?;
+        .map_err(|e| {
+            conflict_or(
+                e,
+                DBError::CannotUpdateCondition(Into::<i32>::into(forward.fwd_id)),
+            )
+        })?;
 
-    // Update the target
-    sqlx::query("UPDATE call_forward SET to_extension = $1 WHERE fwd_id = $2")
-        .bind(&forward.to.extension)
+    // Update the validity
+    query("UPDATE call_forward SET valid_from = $1, valid_until = $2 WHERE fwd_id = $3")
+        .bind(forward.valid_from)
+        .bind(forward.valid_until)
         .bind(Into::<i32>::into(forward.fwd_id))
         .execute(&mut *tx)
         .await
-        .map_err(|_| DBError::CannotUpdateCallForwardDestination)?;
+        .map_err(|e| {
+            conflict_or(
+                e,
+                DBError::CannotUpdateValidity(Into::<i32>::into(forward.fwd_id)),
+            )
+        })?;
 
-    // Get the contexts currently in the DB
-    let context_res: Vec<String> =
-        sqlx::query("SELECT context FROM map_call_forward_context WHERE fwd_id = $1")
+    // Replace the schedule
+    query("UPDATE call_forward SET schedule_timezone = $1 WHERE fwd_id = $2")
+        .bind(forward.schedule.as_ref().map(|s| s.timezone.name()))
+        .bind(Into::<i32>::into(forward.fwd_id))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            conflict_or(
+                e,
+                DBError::CannotUpdateSchedule(Into::<i32>::into(forward.fwd_id)),
+            )
+        })?;
+    query("DELETE FROM call_forward_schedule_window WHERE fwd_id = $1")
+        .bind(Into::<i32>::into(forward.fwd_id))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            conflict_or(
+                e,
+                DBError::CannotDeleteScheduleWindows(Into::<i32>::into(forward.fwd_id)),
+            )
+        })?;
+    insert_schedule_windows(tx, Into::<i32>::into(forward.fwd_id), &forward.schedule).await?;
+
+    // Replace the caller ids
+    query("DELETE FROM call_forward_caller_id WHERE fwd_id = $1")
+        .bind(Into::<i32>::into(forward.fwd_id))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| {
+            conflict_or(
+                e,
+                DBError::CannotDeleteCallerIds(Into::<i32>::into(forward.fwd_id)),
+            )
+        })?;
+    insert_caller_ids(tx, Into::<i32>::into(forward.fwd_id), &forward.caller_ids).await?;
+
+    // Get the contexts currently in the DB
+    let context_res: Vec<String> =
+        query("SELECT context FROM map_call_forward_context WHERE fwd_id = $1")
             .bind(Into::<i32>::into(forward.fwd_id))
-            .map(|row: PgRow| row.get("context"))
             .fetch_all(&mut *tx)
             .await
-            .map_err(|_| DBError::CannotSelectContexts(Into::<i32>::into(forward.fwd_id)))?;
+            .map_err(|e| {
+                conflict_or(
+                    e,
+                    DBError::CannotSelectContexts(Into::<i32>::into(forward.fwd_id)),
+                )
+            })?
+            .iter()
+            .map(|row| row.get("context"))
+            .collect();
 
     // The contexts that are set in forward, but not yet in the DB
     let contexts_to_set = forward.in_contexts.iter().filter_map(|x| {
@@ -314,134 +1152,1663 @@ pub async fn update_call_forward<'a>(
     });
     // Insert the contexts which are new
     for ctx_to_set in contexts_to_set {
-        sqlx::query("INSERT INTO map_call_forward_context (fwd_id, context) VALUES ($1, $2)")
+        query("INSERT INTO map_call_forward_context (fwd_id, context) VALUES ($1, $2)")
             .bind(Into::<i32>::into(forward.fwd_id))
             .bind(&ctx_to_set)
             .execute(&mut *tx)
             .await
-            .map_err(|_| {
-                DBError::CannotInsertContextMapping(
-                    ctx_to_set.clone(),
-                    Into::<i32>::into(forward.fwd_id),
+            .map_err(|e| {
+                conflict_or(
+                    e,
+                    DBError::CannotInsertContextMapping(
+                        ctx_to_set.clone(),
+                        Into::<i32>::into(forward.fwd_id),
+                    ),
                 )
             })?;
     }
 
     // The contexts that are in the DB, but not in forward anymore
     let contexts_to_delete = context_res.iter().filter(|&x| {
-        forward
+        !forward
             .in_contexts
             .iter()
             .any(|ctx| ctx.asterisk_name == *x)
     });
     // Delete the contexts which are no longer required
     for ctx_to_delete in contexts_to_delete {
-        sqlx::query("DELETE FROM map_call_forward_context WHERE fwd_id = $1 and context = $2 ")
+        query("DELETE FROM map_call_forward_context WHERE fwd_id = $1 and context = $2 ")
             .bind(Into::<i32>::into(forward.fwd_id))
             .bind(ctx_to_delete)
             .execute(&mut *tx)
             .await
-            .map_err(|_| {
-                DBError::CannotDeleteContextMapping(
-                    ctx_to_delete.clone(),
-                    Into::<i32>::into(forward.fwd_id),
+            .map_err(|e| {
+                conflict_or(
+                    e,
+                    DBError::CannotDeleteContextMapping(
+                        ctx_to_delete.clone(),
+                        Into::<i32>::into(forward.fwd_id),
+                    ),
                 )
             })?;
     }
 
+    insert_audit_entry(
+        tx,
+        actor,
+        AuditAction::Update,
+        forward.fwd_id.into(),
+        Some(&before),
+        Some(&after),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Switch the call forward `fwd_id` on or off.
+///
+/// Switching it on returns DBError::OverlappingCallForwards or DBError::CyclicCallForwards if it
+/// would overlap another active call forward or form a loop with them, like a new call forward.
+/// The change is recorded in the audit log as made by `actor`.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn set_call_forward_active(
+    config: &Config,
+    fwd_id: i32,
+    active: bool,
+    actor: &str,
+) -> Result<(), DBError> {
+    let _timer = time_db_query("set_call_forward_active");
+    retry_on_conflict(|| try_set_call_forward_active(config, fwd_id, active, actor)).await
+}
+
+/// One attempt of [`set_call_forward_active`]
+async fn try_set_call_forward_active(
+    config: &Config,
+    fwd_id: i32,
+    active: bool,
+    actor: &str,
+) -> Result<(), DBError> {
+    let mut tx = begin_serializable(config).await?;
+    switch_call_forward(config, &mut tx, fwd_id, active, actor).await?;
+    tx.commit()
+        .await
+        .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
+    Ok(())
+}
+
+/// Switch the call forward `fwd_id` on or off in `tx`, recording the change as made by `actor`
+async fn switch_call_forward(
+    config: &Config,
+    tx: &mut DbTransaction,
+    fwd_id: i32,
+    active: bool,
+    actor: &str,
+) -> Result<(), DBError> {
+    let before = select_call_forward_by_id(config, tx, fwd_id, false).await?;
+    let forward = before
+        .clone()
+        .set_active(active)
+        .set_version(before.version + 1);
+    if active {
+        check_call_forward(config, tx, &forward, Some(fwd_id)).await?;
+    };
+    let res =
+        query("UPDATE call_forward SET active = $1, version = version + 1 WHERE fwd_id = $2 AND deleted_at IS NULL")
+            .bind(active)
+            .bind(fwd_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| conflict_or(e, DBError::CannotUpdateActive(fwd_id)))?;
+    if res != 1 {
+        return Err(DBError::CannotSelectCallForward(fwd_id));
+    };
+    let action = if active {
+        AuditAction::SwitchOn
+    } else {
+        AuditAction::SwitchOff
+    };
+    insert_audit_entry(tx, actor, action, fwd_id, Some(&before), Some(&forward)).await?;
+    Ok(())
+}
+
+/// Make all `changes` in one transaction, in the given order, recording each in the audit log as
+/// made by `actor`.
+///
+/// Every change is checked like in [`new_call_forward`] and [`update_call_forward`], against the
+/// call forwards as the changes before it left them. If one fails, none is made and
+/// DBError::CannotApplyChange says which one it was.
+/// Unless `commit` is set, the changes are rolled back in any case, so this only finds out whether
+/// they could be made.
+///
+/// Returns the added call forwards, in the order of `changes`. Their ids are only valid if
+/// `commit` is set.
+#[tracing::instrument(level=Level::DEBUG,skip_all,err)]
+pub async fn apply_call_forward_changes<'a>(
+    config: &'a Config,
+    changes: &[ForwardChange<'a>],
+    actor: &str,
+    commit: bool,
+) -> Result<Vec<CallForward<'a, HasId>>, DBError> {
+    let _timer = time_db_query("apply_call_forward_changes");
+    retry_on_conflict(|| try_apply_call_forward_changes(config, changes, actor, commit)).await
+}
+
+/// One attempt of [`apply_call_forward_changes`]
+async fn try_apply_call_forward_changes<'a>(
+    config: &'a Config,
+    changes: &[ForwardChange<'a>],
+    actor: &str,
+    commit: bool,
+) -> Result<Vec<CallForward<'a, HasId>>, DBError> {
+    let mut tx = begin_serializable(config).await?;
+    let mut added = vec![];
+    for (idx, change) in changes.iter().enumerate() {
+        let res = apply_call_forward_change(config, &mut tx, change, actor)
+            .await
+            .map_err(|e| match e {
+                // the whole transaction is tried again
+                DBError::ConcurrentChange => e,
+                e => DBError::CannotApplyChange(idx, Box::new(e)),
+            })?;
+        added.extend(res);
+    }
+    if commit {
+        tx.commit()
+            .await
+            .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
+    } else {
+        tx.rollback()
+            .await
+            .map_err(|_| DBError::CannotRollbackTransaction)?;
+    };
+    Ok(added)
+}
+
+/// Make `change` in `tx`, returning the call forward it added, if any
+async fn apply_call_forward_change<'a>(
+    config: &'a Config,
+    tx: &mut DbTransaction,
+    change: &ForwardChange<'a>,
+    actor: &str,
+) -> Result<Option<CallForward<'a, HasId>>, DBError> {
+    match change {
+        ForwardChange::Add(forward) => {
+            return Ok(Some(
+                insert_call_forward(config, tx, forward.clone(), actor).await?,
+            ));
+        }
+        ForwardChange::Update { before, after } => {
+            let fwd_id: i32 = before.fwd_id.into();
+            let mut version = after.version;
+            // switch off first and on last, so that it is only checked while it is active
+            if before.active && !after.active {
+                switch_call_forward(config, tx, fwd_id, false, actor).await?;
+                version += 1;
+            };
+            if (**after)
+                .clone()
+                .set_active(before.active)
+                .set_version(before.version)
+                != **before
+            {
+                change_call_forward(config, tx, &(**after).clone().set_version(version), actor)
+                    .await?;
+            };
+            if !before.active && after.active {
+                switch_call_forward(config, tx, fwd_id, true, actor).await?;
+            };
+        }
+        ForwardChange::Remove(forward) => {
+            mark_call_forward_deleted(
+                config,
+                tx,
+                forward.fwd_id.into(),
+                Some(forward.version),
+                actor,
+            )
+            .await?;
+        }
+    };
+    Ok(None)
+}
+
+/// Delete the call forwards that expired before `before`, recording each in the audit log.
+///
+/// Returns the number of deleted call forwards.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn delete_call_forwards_expired_before(
+    config: &Config,
+    before: DateTime<Utc>,
+) -> Result<u64, DBError> {
+    let _timer = time_db_query("delete_call_forwards_expired_before");
+    let expired = get_all_call_forwards(config)
+        .await?
+        .into_iter()
+        .filter(|fwd| fwd.valid_until.is_some_and(|until| until < before))
+        .collect::<Vec<_>>();
+    if expired.is_empty() {
+        return Ok(0);
+    };
+    let mut tx = config
+        .pool
+        .begin()
+        .await
+        .map_err(|_| DBError::CannotStartTransaction)?;
+    let mut deleted = 0;
+    for fwd in expired.iter() {
+        // it may have been extended in the meantime
+        let res = query(
+            "DELETE FROM call_forward WHERE fwd_id = $1 AND valid_until < $2 AND deleted_at IS NULL",
+        )
+            .bind(Into::<i32>::into(fwd.fwd_id))
+            .bind(before)
+            .execute(&mut tx)
+            .await
+            .map_err(|_| DBError::CannotDeleteExpiredCallForwards)?;
+        if res == 1 {
+            insert_audit_entry(
+                &mut tx,
+                SYSTEM_ACTOR,
+                AuditAction::Purge,
+                fwd.fwd_id.into(),
+                Some(fwd),
+                None,
+            )
+            .await?;
+            deleted += 1;
+        };
+    }
+    tx.commit()
+        .await
+        .map_err(|_| DBError::CannotCommitTransaction)?;
+    Ok(deleted)
+}
+
+/// Get all deleted call forwards, most recently deleted first
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_deleted_call_forwards<'a>(
+    config: &'a Config,
+) -> Result<Vec<DeletedCallForward<'a>>, DBError> {
+    let _timer = time_db_query("get_deleted_call_forwards");
+    let call_forwards = query(
+        "SELECT call_forward.fwd_id, call_forward.from_extension, call_forward_destination.to_extension, call_forward.schedule_timezone, call_forward.condition, call_forward.no_answer_timeout, call_forward.active, call_forward.valid_from, call_forward.valid_until, call_forward.version, call_forward.deleted_at, map_call_forward_context.context
+            FROM call_forward
+         INNER JOIN map_call_forward_context
+            ON map_call_forward_context.fwd_id = call_forward.fwd_id
+         INNER JOIN call_forward_destination
+            ON call_forward_destination.fwd_id = call_forward.fwd_id
+         WHERE call_forward.deleted_at IS NOT NULL
+         ORDER BY call_forward.fwd_id, call_forward_destination.position"
+    )
+        .fetch_all(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotSelectCallForwards)?;
+    let deleted_at = call_forwards
+        .iter()
+        .map(|row| {
+            (
+                row.get::<i32, &str>("fwd_id"),
+                row.get::<DateTime<Utc>, &str>("deleted_at"),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let mut forwards = convert_to_call_forwards(config, call_forwards)?;
+    let windows = query(
+        "SELECT fwd_id, first_weekday, last_weekday, start_time, end_time FROM call_forward_schedule_window",
+    )
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectScheduleWindows)?;
+    attach_schedule_windows(&mut forwards, windows)?;
+    let caller_ids =
+        query("SELECT fwd_id, pattern, is_prefix FROM call_forward_caller_id ORDER BY pattern")
+            .fetch_all(&config.pool)
+            .await
+            .map_err(|_| DBError::CannotSelectCallerIds)?;
+    attach_caller_ids(&mut forwards, caller_ids);
+    let mut forwards = forwards
+        .into_iter()
+        .map(|forward| DeletedCallForward {
+            deleted_at: deleted_at[&Into::<i32>::into(forward.fwd_id)],
+            forward,
+        })
+        .collect::<Vec<_>>();
+    forwards.sort_by_key(|x| std::cmp::Reverse(x.deleted_at));
+    Ok(forwards)
+}
+
+/// Restore the deleted call forward `fwd_id`, recording in the audit log that `actor` restored it.
+///
+/// Like a new call forward, this returns DBError::OverlappingCallForwards or
+/// DBError::CyclicCallForwards if it is switched on and would overlap a current call forward or
+/// form a loop with them.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn restore_call_forward(
+    config: &Config,
+    fwd_id: i32,
+    actor: &str,
+) -> Result<(), DBError> {
+    let _timer = time_db_query("restore_call_forward");
+    retry_on_conflict(|| try_restore_call_forward(config, fwd_id, actor)).await
+}
+
+/// One attempt of [`restore_call_forward`]
+async fn try_restore_call_forward(
+    config: &Config,
+    fwd_id: i32,
+    actor: &str,
+) -> Result<(), DBError> {
+    let mut tx = begin_serializable(config).await?;
+    let forward = select_call_forward_by_id(config, &mut tx, fwd_id, true).await?;
+    if forward.active {
+        check_call_forward(config, &mut tx, &forward, Some(fwd_id)).await?;
+    };
+    let res = query(
+        "UPDATE call_forward SET deleted_at = NULL WHERE fwd_id = $1 AND deleted_at IS NOT NULL",
+    )
+    .bind(fwd_id)
+    .execute(&mut tx)
+    .await
+    .map_err(|e| conflict_or(e, DBError::CannotRestoreCallForward(fwd_id)))?;
+    if res != 1 {
+        return Err(DBError::CannotSelectCallForward(fwd_id));
+    };
+    insert_audit_entry(
+        &mut tx,
+        actor,
+        AuditAction::Restore,
+        fwd_id,
+        None,
+        Some(&forward),
+    )
+    .await?;
+    tx.commit()
+        .await
+        .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
+    Ok(())
+}
+
+/// Remove the call forwards that were deleted before `before` for good, recording each in the
+/// audit log.
+///
+/// Returns the number of removed call forwards.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn delete_call_forwards_deleted_before(
+    config: &Config,
+    before: DateTime<Utc>,
+) -> Result<u64, DBError> {
+    let _timer = time_db_query("delete_call_forwards_deleted_before");
+    let deleted = get_deleted_call_forwards(config)
+        .await?
+        .into_iter()
+        .filter(|x| x.deleted_at < before)
+        .collect::<Vec<_>>();
+    if deleted.is_empty() {
+        return Ok(0);
+    };
+    let mut tx = config
+        .pool
+        .begin()
+        .await
+        .map_err(|_| DBError::CannotStartTransaction)?;
+    let mut purged = 0;
+    for fwd in deleted.iter().map(|x| &x.forward) {
+        // it may have been restored in the meantime
+        let res = query("DELETE FROM call_forward WHERE fwd_id = $1 AND deleted_at < $2")
+            .bind(Into::<i32>::into(fwd.fwd_id))
+            .bind(before)
+            .execute(&mut tx)
+            .await
+            .map_err(|_| DBError::CannotPurgeDeletedCallForwards)?;
+        if res == 1 {
+            insert_audit_entry(
+                &mut tx,
+                SYSTEM_ACTOR,
+                AuditAction::PurgeDeleted,
+                fwd.fwd_id.into(),
+                Some(fwd),
+                None,
+            )
+            .await?;
+            purged += 1;
+        };
+    }
+    tx.commit()
+        .await
+        .map_err(|_| DBError::CannotCommitTransaction)?;
+    Ok(purged)
+}
+
+/// Get the latest `limit` changes to call forwards, newest first.
+///
+/// Only changes to call forwards from or to `extension` are returned, if it is given,
+/// and only changes made by `actor`, if it is given.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_audit_entries(
+    config: &Config,
+    extension: Option<&str>,
+    actor: Option<&str>,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, DBError> {
+    let _timer = time_db_query("get_audit_entries");
+    let rows = query(
+        "SELECT audit_id, changed_at, actor, action, fwd_id, before::text AS before, after::text AS after
+            FROM audit_log
+         WHERE ($1::text IS NULL
+                OR before->>'from' = $1 OR after->>'from' = $1
+                OR before->'to' ? $1 OR after->'to' ? $1)
+            AND ($2::text IS NULL OR actor = $2)
+         ORDER BY changed_at DESC, audit_id DESC
+         LIMIT $3",
+    )
+    .sqlite(
+        "SELECT audit_id, changed_at, actor, action, fwd_id, before, after
+            FROM audit_log
+         WHERE ($1 IS NULL
+                OR before->>'from' = $1 OR after->>'from' = $1
+                OR EXISTS (SELECT 1 FROM json_each(before, '$.to') WHERE value = $1)
+                OR EXISTS (SELECT 1 FROM json_each(after, '$.to') WHERE value = $1))
+            AND ($2 IS NULL OR actor = $2)
+         ORDER BY changed_at DESC, audit_id DESC
+         LIMIT $3",
+    )
+    .bind(extension)
+    .bind(actor)
+    .bind(limit)
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectAuditEntries)?;
+    rows.into_iter()
+        .map(|row| {
+            let audit_id: i64 = row.get("audit_id");
+            let invalid = |_| DBError::InvalidAuditEntry(audit_id);
+            let from_json = |column: &str| {
+                row.get::<Option<String>, &str>(column)
+                    .map(|x| serde_json::from_str::<CachedCallForward>(&x))
+                    .transpose()
+                    .map_err(invalid)
+            };
+            Ok(AuditEntry {
+                changed_at: row.get("changed_at"),
+                actor: row.get("actor"),
+                action: AuditAction::from_kind(row.get("action"))
+                    .ok_or(DBError::InvalidAuditEntry(audit_id))?,
+                fwd_id: row.get("fwd_id"),
+                before: from_json("before")?,
+                after: from_json("after")?,
+            })
+        })
+        .collect()
+}
+
+/// Get the PIN of `extension`, if one is set
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_extension_pin(
+    config: &Config,
+    extension: &str,
+) -> Result<Option<ExtensionPin>, DBError> {
+    let _timer = time_db_query("get_extension_pin");
+    let row = query(
+        "SELECT extension, pin_hash, failed_attempts, locked_until FROM extension_pin WHERE extension = $1",
+    )
+    .bind(extension)
+    .fetch_optional(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectPin(extension.to_string()))?;
+    Ok(row.map(|row| ExtensionPin {
+        extension: row.get("extension"),
+        pin_hash: row.get("pin_hash"),
+        failed_attempts: row.get::<i32, &str>("failed_attempts") as u32,
+        locked_until: row.get("locked_until"),
+    }))
+}
+
+/// Set the PIN of `extension` to the (already hashed) `pin_hash`.
+///
+/// This also lifts a lockout.
+#[tracing::instrument(level=Level::DEBUG,skip(config, pin_hash),err)]
+pub async fn set_extension_pin(
+    config: &Config,
+    extension: &str,
+    pin_hash: &str,
+) -> Result<(), DBError> {
+    let _timer = time_db_query("set_extension_pin");
+    query(
+        "INSERT INTO extension_pin (extension, pin_hash, failed_attempts, locked_until) VALUES ($1, $2, 0, NULL)
+         ON CONFLICT (extension) DO UPDATE SET pin_hash = $2, failed_attempts = 0, locked_until = NULL",
+    )
+    .bind(extension)
+    .bind(pin_hash)
+    .execute(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSetPin(extension.to_string()))?;
+    Ok(())
+}
+
+/// Remove the PIN of `extension`, disabling remote access to it
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn delete_extension_pin(config: &Config, extension: &str) -> Result<(), DBError> {
+    let _timer = time_db_query("delete_extension_pin");
+    query("DELETE FROM extension_pin WHERE extension = $1")
+        .bind(extension)
+        .execute(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotDeletePin(extension.to_string()))?;
+    Ok(())
+}
+
+/// Count an attempt to enter the PIN of `extension` at `now`, before the PIN is checked.
+///
+/// The count is taken in one statement, so that parallel attempts are counted one after the other
+/// and no more than `config.pin_max_attempts` PINs are checked before the lockout. The attempt
+/// reaching it locks the extension until `locked_until` and starts the count again.
+/// Returns the hash to check the PIN against, or None if there is no PIN or it is locked.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn reserve_pin_attempt(
+    config: &Config,
+    extension: &str,
+    now: DateTime<Utc>,
+    locked_until: DateTime<Utc>,
+) -> Result<Option<String>, DBError> {
+    let _timer = time_db_query("reserve_pin_attempt");
+    let row = query(
+        "UPDATE extension_pin SET
+            failed_attempts = CASE WHEN failed_attempts + 1 >= $2 THEN 0 ELSE failed_attempts + 1 END,
+            locked_until = CASE WHEN failed_attempts + 1 >= $2 THEN $4 ELSE locked_until END
+         WHERE extension = $1 AND (locked_until IS NULL OR locked_until <= $3)
+         RETURNING pin_hash",
+    )
+    .bind(extension)
+    .bind(config.pin_max_attempts as i32)
+    .bind(now)
+    .bind(locked_until)
+    .fetch_optional(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotUpdatePinAttempts(extension.to_string()))?;
+    Ok(row.map(|row| row.get("pin_hash")))
+}
+
+/// Forget the attempts counted for `extension` after a correct PIN, along with the lockout the
+/// last of them may have started
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn reset_failed_pin_attempts(config: &Config, extension: &str) -> Result<(), DBError> {
+    let _timer = time_db_query("reset_failed_pin_attempts");
+    query("UPDATE extension_pin SET failed_attempts = 0, locked_until = NULL WHERE extension = $1")
+        .bind(extension)
+        .execute(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotUpdatePinAttempts(extension.to_string()))?;
+    Ok(())
+}
+
+/// Log that asterisk looked up where to put a call through
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn insert_call_lookup(config: &Config, lookup: &CallLookup) -> Result<(), DBError> {
+    let _timer = time_db_query("insert_call_lookup");
+    query(
+        "INSERT INTO call_lookup (looked_up_at, caller_id, dialed_extension, context, destination, fwd_id)
+            VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(lookup.looked_up_at)
+    .bind(&lookup.caller_id)
+    .bind(&lookup.dialed_extension)
+    .bind(&lookup.context)
+    .bind(lookup.destinations.join("&"))
+    .bind(lookup.fwd_id)
+    .execute(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotInsertCallLookup(lookup.dialed_extension.clone()))?;
+    Ok(())
+}
+
+/// Get the latest `limit` lookups for calls to `extension`, newest first
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_call_lookups(
+    config: &Config,
+    extension: &str,
+    limit: i64,
+) -> Result<Vec<CallLookup>, DBError> {
+    let _timer = time_db_query("get_call_lookups");
+    let rows = query(
+        "SELECT looked_up_at, caller_id, dialed_extension, context, destination, fwd_id
+            FROM call_lookup
+         WHERE dialed_extension = $1
+         ORDER BY looked_up_at DESC, lookup_id DESC
+         LIMIT $2",
+    )
+    .bind(extension)
+    .bind(limit)
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectCallLookups(extension.to_string()))?;
+    Ok(rows
+        .into_iter()
+        .map(|row| CallLookup {
+            looked_up_at: row.get("looked_up_at"),
+            caller_id: row.get("caller_id"),
+            dialed_extension: row.get("dialed_extension"),
+            context: row.get("context"),
+            destinations: row
+                .get::<String, &str>("destination")
+                .split('&')
+                .filter(|x| !x.is_empty())
+                .map(str::to_string)
+                .collect(),
+            fwd_id: row.get("fwd_id"),
+        })
+        .collect())
+}
+
+/// How often each call forward was applied, by fwd_id.
+///
+/// Only looks at the call forward `fwd_id`, if given. Call forwards that were never applied are
+/// missing.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_call_forward_usage(
+    config: &Config,
+    fwd_id: Option<i32>,
+) -> Result<HashMap<i32, ForwardUsage>, DBError> {
+    let _timer = time_db_query("get_call_forward_usage");
+    let rows = query(
+        "SELECT fwd_id, count(*) AS hits, max(looked_up_at) AS last_used
+            FROM call_lookup
+         WHERE fwd_id IS NOT NULL AND ($1::integer IS NULL OR fwd_id = $1)
+         GROUP BY fwd_id",
+    )
+    .sqlite(
+        "SELECT fwd_id, count(*) AS hits, max(looked_up_at) AS last_used
+            FROM call_lookup
+         WHERE fwd_id IS NOT NULL AND ($1 IS NULL OR fwd_id = $1)
+         GROUP BY fwd_id",
+    )
+    .bind(fwd_id)
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectForwardUsage)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.get("fwd_id"),
+                ForwardUsage {
+                    hits: row.get("hits"),
+                    last_used: row.get("last_used"),
+                },
+            )
+        })
+        .collect())
+}
+
+/// Delete the call lookups older than `before`.
+///
+/// Returns the number of deleted lookups.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn delete_call_lookups_before(
+    config: &Config,
+    before: DateTime<Utc>,
+) -> Result<u64, DBError> {
+    let _timer = time_db_query("delete_call_lookups_before");
+    let res = query("DELETE FROM call_lookup WHERE looked_up_at < $1")
+        .bind(before)
+        .execute(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotDeleteCallLookups)?;
+    Ok(res)
+}
+
+/// Copy `extensions` and `contexts` into the DB, unless it knows any extension or context yet.
+///
+/// Returns whether they were copied.
+#[tracing::instrument(level=Level::DEBUG,skip_all,err)]
+pub async fn seed_extensions_and_contexts(
+    config: &Config,
+    extensions: &[Extension],
+    contexts: &[Context],
+) -> Result<bool, DBError> {
+    let _timer = time_db_query("seed_extensions_and_contexts");
+    let mut tx = config
+        .pool
+        .begin()
+        .await
+        .map_err(|_| DBError::CannotStartTransaction)?;
+    let known: i64 =
+        query("SELECT (SELECT count(*) FROM extension) + (SELECT count(*) FROM context) AS known")
+            .fetch_one(&mut tx)
+            .await
+            .map_err(|_| DBError::CannotSeedExtensionsAndContexts)?
+            .get("known");
+    if known > 0 {
+        return Ok(false);
+    };
+    // another instance may be seeding at the same time
+    for exten in extensions {
+        query("INSERT INTO extension (extension, name, owner) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING")
+            .bind(&exten.extension)
+            .bind(exten.name().unwrap_or_default())
+            .bind(&exten.owner)
+            .execute(&mut tx)
+            .await
+            .map_err(|_| DBError::CannotSeedExtensionsAndContexts)?;
+    }
+    for ctx in contexts {
+        query("INSERT INTO context (asterisk_name, display_name) VALUES ($1, $2) ON CONFLICT DO NOTHING")
+            .bind(&ctx.asterisk_name)
+            .bind(&ctx.display_name)
+            .execute(&mut tx)
+            .await
+            .map_err(|_| DBError::CannotSeedExtensionsAndContexts)?;
+    }
     tx.commit()
         .await
         .map_err(|_| DBError::CannotCommitTransaction)?;
+    Ok(true)
+}
+
+/// Get all known extensions
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_extensions(config: &Config) -> Result<Vec<Extension>, DBError> {
+    let _timer = time_db_query("get_extensions");
+    let rows = query("SELECT extension, name, owner FROM extension")
+        .fetch_all(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotSelectExtensions)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Extension::new(row.get("extension"), row.get("name"), row.get("owner")))
+        .collect())
+}
+
+/// Add the extension `exten`.
+///
+/// Returns DBError::ExtensionExists if it is already known.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn insert_extension(config: &Config, exten: &Extension) -> Result<(), DBError> {
+    let _timer = time_db_query("insert_extension");
+    let res = query(
+        "INSERT INTO extension (extension, name, owner) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+    )
+    .bind(&exten.extension)
+    .bind(exten.name().unwrap_or_default())
+    .bind(&exten.owner)
+    .execute(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotChangeExtension(exten.extension.clone()))?;
+    if res == 0 {
+        return Err(DBError::ExtensionExists(exten.extension.clone()));
+    };
+    Ok(())
+}
+
+/// Change the name and owner of the known extension `exten`
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn update_extension(config: &Config, exten: &Extension) -> Result<(), DBError> {
+    let _timer = time_db_query("update_extension");
+    let res = query("UPDATE extension SET name = $2, owner = $3 WHERE extension = $1")
+        .bind(&exten.extension)
+        .bind(exten.name().unwrap_or_default())
+        .bind(&exten.owner)
+        .execute(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotChangeExtension(exten.extension.clone()))?;
+    if res == 0 {
+        return Err(DBError::ExtensionDoesNotExist(exten.extension.clone()));
+    };
+    Ok(())
+}
+
+/// Forget the extension `extension`.
+///
+/// Its call forwards stay, they just show the bare extension from then on.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn delete_extension(config: &Config, extension: &str) -> Result<(), DBError> {
+    let _timer = time_db_query("delete_extension");
+    query("DELETE FROM extension WHERE extension = $1")
+        .bind(extension)
+        .execute(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotChangeExtension(extension.to_string()))?;
+    Ok(())
+}
+
+/// Get all known contexts
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_contexts(config: &Config) -> Result<Vec<Context>, DBError> {
+    let _timer = time_db_query("get_contexts");
+    let rows = query("SELECT asterisk_name, display_name FROM context")
+        .fetch_all(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotSelectKnownContexts)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| Context {
+            display_name: row.get("display_name"),
+            asterisk_name: row.get("asterisk_name"),
+        })
+        .collect())
+}
+
+/// Add the context `ctx`.
+///
+/// Returns DBError::ContextExists if it is already known.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn insert_context(config: &Config, ctx: &Context) -> Result<(), DBError> {
+    let _timer = time_db_query("insert_context");
+    let res = query(
+        "INSERT INTO context (asterisk_name, display_name) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+    )
+    .bind(&ctx.asterisk_name)
+    .bind(&ctx.display_name)
+    .execute(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotChangeContext(ctx.asterisk_name.clone()))?;
+    if res == 0 {
+        return Err(DBError::ContextExists(ctx.asterisk_name.clone()));
+    };
+    Ok(())
+}
+
+/// Change the display name of the known context `ctx`
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn update_context(config: &Config, ctx: &Context) -> Result<(), DBError> {
+    let _timer = time_db_query("update_context");
+    let res = query("UPDATE context SET display_name = $2 WHERE asterisk_name = $1")
+        .bind(&ctx.asterisk_name)
+        .bind(&ctx.display_name)
+        .execute(&config.pool)
+        .await
+        .map_err(|_| DBError::CannotChangeContext(ctx.asterisk_name.clone()))?;
+    if res == 0 {
+        return Err(DBError::ContextDoesNotExist(ctx.asterisk_name.clone()));
+    };
+    Ok(())
+}
+
+/// The call forwards, even deleted ones, set in contexts that are not known, with those contexts
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn get_call_forwards_in_unknown_contexts(
+    config: &Config,
+) -> Result<Vec<(i32, String)>, DBError> {
+    let _timer = time_db_query("get_call_forwards_in_unknown_contexts");
+    let rows = query(
+        "SELECT fwd_id, context FROM map_call_forward_context WHERE context NOT IN (SELECT asterisk_name FROM context) ORDER BY fwd_id",
+    )
+    .fetch_all(&config.pool)
+    .await
+    .map_err(|_| DBError::CannotSelectKnownContexts)?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get("fwd_id"), row.get("context")))
+        .collect())
+}
+
+/// Forget the context `asterisk_name`.
+///
+/// Returns DBError::ContextInUse while any call forward, even a deleted one, is set in it.
+#[tracing::instrument(level=Level::DEBUG,skip(config),err)]
+pub async fn delete_context(config: &Config, asterisk_name: &str) -> Result<(), DBError> {
+    let _timer = time_db_query("delete_context");
+    retry_on_conflict(|| try_delete_context(config, asterisk_name)).await
+}
+
+/// One attempt of [`delete_context`]
+async fn try_delete_context(config: &Config, asterisk_name: &str) -> Result<(), DBError> {
+    let mut tx = begin_serializable(config).await?;
+    let in_use = query("SELECT fwd_id FROM map_call_forward_context WHERE context = $1")
+        .bind(asterisk_name)
+        .fetch_optional(&mut tx)
+        .await
+        .map_err(|e| conflict_or(e, DBError::CannotChangeContext(asterisk_name.to_string())))?;
+    if in_use.is_some() {
+        return Err(DBError::ContextInUse(asterisk_name.to_string()));
+    };
+    query("DELETE FROM context WHERE asterisk_name = $1")
+        .bind(asterisk_name)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| conflict_or(e, DBError::CannotChangeContext(asterisk_name.to_string())))?;
+    tx.commit()
+        .await
+        .map_err(|e| conflict_or(e, DBError::CannotCommitTransaction))?;
     Ok(())
 }
 
 #[cfg(test)]
 mod db_tests {
-    use sqlx::{PgPool, Row};
+    use chrono::{NaiveTime, Weekday};
+    use sqlx::{PgPool, Row, SqlitePool};
 
-    use crate::types::{CallForward, Config, Context, Extension, NoId};
+    use crate::cache::CachedCallForward;
+    use crate::types::{
+        AuditAction, CallForward, CallLookup, CallerIdPattern, Config, Context, Extension,
+        ForwardChange, ForwardCondition, HasId, NoId, Schedule, ScheduleWindow,
+    };
 
     #[sqlx::test]
     async fn auth_test(pool: PgPool) -> sqlx::Result<()> {
         let mut conn = pool.acquire().await?;
 
-        let foo = sqlx::query("SELECT 1 + 1 AS sum")
-            .fetch_one(&mut *conn)
-            .await?
-            .get::<i32, &str>("sum");
-        assert_eq!(foo, 2);
+        let foo = sqlx::query("SELECT 1 + 1 AS sum")
+            .fetch_one(&mut *conn)
+            .await?
+            .get::<i32, &str>("sum");
+        assert_eq!(foo, 2);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn get_call_forwards_from_startpoint(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let startpoint = Extension::create_from_name(&config, "702".to_string());
+        let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].in_contexts.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn get_all_call_forwards(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let res = super::get_all_call_forwards(&config).await?;
+        assert_eq!(res.len(), 4);
+        assert_eq!(res[0].in_contexts.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn get_call_forward_by_id(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let res = super::get_call_forward_by_id(&config, 2).await?;
+        assert_eq!(res.in_contexts.len(), 2);
+        let res = super::get_call_forward_by_id(&config, 5).await;
+        assert_eq!(res, Err(super::DBError::CannotSelectCallForward(5)));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn insert_call_forward(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?;
+        super::new_call_forward(&config, forward, "jdoe").await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn insert_call_forward_with_schedule(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let schedule = Schedule {
+            timezone: chrono_tz::Tz::Europe__Berlin,
+            windows: vec![
+                ScheduleWindow::default(),
+                ScheduleWindow::new(
+                    Weekday::Sat,
+                    Weekday::Sat,
+                    NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                    NaiveTime::from_hms_opt(12, 30, 0).unwrap(),
+                )
+                .unwrap(),
+            ],
+        };
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_schedule(Some(schedule.clone()));
+        let inserted = super::new_call_forward(&config, forward, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, inserted.fwd_id.into()).await?;
+        assert_eq!(res.schedule, Some(schedule));
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn insert_call_forward_with_multiple_destinations(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["704".to_string(), "12341234".to_string(), "703".to_string()],
+            vec!["from_external".to_string(), "from_internal".to_string()],
+        )?;
+        let inserted = super::new_call_forward(&config, forward, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, inserted.fwd_id.into()).await?;
+        assert_eq!(res.destination_extensions(), vec!["704", "12341234", "703"]);
+        assert_eq!(res.in_contexts.len(), 2);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn insert_call_forward_without_destination(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec![],
+            vec!["from_external".to_string()],
+        );
+        assert_eq!(
+            forward,
+            Err(super::DBError::NoDestination(Extension::create_from_name(
+                &config,
+                "702".to_string()
+            )))
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn insert_call_forward_with_dial_options(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        for to in ["100&SIP/evil", "100,,D(1)", "100 200", ""] {
+            let forward = CallForward::<NoId>::new(
+                &config,
+                "702".to_string(),
+                vec!["704".to_string(), to.to_string()],
+                vec!["from_external".to_string()],
+            );
+            assert_eq!(
+                forward,
+                Err(super::DBError::InvalidDestination(to.to_string()))
+            );
+        }
+        // stored call forwards are read as they are
+        assert!(CallForward::<HasId>::new(
+            &config,
+            "702".to_string(),
+            vec!["100&SIP/evil".to_string()],
+            vec!["from_external".to_string()],
+            1,
+        )
+        .is_ok());
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn update_call_forward_remove_schedule(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let mut fwd = super::get_call_forward_by_id(&config, 3).await?;
+        assert_eq!(fwd.schedule, None);
+        fwd.schedule = Some(Schedule {
+            timezone: chrono_tz::Tz::UTC,
+            windows: vec![ScheduleWindow::default()],
+        });
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, 3).await?;
+        assert_eq!(res.schedule, fwd.schedule);
+
+        // continue with the stored state, the one read before is outdated now
+        let mut fwd = res;
+        fwd.schedule = None;
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, 3).await?;
+        assert_eq!(res.schedule, None);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn update_stale_call_forward(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // two users open the same call forward
+        let mut first = super::get_call_forward_by_id(&config, 3).await?;
+        let mut second = first.clone();
+        assert_eq!(first.version, 1);
+
+        first.to = vec![Extension::create_from_name(&config, "0151234".to_string())];
+        super::update_call_forward(&config, &first, "jdoe").await?;
+        second.to = vec![Extension::create_from_name(&config, "0170".to_string())];
+        assert_eq!(
+            super::update_call_forward(&config, &second, "asmith").await,
+            Err(super::DBError::StaleCallForward(3))
+        );
+        let res = super::get_call_forward_by_id(&config, 3).await?;
+        assert_eq!(res.destination_extensions(), vec!["0151234"]);
+        assert_eq!(res.version, 2);
+
+        // switching it off is a change, too
+        super::set_call_forward_active(&config, 3, false, "asmith").await?;
+        assert_eq!(
+            super::update_call_forward(&config, &res, "jdoe").await,
+            Err(super::DBError::StaleCallForward(3))
+        );
+        assert_eq!(super::get_call_forward_by_id(&config, 3).await?.version, 3);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn insert_conflicting_context(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?;
+        let newly_inserted = super::new_call_forward(&config, forward, "jdoe").await;
+        assert_eq!(
+            newly_inserted,
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "702".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn insert_conditional_call_forward(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // 702 is already forwarded unconditionally in from_external, which does not overlap
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_condition(ForwardCondition::NoAnswer(20));
+        let inserted = super::new_call_forward(&config, forward, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, inserted.fwd_id.into()).await?;
+        assert_eq!(res.condition, ForwardCondition::NoAnswer(20));
+
+        // but a second no-answer call forward does, even with another timeout
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["704".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_condition(ForwardCondition::NoAnswer(30));
+        let newly_inserted = super::new_call_forward(&config, forward, "jdoe").await;
+        assert_eq!(
+            newly_inserted,
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "702".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn insert_call_forward_with_caller_ids(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // 702 is already forwarded for every caller in from_external, which does not overlap
+        let caller_ids = vec![
+            CallerIdPattern::Exact("0151234".to_string()),
+            CallerIdPattern::Prefix("0170".to_string()),
+        ];
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_caller_ids(caller_ids.clone());
+        let inserted = super::new_call_forward(&config, forward, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, inserted.fwd_id.into()).await?;
+        assert_eq!(res.caller_ids, caller_ids);
+
+        // another prefix does not overlap either
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["704".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_caller_ids(vec![CallerIdPattern::Prefix("01702".to_string())]);
+        super::new_call_forward(&config, forward, "jdoe").await?;
+
+        // but the same pattern does
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "702".to_string(),
+            vec!["704".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_caller_ids(vec![CallerIdPattern::Exact("0151234".to_string())]);
+        let newly_inserted = super::new_call_forward(&config, forward, "jdoe").await;
+        assert_eq!(
+            newly_inserted,
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "702".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn update_call_forward_condition(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let fwd = super::get_call_forward_by_id(&config, 3).await?;
+        assert_eq!(fwd.condition, ForwardCondition::Always);
+        let fwd = fwd.set_condition(ForwardCondition::Busy);
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, 3).await?;
+        assert_eq!(res.condition, ForwardCondition::Busy);
         Ok(())
     }
 
     #[sqlx::test(fixtures("call_forward"))]
-    async fn get_call_forwards_from_startpoint(
+    async fn switch_call_forward_off_and_on(
         pool: PgPool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
-        let startpoint = Extension::create_from_name(&config, "702".to_string());
-        let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
-        assert_eq!(res.len(), 2);
-        assert_eq!(res[0].in_contexts.len(), 2);
+        super::set_call_forward_active(&config, 3, false, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, 3).await?;
+        assert!(!res.active);
+        assert!(!res.is_active_now());
+
+        // the switched off call forward does not block a new one
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?;
+        super::new_call_forward(&config, forward, "jdoe").await?;
+
+        // but it cannot be switched on again while the new one exists
+        let res = super::set_call_forward_active(&config, 3, true, "jdoe").await;
+        assert_eq!(
+            res,
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "703".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        assert_eq!(
+            super::set_call_forward_active(&config, 42, false, "jdoe").await,
+            Err(super::DBError::CannotSelectCallForward(42))
+        );
         Ok(())
     }
 
     #[sqlx::test(fixtures("call_forward"))]
-    async fn get_all_call_forwards(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    async fn delete_and_restore_call_forward(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
-        let res = super::get_all_call_forwards(&config).await?;
-        assert_eq!(res.len(), 4);
-        assert_eq!(res[0].in_contexts.len(), 2);
+        super::delete_call_forward_by_id(&config, 3, "jdoe").await?;
+        assert_eq!(
+            super::get_call_forward_by_id(&config, 3).await,
+            Err(super::DBError::CannotSelectCallForward(3))
+        );
+        assert_eq!(super::get_all_call_forwards(&config).await?.len(), 3);
+        let deleted = super::get_deleted_call_forwards(&config).await?;
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].forward.from.extension, "703");
+
+        // the deleted call forward does not block a new one
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["12341234".to_string()],
+            vec!["from_external".to_string()],
+        )?;
+        let inserted = super::new_call_forward(&config, forward, "jdoe").await?;
+
+        // but it cannot be restored while the new one exists
+        assert_eq!(
+            super::restore_call_forward(&config, 3, "jdoe").await,
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "703".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        super::delete_call_forward_by_id(&config, inserted.fwd_id.into(), "jdoe").await?;
+        super::restore_call_forward(&config, 3, "jdoe").await?;
+        assert_eq!(super::get_call_forward_by_id(&config, 3).await?.to.len(), 1);
+        assert_eq!(
+            super::restore_call_forward(&config, 3, "jdoe").await,
+            Err(super::DBError::CannotSelectCallForward(3))
+        );
+
+        // only the one deleted before the retention ended is removed for good
+        assert_eq!(
+            super::delete_call_forwards_deleted_before(
+                &config,
+                chrono::Utc::now() - chrono::Duration::days(1)
+            )
+            .await?,
+            0
+        );
+        assert_eq!(
+            super::delete_call_forwards_deleted_before(&config, chrono::Utc::now()).await?,
+            1
+        );
+        assert!(super::get_deleted_call_forwards(&config).await?.is_empty());
+        assert_eq!(super::get_all_call_forwards(&config).await?.len(), 4);
+
+        let actions = super::get_audit_entries(&config, Some("703"), None, 100)
+            .await?
+            .into_iter()
+            .map(|x| (x.fwd_id, x.action))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actions[..3],
+            [
+                (inserted.fwd_id.into(), AuditAction::PurgeDeleted),
+                (3, AuditAction::Restore),
+                (inserted.fwd_id.into(), AuditAction::Delete),
+            ]
+        );
         Ok(())
     }
 
     #[sqlx::test(fixtures("call_forward"))]
-    async fn get_call_forward_by_id(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    async fn audit_log(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
-        let res = super::get_call_forward_by_id(&config, 2).await?;
-        assert_eq!(res.in_contexts.len(), 2);
-        let res = super::get_call_forward_by_id(&config, 5).await;
-        assert_eq!(res, Err(super::DBError::CannotSelectCallForward(5)));
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "705".to_string(),
+            vec!["0151234".to_string()],
+            vec!["from_external".to_string()],
+        )?;
+        let created = super::new_call_forward(&config, forward, "jdoe").await?;
+        let fwd_id: i32 = created.fwd_id.into();
+        let mut updated = created.clone();
+        updated.to = vec![Extension::create_from_name(&config, "0170".to_string())];
+        super::update_call_forward(&config, &updated, "jdoe").await?;
+        super::set_call_forward_active(&config, fwd_id, false, "asmith").await?;
+        super::delete_call_forward_by_id(&config, fwd_id, "jdoe").await?;
+        // deleting it again is no change
+        super::delete_call_forward_by_id(&config, fwd_id, "jdoe").await?;
+
+        let entries = super::get_audit_entries(&config, Some("705"), None, 100).await?;
+        assert_eq!(
+            entries.iter().map(|x| x.action).collect::<Vec<_>>(),
+            vec![
+                AuditAction::Delete,
+                AuditAction::SwitchOff,
+                AuditAction::Update,
+                AuditAction::Create
+            ]
+        );
+        assert!(entries.iter().all(|x| x.fwd_id == fwd_id));
+        let switched_off = CachedCallForward::from_call_forward(&updated.clone().set_active(false));
+        assert_eq!(entries[0].before.as_ref(), Some(&switched_off));
+        assert_eq!(entries[0].after, None);
+        assert_eq!(
+            entries[2].before,
+            Some(CachedCallForward::from_call_forward(&created))
+        );
+        assert_eq!(
+            entries[2].after,
+            Some(CachedCallForward::from_call_forward(&updated))
+        );
+        assert_eq!(entries[3].before, None);
+
+        // destinations are found in both snapshots
+        assert_eq!(
+            super::get_audit_entries(&config, Some("0151234"), None, 100)
+                .await?
+                .len(),
+            2
+        );
+        let by_actor = super::get_audit_entries(&config, None, Some("asmith"), 100).await?;
+        assert_eq!(by_actor.len(), 1);
+        assert_eq!(by_actor[0].action, AuditAction::SwitchOff);
+        assert_eq!(
+            super::get_audit_entries(&config, Some("705"), Some("jdoe"), 2)
+                .await?
+                .len(),
+            2
+        );
         Ok(())
     }
 
     #[sqlx::test]
-    async fn insert_call_forward(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    async fn remove_stale_call_forward(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
         let forward = CallForward::<NoId>::new(
             &config,
-            "702".to_string(),
-            "12341234".to_string(),
+            "705".to_string(),
+            vec!["0151234".to_string()],
             vec!["from_external".to_string()],
         )?;
-        super::new_call_forward(&config, forward).await?;
+        let created = super::new_call_forward(&config, forward, "jdoe").await?;
+        let fwd_id: i32 = created.fwd_id.into();
+        let mut updated = created.clone();
+        updated.to = vec![Extension::create_from_name(&config, "0170".to_string())];
+        super::update_call_forward(&config, &updated, "asmith").await?;
+
+        // the removal was planned before the change
+        assert_eq!(
+            super::apply_call_forward_changes(
+                &config,
+                &[ForwardChange::Remove(created)],
+                "jdoe",
+                true
+            )
+            .await,
+            Err(super::DBError::CannotApplyChange(
+                0,
+                Box::new(super::DBError::StaleCallForward(fwd_id))
+            ))
+        );
+        let current = super::get_call_forward_by_id(&config, fwd_id).await?;
+        super::apply_call_forward_changes(
+            &config,
+            &[ForwardChange::Remove(current.clone())],
+            "jdoe",
+            true,
+        )
+        .await?;
+        // the audit log shows what was deleted
+        let entries = super::get_audit_entries(&config, Some("705"), None, 1).await?;
+        assert_eq!(entries[0].action, AuditAction::Delete);
+        assert_eq!(
+            entries[0].before,
+            Some(CachedCallForward::from_call_forward(&current))
+        );
         Ok(())
     }
 
-    #[sqlx::test(fixtures("call_forward"))]
-    async fn insert_conflicting_context(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+    #[sqlx::test(fixtures("call_forward_chain"))]
+    async fn insert_cyclic_call_forward(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
+        // 0151234 -> 702 -> 704 -> 0151234 in from_external
         let forward = CallForward::<NoId>::new(
             &config,
-            "702".to_string(),
-            "12341234".to_string(),
+            "0151234".to_string(),
+            vec!["702".to_string()],
             vec!["from_external".to_string()],
         )?;
-        let newly_inserted = super::new_call_forward(&config, forward).await;
+        let newly_inserted = super::new_call_forward(&config, forward, "jdoe").await;
         assert_eq!(
             newly_inserted,
+            Err(super::DBError::CyclicCallForwards(
+                Extension::create_from_name(&config, "0151234".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+
+        // 704 is not forwarded in from_internal, so this is fine
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "0151234".to_string(),
+            vec!["702".to_string()],
+            vec!["from_internal".to_string()],
+        )?;
+        super::new_call_forward(&config, forward, "jdoe").await?;
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward_caller_id"))]
+    async fn insert_cyclic_call_forward_over_caller_id(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // 704 -> 702 -> 704 for callers starting with 0151, but 702 first goes to 701 for everyone
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "704".to_string(),
+            vec!["702".to_string()],
+            vec!["from_external".to_string()],
+        )?;
+        assert_eq!(
+            super::new_call_forward(&config, forward, "jdoe").await,
+            Err(super::DBError::CyclicCallForwards(
+                Extension::create_from_name(&config, "704".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn update_overlapping_call_forward(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // the call forward for when 702 is busy would overlap the unconditional one
+        let mut fwd = super::get_call_forward_by_id(&config, 1).await?;
+        fwd.condition = ForwardCondition::Always;
+        assert_eq!(
+            super::update_call_forward(&config, &fwd, "jdoe").await,
             Err(super::DBError::OverlappingCallForwards(
                 Extension::create_from_name(&config, "702".to_string()),
-                Context::create_from_name(&config, "from_external".to_string())
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+        assert_eq!(
+            super::get_call_forward_by_id(&config, 1).await?.condition,
+            ForwardCondition::Busy
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn concurrent_overlapping_call_forwards(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let forward = |to: &str| {
+            CallForward::<NoId>::new(
+                &config,
+                "705".to_string(),
+                vec![to.to_string()],
+                vec!["from_external".to_string()],
+            )
+        };
+        // both are checked before either is inserted, unless the checks are serialized
+        let (first, second) = tokio::join!(
+            super::new_call_forward(&config, forward("0151234")?, "jdoe"),
+            super::new_call_forward(&config, forward("0170")?, "asmith"),
+        );
+        let overlap = || {
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "705".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone(),
+            ))
+        };
+        assert!((first.is_ok() && second == overlap()) || (first == overlap() && second.is_ok()));
+        let startpoint = Extension::create_from_name(&config, "705".to_string());
+        assert_eq!(
+            super::get_call_forwards_from_startpoint(&config, &startpoint)
+                .await?
+                .len(),
+            1
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn update_call_forward_to_itself(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let mut fwd = super::get_call_forward_by_id(&config, 3).await?;
+        fwd.to = vec![fwd.from.clone()];
+        let res = super::update_call_forward(&config, &fwd, "jdoe").await;
+        assert_eq!(
+            res,
+            Err(super::DBError::CyclicCallForwards(
+                Extension::create_from_name(&config, "703".to_string()),
+                Context::create_from_name(&config, "from_external")
                     .unwrap()
                     .clone()
             ))
@@ -452,13 +2819,13 @@ mod db_tests {
     #[sqlx::test(fixtures("call_forward"))]
     async fn delete_call_forward(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
         let startpoint = Extension::create_from_name(&config, "702".to_string());
         let mut res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         let first_len = res.len();
         let to_delete = res.pop().unwrap();
-        super::delete_call_forward_by_id(&config, to_delete.fwd_id.into()).await?;
+        super::delete_call_forward_by_id(&config, to_delete.fwd_id.into(), "jdoe").await?;
         let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         assert_eq!(first_len - 1, res.len());
         Ok(())
@@ -469,15 +2836,18 @@ mod db_tests {
         pool: PgPool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
         let startpoint = Extension::create_from_name(&config, "702".to_string());
         let mut res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         let mut fwd = res.pop().unwrap();
-        fwd.to = startpoint.clone();
-        super::update_call_forward(&config, &fwd).await?;
+        fwd.to = vec![Extension::create_from_name(&config, "12341234".to_string())];
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
         let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
-        assert_eq!(res.last().unwrap().to.extension, "702".to_string());
+        assert_eq!(
+            res.last().unwrap().destination_extensions(),
+            vec!["12341234"]
+        );
 
         Ok(())
     }
@@ -487,13 +2857,13 @@ mod db_tests {
         pool: PgPool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
         let startpoint = Extension::create_from_name(&config, "704".to_string());
         let mut res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         let mut fwd = res.pop().unwrap();
         fwd.from = startpoint.clone();
-        super::update_call_forward(&config, &fwd).await?;
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
         let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         assert_eq!(res.last().unwrap().from.extension, "704".to_string());
 
@@ -505,7 +2875,7 @@ mod db_tests {
         pool: PgPool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
         let startpoint = Extension::create_from_name(&config, "702".to_string());
         let from_sales = Context::create_from_name(&config, "from_sales").unwrap();
@@ -515,7 +2885,7 @@ mod db_tests {
 
         assert!(!fwd.in_contexts.contains(&from_sales));
         fwd.in_contexts.push(from_sales);
-        super::update_call_forward(&config, &fwd).await?;
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
         let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         assert!(res.last().unwrap().in_contexts.contains(&from_sales));
 
@@ -527,7 +2897,7 @@ mod db_tests {
         pool: PgPool,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut config = Config::create().await?;
-        config.pool = pool;
+        config.pool = pool.into();
 
         let startpoint = Extension::create_from_name(&config, "702".to_string());
         let mut res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
@@ -537,10 +2907,322 @@ mod db_tests {
 
         // the last thing inserted is the from_internal forwarding
         fwd.in_contexts.pop();
-        super::update_call_forward(&config, &fwd).await?;
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
         let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
         assert!(!res.last().unwrap().in_contexts.contains(&from_internal));
 
         Ok(())
     }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn update_call_forward_keeps_remaining_contexts(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // removing a context used to delete the mappings of the contexts that were kept
+        let mut fwd = super::get_call_forward_by_id(&config, 2).await?;
+        let from_external = Context::create_from_name(&config, "from_external").unwrap();
+        fwd.in_contexts.retain(|&x| x == from_external);
+        super::update_call_forward(&config, &fwd, "jdoe").await?;
+        let res = super::get_call_forward_by_id(&config, 2).await?;
+        assert_eq!(res.in_contexts, vec![from_external]);
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn get_call_forwards_with_same_destination(
+        pool: PgPool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        // rows used to be grouped by source and destination, merging these two call forwards
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "703".to_string(),
+            vec!["702".to_string()],
+            vec!["from_sales".to_string()],
+        )?;
+        super::new_call_forward(&config, forward, "jdoe").await?;
+        let startpoint = Extension::create_from_name(&config, "703".to_string());
+        let res = super::get_call_forwards_from_startpoint(&config, &startpoint).await?;
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|x| x.in_contexts.len() == 1));
+
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn call_lookups_and_usage(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let earlier = chrono::Utc::now() - chrono::Duration::hours(1);
+        let later = chrono::Utc::now();
+        for (looked_up_at, fwd_id) in [(earlier, Some(1)), (later, Some(1)), (earlier, None)] {
+            super::insert_call_lookup(
+                &config,
+                &CallLookup {
+                    looked_up_at,
+                    caller_id: Some("0151234".to_string()),
+                    dialed_extension: "702".to_string(),
+                    context: "from_external".to_string(),
+                    destinations: vec!["something-external".to_string()],
+                    fwd_id,
+                },
+            )
+            .await?;
+        }
+
+        let usage = super::get_call_forward_usage(&config, None).await?;
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[&1].hits, 2);
+        assert_eq!(
+            usage[&1].last_used.map(|x| x.timestamp_micros()),
+            Some(later.timestamp_micros())
+        );
+
+        let lookups = super::get_call_lookups(&config, "702", 2).await?;
+        assert_eq!(lookups.len(), 2);
+        assert_eq!(lookups[0].fwd_id, Some(1));
+        assert_eq!(lookups[0].destinations, vec!["something-external"]);
+        assert!(super::get_call_lookups(&config, "703", 10)
+            .await?
+            .is_empty());
+
+        // deleting the call forward keeps its usage, in case it is restored
+        super::delete_call_forward_by_id(&config, 1, "jdoe").await?;
+        assert_eq!(
+            super::get_call_forward_usage(&config, Some(1)).await?[&1].hits,
+            2
+        );
+        // removing it for good keeps its lookups
+        super::delete_call_forwards_deleted_before(&config, chrono::Utc::now()).await?;
+        assert!(super::get_call_forward_usage(&config, Some(1))
+            .await?
+            .is_empty());
+        assert_eq!(super::get_call_lookups(&config, "702", 10).await?.len(), 3);
+
+        assert_eq!(super::delete_call_lookups_before(&config, later).await?, 2);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn vacation_call_forwards(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let now = chrono::Utc::now();
+        let vacation = |dest: &str, from: Option<i64>, until: Option<i64>| {
+            CallForward::<NoId>::new(
+                &config,
+                "704".to_string(),
+                vec![dest.to_string()],
+                vec!["from_external".to_string()],
+            )
+            .map(|x| {
+                x.set_validity(
+                    from.map(|d| now + chrono::Duration::days(d)),
+                    until.map(|d| now + chrono::Duration::days(d)),
+                )
+            })
+        };
+
+        let current =
+            super::new_call_forward(&config, vacation("0151234", Some(-1), Some(7))?, "jdoe")
+                .await?;
+        let res = super::get_call_forward_by_id(&config, current.fwd_id.into()).await?;
+        assert_eq!(
+            res.valid_until.map(|x| x.timestamp_micros()),
+            Some((now + chrono::Duration::days(7)).timestamp_micros())
+        );
+        assert!(res.is_active_now());
+
+        // the next vacation does not overlap
+        let next =
+            super::new_call_forward(&config, vacation("0151234", Some(7), Some(14))?, "jdoe")
+                .await?;
+        assert!(!next.is_active_now());
+
+        // but a call forward without an end does
+        let res = super::new_call_forward(&config, vacation("0170", Some(3), None)?, "jdoe").await;
+        assert_eq!(
+            res,
+            Err(super::DBError::OverlappingCallForwards(
+                Extension::create_from_name(&config, "704".to_string()),
+                Context::create_from_name(&config, "from_external")
+                    .unwrap()
+                    .clone()
+            ))
+        );
+
+        // expired call forwards do not overlap and are purged after a while
+        super::new_call_forward(&config, vacation("0170", Some(-50), Some(-40))?, "jdoe").await?;
+        super::new_call_forward(&config, vacation("0170", Some(-20), Some(-10))?, "jdoe").await?;
+        assert_eq!(
+            super::delete_call_forwards_expired_before(&config, now - chrono::Duration::days(30))
+                .await?,
+            1
+        );
+        assert_eq!(super::get_all_call_forwards(&config).await?.len(), 7);
+        Ok(())
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn extensions_and_contexts(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let exten = Extension::new("705".to_string(), "Empfang".to_string(), None);
+        super::insert_extension(&config, &exten).await?;
+        assert!(matches!(
+            super::insert_extension(&config, &exten).await,
+            Err(super::DBError::ExtensionExists(_))
+        ));
+        let exten = Extension::new(
+            "705".to_string(),
+            "Rezeption".to_string(),
+            Some("alice".to_string()),
+        );
+        super::update_extension(&config, &exten).await?;
+        assert_eq!(super::get_extensions(&config).await?, vec![exten]);
+
+        // call forwards are set in from_external, from_internal and from_sales
+        for asterisk_name in ["from_external", "from_storage"] {
+            super::insert_context(
+                &config,
+                &Context {
+                    display_name: asterisk_name.to_string(),
+                    asterisk_name: asterisk_name.to_string(),
+                },
+            )
+            .await?;
+        }
+        assert!(matches!(
+            super::delete_context(&config, "from_external").await,
+            Err(super::DBError::ContextInUse(_))
+        ));
+        super::delete_context(&config, "from_storage").await?;
+        let contexts = super::get_contexts(&config).await?;
+        assert_eq!(contexts.len(), 1);
+        assert_eq!(contexts[0].asterisk_name, "from_external");
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations_sqlite", fixtures("call_forward"))]
+    async fn sqlite_call_forwards(pool: SqlitePool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        assert_eq!(super::get_all_call_forwards(&config).await?.len(), 4);
+        let schedule = Schedule {
+            timezone: chrono_tz::Tz::Europe__Berlin,
+            windows: vec![ScheduleWindow::new(
+                Weekday::Mon,
+                Weekday::Fri,
+                NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(17, 30, 0).unwrap(),
+            )
+            .unwrap()],
+        };
+        let now = chrono::Utc::now();
+        let forward = CallForward::<NoId>::new(
+            &config,
+            "705".to_string(),
+            vec!["0151234".to_string()],
+            vec!["from_external".to_string()],
+        )?
+        .set_schedule(Some(schedule.clone()))
+        .set_caller_ids(vec![CallerIdPattern::parse("0170*").unwrap()])
+        .set_validity(None, Some(now + chrono::Duration::days(7)));
+        let created = super::new_call_forward(&config, forward.clone(), "jdoe").await?;
+        let fwd_id: i32 = created.fwd_id.into();
+        let res = super::get_call_forward_by_id(&config, fwd_id).await?;
+        assert_eq!(res.schedule, Some(schedule));
+        assert_eq!(res.caller_ids, created.caller_ids);
+        assert_eq!(
+            res.valid_until.map(|x| x.timestamp_micros()),
+            Some((now + chrono::Duration::days(7)).timestamp_micros())
+        );
+        assert!(matches!(
+            super::new_call_forward(&config, forward, "jdoe").await,
+            Err(super::DBError::OverlappingCallForwards(_, _))
+        ));
+
+        // versions, soft deletes and the audit log work as on postgres
+        let mut updated = res.clone();
+        updated.to = vec![Extension::create_from_name(&config, "0170".to_string())];
+        super::update_call_forward(&config, &updated, "jdoe").await?;
+        assert_eq!(
+            super::update_call_forward(&config, &updated, "jdoe").await,
+            Err(super::DBError::StaleCallForward(fwd_id))
+        );
+        super::delete_call_forward_by_id(&config, fwd_id, "asmith").await?;
+        assert_eq!(super::get_deleted_call_forwards(&config).await?.len(), 1);
+        super::restore_call_forward(&config, fwd_id, "jdoe").await?;
+        let entries = super::get_audit_entries(&config, Some("0151234"), None, 100).await?;
+        assert_eq!(
+            entries.iter().map(|x| x.action).collect::<Vec<_>>(),
+            vec![AuditAction::Update, AuditAction::Create]
+        );
+        let by_actor = super::get_audit_entries(&config, Some("705"), Some("asmith"), 100).await?;
+        assert_eq!(by_actor.len(), 1);
+        assert_eq!(by_actor[0].action, AuditAction::Delete);
+
+        super::insert_call_lookup(
+            &config,
+            &CallLookup {
+                looked_up_at: now,
+                caller_id: None,
+                dialed_extension: "705".to_string(),
+                context: "from_external".to_string(),
+                destinations: vec!["0170".to_string()],
+                fwd_id: Some(fwd_id),
+            },
+        )
+        .await?;
+        let usage = super::get_call_forward_usage(&config, Some(fwd_id)).await?;
+        assert_eq!(usage[&fwd_id].hits, 1);
+        assert_eq!(
+            usage[&fwd_id].last_used.map(|x| x.timestamp_micros()),
+            Some(now.timestamp_micros())
+        );
+        assert_eq!(
+            super::delete_call_lookups_before(&config, now + chrono::Duration::seconds(1)).await?,
+            1
+        );
+        Ok(())
+    }
+
+    #[sqlx::test(migrations = "./migrations_sqlite", fixtures("call_forward"))]
+    async fn sqlite_concurrent_overlapping_call_forwards(
+        pool: SqlitePool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        let forward = |to: &str| {
+            CallForward::<NoId>::new(
+                &config,
+                "705".to_string(),
+                vec![to.to_string()],
+                vec!["from_external".to_string()],
+            )
+        };
+        let (first, second) = tokio::join!(
+            super::new_call_forward(&config, forward("0151234")?, "jdoe"),
+            super::new_call_forward(&config, forward("0170")?, "asmith"),
+        );
+        assert!(first.is_ok() != second.is_ok());
+        let startpoint = Extension::create_from_name(&config, "705".to_string());
+        assert_eq!(
+            super::get_call_forwards_from_startpoint(&config, &startpoint)
+                .await?
+                .len(),
+            1
+        );
+        Ok(())
+    }
 }
@@ -1,9 +1,16 @@
 -- some call forwards to do unit tests with
 
-INSERT INTO call_forward (from_extension, to_extension) VALUES ('702', 'something-external');
-INSERT INTO call_forward (from_extension, to_extension) VALUES ('702', '704');
-INSERT INTO call_forward (from_extension, to_extension) VALUES ('703', '702');
-INSERT INTO call_forward (from_extension, to_extension) VALUES ('704', 'something-external');
+-- only when 702 is busy, so it does not overlap the next one
+INSERT INTO call_forward (from_extension, condition) VALUES ('702', 'busy');
+INSERT INTO call_forward (from_extension) VALUES ('702');
+INSERT INTO call_forward (from_extension) VALUES ('703');
+INSERT INTO call_forward (from_extension) VALUES ('704');
+
+INSERT INTO call_forward_destination (fwd_id, position, to_extension) VALUES
+	(1, 0, 'something-external'),
+	(2, 0, '704'),
+	(3, 0, '702'),
+	(4, 0, 'something-external');
 
 INSERT INTO map_call_forward_context (fwd_id, context) VALUES
 	(1, 'from_external'),
@@ -12,4 +19,3 @@ INSERT INTO map_call_forward_context (fwd_id, context) VALUES
 	(2, 'from_internal'),
 	(3, 'from_external'),
 	(4, 'from_sales');
-
@@ -0,0 +1,24 @@
+-- call forwards from the same extension for different callers
+
+INSERT INTO call_forward (from_extension) VALUES ('702');
+INSERT INTO call_forward (from_extension) VALUES ('702');
+INSERT INTO call_forward (from_extension) VALUES ('702');
+INSERT INTO call_forward (from_extension) VALUES ('702');
+
+INSERT INTO call_forward_destination (fwd_id, position, to_extension) VALUES
+	(1, 0, '701'),
+	(2, 0, '704'),
+	(3, 0, '0160000'),
+	(4, 0, '703');
+
+INSERT INTO map_call_forward_context (fwd_id, context) VALUES
+	(1, 'from_external'),
+	(2, 'from_external'),
+	(3, 'from_external'),
+	(4, 'from_external');
+
+-- 1 applies to every caller
+INSERT INTO call_forward_caller_id (fwd_id, pattern, is_prefix) VALUES
+	(2, '0151', true),
+	(3, '01511', true),
+	(4, '0151123', false);
@@ -0,0 +1,25 @@
+-- call forwards that have to be followed over multiple hops
+
+INSERT INTO call_forward (from_extension) VALUES ('702');
+INSERT INTO call_forward (from_extension) VALUES ('704');
+-- these two form a loop
+INSERT INTO call_forward (from_extension) VALUES ('703');
+INSERT INTO call_forward (from_extension) VALUES ('705');
+-- rings two phones, one of which is forwarded again
+INSERT INTO call_forward (from_extension) VALUES ('706');
+
+INSERT INTO call_forward_destination (fwd_id, position, to_extension) VALUES
+	(1, 0, '704'),
+	(2, 0, '0151234'),
+	(3, 0, '705'),
+	(4, 0, '703'),
+	(5, 0, '702'),
+	(5, 1, '707');
+
+INSERT INTO map_call_forward_context (fwd_id, context) VALUES
+	(1, 'from_external'),
+	(1, 'from_internal'),
+	(2, 'from_external'),
+	(3, 'from_internal'),
+	(4, 'from_internal'),
+	(5, 'from_external');
@@ -0,0 +1,16 @@
+-- call forwards that only apply after the dialed extension did not answer
+
+INSERT INTO call_forward (from_extension, condition) VALUES ('702', 'busy');
+INSERT INTO call_forward (from_extension, condition, no_answer_timeout) VALUES ('702', 'no_answer', 20);
+-- only followed after the no-answer call forward above
+INSERT INTO call_forward (from_extension) VALUES ('704');
+
+INSERT INTO call_forward_destination (fwd_id, position, to_extension) VALUES
+	(1, 0, '0151234'),
+	(2, 0, '704'),
+	(3, 0, '0160000');
+
+INSERT INTO map_call_forward_context (fwd_id, context) VALUES
+	(1, 'from_external'),
+	(2, 'from_external'),
+	(3, 'from_external');
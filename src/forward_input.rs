@@ -0,0 +1,507 @@
+//! Call forwards as entered by users, in the web interface or on the command line
+//!
+//! Both are checked by [`ForwardInput::check`], so that they follow the same rules. The messages
+//! it returns are meant to be shown to the user as they are.
+use chrono::{DateTime, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::types::{
+    CallForward, CallerIdPattern, Config, Context, Extension, ForwardCondition, HasId, NoId,
+    Schedule, ScheduleWindow, VALIDITY_INPUT_FORMAT,
+};
+
+/// A schedule window as entered by a user
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct WindowInput {
+    /// days from monday
+    pub(crate) first_day: u8,
+    pub(crate) last_day: u8,
+    pub(crate) start: String,
+    pub(crate) end: String,
+}
+impl WindowInput {
+    /// Read a window written like `Mon-Fri 08:00-17:00` or `Sat 10:00-12:00`
+    pub(crate) fn parse(text: &str) -> Result<WindowInput, String> {
+        let invalid =
+            || format!("Ungültiges Zeitfenster {text}. Erwartet wird z.B. Mon-Fri 08:00-17:00.");
+        let (days, times) = text.trim().split_once(' ').ok_or_else(invalid)?;
+        let (first_day, last_day) = days.split_once('-').unwrap_or((days, days));
+        let (start, end) = times.trim().split_once('-').ok_or_else(invalid)?;
+        let day_index = |day: &str| {
+            day.trim()
+                .parse::<Weekday>()
+                .map(|x| x.num_days_from_monday() as u8)
+                .map_err(|_| invalid())
+        };
+        Ok(WindowInput {
+            first_day: day_index(first_day)?,
+            last_day: day_index(last_day)?,
+            start: start.trim().to_string(),
+            end: end.trim().to_string(),
+        })
+    }
+}
+
+/// A call forward as entered by a user, before it is checked
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct ForwardInput {
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+    /// asterisk names of the contexts
+    pub(crate) contexts: Vec<String>,
+    /// the default timezone if not given
+    pub(crate) schedule_timezone: Option<String>,
+    /// the call forward is always active if there are none
+    pub(crate) windows: Vec<WindowInput>,
+    /// `always` if not given
+    pub(crate) condition: Option<String>,
+    pub(crate) no_answer_timeout: Option<String>,
+    /// separated by commas or whitespace
+    pub(crate) caller_ids: Option<String>,
+    /// in the default timezone, unless given as RFC 3339
+    pub(crate) valid_from: Option<String>,
+    pub(crate) valid_until: Option<String>,
+    pub(crate) active: bool,
+}
+impl ForwardInput {
+    /// The input that would create `forward` again
+    pub(crate) fn from_forward<S: crate::types::IdState>(forward: &CallForward<S>) -> ForwardInput {
+        ForwardInput {
+            from: forward.from.extension.clone(),
+            to: forward.to.iter().map(|x| x.extension.clone()).collect(),
+            contexts: forward
+                .in_contexts
+                .iter()
+                .map(|x| x.asterisk_name.clone())
+                .collect(),
+            schedule_timezone: forward.schedule.as_ref().map(|x| x.timezone.to_string()),
+            windows: forward
+                .schedule
+                .iter()
+                .flat_map(|x| x.windows.iter())
+                .map(|x| WindowInput {
+                    first_day: x.first_day_index() as u8,
+                    last_day: x.last_day_index() as u8,
+                    start: x.start.format("%H:%M:%S").to_string(),
+                    end: x.end.format("%H:%M:%S").to_string(),
+                })
+                .collect(),
+            condition: Some(forward.condition.kind().to_string()),
+            no_answer_timeout: forward.condition.no_answer_timeout().map(|x| x.to_string()),
+            caller_ids: Some(forward.caller_ids_string()),
+            valid_from: forward.valid_from.map(|x| x.to_rfc3339()),
+            valid_until: forward.valid_until.map(|x| x.to_rfc3339()),
+            active: forward.active,
+        }
+    }
+
+    /// Check the input against the known extensions and contexts and build the call forward.
+    ///
+    /// Returns the message to display to the user if anything is malformed. Overlaps and loops
+    /// with other call forwards are only found when it is stored.
+    pub(crate) fn check(&self, config: &Config) -> Result<CallForward<'static, NoId>, String> {
+        self.check_with(config, None)
+    }
+
+    /// Like [`Self::check`], for a change to the stored call forward `before`.
+    ///
+    /// Expired call forwards are kept for a while, so an expiry in the past is only rejected if
+    /// it is changed.
+    pub(crate) fn check_change(
+        &self,
+        config: &Config,
+        before: &CallForward<HasId>,
+    ) -> Result<CallForward<'static, NoId>, String> {
+        self.check_with(config, Some(before))
+    }
+
+    /// [`Self::check`], accepting the expiry of `before` even if it is in the past
+    fn check_with(
+        &self,
+        config: &Config,
+        before: Option<&CallForward<HasId>>,
+    ) -> Result<CallForward<'static, NoId>, String> {
+        let from = self.from.trim();
+        let Some(from_ext) = config.registry.extension(from) else {
+            return Err(format!("Die Nummer {from} ist nicht bekannt."));
+        };
+        let schedule = self.schedule(config)?;
+        let condition = self.condition()?;
+        let caller_ids = self.caller_ids()?;
+        let (valid_from, valid_until) =
+            self.validity(config, before.and_then(|x| x.valid_until))?;
+        let Some(to) = self.destinations(config)? else {
+            return Err("Eine Weiterleitung muss mindestens ein Ziel haben.".to_string());
+        };
+        let in_contexts = self.in_contexts(config)?;
+        Ok(CallForward {
+            fwd_id: NoId {},
+            from: from_ext,
+            to,
+            in_contexts,
+            schedule,
+            condition,
+            caller_ids,
+            active: self.active,
+            valid_from,
+            valid_until,
+            version: 1,
+        })
+    }
+
+    /// The destinations, ignoring empty entries and duplicates.
+    ///
+    /// Returns None if there is no destination.
+    fn destinations(&self, config: &Config) -> Result<Option<Vec<Extension>>, String> {
+        let mut destinations: Vec<Extension> = vec![];
+        for dest in &self.to {
+            let dest = dest.trim();
+            if dest.is_empty() || destinations.iter().any(|x| x.extension == dest) {
+                continue;
+            };
+            if !Extension::is_dialable(dest) {
+                return Err(format!(
+                    "Das Ziel {dest} ist keine gültige Nummer. Erlaubt sind nur Ziffern, Buchstaben, *, #, + und -."
+                ));
+            };
+            destinations.push(Extension::create_from_name(config, dest.to_string()));
+        }
+        if destinations.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(destinations))
+        }
+    }
+
+    fn in_contexts(&self, config: &Config) -> Result<Vec<&'static Context>, String> {
+        let mut contexts: Vec<&'static Context> = vec![];
+        for ctx in &self.contexts {
+            let Some(this_ctx) = config.registry.context(ctx.trim()) else {
+                return Err(format!("Konnte den Kontext {ctx} nicht finden."));
+            };
+            if !contexts.contains(&this_ctx) {
+                contexts.push(this_ctx);
+            };
+        }
+        if contexts.is_empty() {
+            return Err("Eine Weiterleitung muss mindestens einen Kontext enthalten.".to_string());
+        };
+        Ok(contexts)
+    }
+
+    fn schedule(&self, config: &Config) -> Result<Option<Schedule>, String> {
+        if self.windows.is_empty() {
+            return Ok(None);
+        };
+        let timezone = match self.schedule_timezone.as_deref().map(str::trim) {
+            None | Some("") => config.default_timezone,
+            Some(x) => x
+                .parse::<Tz>()
+                .map_err(|_| format!("Die Zeitzone {x} ist unbekannt."))?,
+        };
+
+        let mut windows = vec![];
+        for window in &self.windows {
+            let (Ok(first_day), Ok(last_day)) = (
+                Weekday::try_from(window.first_day),
+                Weekday::try_from(window.last_day),
+            ) else {
+                return Err("Ungültiger Wochentag im Zeitplan.".to_string());
+            };
+            let (Some(start), Some(end)) = (parse_time(&window.start), parse_time(&window.end))
+            else {
+                return Err("Ungültige Uhrzeit im Zeitplan.".to_string());
+            };
+            let Some(window) = ScheduleWindow::new(first_day, last_day, start, end) else {
+                return Err(format!(
+                    "Im Zeitplan muss die Startzeit vor der Endzeit liegen ({} - {}).",
+                    start.format("%H:%M"),
+                    end.format("%H:%M")
+                ));
+            };
+            windows.push(window);
+        }
+        Ok(Some(Schedule { timezone, windows }))
+    }
+
+    fn condition(&self) -> Result<ForwardCondition, String> {
+        let timeout = match self.no_answer_timeout.as_deref().map(str::trim) {
+            None | Some("") => None,
+            Some(x) => match x.parse::<u32>() {
+                Ok(t) if t > 0 => Some(t),
+                _ => {
+                    return Err("Die Wartezeit muss eine positive Anzahl Sekunden sein.".to_string())
+                }
+            },
+        };
+        let kind = self.condition.as_deref().map(str::trim).unwrap_or("always");
+        ForwardCondition::from_kind(kind, timeout)
+            .ok_or_else(|| "Ungültige Bedingung für die Weiterleitung.".to_string())
+    }
+
+    fn caller_ids(&self) -> Result<Vec<CallerIdPattern>, String> {
+        let mut caller_ids = vec![];
+        for raw in self
+            .caller_ids
+            .as_deref()
+            .unwrap_or_default()
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+        {
+            let Some(pattern) = CallerIdPattern::parse(raw) else {
+                return Err(format!(
+                    "Ungültige Anrufernummer {raw}. Ein * ist nur am Ende erlaubt."
+                ));
+            };
+            if !caller_ids.contains(&pattern) {
+                caller_ids.push(pattern);
+            };
+        }
+        Ok(caller_ids)
+    }
+
+    /// The start and the end of the validity. The end must be in the future, unless it is
+    /// `stored_until`.
+    #[allow(clippy::type_complexity)]
+    fn validity(
+        &self,
+        config: &Config,
+        stored_until: Option<DateTime<Utc>>,
+    ) -> Result<(Option<DateTime<Utc>>, Option<DateTime<Utc>>), String> {
+        let timezone = &config.default_timezone;
+        let valid_from = parse_datetime(timezone, self.valid_from.as_deref())?;
+        let valid_until = parse_datetime(timezone, self.valid_until.as_deref())?;
+        if let (Some(from), Some(until)) = (valid_from, valid_until) {
+            if from >= until {
+                return Err("Eine Weiterleitung muss gültig werden bevor sie abläuft.".to_string());
+            };
+        };
+        if valid_until.is_some_and(|until| until <= Utc::now() && Some(until) != stored_until) {
+            return Err("Das Ende der Gültigkeit liegt in der Vergangenheit.".to_string());
+        };
+        Ok((valid_from, valid_until))
+    }
+}
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    let time = time.trim();
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M:%S"))
+        .ok()
+}
+
+/// Read a point in time entered in `timezone`, or with an offset as RFC 3339.
+///
+/// Returns the message to display to the user if it is malformed.
+fn parse_datetime(timezone: &Tz, datetime: Option<&str>) -> Result<Option<DateTime<Utc>>, String> {
+    let datetime = match datetime.map(str::trim) {
+        None | Some("") => return Ok(None),
+        Some(x) => x,
+    };
+    if let Ok(x) = DateTime::parse_from_rfc3339(datetime) {
+        return Ok(Some(x.with_timezone(&Utc)));
+    };
+    let naive = NaiveDateTime::parse_from_str(datetime, VALIDITY_INPUT_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(datetime, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| format!("Ungültiger Zeitpunkt {datetime}."))?;
+    // a time skipped when switching to daylight saving time does not exist
+    let local = timezone
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or_else(|| format!("Den Zeitpunkt {datetime} gibt es in {timezone} nicht."))?;
+    Ok(Some(local.with_timezone(&Utc)))
+}
+
+/// A call forward as exported to and imported from files, and shown on the command line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ForwardRecord {
+    /// None for call forwards that are not stored yet
+    #[serde(default)]
+    pub(crate) id: Option<i32>,
+    pub(crate) from: String,
+    pub(crate) to: Vec<String>,
+    /// asterisk names of the contexts
+    pub(crate) contexts: Vec<String>,
+    #[serde(default = "default_condition")]
+    pub(crate) condition: String,
+    #[serde(default)]
+    pub(crate) no_answer_timeout: Option<u32>,
+    #[serde(default)]
+    pub(crate) caller_ids: Vec<String>,
+    #[serde(default)]
+    pub(crate) schedule_timezone: Option<String>,
+    /// like `Mon-Fri 08:00-17:00`, see [`WindowInput::parse`]
+    #[serde(default)]
+    pub(crate) windows: Vec<String>,
+    /// RFC 3339, or without an offset in the default timezone
+    #[serde(default)]
+    pub(crate) valid_from: Option<String>,
+    #[serde(default)]
+    pub(crate) valid_until: Option<String>,
+    #[serde(default = "default_active")]
+    pub(crate) active: bool,
+    /// ignored on import
+    #[serde(default)]
+    pub(crate) version: Option<i32>,
+}
+fn default_condition() -> String {
+    ForwardCondition::Always.kind().to_string()
+}
+fn default_active() -> bool {
+    true
+}
+impl From<&CallForward<'_, HasId>> for ForwardRecord {
+    fn from(forward: &CallForward<'_, HasId>) -> Self {
+        let time = |x: &NaiveTime| {
+            if x.second() == 0 {
+                x.format("%H:%M").to_string()
+            } else {
+                x.format("%H:%M:%S").to_string()
+            }
+        };
+        ForwardRecord {
+            id: Some(forward.fwd_id.into()),
+            from: forward.from.extension.clone(),
+            to: forward.to.iter().map(|x| x.extension.clone()).collect(),
+            contexts: forward
+                .in_contexts
+                .iter()
+                .map(|x| x.asterisk_name.clone())
+                .collect(),
+            condition: forward.condition.kind().to_string(),
+            no_answer_timeout: forward.condition.no_answer_timeout(),
+            caller_ids: forward.caller_ids.iter().map(|x| x.to_string()).collect(),
+            schedule_timezone: forward.schedule.as_ref().map(|x| x.timezone.to_string()),
+            windows: forward
+                .schedule
+                .iter()
+                .flat_map(|x| x.windows.iter())
+                .map(|x| {
+                    let days = if x.first_day == x.last_day {
+                        x.first_day.to_string()
+                    } else {
+                        format!("{}-{}", x.first_day, x.last_day)
+                    };
+                    format!("{days} {}-{}", time(&x.start), time(&x.end))
+                })
+                .collect(),
+            valid_from: forward.valid_from.map(|x| x.to_rfc3339()),
+            valid_until: forward.valid_until.map(|x| x.to_rfc3339()),
+            active: forward.active,
+            version: Some(forward.version),
+        }
+    }
+}
+impl ForwardRecord {
+    /// The input to check this record with
+    ///
+    /// Returns the message to display to the user if a window is malformed.
+    pub(crate) fn to_input(&self) -> Result<ForwardInput, String> {
+        Ok(ForwardInput {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            contexts: self.contexts.clone(),
+            schedule_timezone: self.schedule_timezone.clone(),
+            windows: self
+                .windows
+                .iter()
+                .map(|x| WindowInput::parse(x))
+                .collect::<Result<_, _>>()?,
+            condition: Some(self.condition.clone()),
+            no_answer_timeout: self.no_answer_timeout.map(|x| x.to_string()),
+            caller_ids: Some(self.caller_ids.join(",")),
+            valid_from: self.valid_from.clone(),
+            valid_until: self.valid_until.clone(),
+            active: self.active,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::PgPool;
+
+    use super::{ForwardInput, WindowInput};
+    use crate::types::Config;
+
+    #[test]
+    fn parse_windows() {
+        assert_eq!(
+            WindowInput::parse("Mon-Fri 08:00-17:00"),
+            Ok(WindowInput {
+                first_day: 0,
+                last_day: 4,
+                start: "08:00".to_string(),
+                end: "17:00".to_string(),
+            })
+        );
+        assert_eq!(
+            WindowInput::parse("sat 10:00-12:30").map(|x| (x.first_day, x.last_day)),
+            Ok((5, 5))
+        );
+        assert!(WindowInput::parse("Mon-Fri").is_err());
+        assert!(WindowInput::parse("Mon-Xyz 08:00-17:00").is_err());
+    }
+
+    #[sqlx::test(fixtures("call_forward"))]
+    async fn check_input(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let fwd = crate::db::get_call_forward_by_id(&config, 2).await?;
+
+        // a stored call forward is entered again as it is
+        let input = ForwardInput::from_forward(&fwd);
+        let checked = input.check(&config)?;
+        assert_eq!(checked.set_id(2).set_version(fwd.version), fwd);
+
+        let mut input = ForwardInput {
+            from: "702".to_string(),
+            to: vec!["704".to_string(), " ".to_string(), "704".to_string()],
+            contexts: vec!["from_sales".to_string()],
+            windows: vec![WindowInput::parse("Mon-Fri 08:00-17:00")?],
+            caller_ids: Some("0171*, 0172".to_string()),
+            active: true,
+            ..Default::default()
+        };
+        let checked = input.check(&config)?;
+        assert_eq!(checked.destination_extensions(), vec!["704"]);
+        assert_eq!(checked.caller_ids.len(), 2);
+        assert_eq!(
+            checked.schedule.map(|x| x.timezone),
+            Some(config.default_timezone)
+        );
+
+        // nothing that would add targets or options to Dial()
+        for to in ["100&SIP/evil", "100,,D(1)", "SIP/100", "100 200"] {
+            input.to = vec![to.to_string()];
+            assert!(input.check(&config).is_err());
+        }
+        input.to = vec!["+49151*1#".to_string()];
+        assert!(input.check(&config).is_ok());
+
+        input.from = "unknown".to_string();
+        assert!(input.check(&config).is_err());
+        input.from = "702".to_string();
+        input.contexts = vec!["not-a-context".to_string()];
+        assert!(input.check(&config).is_err());
+        input.contexts = vec![];
+        assert!(input.check(&config).is_err());
+        input.contexts = vec!["from_sales".to_string()];
+        input.condition = Some("no_answer".to_string());
+        assert!(input.check(&config).is_err());
+        input.no_answer_timeout = Some("20".to_string());
+        input.valid_until = Some("2001-01-01T00:00".to_string());
+        assert!(input.check(&config).is_err());
+
+        // an expired call forward can still be changed, as long as its expiry stays
+        let expired = fwd.set_validity(None, Some(chrono::Utc::now() - chrono::Duration::days(1)));
+        let mut input = ForwardInput::from_forward(&expired);
+        assert!(input.check(&config).is_err());
+        input.active = false;
+        assert!(!input.check_change(&config, &expired)?.active);
+        input.valid_until = Some("2001-01-01T00:00".to_string());
+        assert!(input.check_change(&config, &expired).is_err());
+        Ok(())
+    }
+}
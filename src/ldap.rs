@@ -1,8 +1,12 @@
+use std::sync::{Arc, RwLock};
+
 use axum_login::{AuthUser, AuthnBackend, UserId};
 use ldap3::{Ldap, LdapConnAsync, Scope, SearchEntry};
 use serde::Deserialize;
 use tracing::{warn, Level};
 
+use crate::metrics::METRICS;
+
 /// escape parameter such that it may be used in a search filter
 /// uses RFC2254 Section 4 and RFC4514 Section 2.4
 ///
@@ -73,20 +77,20 @@ pub(crate) struct UserCredentials {
 }
 
 #[derive(Clone)]
-pub(crate) struct LDAPBackend {
+struct LDAPSettings {
     /// String defining the ldaps server to bind against
     bind_string: String,
     /// filter to search for users. Contains {username} which will be replaced
-    pub(crate) user_filter: String,
+    user_filter: String,
     /// the base dn under which users lie
-    pub(crate) base_dn: String,
+    base_dn: String,
     /// dn and password of the search user
     bind_dn: String,
     bind_pw: String,
 }
-impl std::fmt::Debug for LDAPBackend {
+impl std::fmt::Debug for LDAPSettings {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.debug_struct("LDAPBackend")
+        f.debug_struct("LDAPSettings")
             .field("bind_string", &self.bind_string)
             .field("bind_dn", &self.bind_dn)
             .field("base_dn", &self.base_dn)
@@ -95,6 +99,12 @@ impl std::fmt::Debug for LDAPBackend {
             .finish()
     }
 }
+
+#[derive(Clone, Debug)]
+pub(crate) struct LDAPBackend {
+    /// shared between all clones, so that a reloaded config reaches the login of the web server
+    settings: Arc<RwLock<LDAPSettings>>,
+}
 impl LDAPBackend {
     #[tracing::instrument(level=Level::DEBUG,skip_all,err)]
     pub async fn new(
@@ -107,38 +117,73 @@ impl LDAPBackend {
     ) -> Result<Self, LDAPError> {
         let bind_string = format!("ldaps://{hostname}:{port}");
         Ok(LDAPBackend {
-            bind_string,
-            user_filter: user_filter.to_string(),
-            base_dn: base_dn.to_string(),
-            bind_dn: bind_dn.to_string(),
-            bind_pw: bind_pw.to_string(),
+            settings: Arc::new(RwLock::new(LDAPSettings {
+                bind_string,
+                user_filter: user_filter.to_string(),
+                base_dn: base_dn.to_string(),
+                bind_dn: bind_dn.to_string(),
+                bind_pw: bind_pw.to_string(),
+            })),
         })
     }
 
+    /// Use the settings of `other` from now on, in this and all its clones
+    pub(crate) fn replace(&self, other: &LDAPBackend) {
+        let new_settings = other.settings();
+        *self.settings.write().expect("LDAP settings lock poisoned") = new_settings;
+    }
+
+    fn settings(&self) -> LDAPSettings {
+        self.settings
+            .read()
+            .expect("LDAP settings lock poisoned")
+            .clone()
+    }
+
     async fn new_bound_connection(&self) -> Result<Ldap, LDAPError> {
-        let (conn, mut ldap) = LdapConnAsync::new(&self.bind_string)
+        let settings = self.settings();
+        let (conn, mut ldap) = LdapConnAsync::new(&settings.bind_string)
             .await
             .map_err(|_| LDAPError::CannotConnect)?;
         // spawn a task that drives the connection until ldap is dropped
         ldap3::drive!(conn);
         // LDAP-bind the handle
-        ldap.simple_bind(&self.bind_dn, &self.bind_pw)
+        let timer = METRICS
+            .ldap_bind_duration
+            .with_label_values(&["search"])
+            .start_timer();
+        let bound = ldap
+            .simple_bind(&settings.bind_dn, &settings.bind_pw)
             .await
-            .map_err(|_| LDAPError::CannotBind)?
-            .success()
-            .map_err(LDAPError::UserError)?;
+            .map_err(|_| LDAPError::CannotBind)
+            .and_then(|x| x.success().map_err(LDAPError::UserError));
+        timer.observe_duration();
+        if bound.is_err() {
+            METRICS
+                .ldap_bind_failures
+                .with_label_values(&["search"])
+                .inc();
+        };
+        bound?;
         Ok(ldap)
     }
 
+    /// Bind as the search user and unbind again, to see whether LDAP can be used
+    pub(crate) async fn check_connection(&self) -> Result<(), LDAPError> {
+        let mut handle = self.new_bound_connection().await?;
+        handle.unbind().await.map_err(|_| LDAPError::CannotUnbind)
+    }
+
     /// Bind, get a user (potentially) and DO NOT UNBIND, returning the (still live and bound)
     /// connection on success
     async fn get_user_no_unbind(&self, id: &str) -> Result<(Ldap, Option<User>), LDAPError> {
+        let settings = self.settings();
         let mut our_handle = self.new_bound_connection().await?;
         let (rs, _res) = our_handle
             .search(
-                &self.base_dn,
+                &settings.base_dn,
                 Scope::OneLevel,
-                &self
+                &settings
                     .user_filter
                     .replace("{username}", &escape_ldap_search_filter_parameter(id)),
                 vec!["uid", "userPassword"],
@@ -210,7 +255,8 @@ impl AuthnBackend for LDAPBackend {
             None => {
                 warn!(
                     "User {} tried logging in but was not found via the search filter {}",
-                    creds.username, self.user_filter
+                    creds.username,
+                    self.settings().user_filter
                 );
                 return Ok(None);
             }
@@ -219,14 +265,24 @@ impl AuthnBackend for LDAPBackend {
         // try to bind as that user
         // get a new handle and re-bind
         // we need to rebind as the search user
-        let res = handle
-            .simple_bind(&user.ldap_dn, &creds.password)
+        let timer = METRICS
+            .ldap_bind_duration
+            .with_label_values(&["user"])
+            .start_timer();
+        let bound = handle.simple_bind(&user.ldap_dn, &creds.password).await;
+        timer.observe_duration();
+        let res = bound
             // on a connection error, return Err(_)
-            .await
             .map_err(|_| LDAPError::CannotBind)?
             .success()
             // if the password is wrong, return Ok(None), else Ok(Some(the-user))
             .map_or(Ok(None), |_| Ok(Some(user)))?;
+        if res.is_none() {
+            METRICS
+                .ldap_bind_failures
+                .with_label_values(&["user"])
+                .inc();
+        };
         // unbind to cleanly exit the ldap session
         handle.unbind().await.map_err(|_| LDAPError::CannotUnbind)?;
         Ok(res)
@@ -300,8 +356,7 @@ mod ldap_test {
     #[ignore]
     async fn ldap_bind() {
         let backend = Config::create().await.unwrap().ldap_config;
-        let mut handle = backend.new_bound_connection().await.unwrap();
-        handle.unbind().await.unwrap();
+        backend.check_connection().await.unwrap();
     }
 
     #[tokio::test]
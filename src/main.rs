@@ -1,13 +1,30 @@
 use std::panic;
+use std::path::Path;
 use std::sync::Arc;
 
-use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use clap::Parser;
+
+use tokio::sync::watch;
+use tracing::{event, Level};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
 use tracing_subscriber::{filter, fmt::format::FmtSpan};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 mod agi_server;
+mod bulk;
+mod cache;
+mod cli;
 mod db;
+mod forward_input;
 pub(crate) mod ldap;
+mod metrics;
+mod pin;
+mod registry;
+mod reload;
+mod storage;
 pub mod types;
 mod web_server;
 
@@ -19,56 +36,181 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    let file_appender = RollingFileAppender::builder()
-        .rotation(Rotation::DAILY)
-        .filename_prefix("asterconf.log")
-        .build("/var/log/asterconf")
-        .expect("Should be able to create file appender");
-    let (writer, _guard) = tracing_appender::non_blocking(file_appender);
+    let cli = cli::Cli::parse();
+    match cli.command {
+        None => serve(&cli.config, &cli.log_dir, cli::Part::Both).await,
+        Some(cli::Command::Serve { part }) => serve(&cli.config, &cli.log_dir, part).await,
+        Some(cli::Command::CheckConfig) => {
+            // only report on the terminal, without touching the log files
+            init_logging(None, true)?;
+            cli::check_config(&cli.config).await
+        }
+        Some(cli::Command::Forward { action }) => {
+            // changes are logged like those in the web interface, output is for the caller only
+            let (log_filter_handle, _guard) = init_logging(Some(&cli.log_dir), false)?;
+            cli::forward(&cli.config, action, &log_filter_handle).await
+        }
+    }
+}
 
-    let my_crate_filter = EnvFilter::new("asterconf,blazing_agi");
+/// Log to stdout if `to_stdout` is set and, if `log_dir` is given, to a new file there every day
+///
+/// Returns the handle to change which events are logged and the guard that has to be kept
+/// until the end, so that the last events still reach the file.
+fn init_logging(
+    log_dir: Option<&Path>,
+    to_stdout: bool,
+) -> Result<(reload::LogFilterHandle, Option<WorkerGuard>), Box<dyn std::error::Error>> {
+    let (writer, guard) = match log_dir {
+        Some(dir) => {
+            let file_appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix("asterconf.log")
+                .build(dir)?;
+            let (writer, guard) = tracing_appender::non_blocking(file_appender);
+            (Some(writer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    // replaced by the filter from the config file as soon as it is read
+    let (my_crate_filter, log_filter_handle) =
+        tracing_subscriber::reload::Layer::new(EnvFilter::new(types::DEFAULT_LOG_FILTER));
     let subscriber = tracing_subscriber::registry()
         .with(my_crate_filter)
-        .with(
+        .with(to_stdout.then(|| {
             tracing_subscriber::fmt::layer()
                 .compact()
                 .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
                 .with_line_number(true)
-                .with_filter(filter::LevelFilter::TRACE),
-        )
-        .with(fmt::Layer::default().with_writer(writer));
+                .with_filter(filter::LevelFilter::TRACE)
+        }))
+        .with(writer.map(|x| fmt::Layer::default().with_writer(x)));
     if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
         eprintln!("Error setting global tracing subscriber: {e}");
         Err(e)?;
     };
+    Ok((log_filter_handle, guard))
+}
 
-    let config = types::Config::create().await?;
+/// Run the servers of `part` with the config from `config_path` until they stop
+async fn serve(
+    config_path: &Path,
+    log_dir: &Path,
+    part: cli::Part,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (log_filter_handle, _guard) = init_logging(Some(log_dir), true)?;
+
+    let config = types::Config::create_from(config_path).await?;
+    log_filter_handle.reload(EnvFilter::new(&config.log_filter))?;
     let config_capsule = Arc::new(config);
 
-    sqlx::migrate!().run(&config_capsule.pool).await?;
+    // the last known call forwards, answered from while the DB is unreachable
+    let mut snapshot_loaded = false;
+    if let Some(path) = config_capsule
+        .snapshot_file
+        .as_ref()
+        .filter(|_| part.runs_agi())
+    {
+        match config_capsule
+            .forward_cache
+            .load_snapshot(&config_capsule.registry, path)
+            .await
+        {
+            Ok(loaded_at) => {
+                event!(
+                    Level::INFO,
+                    "Loaded the call forward snapshot from {loaded_at}."
+                );
+                snapshot_loaded = true;
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Could not load the call forward snapshot from {}: {e}",
+                    path.display()
+                );
+            }
+        };
+    };
 
-    // start the agi server
-    let config_for_agi = config_capsule.clone();
-    let agi_handle = tokio::spawn(async move {
-        if let Err(e) = agi_server::run_agi_server(config_for_agi).await {
-            eprintln!("Could not start the AGI server: {e}");
-            panic!("Unable to start AGI server. Unrecoverable");
+    if let Err(e) = config_capsule.pool.run_migrations().await {
+        if !snapshot_loaded {
+            return Err(e.into());
+        };
+        // better to answer calls from the snapshot than not at all
+        event!(
+            Level::ERROR,
+            "Could not run the migrations: {e}. Starting with the call forwards from the snapshot. The migrations will run on the next start."
+        );
+    };
+    // until this works, the extensions and contexts from the snapshot are used,
+    // or the ones from the config file if there is none
+    if let Err(e) = registry::load(&config_capsule).await {
+        let source = if snapshot_loaded {
+            "the snapshot"
+        } else {
+            "the config file"
+        };
+        event!(
+            Level::ERROR,
+            "Could not load the extensions and contexts from the DB: {e}. Using the ones from {source}."
+        );
+    };
+    reload::report_unknown_contexts(&config_capsule).await;
+
+    // everything that takes over changes to the config file follows this
+    let (config_sender, configs) = watch::channel(config_capsule.clone());
+    tokio::spawn(async move {
+        if let Err(e) = reload::run_reloader(config_sender, log_filter_handle).await {
+            event!(Level::ERROR, "Could not watch the config file: {e}");
         };
     });
 
-    // start the web server
-    let config_for_web = config_capsule.clone();
-    let webserver = web_server::Webserver::new().await?;
-    let web_handle = tokio::spawn(async move {
-        if let Err(e) = webserver.run_web_server(config_for_web).await {
-            eprintln!("Could not start the web server: {e}");
-            panic!("Unable to start web server. Unrecoverable");
+    // keep the call forward cache up to date, along with the extensions and contexts
+    tokio::spawn(cache::run_listener(config_capsule.clone()));
+
+    // serve the metrics outside of the web UI, so they need no login
+    let config_for_metrics = config_capsule.clone();
+    tokio::spawn(async move {
+        if let Err(e) = metrics::run_metrics_server(config_for_metrics).await {
+            event!(Level::ERROR, "Could not start the metrics server: {e}");
         };
     });
 
-    let res = tokio::join!(agi_handle, web_handle);
-    res.0?;
-    res.1?;
+    let mut handles = vec![];
+    if part.runs_agi() {
+        tokio::spawn(cache::run_snapshot_writer(config_capsule.clone()));
+        tokio::spawn(agi_server::run_lookup_writer(configs.clone()));
+        tokio::spawn(agi_server::run_lookup_purge(configs.clone()));
+        tokio::spawn(agi_server::run_expired_forward_purge(configs.clone()));
+        tokio::spawn(agi_server::run_deleted_forward_purge(configs.clone()));
+
+        // start the agi server
+        let config_for_agi = configs.clone();
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = agi_server::run_agi_server(config_for_agi).await {
+                eprintln!("Could not start the AGI server: {e}");
+                panic!("Unable to start AGI server. Unrecoverable");
+            };
+        }));
+    };
+
+    if part.runs_web() {
+        // start the web server
+        let config_for_web = configs.clone();
+        let webserver = web_server::Webserver::new().await?;
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = webserver.run_web_server(config_for_web).await {
+                eprintln!("Could not start the web server: {e}");
+                panic!("Unable to start web server. Unrecoverable");
+            };
+        }));
+    };
+
+    for handle in handles {
+        handle.await?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,227 @@
+//! Prometheus metrics
+//!
+//! They are served on their own bind address (see [`run_metrics_server`]), so that the scraper
+//! does not need to log in.
+use std::sync::{Arc, LazyLock};
+
+use axum::{http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, Opts, Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+use tracing::{event, Level};
+
+use crate::types::Config;
+
+/// All metrics of asterconf
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// AGI requests by route and peer
+    pub agi_requests: IntCounterVec,
+    /// AGI connections from addresses that belong to no peer
+    pub agi_refused_connections: IntCounter,
+    /// AGI requests whose handler returned an error, by route
+    pub agi_errors: IntCounterVec,
+    pub agi_request_duration: HistogramVec,
+    /// unix timestamp of the last AGI request by peer. Alert if this gets old during office hours
+    pub agi_last_request: GaugeVec,
+    /// lookups by route and result (forwarded, not_forwarded or cycle)
+    pub call_forward_results: IntCounterVec,
+    pub digest_failures: IntCounter,
+    /// by the function in db.rs
+    pub db_query_duration: HistogramVec,
+    /// by bind ("search" for the bind user, "user" for users logging in)
+    pub ldap_bind_duration: HistogramVec,
+    pub ldap_bind_failures: IntCounterVec,
+    /// by result (success, rejected or error)
+    pub web_logins: IntCounterVec,
+    /// 1 while AGI lookups are answered from outdated call forwards, because the DB is unreachable
+    pub degraded: IntGauge,
+    /// call lookups not logged, because too many were waiting for the DB
+    pub dropped_call_lookups: IntCounter,
+}
+impl Metrics {
+    fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("asterconf".to_string()), None).expect("the prefix is valid");
+        let agi_requests = IntCounterVec::new(
+            Opts::new("agi_requests_total", "AGI requests by route and peer"),
+            &["route", "peer"],
+        )
+        .expect("metric is valid");
+        let agi_refused_connections = IntCounter::new(
+            "agi_refused_connections_total",
+            "AGI connections from unknown addresses",
+        )
+        .expect("metric is valid");
+        let agi_errors = IntCounterVec::new(
+            Opts::new(
+                "agi_errors_total",
+                "AGI requests that could not be answered, by route",
+            ),
+            &["route"],
+        )
+        .expect("metric is valid");
+        let agi_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "agi_request_duration_seconds",
+                "Time to answer an AGI request, by route",
+            ),
+            &["route"],
+        )
+        .expect("metric is valid");
+        let agi_last_request = GaugeVec::new(
+            Opts::new(
+                "agi_last_request_timestamp_seconds",
+                "Unix time of the last AGI request by peer",
+            ),
+            &["peer"],
+        )
+        .expect("metric is valid");
+        let call_forward_results = IntCounterVec::new(
+            Opts::new(
+                "call_forward_results_total",
+                "Call forward lookups by route and result",
+            ),
+            &["route", "result"],
+        )
+        .expect("metric is valid");
+        let digest_failures = IntCounter::new(
+            "agi_digest_failures_total",
+            "AGI requests with a wrong digest",
+        )
+        .expect("metric is valid");
+        let db_query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time spent in the DB, by query",
+            ),
+            &["query"],
+        )
+        .expect("metric is valid");
+        let ldap_bind_duration = HistogramVec::new(
+            HistogramOpts::new("ldap_bind_duration_seconds", "Time to bind against LDAP"),
+            &["bind"],
+        )
+        .expect("metric is valid");
+        let ldap_bind_failures = IntCounterVec::new(
+            Opts::new(
+                "ldap_bind_failures_total",
+                "Failed or rejected binds against LDAP",
+            ),
+            &["bind"],
+        )
+        .expect("metric is valid");
+        let web_logins = IntCounterVec::new(
+            Opts::new("web_logins_total", "Login attempts in the web UI by result"),
+            &["result"],
+        )
+        .expect("metric is valid");
+        let degraded = IntGauge::new(
+            "degraded",
+            "1 while calls are answered from outdated call forwards",
+        )
+        .expect("metric is valid");
+        let dropped_call_lookups = IntCounter::new(
+            "dropped_call_lookups_total",
+            "Call lookups not logged, because too many were waiting for the DB",
+        )
+        .expect("metric is valid");
+
+        for metric in [
+            Box::new(agi_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(agi_refused_connections.clone()),
+            Box::new(agi_errors.clone()),
+            Box::new(agi_request_duration.clone()),
+            Box::new(agi_last_request.clone()),
+            Box::new(call_forward_results.clone()),
+            Box::new(digest_failures.clone()),
+            Box::new(db_query_duration.clone()),
+            Box::new(ldap_bind_duration.clone()),
+            Box::new(ldap_bind_failures.clone()),
+            Box::new(web_logins.clone()),
+            Box::new(degraded.clone()),
+            Box::new(dropped_call_lookups.clone()),
+        ] {
+            registry.register(metric).expect("metric names are unique");
+        }
+
+        Metrics {
+            registry,
+            agi_requests,
+            agi_refused_connections,
+            agi_errors,
+            agi_request_duration,
+            agi_last_request,
+            call_forward_results,
+            digest_failures,
+            db_query_duration,
+            ldap_bind_duration,
+            ldap_bind_failures,
+            web_logins,
+            degraded,
+            dropped_call_lookups,
+        }
+    }
+
+    /// All metrics in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            event!(Level::WARN, "Unable to encode the metrics: {e}");
+        };
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Measure the time until the returned timer is dropped as a query to the DB
+pub fn time_db_query(query: &str) -> HistogramTimer {
+    METRICS
+        .db_query_duration
+        .with_label_values(&[query])
+        .start_timer()
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        METRICS.render(),
+    )
+}
+
+/// Serve `/metrics` on `config.metrics_bind_string`, if it is set
+pub async fn run_metrics_server(config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(bind_string) = config.metrics_bind_string.as_ref() else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(bind_string).await?;
+    event!(
+        Level::INFO,
+        "Metrics server listening on {}",
+        listener.local_addr()?
+    );
+    let app = Router::new().route("/metrics", get(metrics));
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::METRICS;
+
+    #[test]
+    fn render_metrics() {
+        METRICS
+            .agi_requests
+            .with_label_values(&["call_forward", "pbx1"])
+            .inc();
+        let rendered = METRICS.render();
+        assert!(
+            rendered.contains("asterconf_agi_requests_total{peer=\"pbx1\",route=\"call_forward\"}")
+        );
+        assert!(rendered.contains("asterconf_degraded"));
+    }
+}
@@ -0,0 +1,185 @@
+//! PINs protecting remote access to the call forwards of an extension
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use std::sync::OnceLock;
+
+use chrono::Utc;
+use tracing::{event, Level};
+
+use crate::{
+    db::{get_extension_pin, reserve_pin_attempt, reset_failed_pin_attempts, DBError},
+    types::Config,
+};
+
+/// The shortest PIN we accept
+pub const MIN_PIN_LENGTH: usize = 4;
+/// The longest PIN we accept
+pub const MAX_PIN_LENGTH: usize = 12;
+
+/// Can `pin` be entered on a phone keypad and is it long enough?
+pub fn is_valid_pin(pin: &str) -> bool {
+    (MIN_PIN_LENGTH..=MAX_PIN_LENGTH).contains(&pin.len())
+        && pin.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Hash `pin` for storage in the DB
+pub fn hash_pin(pin: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(pin.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Does `pin` match `pin_hash` (as returned by [`hash_pin`])?
+fn verify_pin(pin: &str, pin_hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(pin_hash) else {
+        event!(Level::WARN, "Stored PIN hash is not a valid PHC string.");
+        return false;
+    };
+    Argon2::default()
+        .verify_password(pin.as_bytes(), &parsed)
+        .is_ok()
+}
+
+/// [`verify_pin`] on a thread that may block, since Argon2 is slow on purpose and would hold up
+/// the AGI server otherwise.
+///
+/// Verifies against [`dummy_pin_hash`] if `pin_hash` is None.
+async fn verify_pin_blocking(pin: &str, pin_hash: Option<String>) -> bool {
+    let pin = pin.to_string();
+    tokio::task::spawn_blocking(move || match pin_hash {
+        Some(x) => verify_pin(&pin, &x),
+        None => verify_pin(&pin, dummy_pin_hash()),
+    })
+    .await
+    .unwrap_or(false)
+}
+
+/// A hash that no PIN matches, since it is not made of digits.
+///
+/// PINs for extensions without a PIN are verified against it, so that checking them takes about as
+/// long as checking a wrong PIN.
+fn dummy_pin_hash() -> &'static str {
+    static DUMMY_PIN_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_PIN_HASH.get_or_init(|| hash_pin("no-pin").expect("a fixed PIN can be hashed"))
+}
+
+/// The result of entering a PIN for an extension
+#[derive(Debug, PartialEq)]
+pub enum PinCheck {
+    Correct,
+    Wrong,
+    /// Too many wrong PINs were entered. The PIN is not checked at all.
+    Locked,
+    /// The extension has no PIN, so it cannot be accessed remotely
+    NotSet,
+}
+
+/// Check `pin` for `extension`, counting wrong PINs towards the lockout.
+///
+/// Every attempt is counted before the PIN is checked and forgotten again if it was correct, so
+/// that parallel attempts get no more guesses than attempts one after the other.
+/// Unknown extensions have no PIN either. The time this takes does not tell whether the extension
+/// has a PIN or is locked.
+pub async fn check_pin(config: &Config, extension: &str, pin: &str) -> Result<PinCheck, DBError> {
+    let now = Utc::now();
+    let Some(pin_hash) =
+        reserve_pin_attempt(config, extension, now, now + config.pin_lockout).await?
+    else {
+        verify_pin_blocking(pin, None).await;
+        return Ok(match get_extension_pin(config, extension).await? {
+            Some(_) => PinCheck::Locked,
+            None => PinCheck::NotSet,
+        });
+    };
+    if verify_pin_blocking(pin, Some(pin_hash)).await {
+        reset_failed_pin_attempts(config, extension).await?;
+        Ok(PinCheck::Correct)
+    } else {
+        Ok(PinCheck::Wrong)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::PgPool;
+
+    use super::{check_pin, dummy_pin_hash, hash_pin, is_valid_pin, verify_pin, PinCheck};
+    use crate::{
+        db::{get_extension_pin, set_extension_pin},
+        types::Config,
+    };
+
+    #[test]
+    fn valid_pins() {
+        assert!(is_valid_pin("1234"));
+        assert!(!is_valid_pin("123"));
+        assert!(!is_valid_pin("12a4"));
+        assert!(!is_valid_pin("1234567890123"));
+    }
+
+    #[test]
+    fn hash_and_verify() {
+        let hash = hash_pin("1234").unwrap();
+        assert!(!hash.contains("1234"));
+        assert!(verify_pin("1234", &hash));
+        assert!(!verify_pin("4321", &hash));
+        assert!(!verify_pin("1234", "not a hash"));
+        assert!(!verify_pin("", dummy_pin_hash()));
+    }
+
+    #[sqlx::test]
+    async fn lockout(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.pin_max_attempts = 3;
+
+        assert_eq!(check_pin(&config, "702", "1234").await?, PinCheck::NotSet);
+        set_extension_pin(&config, "702", &hash_pin("1234").unwrap()).await?;
+
+        assert_eq!(check_pin(&config, "702", "0000").await?, PinCheck::Wrong);
+        // a correct PIN resets the count
+        assert_eq!(check_pin(&config, "702", "1234").await?, PinCheck::Correct);
+        assert_eq!(check_pin(&config, "702", "0000").await?, PinCheck::Wrong);
+        assert_eq!(check_pin(&config, "702", "0000").await?, PinCheck::Wrong);
+        assert_eq!(check_pin(&config, "702", "0000").await?, PinCheck::Wrong);
+        // even the correct PIN is rejected now
+        assert_eq!(check_pin(&config, "702", "1234").await?, PinCheck::Locked);
+
+        // setting a new PIN lifts the lockout
+        set_extension_pin(&config, "702", &hash_pin("5678").unwrap()).await?;
+        let stored = get_extension_pin(&config, "702").await?.unwrap();
+        assert_eq!(stored.locked_until, None);
+        assert_eq!(check_pin(&config, "702", "5678").await?, PinCheck::Correct);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn parallel_attempts(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        config.pin_max_attempts = 3;
+        set_extension_pin(&config, "702", &hash_pin("1234").unwrap()).await?;
+
+        // all are started before any is checked, but only three get to guess
+        let config = std::sync::Arc::new(config);
+        let mut attempts = tokio::task::JoinSet::new();
+        for x in 0..10 {
+            let config = config.clone();
+            attempts.spawn(async move { check_pin(&config, "702", &format!("{x:04}")).await });
+        }
+        let mut results = vec![];
+        while let Some(res) = attempts.join_next().await {
+            results.push(res??);
+        }
+        assert_eq!(results.iter().filter(|x| **x == PinCheck::Wrong).count(), 3);
+        assert_eq!(
+            results.iter().filter(|x| **x == PinCheck::Locked).count(),
+            7
+        );
+        assert_eq!(check_pin(&config, "702", "1234").await?, PinCheck::Locked);
+        Ok(())
+    }
+}
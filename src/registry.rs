@@ -0,0 +1,166 @@
+//! The known extensions and contexts
+//!
+//! They are kept in the DB and managed on the admin page. The lists in the config file only seed
+//! the DB on the first start (see [`load`]).
+//! Every clone of a [`Config`] shares one [`Registry`], which is refreshed whenever they change.
+use std::{
+    collections::HashMap,
+    sync::{Mutex, RwLock},
+};
+
+use tracing::{event, Level};
+
+use crate::{
+    db::{get_contexts, get_extensions, seed_extensions_and_contexts, DBError},
+    types::{Config, Context, Extension},
+};
+
+/// Every context ever loaded, so that each is only leaked once
+static INTERNED_CONTEXTS: Mutex<Vec<&'static Context>> = Mutex::new(vec![]);
+
+/// A context that lives as long as the process.
+///
+/// Call forwards borrow their contexts, and a refresh must not pull them away underneath.
+/// Contexts rarely change, so keeping every version ever seen costs next to nothing.
+fn intern(context: Context) -> &'static Context {
+    let mut interned = INTERNED_CONTEXTS.lock().expect("context lock poisoned");
+    if let Some(x) = interned.iter().find(|x| ***x == context) {
+        return x;
+    };
+    let leaked: &'static Context = Box::leak(Box::new(context));
+    interned.push(leaked);
+    leaked
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    /// extension to Extension
+    extensions: HashMap<String, Extension>,
+    /// asterisk name to Context
+    contexts: HashMap<String, &'static Context>,
+}
+
+/// The known extensions and contexts, shared by all clones of a [`Config`]
+#[derive(Debug, Default)]
+pub struct Registry {
+    state: RwLock<RegistryState>,
+}
+impl Registry {
+    pub(crate) fn new(extensions: Vec<Extension>, contexts: Vec<Context>) -> Self {
+        let registry = Self::default();
+        registry.replace(extensions, contexts);
+        registry
+    }
+
+    /// Replace all extensions and contexts
+    pub(crate) fn replace(&self, extensions: Vec<Extension>, contexts: Vec<Context>) {
+        let extensions = extensions
+            .into_iter()
+            .map(|x| (x.extension.clone(), x))
+            .collect();
+        let contexts = contexts
+            .into_iter()
+            .map(|x| (x.asterisk_name.clone(), intern(x)))
+            .collect();
+        let mut state = self.state.write().expect("registry lock poisoned");
+        state.extensions = extensions;
+        state.contexts = contexts;
+    }
+
+    /// The known extension `extension`, if there is one
+    pub(crate) fn extension(&self, extension: &str) -> Option<Extension> {
+        let state = self.state.read().expect("registry lock poisoned");
+        state.extensions.get(extension).cloned()
+    }
+
+    /// Is `extension` a known extension?
+    pub(crate) fn has_extension(&self, extension: &str) -> bool {
+        let state = self.state.read().expect("registry lock poisoned");
+        state.extensions.contains_key(extension)
+    }
+
+    /// All known extensions, ordered by extension
+    pub(crate) fn extensions(&self) -> Vec<Extension> {
+        let state = self.state.read().expect("registry lock poisoned");
+        let mut extensions = state.extensions.values().cloned().collect::<Vec<_>>();
+        extensions.sort_unstable_by(|a, b| a.extension.cmp(&b.extension));
+        extensions
+    }
+
+    /// The context with the asterisk name `asterisk_name`, if there is one
+    pub(crate) fn context(&self, asterisk_name: &str) -> Option<&'static Context> {
+        let state = self.state.read().expect("registry lock poisoned");
+        state.contexts.get(asterisk_name).copied()
+    }
+
+    /// All contexts, ordered by display name
+    pub(crate) fn contexts(&self) -> Vec<&'static Context> {
+        let state = self.state.read().expect("registry lock poisoned");
+        let mut contexts = state.contexts.values().copied().collect::<Vec<_>>();
+        contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
+        contexts
+    }
+}
+
+/// Refresh the registry of `config` from the DB.
+///
+/// On the first start, the extensions and contexts from the config file are copied into the DB.
+/// After that, the config file is ignored.
+pub async fn load(config: &Config) -> Result<(), DBError> {
+    if seed_extensions_and_contexts(config, &config.seed_extensions, &config.seed_contexts).await? {
+        event!(
+            Level::INFO,
+            "Copied {} extensions and {} contexts from the config file into the DB.",
+            config.seed_extensions.len(),
+            config.seed_contexts.len()
+        );
+    };
+    let extensions = get_extensions(config).await?;
+    let contexts = get_contexts(config).await?;
+    config.registry.replace(extensions, contexts);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::PgPool;
+
+    use crate::types::{Config, Context, Extension};
+
+    #[sqlx::test]
+    async fn seed_only_once(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+
+        super::load(&config).await?;
+        let seeded = config.registry.contexts().len();
+        assert_eq!(seeded, config.seed_contexts.len());
+        crate::db::delete_extension(&config, "702").await?;
+        crate::db::insert_context(
+            &config,
+            &Context {
+                display_name: "Lager".to_string(),
+                asterisk_name: "from_storage".to_string(),
+            },
+        )
+        .await?;
+
+        // the changes stay, and the config file is not copied again
+        super::load(&config).await?;
+        assert!(!config.registry.has_extension("702"));
+        assert_eq!(config.registry.contexts().len(), seeded + 1);
+        let ctx = config.registry.context("from_storage").unwrap();
+        assert_eq!(ctx.display_name, "Lager");
+        // call forwards keep borrowing the same context across refreshes
+        super::load(&config).await?;
+        assert!(std::ptr::eq(
+            ctx,
+            config.registry.context("from_storage").unwrap()
+        ));
+        assert_eq!(
+            Extension::create_from_name(&config, "702".to_string()).to_string(),
+            "702"
+        );
+        Ok(())
+    }
+}
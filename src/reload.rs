@@ -0,0 +1,212 @@
+//! Taking over changes to the config file without a restart
+//!
+//! The config file is read again on SIGHUP and whenever it changes. A new config that is not valid
+//! is logged and dropped, and the old one stays in place. Otherwise, the web and AGI servers pick it
+//! up from the [`watch`] channel for their next request, while requests that already started
+//! finish with the old one.
+//!
+//! The DB settings, the addresses to listen on and the snapshot file only apply on start.
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use tracing::{event, Level};
+use tracing_subscriber::{EnvFilter, Registry};
+
+use crate::{db::get_call_forwards_in_unknown_contexts, types::Config};
+
+/// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Changes which events are logged
+pub type LogFilterHandle = tracing_subscriber::reload::Handle<EnvFilter, Registry>;
+
+/// When the file at `path` was last changed, if it can be read
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Read the config file again on SIGHUP and whenever it changes. This never returns otherwise.
+pub async fn run_reloader(
+    config: watch::Sender<Arc<Config>>,
+    log_filter: LogFilterHandle,
+) -> Result<(), std::io::Error> {
+    let path = config.borrow().config_path.clone();
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut last_modified = modified_at(&path);
+    let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                event!(Level::INFO, "Reloading the config after SIGHUP.");
+            }
+            _ = interval.tick() => {
+                let modified = modified_at(&path);
+                if modified == last_modified {
+                    continue;
+                };
+                event!(Level::INFO, "Reloading the config, because {} changed.", path.display());
+            }
+        };
+        last_modified = modified_at(&path);
+        if let Err(e) = reload(&config, Some(&log_filter)).await {
+            event!(
+                Level::ERROR,
+                "Keeping the old config, because the new one is not valid: {e}"
+            );
+        };
+    }
+}
+
+/// Swap in the config file as it is now, unless it is not valid
+pub(crate) async fn reload(
+    config: &watch::Sender<Arc<Config>>,
+    log_filter: Option<&LogFilterHandle>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let current = config.borrow().clone();
+    let mut new = current.reread().await?;
+    warn_about_ignored_changes(&current, &new);
+
+    if let Some(handle) = log_filter {
+        // reread made sure that the filter is valid
+        handle.reload(EnvFilter::new(&new.log_filter))?;
+    };
+    // the running web server holds on to these, so they are changed in place
+    current
+        .rustls_config
+        .reload_from_config(new.rustls_config.get_inner());
+    new.rustls_config = current.rustls_config.clone();
+    current.ldap_config.replace(&new.ldap_config);
+    new.ldap_config = current.ldap_config.clone();
+
+    let new = Arc::new(new);
+    config.send_replace(new.clone());
+    event!(
+        Level::INFO,
+        "Reloaded the config from {}.",
+        new.config_path.display()
+    );
+    if let Err(e) = crate::registry::load(&new).await {
+        event!(
+            Level::WARN,
+            "Could not refresh the extensions and contexts: {e}"
+        );
+    };
+    report_unknown_contexts(&new).await;
+    Ok(())
+}
+
+/// Warn about changes in the config file that do not apply until the next start
+fn warn_about_ignored_changes(current: &Config, new: &Config) {
+    let restart_only = [
+        (
+            "web_bind_addr and web_bind_port",
+            current.web_bind_string != new.web_bind_string,
+        ),
+        (
+            "web_bind_port_tls",
+            current.web_bind_string_tls != new.web_bind_string_tls,
+        ),
+        (
+            "agi_bind_addr and agi_bind_port",
+            current.agi_bind_string != new.agi_bind_string,
+        ),
+        (
+            "metrics_bind_addr and metrics_bind_port",
+            current.metrics_bind_string != new.metrics_bind_string,
+        ),
+        (
+            "snapshot_file and snapshot_interval_seconds",
+            current.snapshot_file != new.snapshot_file
+                || current.snapshot_interval != new.snapshot_interval,
+        ),
+    ];
+    for (settings, changed) in restart_only {
+        if changed {
+            event!(
+                Level::WARN,
+                "{settings} changed in the config file. This only applies after a restart."
+            );
+        };
+    }
+    if current.seed_extensions != new.seed_extensions || current.seed_contexts != new.seed_contexts
+    {
+        event!(
+            Level::WARN,
+            "extensions and contexts changed in the config file. They are only copied into an empty DB, manage them on the admin page instead."
+        );
+    };
+}
+
+/// Log the call forwards that are set in contexts which are not known anymore, and the AGI peers
+/// allowed such contexts.
+///
+/// Asterisk never gets such a call forward in that context. It cannot be changed or restored in the
+/// web interface either, until the context is added again.
+pub(crate) async fn report_unknown_contexts(config: &Config) {
+    for peer in config.agi_peers.iter() {
+        for context in peer.unknown_contexts(&config.registry) {
+            event!(
+                Level::WARN,
+                "The AGI peer {} is allowed the context {context}, which is not known.",
+                peer.name
+            );
+        }
+    }
+    match get_call_forwards_in_unknown_contexts(config).await {
+        Ok(forwards) => {
+            for (fwd_id, context) in forwards {
+                event!(
+                    Level::WARN,
+                    "Call forward {fwd_id} is set in the context {context}, which is not known."
+                );
+            }
+        }
+        Err(e) => event!(
+            Level::WARN,
+            "Could not look for call forwards in unknown contexts: {e}"
+        ),
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use sqlx::PgPool;
+    use tokio::sync::watch;
+
+    use crate::types::Config;
+
+    #[sqlx::test]
+    async fn keep_old_config_if_invalid(pool: PgPool) -> Result<(), Box<dyn std::error::Error>> {
+        let mut config = Config::create().await?;
+        config.pool = pool.into();
+        let original = std::fs::read_to_string(&config.config_path)?;
+        let path = std::env::temp_dir().join(format!("asterconf-{}.yaml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, &original)?;
+        config.config_path = path.clone();
+        let (tx, rx) = watch::channel(Arc::new(config));
+        let before = rx.borrow().clone();
+
+        std::fs::write(&path, "extensions: 702")?;
+        assert!(super::reload(&tx, None).await.is_err());
+        assert!(Arc::ptr_eq(&before, &rx.borrow()));
+
+        std::fs::write(&path, format!("{original}\nmax_forward_depth: 2\n"))?;
+        super::reload(&tx, None).await?;
+        let after = rx.borrow().clone();
+        assert_eq!(after.max_forward_depth, 2);
+        // what cannot change while running is kept
+        assert!(Arc::ptr_eq(&before.registry, &after.registry));
+        assert!(Arc::ptr_eq(&before.forward_cache, &after.forward_cache));
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
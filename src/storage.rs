@@ -0,0 +1,330 @@
+//! Running queries on postgres or on a single SQLite file, whichever the config asks for
+//!
+//! Both understand most of the SQL in [`crate::db`] and bind `$1`, `$2`, ... the same way.
+//! Where they do not, [`Query::sqlite`] gives the SQLite version of a statement.
+//! Rows are read with [`DbRow::get`] for any type that both can decode.
+use chrono::{DateTime, NaiveTime, Utc};
+use sqlx::{
+    migrate::MigrateError,
+    postgres::{PgArguments, PgRow},
+    sqlite::{SqliteArguments, SqliteRow},
+    ColumnIndex, Decode, PgConnection, PgPool, Postgres, Row, Sqlite, SqliteConnection, SqlitePool,
+    Transaction, Type,
+};
+
+/// The pool of connections to the configured DB
+#[derive(Debug, Clone)]
+pub enum DbPool {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+}
+impl From<PgPool> for DbPool {
+    fn from(pool: PgPool) -> Self {
+        Self::Postgres(pool)
+    }
+}
+impl From<SqlitePool> for DbPool {
+    fn from(pool: SqlitePool) -> Self {
+        Self::Sqlite(pool)
+    }
+}
+impl DbPool {
+    pub(crate) async fn begin(&self) -> Result<DbTransaction, sqlx::Error> {
+        Ok(match self {
+            Self::Postgres(pool) => DbTransaction::Postgres(Box::new(pool.begin().await?)),
+            Self::Sqlite(pool) => DbTransaction::Sqlite(pool.begin().await?),
+        })
+    }
+
+    /// Bring the schema up to date, using the migrations written for this kind of DB
+    pub async fn run_migrations(&self) -> Result<(), MigrateError> {
+        match self {
+            Self::Postgres(pool) => sqlx::migrate!().run(pool).await,
+            Self::Sqlite(pool) => sqlx::migrate!("./migrations_sqlite").run(pool).await,
+        }
+    }
+}
+
+/// A transaction on the configured DB. It is rolled back when dropped without a commit.
+pub(crate) enum DbTransaction {
+    Postgres(Box<Transaction<'static, Postgres>>),
+    Sqlite(Transaction<'static, Sqlite>),
+}
+impl DbTransaction {
+    pub(crate) async fn commit(self) -> Result<(), sqlx::Error> {
+        match self {
+            Self::Postgres(tx) => tx.commit().await,
+            Self::Sqlite(tx) => tx.commit().await,
+        }
+    }
+
+    pub(crate) async fn rollback(self) -> Result<(), sqlx::Error> {
+        match self {
+            Self::Postgres(tx) => tx.rollback().await,
+            Self::Sqlite(tx) => tx.rollback().await,
+        }
+    }
+}
+
+/// Where a [`Query`] runs: on any connection of a pool or inside a transaction
+pub(crate) enum Executor<'e> {
+    PostgresPool(&'e PgPool),
+    SqlitePool(&'e SqlitePool),
+    Postgres(&'e mut PgConnection),
+    Sqlite(&'e mut SqliteConnection),
+}
+impl<'e> From<&'e DbPool> for Executor<'e> {
+    fn from(pool: &'e DbPool) -> Self {
+        match pool {
+            DbPool::Postgres(pool) => Self::PostgresPool(pool),
+            DbPool::Sqlite(pool) => Self::SqlitePool(pool),
+        }
+    }
+}
+impl<'e> From<&'e mut DbTransaction> for Executor<'e> {
+    fn from(tx: &'e mut DbTransaction) -> Self {
+        match tx {
+            DbTransaction::Postgres(tx) => Self::Postgres(tx),
+            DbTransaction::Sqlite(tx) => Self::Sqlite(tx),
+        }
+    }
+}
+
+/// A value bound to a [`Query`]
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Bool(bool),
+    SmallInt(i16),
+    Int(Option<i32>),
+    BigInt(i64),
+    Text(Option<String>),
+    Time(NaiveTime),
+    Timestamp(Option<DateTime<Utc>>),
+}
+impl From<bool> for Value {
+    fn from(x: bool) -> Self {
+        Self::Bool(x)
+    }
+}
+impl From<i16> for Value {
+    fn from(x: i16) -> Self {
+        Self::SmallInt(x)
+    }
+}
+impl From<i32> for Value {
+    fn from(x: i32) -> Self {
+        Self::Int(Some(x))
+    }
+}
+impl From<Option<i32>> for Value {
+    fn from(x: Option<i32>) -> Self {
+        Self::Int(x)
+    }
+}
+impl From<i64> for Value {
+    fn from(x: i64) -> Self {
+        Self::BigInt(x)
+    }
+}
+impl From<&str> for Value {
+    fn from(x: &str) -> Self {
+        Self::Text(Some(x.to_string()))
+    }
+}
+impl From<&String> for Value {
+    fn from(x: &String) -> Self {
+        Self::Text(Some(x.clone()))
+    }
+}
+impl From<String> for Value {
+    fn from(x: String) -> Self {
+        Self::Text(Some(x))
+    }
+}
+impl From<Option<&str>> for Value {
+    fn from(x: Option<&str>) -> Self {
+        Self::Text(x.map(str::to_string))
+    }
+}
+impl From<&Option<String>> for Value {
+    fn from(x: &Option<String>) -> Self {
+        Self::Text(x.clone())
+    }
+}
+impl From<Option<String>> for Value {
+    fn from(x: Option<String>) -> Self {
+        Self::Text(x)
+    }
+}
+impl From<NaiveTime> for Value {
+    fn from(x: NaiveTime) -> Self {
+        Self::Time(x)
+    }
+}
+impl From<DateTime<Utc>> for Value {
+    fn from(x: DateTime<Utc>) -> Self {
+        Self::Timestamp(Some(x))
+    }
+}
+impl From<Option<DateTime<Utc>>> for Value {
+    fn from(x: Option<DateTime<Utc>>) -> Self {
+        Self::Timestamp(x)
+    }
+}
+
+/// Bind all `values` to the sqlx query `query`
+macro_rules! bind_values {
+    ($query:expr, $values:expr) => {
+        $values.iter().fold($query, |query, value| match value {
+            Value::Bool(x) => query.bind(*x),
+            Value::SmallInt(x) => query.bind(*x),
+            Value::Int(x) => query.bind(*x),
+            Value::BigInt(x) => query.bind(*x),
+            Value::Text(x) => query.bind(x.clone()),
+            Value::Time(x) => query.bind(*x),
+            Value::Timestamp(x) => query.bind(*x),
+        })
+    };
+}
+
+/// A statement with its bound values, that runs on either DB
+#[derive(Debug, Clone)]
+pub(crate) struct Query<'q> {
+    sql: &'q str,
+    sqlite_sql: Option<&'q str>,
+    values: Vec<Value>,
+}
+
+/// Prepare the statement `sql`, which both DBs understand unless [`Query::sqlite`] is given
+pub(crate) fn query(sql: &str) -> Query<'_> {
+    Query {
+        sql,
+        sqlite_sql: None,
+        values: vec![],
+    }
+}
+
+impl<'q> Query<'q> {
+    /// Run `sql` instead on SQLite, binding the same values
+    pub(crate) fn sqlite(mut self, sql: &'q str) -> Self {
+        self.sqlite_sql = Some(sql);
+        self
+    }
+
+    /// Bind `value` to the next parameter
+    pub(crate) fn bind(mut self, value: impl Into<Value>) -> Self {
+        self.values.push(value.into());
+        self
+    }
+
+    fn postgres_query(&self) -> sqlx::query::Query<'_, Postgres, PgArguments> {
+        bind_values!(sqlx::query(self.sql), self.values)
+    }
+
+    fn sqlite_query(&self) -> sqlx::query::Query<'_, Sqlite, SqliteArguments<'_>> {
+        bind_values!(
+            sqlx::query(self.sqlite_sql.unwrap_or(self.sql)),
+            self.values
+        )
+    }
+
+    /// Run the statement and return the number of affected rows
+    pub(crate) async fn execute<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<u64, sqlx::Error> {
+        Ok(match executor.into() {
+            Executor::PostgresPool(pool) => {
+                self.postgres_query().execute(pool).await?.rows_affected()
+            }
+            Executor::Postgres(conn) => self.postgres_query().execute(conn).await?.rows_affected(),
+            Executor::SqlitePool(pool) => self.sqlite_query().execute(pool).await?.rows_affected(),
+            Executor::Sqlite(conn) => self.sqlite_query().execute(conn).await?.rows_affected(),
+        })
+    }
+
+    pub(crate) async fn fetch_all<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Vec<DbRow>, sqlx::Error> {
+        Ok(match executor.into() {
+            Executor::PostgresPool(pool) => wrap(self.postgres_query().fetch_all(pool).await?),
+            Executor::Postgres(conn) => wrap(self.postgres_query().fetch_all(conn).await?),
+            Executor::SqlitePool(pool) => wrap(self.sqlite_query().fetch_all(pool).await?),
+            Executor::Sqlite(conn) => wrap(self.sqlite_query().fetch_all(conn).await?),
+        })
+    }
+
+    pub(crate) async fn fetch_optional<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<Option<DbRow>, sqlx::Error> {
+        Ok(match executor.into() {
+            Executor::PostgresPool(pool) => self
+                .postgres_query()
+                .fetch_optional(pool)
+                .await?
+                .map(Into::into),
+            Executor::Postgres(conn) => self
+                .postgres_query()
+                .fetch_optional(conn)
+                .await?
+                .map(Into::into),
+            Executor::SqlitePool(pool) => self
+                .sqlite_query()
+                .fetch_optional(pool)
+                .await?
+                .map(Into::into),
+            Executor::Sqlite(conn) => self
+                .sqlite_query()
+                .fetch_optional(conn)
+                .await?
+                .map(Into::into),
+        })
+    }
+
+    pub(crate) async fn fetch_one<'e>(
+        self,
+        executor: impl Into<Executor<'e>>,
+    ) -> Result<DbRow, sqlx::Error> {
+        self.fetch_optional(executor)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+}
+
+fn wrap<R: Into<DbRow>>(rows: Vec<R>) -> Vec<DbRow> {
+    rows.into_iter().map(Into::into).collect()
+}
+
+/// A row read from either DB
+pub(crate) enum DbRow {
+    Postgres(PgRow),
+    Sqlite(SqliteRow),
+}
+impl From<PgRow> for DbRow {
+    fn from(row: PgRow) -> Self {
+        Self::Postgres(row)
+    }
+}
+impl From<SqliteRow> for DbRow {
+    fn from(row: SqliteRow) -> Self {
+        Self::Sqlite(row)
+    }
+}
+impl DbRow {
+    /// The value of the column `index`, like [`sqlx::Row::get`]
+    ///
+    /// # Panics
+    /// if the column does not exist or its value cannot be decoded into `T`
+    pub(crate) fn get<'r, T, I>(&'r self, index: I) -> T
+    where
+        T: Decode<'r, Postgres> + Type<Postgres> + Decode<'r, Sqlite> + Type<Sqlite>,
+        I: ColumnIndex<PgRow> + ColumnIndex<SqliteRow>,
+    {
+        match self {
+            Self::Postgres(row) => row.get(index),
+            Self::Sqlite(row) => row.get(index),
+        }
+    }
+}
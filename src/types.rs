@@ -1,16 +1,20 @@
+use std::fmt::Display;
 use std::fs::File;
-use std::path::Path;
-use std::{collections::HashMap, fmt::Display};
+use std::path::{Path, PathBuf};
 
 use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
 /// Structs used by the other components
-use serde::Deserialize;
-use sqlx::PgPool;
+use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
+use crate::agi_server::{AgiPeer, DigestAlgorithm};
+use crate::cache::CachedCallForward;
 use crate::db::DBError;
+use crate::storage::DbPool;
 
-#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Extension {
     // we may call-forward to external extensions that are not known by name statically
     // in this case, name will be empty
@@ -18,6 +22,9 @@ pub struct Extension {
     // Note: this is usually a number code
     // but we have no guarantee of this, so we make it a raw String instead
     pub(crate) extension: String,
+    // the LDAP uid of the user owning this extension, who may manage its PIN
+    #[serde(default)]
+    pub(crate) owner: Option<String>,
 }
 impl Display for Extension {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -32,19 +39,44 @@ impl Display for Extension {
     }
 }
 impl Extension {
+    /// A known extension with the human readable `name`
+    pub fn new(extension: String, name: String, owner: Option<String>) -> Extension {
+        Extension {
+            name: Some(name),
+            extension,
+            owner,
+        }
+    }
+
+    /// Can `extension` be used as a destination?
+    ///
+    /// Destinations are passed to `Dial()` in asterisk, where `&`, `,`, `/` and the like would add
+    /// further targets or options, so only digits, letters, `*`, `#`, `+` and `-` are allowed.
+    pub(crate) fn is_dialable(extension: &str) -> bool {
+        !extension.is_empty()
+            && extension
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || matches!(x, '*' | '#' | '+' | '-'))
+    }
+
     pub fn create_from_name(config: &Config, extension: String) -> Extension {
-        let exten = config.extensions.get(&extension);
-        match exten {
+        match config.registry.extension(&extension) {
             None => Extension {
                 name: None,
                 extension,
+                owner: None,
             },
-            Some(x) => x.clone(),
+            Some(x) => x,
         }
     }
+
+    /// The human readable name, if this is a known extension
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct Context {
     pub(crate) display_name: String,
     pub(crate) asterisk_name: String,
@@ -58,15 +90,482 @@ impl Context {
     /// get the correct display name from the config
     /// return the object with display_name and asterisk_name set
     ///
-    /// Returns None, if the context does not exist in the config
-    pub fn create_from_name<S: AsRef<str>>(config: &Config, asterisk_name: S) -> Option<&Context> {
-        config.contexts.get(asterisk_name.as_ref())
+    /// Returns None, if the context is not known
+    pub fn create_from_name<S: AsRef<str>>(
+        config: &Config,
+        asterisk_name: S,
+    ) -> Option<&'static Context> {
+        config.registry.context(asterisk_name.as_ref())
+    }
+}
+
+/// All weekdays, in the order they are displayed in the GUI
+pub const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// Short (german) name of a weekday, as displayed in the GUI
+pub fn weekday_short_name(day: &Weekday) -> &'static str {
+    match day {
+        Weekday::Mon => "Mo",
+        Weekday::Tue => "Di",
+        Weekday::Wed => "Mi",
+        Weekday::Thu => "Do",
+        Weekday::Fri => "Fr",
+        Weekday::Sat => "Sa",
+        Weekday::Sun => "So",
+    }
+}
+
+/// A weekly recurring window in which a call forward is active.
+///
+/// The window is active on all days from `first_day` to `last_day` (inclusive, wrapping around
+/// the end of the week if `last_day` comes before `first_day`), from `start` (inclusive) to `end`
+/// (exclusive).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScheduleWindow {
+    pub(crate) first_day: Weekday,
+    pub(crate) last_day: Weekday,
+    pub(crate) start: NaiveTime,
+    pub(crate) end: NaiveTime,
+}
+impl Default for ScheduleWindow {
+    /// Regular office hours
+    fn default() -> Self {
+        ScheduleWindow {
+            first_day: Weekday::Mon,
+            last_day: Weekday::Fri,
+            start: NaiveTime::from_hms_opt(8, 0, 0).expect("static time"),
+            end: NaiveTime::from_hms_opt(17, 0, 0).expect("static time"),
+        }
+    }
+}
+impl Display for ScheduleWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.first_day == self.last_day {
+            write!(f, "{}", weekday_short_name(&self.first_day))?;
+        } else {
+            write!(
+                f,
+                "{}-{}",
+                weekday_short_name(&self.first_day),
+                weekday_short_name(&self.last_day)
+            )?;
+        };
+        write!(
+            f,
+            " {}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+impl ScheduleWindow {
+    /// Create a new window. Returns None if the window does not contain any time
+    pub fn new(
+        first_day: Weekday,
+        last_day: Weekday,
+        start: NaiveTime,
+        end: NaiveTime,
+    ) -> Option<ScheduleWindow> {
+        if start >= end {
+            return None;
+        };
+        Some(ScheduleWindow {
+            first_day,
+            last_day,
+            start,
+            end,
+        })
+    }
+
+    /// Days from monday of first_day (used in the DB and in the GUI)
+    pub fn first_day_index(&self) -> u32 {
+        self.first_day.num_days_from_monday()
+    }
+
+    /// Days from monday of last_day (used in the DB and in the GUI)
+    pub fn last_day_index(&self) -> u32 {
+        self.last_day.num_days_from_monday()
+    }
+
+    fn contains_day(&self, day: Weekday) -> bool {
+        let first = self.first_day.num_days_from_monday();
+        let last = self.last_day.num_days_from_monday();
+        let day = day.num_days_from_monday();
+        if first <= last {
+            first <= day && day <= last
+        } else {
+            day >= first || day <= last
+        }
+    }
+
+    /// Is this window active on `day` at `time`?
+    pub fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        self.contains_day(day) && self.start <= time && time < self.end
+    }
+}
+
+/// The schedule of a call forward: a set of windows, interpreted in a timezone.
+///
+/// A call forward with a schedule is only applied while at least one of its windows is active.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub(crate) timezone: Tz,
+    pub(crate) windows: Vec<ScheduleWindow>,
+}
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let windows = self
+            .windows
+            .iter()
+            .map(|w| w.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(f, "{windows} ({})", self.timezone)
+    }
+}
+impl Schedule {
+    /// Is any window of this schedule active at `instant`?
+    pub fn is_active_at<T: TimeZone>(&self, instant: &DateTime<T>) -> bool {
+        let local = instant.with_timezone(&self.timezone);
+        self.windows
+            .iter()
+            .any(|w| w.contains(local.weekday(), local.time()))
+    }
+}
+
+/// When a call forward is applied
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ForwardCondition {
+    /// Forward every call, without ringing the original destination
+    Always,
+    /// Forward when the original destination is busy
+    Busy,
+    /// Forward when the original destination did not answer within this many seconds
+    NoAnswer(u32),
+    /// Forward when the original destination cannot be reached (e.g. not registered)
+    Unavailable,
+}
+impl Display for ForwardCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Always => write!(f, "immer"),
+            Self::Busy => write!(f, "bei besetzt"),
+            Self::NoAnswer(x) => write!(f, "nach {x} s ohne Antwort"),
+            Self::Unavailable => write!(f, "wenn nicht erreichbar"),
+        }
+    }
+}
+impl ForwardCondition {
+    /// The name of this condition as stored in the DB and used in the GUI
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::Busy => "busy",
+            Self::NoAnswer(_) => "no_answer",
+            Self::Unavailable => "unavailable",
+        }
+    }
+
+    /// The seconds to wait for an answer, if this is a no-answer condition
+    pub fn no_answer_timeout(&self) -> Option<u32> {
+        match self {
+            Self::NoAnswer(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    /// Create the condition from its kind and (for no-answer conditions) its timeout.
+    ///
+    /// Returns None if the kind is unknown or a no-answer condition is missing its timeout.
+    pub fn from_kind(kind: &str, no_answer_timeout: Option<u32>) -> Option<ForwardCondition> {
+        match kind {
+            "always" => Some(Self::Always),
+            "busy" => Some(Self::Busy),
+            "no_answer" => no_answer_timeout.map(Self::NoAnswer),
+            "unavailable" => Some(Self::Unavailable),
+            _ => None,
+        }
+    }
+
+    /// The condition that matches the DIALSTATUS asterisk sets after Dial().
+    ///
+    /// Returns None for dial statuses which should never cause a call forward (e.g. ANSWER).
+    pub fn from_dial_status(dial_status: &str) -> Option<ForwardCondition> {
+        match dial_status {
+            "BUSY" => Some(Self::Busy),
+            // the timeout is irrelevant when matching against forwards
+            "NOANSWER" => Some(Self::NoAnswer(0)),
+            "CHANUNAVAIL" | "CONGESTION" => Some(Self::Unavailable),
+            _ => None,
+        }
+    }
+
+    /// Do `self` and `other` apply in the same situation? (ignoring the no-answer timeout)
+    pub fn same_kind(&self, other: &ForwardCondition) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// A caller id a call has to come from for a call forward to apply
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CallerIdPattern {
+    /// Only this exact caller id
+    Exact(String),
+    /// Every caller id starting with this prefix
+    Prefix(String),
+}
+impl Display for CallerIdPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Exact(x) => write!(f, "{x}"),
+            Self::Prefix(x) => write!(f, "{x}*"),
+        }
+    }
+}
+impl CallerIdPattern {
+    /// Parse a pattern as entered by the user: a trailing `*` makes it a prefix.
+    ///
+    /// Returns None if the pattern is empty or contains a `*` anywhere else.
+    pub fn parse(pattern: &str) -> Option<CallerIdPattern> {
+        let pattern = pattern.trim();
+        let (inner, is_prefix) = match pattern.strip_suffix('*') {
+            Some(x) => (x, true),
+            None => (pattern, false),
+        };
+        if inner.is_empty() || inner.contains('*') {
+            return None;
+        };
+        if is_prefix {
+            Some(Self::Prefix(inner.to_string()))
+        } else {
+            Some(Self::Exact(inner.to_string()))
+        }
+    }
+
+    /// The pattern without the trailing `*` of prefixes
+    pub fn pattern(&self) -> &str {
+        match self {
+            Self::Exact(x) | Self::Prefix(x) => x,
+        }
+    }
+
+    pub fn is_prefix(&self) -> bool {
+        matches!(self, Self::Prefix(_))
+    }
+
+    /// How specifically this pattern matches `caller_id`.
+    ///
+    /// Exact matches beat every prefix, longer prefixes beat shorter ones.
+    /// Returns None if the pattern does not match.
+    pub fn specificity(&self, caller_id: &str) -> Option<usize> {
+        match self {
+            Self::Exact(x) => (x == caller_id).then_some(usize::MAX),
+            Self::Prefix(x) => caller_id.starts_with(x.as_str()).then_some(x.len()),
+        }
+    }
+}
+
+/// The PIN protecting remote access to the call forwards of an extension
+#[derive(Clone, PartialEq)]
+pub struct ExtensionPin {
+    pub(crate) extension: String,
+    /// argon2 hash in PHC string format
+    pub(crate) pin_hash: String,
+    /// wrong PINs entered since the last correct one
+    pub(crate) failed_attempts: u32,
+    /// the PIN is not accepted until then
+    pub(crate) locked_until: Option<DateTime<Utc>>,
+}
+impl std::fmt::Debug for ExtensionPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ExtensionPin")
+            .field("extension", &self.extension)
+            .field("pin_hash", &"[redacted]")
+            .field("failed_attempts", &self.failed_attempts)
+            .field("locked_until", &self.locked_until)
+            .finish()
+    }
+}
+impl ExtensionPin {
+    /// Is the PIN locked at `instant` after too many wrong attempts?
+    pub fn is_locked_at<T: TimeZone>(&self, instant: &DateTime<T>) -> bool {
+        self.locked_until.is_some_and(|x| x > *instant)
+    }
+}
+
+/// How times are shown in the web interface
+const DISPLAY_TIME_FORMAT: &str = "%d.%m.%Y %H:%M:%S";
+/// how the validity of call forwards is shown to users
+const VALIDITY_DISPLAY_FORMAT: &str = "%d.%m.%Y %H:%M";
+/// the format of `<input type="datetime-local">`
+pub(crate) const VALIDITY_INPUT_FORMAT: &str = "%Y-%m-%dT%H:%M";
+
+/// One call forward lookup made by asterisk
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallLookup {
+    pub(crate) looked_up_at: DateTime<Utc>,
+    /// None if asterisk did not know the caller
+    pub(crate) caller_id: Option<String>,
+    pub(crate) dialed_extension: String,
+    pub(crate) context: String,
+    /// the extensions the call went to. Empty if the call was not put through again
+    pub(crate) destinations: Vec<String>,
+    /// the call forward applied to the dialed extension. None if the call was not forwarded
+    pub(crate) fwd_id: Option<i32>,
+}
+impl CallLookup {
+    /// When the lookup happened, as shown to users in `timezone`
+    pub fn looked_up_at_in(&self, timezone: &Tz) -> String {
+        self.looked_up_at
+            .with_timezone(timezone)
+            .format(DISPLAY_TIME_FORMAT)
+            .to_string()
+    }
+}
+
+/// A deleted call forward, which can be restored until it is removed for good
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeletedCallForward<'a> {
+    pub(crate) forward: CallForward<'a, HasId>,
+    pub(crate) deleted_at: DateTime<Utc>,
+}
+impl DeletedCallForward<'_> {
+    /// When the call forward was deleted, as shown to users in `timezone`
+    pub fn deleted_at_in(&self, timezone: &Tz) -> String {
+        self.deleted_at
+            .with_timezone(timezone)
+            .format(DISPLAY_TIME_FORMAT)
+            .to_string()
+    }
+}
+
+/// How often a call forward was applied
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ForwardUsage {
+    pub(crate) hits: i64,
+    /// None if the call forward was never applied
+    pub(crate) last_used: Option<DateTime<Utc>>,
+}
+impl ForwardUsage {
+    /// When the call forward was last applied, as shown to users in `timezone`
+    pub fn last_used_in(&self, timezone: &Tz) -> String {
+        match self.last_used {
+            None => "nie".to_string(),
+            Some(x) => x
+                .with_timezone(timezone)
+                .format(DISPLAY_TIME_FORMAT)
+                .to_string(),
+        }
+    }
+}
+
+/// A change to the stored call forwards, as made by an import
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ForwardChange<'a> {
+    Add(CallForward<'a, NoId>),
+    /// `after` has the version of `before`, so that other changes in the meantime are noticed
+    Update {
+        before: Box<CallForward<'a, HasId>>,
+        after: Box<CallForward<'a, HasId>>,
+    },
+    Remove(CallForward<'a, HasId>),
+}
+
+/// The actor recorded for changes asterconf makes on its own, like deleting expired call forwards
+pub const SYSTEM_ACTOR: &str = "asterconf";
+
+/// What happened to a call forward, as recorded in the audit log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+    SwitchOn,
+    SwitchOff,
+    /// deleted after it expired
+    Purge,
+    /// brought back after it was deleted
+    Restore,
+    /// removed for good some time after it was deleted
+    PurgeDeleted,
+}
+impl AuditAction {
+    /// The name of this action as stored in the DB
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Create => "create",
+            Self::Update => "update",
+            Self::Delete => "delete",
+            Self::SwitchOn => "switch_on",
+            Self::SwitchOff => "switch_off",
+            Self::Purge => "purge",
+            Self::Restore => "restore",
+            Self::PurgeDeleted => "purge_deleted",
+        }
+    }
+
+    pub fn from_kind(kind: &str) -> Option<Self> {
+        match kind {
+            "create" => Some(Self::Create),
+            "update" => Some(Self::Update),
+            "delete" => Some(Self::Delete),
+            "switch_on" => Some(Self::SwitchOn),
+            "switch_off" => Some(Self::SwitchOff),
+            "purge" => Some(Self::Purge),
+            "restore" => Some(Self::Restore),
+            "purge_deleted" => Some(Self::PurgeDeleted),
+            _ => None,
+        }
+    }
+}
+impl Display for AuditAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Create => write!(f, "angelegt"),
+            Self::Update => write!(f, "geändert"),
+            Self::Delete => write!(f, "gelöscht"),
+            Self::SwitchOn => write!(f, "eingeschaltet"),
+            Self::SwitchOff => write!(f, "ausgeschaltet"),
+            Self::Purge => write!(f, "abgelaufen und gelöscht"),
+            Self::Restore => write!(f, "wiederhergestellt"),
+            Self::PurgeDeleted => write!(f, "endgültig gelöscht"),
+        }
+    }
+}
+
+/// One change to a call forward
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub(crate) changed_at: DateTime<Utc>,
+    pub(crate) actor: String,
+    pub(crate) action: AuditAction,
+    pub(crate) fwd_id: i32,
+    /// None if the call forward was just created or restored
+    pub(crate) before: Option<CachedCallForward>,
+    /// None if the call forward was deleted
+    pub(crate) after: Option<CachedCallForward>,
+}
+impl AuditEntry {
+    /// When the change happened, as shown to users in `timezone`
+    pub fn changed_at_in(&self, timezone: &Tz) -> String {
+        self.changed_at
+            .with_timezone(timezone)
+            .format(DISPLAY_TIME_FORMAT)
+            .to_string()
     }
 }
 
 pub trait IdState {}
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub(crate) struct NoId {}
 impl IdState for NoId {}
 
@@ -74,11 +573,6 @@ impl IdState for NoId {}
 pub(crate) struct HasId {
     id: i32,
 }
-impl HasId {
-    pub fn new(x: i32) -> Self {
-        HasId { id: x }
-    }
-}
 impl std::fmt::Display for HasId {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(f, "{}", self.id)
@@ -96,14 +590,197 @@ impl From<&HasId> for i32 {
 }
 impl IdState for HasId {}
 
+/// Where an instant lies relative to the validity of a call forward
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Validity {
+    /// before valid_from
+    Upcoming,
+    Valid,
+    /// at or after valid_until
+    Expired,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct CallForward<'a, S: IdState> {
     pub(crate) fwd_id: S,
     pub(crate) from: Extension,
-    pub(crate) to: Extension,
+    /// all of these ring in parallel. Never empty
+    pub(crate) to: Vec<Extension>,
     pub(crate) in_contexts: Vec<&'a Context>,
+    /// None if the call forward is always active
+    pub(crate) schedule: Option<Schedule>,
+    pub(crate) condition: ForwardCondition,
+    /// empty if the call forward applies to every caller
+    pub(crate) caller_ids: Vec<CallerIdPattern>,
+    /// switched off call forwards are kept, but never applied
+    pub(crate) active: bool,
+    /// not applied before this. None if the call forward is valid right away
+    pub(crate) valid_from: Option<DateTime<Utc>>,
+    /// not applied from this on. None if the call forward never expires
+    pub(crate) valid_until: Option<DateTime<Utc>>,
+    /// increased with every change, so that changes to an outdated state are noticed
+    pub(crate) version: i32,
 }
 impl<'a, S: IdState> CallForward<'a, S> {
+    /// Should this call forward be applied at `instant`?
+    ///
+    /// This is never the case while it is switched off or outside its validity.
+    pub fn is_active_at<T: TimeZone>(&self, instant: &DateTime<T>) -> bool {
+        if !self.active || self.validity_at(instant) != Validity::Valid {
+            return false;
+        };
+        match &self.schedule {
+            None => true,
+            Some(schedule) => schedule.is_active_at(instant),
+        }
+    }
+
+    /// Should this call forward be applied right now?
+    pub fn is_active_now(&self) -> bool {
+        self.is_active_at(&Utc::now())
+    }
+
+    /// Is `instant` before, inside or after the validity of this call forward?
+    pub fn validity_at<T: TimeZone>(&self, instant: &DateTime<T>) -> Validity {
+        let instant = instant.with_timezone(&Utc);
+        if self.valid_from.is_some_and(|from| instant < from) {
+            Validity::Upcoming
+        } else if self.valid_until.is_some_and(|until| instant >= until) {
+            Validity::Expired
+        } else {
+            Validity::Valid
+        }
+    }
+
+    /// Will this call forward never be applied again?
+    pub fn is_expired(&self) -> bool {
+        self.validity_at(&Utc::now()) == Validity::Expired
+    }
+
+    /// Could `self` and `other` both be valid at the same instant?
+    pub fn validity_overlaps<T: IdState>(&self, other: &CallForward<T>) -> bool {
+        let starts_before_other_ends = match (self.valid_from, other.valid_until) {
+            (Some(from), Some(until)) => from < until,
+            _ => true,
+        };
+        let other_starts_before_end = match (other.valid_from, self.valid_until) {
+            (Some(from), Some(until)) => from < until,
+            _ => true,
+        };
+        starts_before_other_ends && other_starts_before_end
+    }
+
+    /// The validity as shown to users in `timezone`
+    pub fn validity_in(&self, timezone: &Tz) -> String {
+        let show = |x: DateTime<Utc>| {
+            x.with_timezone(timezone)
+                .format(VALIDITY_DISPLAY_FORMAT)
+                .to_string()
+        };
+        match (self.valid_from, self.valid_until) {
+            (None, None) => "immer".to_string(),
+            (Some(from), None) => format!("ab {}", show(from)),
+            (None, Some(until)) => format!("bis {}", show(until)),
+            (Some(from), Some(until)) => format!("{} - {}", show(from), show(until)),
+        }
+    }
+
+    /// valid_from as the value of an `<input type="datetime-local">` in `timezone`
+    pub fn valid_from_input(&self, timezone: &Tz) -> String {
+        self.valid_from
+            .map(|x| {
+                x.with_timezone(timezone)
+                    .format(VALIDITY_INPUT_FORMAT)
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    /// valid_until as the value of an `<input type="datetime-local">` in `timezone`
+    pub fn valid_until_input(&self, timezone: &Tz) -> String {
+        self.valid_until
+            .map(|x| {
+                x.with_timezone(timezone)
+                    .format(VALIDITY_INPUT_FORMAT)
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The extensions this call forward rings
+    pub fn destination_extensions(&self) -> Vec<&str> {
+        self.to.iter().map(|x| x.extension.as_str()).collect()
+    }
+
+    pub fn set_schedule(self, schedule: Option<Schedule>) -> Self {
+        CallForward { schedule, ..self }
+    }
+
+    pub fn set_condition(self, condition: ForwardCondition) -> Self {
+        CallForward { condition, ..self }
+    }
+
+    pub fn set_caller_ids(self, caller_ids: Vec<CallerIdPattern>) -> Self {
+        CallForward { caller_ids, ..self }
+    }
+
+    pub fn set_active(self, active: bool) -> Self {
+        CallForward { active, ..self }
+    }
+
+    pub fn set_version(self, version: i32) -> Self {
+        CallForward { version, ..self }
+    }
+
+    pub fn set_validity(
+        self,
+        valid_from: Option<DateTime<Utc>>,
+        valid_until: Option<DateTime<Utc>>,
+    ) -> Self {
+        CallForward {
+            valid_from,
+            valid_until,
+            ..self
+        }
+    }
+
+    /// How specifically this call forward applies to calls from `caller_id`.
+    ///
+    /// Call forwards for every caller have the lowest specificity (0), so that any matching
+    /// pattern takes precedence over them.
+    /// Returns None if this call forward does not apply to `caller_id`.
+    pub fn caller_id_specificity(&self, caller_id: Option<&str>) -> Option<usize> {
+        if self.caller_ids.is_empty() {
+            return Some(0);
+        };
+        let caller_id = caller_id?;
+        self.caller_ids
+            .iter()
+            .filter_map(|p| p.specificity(caller_id))
+            // any matching pattern has to beat the call forwards for every caller
+            .map(|x| x.saturating_add(1))
+            .max()
+    }
+
+    /// The caller id patterns as entered by the user, separated by commas
+    pub fn caller_ids_string(&self) -> String {
+        self.caller_ids
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Could `self` and `other` apply to the same call with the same precedence?
+    ///
+    /// This is the case if both apply to every caller or they share a pattern.
+    pub fn caller_ids_overlap<T: IdState>(&self, other: &CallForward<T>) -> bool {
+        if self.caller_ids.is_empty() || other.caller_ids.is_empty() {
+            return self.caller_ids.is_empty() && other.caller_ids.is_empty();
+        };
+        self.caller_ids.iter().any(|p| other.caller_ids.contains(p))
+    }
+
     pub fn intersecting_contexts<'b, T: IdState>(
         &'a self,
         other: &'b CallForward<T>,
@@ -117,26 +794,49 @@ impl<'a, S: IdState> CallForward<'a, S> {
     }
 }
 impl<'a> CallForward<'a, HasId> {
+    /// A stored call forward.
+    ///
+    /// Its destinations are taken as they are, so that call forwards stored before they were
+    /// checked can still be read.
     pub fn new(
         config: &'a Config,
         from: String,
-        to: String,
+        to: Vec<String>,
         in_contexts: Vec<String>,
         fwd_id: i32,
     ) -> Result<CallForward<'a, HasId>, DBError> {
-        let without_id = CallForward::<NoId>::new(config, from, to, in_contexts)?;
+        let without_id = CallForward::<NoId>::build(config, from, to, in_contexts)?;
         Ok(without_id.set_id(fwd_id))
     }
 }
 impl<'a> CallForward<'a, NoId> {
+    /// A new call forward, whose destinations have to be dialable (see [`Extension::is_dialable`])
     pub fn new(
         config: &'a Config,
         from: String,
-        to: String,
+        to: Vec<String>,
+        in_contexts: Vec<String>,
+    ) -> Result<CallForward<'a, NoId>, DBError> {
+        if let Some(x) = to.iter().find(|x| !Extension::is_dialable(x)) {
+            return Err(DBError::InvalidDestination(x.clone()));
+        };
+        Self::build(config, from, to, in_contexts)
+    }
+
+    fn build(
+        config: &'a Config,
+        from: String,
+        to: Vec<String>,
         in_contexts: Vec<String>,
     ) -> Result<CallForward<'a, NoId>, DBError> {
         let from_as_exten = Extension::create_from_name(config, from);
-        let to_as_exten = Extension::create_from_name(config, to);
+        if to.is_empty() {
+            return Err(DBError::NoDestination(from_as_exten));
+        };
+        let to_as_exten = to
+            .into_iter()
+            .map(|x| Extension::create_from_name(config, x))
+            .collect();
         let mut contexts_as_contexts: Vec<&Context> = vec![];
         for ctx in in_contexts.into_iter() {
             match Context::create_from_name(config, &ctx) {
@@ -153,6 +853,13 @@ impl<'a> CallForward<'a, NoId> {
             from: from_as_exten,
             to: to_as_exten,
             in_contexts: contexts_as_contexts,
+            schedule: None,
+            condition: ForwardCondition::Always,
+            caller_ids: vec![],
+            active: true,
+            valid_from: None,
+            valid_until: None,
+            version: 1,
         })
     }
 
@@ -162,19 +869,32 @@ impl<'a> CallForward<'a, NoId> {
             from: self.from,
             to: self.to,
             in_contexts: self.in_contexts,
+            schedule: self.schedule,
+            condition: self.condition,
+            caller_ids: self.caller_ids,
+            active: self.active,
+            valid_from: self.valid_from,
+            valid_until: self.valid_until,
+            version: self.version,
         }
     }
 }
 
 #[derive(Deserialize)]
 struct ConfigFileData {
+    /// only copied into the DB on the first start
     extensions: Vec<Extension>,
+    /// only copied into the DB on the first start
     contexts: Vec<Context>,
-    db_user: String,
-    db_password: String,
-    db_port: u16,
-    db_host: String,
-    db_database: String,
+    /// the LDAP uids of the users who may manage extensions and contexts
+    admin_users: Option<Vec<String>>,
+    /// the SQLite file to keep everything in. The postgres settings are not needed then
+    db_file: Option<String>,
+    db_user: Option<String>,
+    db_password: Option<String>,
+    db_port: Option<u16>,
+    db_host: Option<String>,
+    db_database: Option<String>,
     tls_cert_file: String,
     tls_key_file: String,
     web_bind_addr: String,
@@ -182,7 +902,40 @@ struct ConfigFileData {
     web_bind_port_tls: u16,
     agi_bind_addr: String,
     agi_bind_port: String,
-    agi_digest_secret: String,
+    /// the secret asterisk uses in the digest, if agi_peers is not set
+    agi_digest_secret: Option<String>,
+    /// all secrets accepted at once, e.g. the old and the new one while rotating
+    agi_digest_secrets: Option<Vec<String>>,
+    /// the asterisk servers allowed to connect. Without it, every address may connect
+    agi_peers: Option<Vec<AgiPeerData>>,
+    /// how asterisk proves it knows the secret. Defaults to sha1 for existing setups
+    agi_digest_algorithm: Option<DigestAlgorithm>,
+    /// how many call forwards are followed in a row. Defaults to 5
+    max_forward_depth: Option<usize>,
+    /// prefixed to every extension in CALL_FORWARDED_TO, e.g. PJSIP
+    dial_technology: Option<String>,
+    /// timezone preset for new call forward schedules. Defaults to UTC
+    default_timezone: Option<String>,
+    /// wrong PINs in a row before an extension is locked out. Defaults to 3
+    pin_max_attempts: Option<u32>,
+    /// how long an extension is locked out. Defaults to 15
+    pin_lockout_minutes: Option<u32>,
+    /// where the last known call forwards are kept for when the DB is unreachable
+    snapshot_file: Option<String>,
+    /// how often the snapshot is written. Defaults to 60
+    snapshot_interval_seconds: Option<u64>,
+    /// how long call lookups are kept. Defaults to 90
+    lookup_retention_days: Option<u32>,
+    /// how long expired call forwards are kept before they are deleted. Defaults to 30
+    expired_forward_retention_days: Option<u32>,
+    /// how long deleted call forwards can be restored before they are removed for good. Defaults to 30
+    deleted_forward_retention_days: Option<u32>,
+    /// address to serve /metrics on. Defaults to 127.0.0.1
+    metrics_bind_addr: Option<String>,
+    /// port to serve /metrics on. Without it, no metrics are served
+    metrics_bind_port: Option<u16>,
+    /// which events are logged, as in RUST_LOG. Defaults to everything from asterconf and blazing_agi
+    log_filter: Option<String>,
     ldap: LDAPConfigData,
 }
 impl std::fmt::Debug for ConfigFileData {
@@ -190,6 +943,8 @@ impl std::fmt::Debug for ConfigFileData {
         f.debug_struct("ConfigFileData")
             .field("extensions", &self.extensions)
             .field("contexts", &self.contexts)
+            .field("admin_users", &self.admin_users)
+            .field("db_file", &self.db_file)
             .field("db_user", &self.db_user)
             .field("db_password", &"[redacted]")
             .field("db_port", &self.db_port)
@@ -202,12 +957,56 @@ impl std::fmt::Debug for ConfigFileData {
             .field("web_bind_port_tls", &self.web_bind_port_tls)
             .field("agi_bind_addr", &self.agi_bind_addr)
             .field("agi_bind_port", &self.agi_bind_port)
-            .field("agi_digest_secret", &self.agi_digest_secret)
+            .field("agi_digest_secret", &"[redacted]")
+            .field("agi_digest_secrets", &"[redacted]")
+            .field("agi_peers", &self.agi_peers)
+            .field("agi_digest_algorithm", &self.agi_digest_algorithm)
+            .field("max_forward_depth", &self.max_forward_depth)
+            .field("dial_technology", &self.dial_technology)
+            .field("default_timezone", &self.default_timezone)
+            .field("pin_max_attempts", &self.pin_max_attempts)
+            .field("pin_lockout_minutes", &self.pin_lockout_minutes)
+            .field("snapshot_file", &self.snapshot_file)
+            .field("snapshot_interval_seconds", &self.snapshot_interval_seconds)
+            .field("lookup_retention_days", &self.lookup_retention_days)
+            .field(
+                "expired_forward_retention_days",
+                &self.expired_forward_retention_days,
+            )
+            .field(
+                "deleted_forward_retention_days",
+                &self.deleted_forward_retention_days,
+            )
+            .field("metrics_bind_addr", &self.metrics_bind_addr)
+            .field("metrics_bind_port", &self.metrics_bind_port)
+            .field("log_filter", &self.log_filter)
             .field("ldap", &self.ldap)
             .finish()
     }
 }
 
+#[derive(Deserialize)]
+struct AgiPeerData {
+    name: String,
+    /// IP addresses or networks in CIDR notation
+    addresses: Vec<String>,
+    digest_secret: Option<String>,
+    digest_secrets: Option<Vec<String>>,
+    /// the contexts this peer may use. All contexts if not set
+    allowed_contexts: Option<Vec<String>>,
+}
+impl std::fmt::Debug for AgiPeerData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AgiPeerData")
+            .field("name", &self.name)
+            .field("addresses", &self.addresses)
+            .field("digest_secret", &"[redacted]")
+            .field("digest_secrets", &"[redacted]")
+            .field("allowed_contexts", &self.allowed_contexts)
+            .finish()
+    }
+}
+
 #[derive(Deserialize)]
 struct LDAPConfigData {
     hostname: String,
@@ -230,14 +1029,26 @@ impl std::fmt::Debug for LDAPConfigData {
     }
 }
 
+/// How long to wait for a connection to the DB
+const DB_ACQUIRE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+/// Where the config file is read from, unless another one is given on the command line
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "/etc/asterconf/config.yaml";
+/// The events logged unless the config file says otherwise
+pub(crate) const DEFAULT_LOG_FILTER: &str = "asterconf,blazing_agi";
+
 #[derive(Clone)]
 pub struct Config {
-    // extension name to Extension
-    pub(crate) extensions: HashMap<String, Extension>,
-    // context name to Context
-    pub(crate) contexts: HashMap<String, Context>,
-    // db connection pool
-    pub(crate) pool: PgPool,
+    // the file this config was read from
+    pub(crate) config_path: PathBuf,
+    // the known extensions and contexts, shared between all clones of the config
+    pub(crate) registry: std::sync::Arc<crate::registry::Registry>,
+    // the extensions and contexts from the config file, copied into an empty DB
+    pub(crate) seed_extensions: Vec<Extension>,
+    pub(crate) seed_contexts: Vec<Context>,
+    // the LDAP uids of the users who may manage extensions and contexts
+    pub(crate) admin_users: Vec<String>,
+    // db connection pool, to postgres or to a SQLite file
+    pub(crate) pool: DbPool,
     // addr:port to bind the webserver to
     pub(crate) web_bind_string: String,
     // the port (we need it as u16 later)
@@ -247,8 +1058,38 @@ pub struct Config {
     pub(crate) web_bind_port_tls: u16,
     // addr:port to bind agi server to
     pub(crate) agi_bind_string: String,
-    // the secret used in the SHA digest
-    pub(crate) agi_digest_secret: String,
+    // the asterisk servers allowed to connect to the AGI server, with their secrets
+    pub(crate) agi_peers: Vec<AgiPeer>,
+    // how the AGI digest is computed
+    pub(crate) agi_digest_algorithm: DigestAlgorithm,
+    // the maximum number of call forwards followed in a row
+    pub(crate) max_forward_depth: usize,
+    // technology prefixed to the extensions in the dial string (e.g. PJSIP)
+    pub(crate) dial_technology: Option<String>,
+    // timezone preset for new call forward schedules
+    pub(crate) default_timezone: Tz,
+    // wrong PINs in a row before an extension is locked out
+    pub(crate) pin_max_attempts: u32,
+    // how long an extension is locked out after too many wrong PINs
+    pub(crate) pin_lockout: chrono::Duration,
+    // file to keep the last known call forwards in, for when the DB is unreachable
+    pub(crate) snapshot_file: Option<PathBuf>,
+    // how often the snapshot file is written
+    pub(crate) snapshot_interval: std::time::Duration,
+    // how long call lookups are kept
+    pub(crate) lookup_retention: chrono::Duration,
+    // how long expired call forwards are kept, so users still see what happened
+    pub(crate) expired_forward_retention: chrono::Duration,
+    // how long deleted call forwards can be restored
+    pub(crate) deleted_forward_retention: chrono::Duration,
+    // addr:port to serve the prometheus metrics on, if any
+    pub(crate) metrics_bind_string: Option<String>,
+    // which events are logged, as in RUST_LOG
+    pub(crate) log_filter: String,
+    // call forwards cached for the AGI server, shared between all clones of the config
+    pub(crate) forward_cache: std::sync::Arc<crate::cache::ForwardCache>,
+    // call lookups waiting to be logged, shared between all clones of the config
+    pub(crate) lookup_queue: std::sync::Arc<crate::agi_server::LookupQueue>,
     /// config for the TLS layer
     pub(crate) rustls_config: RustlsConfig,
     pub(crate) ldap_config: crate::ldap::LDAPBackend,
@@ -256,14 +1097,30 @@ pub struct Config {
 impl std::fmt::Debug for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("Config")
-            .field("extensions", &self.extensions)
-            .field("contexts", &self.contexts)
+            .field("config_path", &self.config_path)
+            .field("registry", &self.registry)
+            .field("seed_extensions", &self.seed_extensions)
+            .field("seed_contexts", &self.seed_contexts)
+            .field("admin_users", &self.admin_users)
             .field("web_bind_string", &self.web_bind_string)
             .field("web_bind_port", &self.web_bind_port)
             .field("web_bind_string_tls", &self.web_bind_string_tls)
             .field("web_bind_port_tls", &self.web_bind_port_tls)
             .field("agi_bind_string", &self.agi_bind_string)
-            .field("agi_digest_secret", &"[redacted]")
+            .field("agi_peers", &self.agi_peers)
+            .field("agi_digest_algorithm", &self.agi_digest_algorithm)
+            .field("max_forward_depth", &self.max_forward_depth)
+            .field("dial_technology", &self.dial_technology)
+            .field("default_timezone", &self.default_timezone)
+            .field("pin_max_attempts", &self.pin_max_attempts)
+            .field("pin_lockout", &self.pin_lockout)
+            .field("snapshot_file", &self.snapshot_file)
+            .field("snapshot_interval", &self.snapshot_interval)
+            .field("lookup_retention", &self.lookup_retention)
+            .field("expired_forward_retention", &self.expired_forward_retention)
+            .field("deleted_forward_retention", &self.deleted_forward_retention)
+            .field("metrics_bind_string", &self.metrics_bind_string)
+            .field("log_filter", &self.log_filter)
             .field("rustls_config", &self.rustls_config)
             .field("ldap_config", &self.ldap_config)
             .finish()
@@ -274,13 +1131,38 @@ impl Config {
     // so I don't care about proper error handling
     // TODO: this needs to log its own errors, because it is called in lazy_static
     pub async fn create() -> Result<Config, Box<dyn std::error::Error>> {
-        let config_path = Path::new("/etc/asterconf/config.yaml");
+        Self::create_from(Path::new(DEFAULT_CONFIG_PATH)).await
+    }
+
+    /// Read the config file at `config_path` and connect to the DB
+    pub async fn create_from(config_path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        Self::from_file(config_path, None).await
+    }
+
+    /// Read the config file of this config again, e.g. after it changed.
+    ///
+    /// Nothing is swapped yet. The new config keeps the DB connection, the registry, the call
+    /// forward cache and the queue of call lookups of this one, which cannot change while running.
+    pub async fn reread(&self) -> Result<Config, Box<dyn std::error::Error>> {
+        let mut config = Self::from_file(&self.config_path, Some(self.pool.clone())).await?;
+        config.registry = self.registry.clone();
+        config.forward_cache = self.forward_cache.clone();
+        config.lookup_queue = self.lookup_queue.clone();
+        Ok(config)
+    }
+
+    /// Read the config at `config_path`, connecting to the DB unless `pool` is given
+    async fn from_file(
+        config_path: &Path,
+        pool: Option<DbPool>,
+    ) -> Result<Config, Box<dyn std::error::Error>> {
         let f = match File::open(config_path) {
             Ok(x) => x,
             Err(e) => {
                 event!(
                     Level::ERROR,
-                    "config file /etc/asterconf/config.yaml not readable: {e}"
+                    "config file {} not readable: {e}",
+                    config_path.display()
                 );
                 return Err(Box::new(e));
             }
@@ -292,31 +1174,60 @@ impl Config {
                 return Err(Box::new(e));
             }
         };
-        // static extensions and contexts
-        let extensions: HashMap<String, Extension> = config_data
-            .extensions
-            .into_iter()
-            .map(|exten| (exten.extension.clone(), exten))
-            .collect();
-        let contexts: HashMap<String, Context> = config_data
-            .contexts
-            .into_iter()
-            .map(|ctx| (ctx.asterisk_name.clone(), ctx))
-            .collect();
-        // postgres settings
-        let url = format!(
-            "postgres://{}:{}@{}:{}/{}",
-            config_data.db_user,
-            config_data.db_password,
-            config_data.db_host,
-            config_data.db_port,
-            config_data.db_database
+        // until the registry is loaded from the DB, the config file is all we know
+        let registry = crate::registry::Registry::new(
+            config_data.extensions.clone(),
+            config_data.contexts.clone(),
         );
-        let pool = match sqlx::postgres::PgPool::connect(&url).await {
-            Ok(x) => x,
-            Err(e) => {
-                event!(Level::ERROR, "Could not connect to postgres: {e}");
-                return Err(Box::new(e));
+        let pool = match (pool, config_data.db_file) {
+            // the DB settings only apply on start
+            (Some(pool), _) => pool,
+            // a single file, e.g. for small setups without a postgres server
+            (None, Some(path)) => {
+                let connect_options = sqlx::sqlite::SqliteConnectOptions::new()
+                    .filename(&path)
+                    .create_if_missing(true)
+                    .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+                match sqlx::sqlite::SqlitePoolOptions::new()
+                    .acquire_timeout(DB_ACQUIRE_TIMEOUT)
+                    .connect_with(connect_options)
+                    .await
+                {
+                    Ok(x) => DbPool::Sqlite(x),
+                    Err(e) => {
+                        event!(Level::ERROR, "Could not open the SQLite file {path}: {e}");
+                        return Err(Box::new(e));
+                    }
+                }
+            }
+            (None, None) => {
+                // postgres settings
+                let (Some(user), Some(password), Some(host), Some(port), Some(database)) = (
+                    config_data.db_user,
+                    config_data.db_password,
+                    config_data.db_host,
+                    config_data.db_port,
+                    config_data.db_database,
+                ) else {
+                    event!(
+                        Level::ERROR,
+                        "db_user, db_password, db_host, db_port and db_database must be set, unless db_file is"
+                    );
+                    return Err("the postgres settings are incomplete".into());
+                };
+                let url = format!("postgres://{user}:{password}@{host}:{port}/{database}");
+                // fail fast while postgres is unreachable, so that calls can be answered from the snapshot
+                let pool_options =
+                    sqlx::postgres::PgPoolOptions::new().acquire_timeout(DB_ACQUIRE_TIMEOUT);
+                let pool = match pool_options.clone().connect(&url).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        // we may still be able to answer calls from the snapshot
+                        event!(Level::ERROR, "Could not connect to postgres: {e}");
+                        pool_options.connect_lazy(&url)?
+                    }
+                };
+                DbPool::Postgres(pool)
             }
         };
         // webserver settings
@@ -332,6 +1243,84 @@ impl Config {
             "{}:{}",
             config_data.agi_bind_addr, config_data.agi_bind_port
         );
+        let agi_peers = match config_data.agi_peers {
+            Some(peers) => peers
+                .into_iter()
+                .map(|peer| {
+                    AgiPeer::new(
+                        peer.name,
+                        &peer.addresses,
+                        peer.digest_secret
+                            .into_iter()
+                            .chain(peer.digest_secrets.unwrap_or_default())
+                            .collect(),
+                        peer.allowed_contexts,
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>(),
+            // any address may connect, as long as it knows the secret
+            None => AgiPeer::new(
+                "asterisk".to_string(),
+                &["0.0.0.0/0".to_string(), "::/0".to_string()],
+                config_data
+                    .agi_digest_secret
+                    .into_iter()
+                    .chain(config_data.agi_digest_secrets.unwrap_or_default())
+                    .collect(),
+                None,
+            )
+            .map(|x| vec![x]),
+        };
+        let agi_peers = match agi_peers {
+            Ok(x) => x,
+            Err(e) => {
+                event!(Level::ERROR, "agi_peers is not valid: {e}");
+                return Err(Box::new(e));
+            }
+        };
+        let agi_digest_algorithm = config_data.agi_digest_algorithm.unwrap_or_else(|| {
+            event!(
+                Level::WARN,
+                "agi_digest_algorithm is not set. Using the legacy sha1, consider hmac-sha256."
+            );
+            DigestAlgorithm::Sha1
+        });
+        let metrics_bind_string = config_data.metrics_bind_port.map(|port| {
+            format!(
+                "{}:{port}",
+                config_data
+                    .metrics_bind_addr
+                    .as_deref()
+                    .unwrap_or("127.0.0.1")
+            )
+        });
+        let default_timezone = match config_data.default_timezone {
+            None => Tz::UTC,
+            Some(x) => match x.parse::<Tz>() {
+                Ok(tz) => tz,
+                Err(e) => {
+                    event!(
+                        Level::ERROR,
+                        "default_timezone {x} is not a valid timezone: {e}"
+                    );
+                    return Err(Box::new(e));
+                }
+            },
+        };
+        let pin_max_attempts = config_data.pin_max_attempts.unwrap_or(3);
+        if pin_max_attempts == 0 {
+            event!(Level::ERROR, "pin_max_attempts must be at least 1");
+            return Err("pin_max_attempts is 0".into());
+        };
+        let log_filter = config_data
+            .log_filter
+            .unwrap_or_else(|| DEFAULT_LOG_FILTER.to_string());
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&log_filter) {
+            event!(Level::ERROR, "log_filter {log_filter} is not valid: {e}");
+            return Err(Box::new(e));
+        };
+        // main installs the crypto provider, but tests create the config without going through main
+        let _ = rustls::crypto::ring::default_provider().install_default();
         let rustls_config =
             match RustlsConfig::from_pem_file(config_data.tls_cert_file, config_data.tls_key_file)
                 .await
@@ -365,17 +1354,193 @@ impl Config {
             }
         };
         Ok(Config {
-            extensions,
-            contexts,
+            config_path: config_path.to_path_buf(),
+            registry: std::sync::Arc::new(registry),
+            seed_extensions: config_data.extensions,
+            seed_contexts: config_data.contexts,
+            admin_users: config_data.admin_users.unwrap_or_default(),
             pool,
             web_bind_string,
             web_bind_string_tls,
             web_bind_port: config_data.web_bind_port,
             web_bind_port_tls: config_data.web_bind_port_tls,
             agi_bind_string,
-            agi_digest_secret: config_data.agi_digest_secret,
+            agi_peers,
+            agi_digest_algorithm,
+            max_forward_depth: config_data.max_forward_depth.unwrap_or(5),
+            dial_technology: config_data.dial_technology,
+            default_timezone,
+            pin_max_attempts,
+            pin_lockout: chrono::Duration::minutes(
+                config_data.pin_lockout_minutes.unwrap_or(15).into(),
+            ),
+            snapshot_file: config_data.snapshot_file.map(PathBuf::from),
+            snapshot_interval: std::time::Duration::from_secs(
+                config_data.snapshot_interval_seconds.unwrap_or(60),
+            ),
+            lookup_retention: chrono::Duration::days(
+                config_data.lookup_retention_days.unwrap_or(90).into(),
+            ),
+            expired_forward_retention: chrono::Duration::days(
+                config_data
+                    .expired_forward_retention_days
+                    .unwrap_or(30)
+                    .into(),
+            ),
+            deleted_forward_retention: chrono::Duration::days(
+                config_data
+                    .deleted_forward_retention_days
+                    .unwrap_or(30)
+                    .into(),
+            ),
+            metrics_bind_string,
+            log_filter,
+            forward_cache: Default::default(),
+            lookup_queue: Default::default(),
             rustls_config,
             ldap_config,
         })
     }
+
+    /// May `username` manage extensions and contexts?
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admin_users.iter().any(|x| x == username)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{NaiveTime, TimeZone, Utc, Weekday};
+    use chrono_tz::Tz;
+
+    use super::{
+        CallForward, CallerIdPattern, Extension, ForwardCondition, NoId, Schedule, ScheduleWindow,
+        Validity,
+    };
+
+    fn office_hours() -> Schedule {
+        Schedule {
+            timezone: Tz::Europe__Berlin,
+            windows: vec![ScheduleWindow::default()],
+        }
+    }
+
+    #[test]
+    fn window_rejects_empty_time_range() {
+        let nine = NaiveTime::from_hms_opt(9, 0, 0).unwrap();
+        assert!(ScheduleWindow::new(Weekday::Mon, Weekday::Fri, nine, nine).is_none());
+    }
+
+    #[test]
+    fn window_wraps_around_the_week() {
+        let window = ScheduleWindow::new(
+            Weekday::Sat,
+            Weekday::Mon,
+            NaiveTime::from_hms_opt(0, 0, 0).unwrap(),
+            NaiveTime::from_hms_opt(12, 0, 0).unwrap(),
+        )
+        .unwrap();
+        let ten = NaiveTime::from_hms_opt(10, 0, 0).unwrap();
+        assert!(window.contains(Weekday::Sun, ten));
+        assert!(window.contains(Weekday::Mon, ten));
+        assert!(!window.contains(Weekday::Wed, ten));
+        assert!(!window.contains(Weekday::Sun, NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn schedule_uses_its_timezone() {
+        let schedule = office_hours();
+        // Wednesday, 07:30 UTC is 08:30 in Berlin (winter time)
+        assert!(schedule.is_active_at(&Utc.with_ymd_and_hms(2024, 1, 10, 7, 30, 0).unwrap()));
+        // Wednesday, 16:30 UTC is 17:30 in Berlin
+        assert!(!schedule.is_active_at(&Utc.with_ymd_and_hms(2024, 1, 10, 16, 30, 0).unwrap()));
+        // Saturday
+        assert!(!schedule.is_active_at(&Utc.with_ymd_and_hms(2024, 1, 13, 10, 0, 0).unwrap()));
+    }
+
+    #[test]
+    fn schedule_display() {
+        assert_eq!(
+            office_hours().to_string(),
+            "Mo-Fr 08:00-17:00 (Europe/Berlin)".to_string()
+        );
+    }
+
+    #[test]
+    fn parse_caller_id_patterns() {
+        assert_eq!(
+            CallerIdPattern::parse(" 0151234 "),
+            Some(CallerIdPattern::Exact("0151234".to_string()))
+        );
+        assert_eq!(
+            CallerIdPattern::parse("0151*"),
+            Some(CallerIdPattern::Prefix("0151".to_string()))
+        );
+        assert_eq!(CallerIdPattern::parse("*"), None);
+        assert_eq!(CallerIdPattern::parse("01*51"), None);
+        assert_eq!(CallerIdPattern::parse(""), None);
+    }
+
+    #[test]
+    fn exact_caller_id_beats_prefix() {
+        let exact = CallerIdPattern::Exact("0151234".to_string());
+        let prefix = CallerIdPattern::Prefix("015123".to_string());
+        assert!(exact.specificity("0151234") > prefix.specificity("0151234"));
+        assert_eq!(exact.specificity("01512345"), None);
+        assert_eq!(prefix.specificity("01512345"), Some(6));
+    }
+
+    fn vacation_forward(
+        from_day: Option<u32>,
+        until_day: Option<u32>,
+    ) -> CallForward<'static, NoId> {
+        let day = |d| Utc.with_ymd_and_hms(2024, 7, d, 0, 0, 0).unwrap();
+        CallForward {
+            fwd_id: NoId {},
+            from: Extension {
+                name: None,
+                extension: "702".to_string(),
+                owner: None,
+            },
+            to: vec![],
+            in_contexts: vec![],
+            schedule: None,
+            condition: ForwardCondition::Always,
+            caller_ids: vec![],
+            active: true,
+            valid_from: from_day.map(day),
+            valid_until: until_day.map(day),
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn forward_only_applies_while_valid() {
+        let fwd = vacation_forward(Some(1), Some(15));
+        let before = Utc.with_ymd_and_hms(2024, 6, 30, 23, 59, 0).unwrap();
+        let during = Utc.with_ymd_and_hms(2024, 7, 10, 12, 0, 0).unwrap();
+        let end = Utc.with_ymd_and_hms(2024, 7, 15, 0, 0, 0).unwrap();
+        assert_eq!(fwd.validity_at(&before), Validity::Upcoming);
+        assert_eq!(fwd.validity_at(&during), Validity::Valid);
+        assert_eq!(fwd.validity_at(&end), Validity::Expired);
+        assert!(!fwd.is_active_at(&before));
+        assert!(fwd.is_active_at(&during));
+        assert!(!fwd.is_active_at(&end));
+        assert!(vacation_forward(None, None).is_active_at(&end));
+    }
+
+    #[test]
+    fn validity_overlap() {
+        let first_half = vacation_forward(Some(1), Some(15));
+        let second_half = vacation_forward(Some(15), Some(31));
+        assert!(!first_half.validity_overlaps(&second_half));
+        assert!(!second_half.validity_overlaps(&first_half));
+        assert!(first_half.validity_overlaps(&vacation_forward(Some(10), None)));
+        assert!(second_half.validity_overlaps(&vacation_forward(None, Some(20))));
+        assert!(first_half.validity_overlaps(&vacation_forward(None, None)));
+        assert_eq!(
+            first_half.validity_in(&Tz::Europe__Berlin),
+            "01.07.2024 02:00 - 15.07.2024 02:00".to_string()
+        );
+    }
 }
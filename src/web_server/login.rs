@@ -27,7 +27,7 @@ mod post {
     use tracing::{info, warn, Level};
     use uuid::Uuid;
 
-    use crate::web_server::InternalServerErrorTemplate;
+    use crate::{metrics::METRICS, web_server::InternalServerErrorTemplate};
 
     use super::*;
 
@@ -38,14 +38,17 @@ mod post {
     ) -> impl IntoResponse {
         let user = match auth_session.authenticate(creds.clone()).await {
             Ok(Some(user)) => {
+                METRICS.web_logins.with_label_values(&["success"]).inc();
                 info!("New user logged in: {:?}", user);
                 user
             }
             Ok(None) => {
+                METRICS.web_logins.with_label_values(&["rejected"]).inc();
                 warn!("Returning redirect, because the user supplied the wrong password or was not found via the user filter.");
                 return Redirect::to("/login").into_response();
             }
             Err(e) => {
+                METRICS.web_logins.with_label_values(&["error"]).inc();
                 warn!(
                     "Returning internal server error, because I could not ldap search a user: {e}"
                 );
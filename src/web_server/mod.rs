@@ -13,13 +13,15 @@ use uuid::Uuid;
 use std::{str::FromStr, sync::Arc};
 
 use axum::{
-    extract::Host,
+    extract::{Host, Request, State},
     handler::HandlerWithoutStateExt,
     http::{header, HeaderMap, StatusCode, Uri},
-    response::{Html, IntoResponse, Redirect},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
     routing::get,
-    Extension, Router,
+    Router,
 };
+use tokio::sync::watch;
 use tracing::{event, Level};
 
 use crate::{ldap::LDAPBackend, types::Config};
@@ -41,17 +43,22 @@ impl Webserver {
         let connect_options = sqlx::sqlite::SqliteConnectOptions::new()
             .filename(".session_data.db")
             .create_if_missing(true);
+        // the session store creates its own table in run_web_server.
+        // The call forward migrations only apply to postgres.
         let db = SqlitePool::connect_with(connect_options).await?;
-        sqlx::migrate!().run(&db).await?;
 
         Ok(Self { db })
     }
 
     /// Run the web server
+    ///
+    /// Every request gets the config that is current when it arrives.
+    /// The addresses to listen on are taken from the config at the start.
     pub async fn run_web_server(
         &self,
-        config: Arc<Config>,
+        configs: watch::Receiver<Arc<Config>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let config = configs.borrow().clone();
         // Session layer.
         //
         // This uses `tower-sessions` to establish a layer that will provide the session
@@ -76,16 +83,16 @@ impl Webserver {
         //
         // This combines the session layer with our backend to establish the auth
         // service which will provide the auth session as a request extension.
+        // A reload changes the settings of the backend in place.
         let auth_backend = config.ldap_config.clone();
         let auth_layer = AuthManagerLayerBuilder::new(auth_backend, session_layer).build();
 
-        let our_config = config.clone();
         let app = Router::new()
             .merge(protected::create_protected_router())
             .route_layer(login_required!(LDAPBackend, login_url = "/login"))
             .merge(login::create_login_router())
             .layer(auth_layer)
-            .layer(Extension(our_config))
+            .layer(middleware::from_fn_with_state(configs, current_config))
             .route("/scripts/htmx@2.0.2.js", get(htmx_script))
             .route(
                 "/scripts/hyperscript.org@0.9.12.js",
@@ -116,6 +123,17 @@ impl Webserver {
     }
 }
 
+/// Hand the config that is current right now to the request
+async fn current_config(
+    State(configs): State<watch::Receiver<Arc<Config>>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let config = configs.borrow().clone();
+    request.extensions_mut().insert(config);
+    next.run(request).await
+}
+
 fn make_https(
     host: String,
     uri: Uri,
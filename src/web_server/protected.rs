@@ -8,7 +8,10 @@ use axum::{
 };
 use uuid::Uuid;
 
-use crate::types::{CallForward, Config, Context, HasId};
+use chrono_tz::Tz;
+
+use crate::db::forward_conflict_message;
+use crate::types::{CallForward, Config, Context, ForwardUsage, HasId};
 
 fn error_display(s: &str) -> String {
     // we cannot control hx-swap separately for hx-target and hx-target-error
@@ -16,6 +19,12 @@ fn error_display(s: &str) -> String {
     format!("<div class=\"text-red-500 flex justify-center\" id=\"error_display\" _=\"on htmx:beforeSend from elsewhere set my innerHTML to ''\">{}</div>", s)
 }
 
+/// Like [`error_display`], but to be sent along with a successful response, which htmx swaps into
+/// its usual target
+fn error_display_oob(s: &str) -> String {
+    format!("<div class=\"text-red-500 flex justify-center\" id=\"error_display\" hx-swap-oob=\"true\" _=\"on htmx:beforeSend from elsewhere set my innerHTML to ''\">{}</div>", s)
+}
+
 pub(crate) fn create_protected_router() -> Router {
     Router::new()
         .route("/", get(self::get::root))
@@ -23,6 +32,10 @@ pub(crate) fn create_protected_router() -> Router {
             "/web/call-forward/:fwdid",
             get(self::get::single_call_forward).delete(self::delete::single_call_forward_delete),
         )
+        .route(
+            "/web/call-forward/:fwdid/active",
+            post(self::post::single_call_forward_active),
+        )
         .route(
             "/web/call-forward/:fwdid/edit",
             get(self::get::single_call_forward_edit).post(self::post::single_call_forward_edit),
@@ -31,13 +44,49 @@ pub(crate) fn create_protected_router() -> Router {
             "/web/call-forward/new",
             get(self::get::single_call_forward_new).post(self::post::single_call_forward_new),
         )
+        .route("/web/schedule-window", get(self::get::schedule_window))
+        .route("/web/destination", get(self::get::destination))
         .route(
             "/web/search-extension/from",
             post(self::post::from_search_extension),
         )
+        // GET, so that htmx only sends the input field that triggered the search
+        // and not all the destinations in the surrounding form
         .route(
             "/web/search-extension/to",
-            post(self::post::to_search_extension),
+            get(self::get::to_search_extension),
+        )
+        .route("/web/lookups/:extension", get(self::get::lookups))
+        .route("/web/history", get(self::get::history))
+        .route("/web/deleted", get(self::get::deleted_call_forwards))
+        .route(
+            "/web/call-forward/:fwdid/restore",
+            post(self::post::single_call_forward_restore),
+        )
+        .route("/web/pin", get(self::get::pin_overview))
+        .route(
+            "/web/pin/:extension",
+            post(self::post::pin_set).delete(self::delete::pin_delete),
+        )
+        .route("/web/admin", get(self::get::admin_overview))
+        .route(
+            "/web/admin/extension",
+            post(self::post::admin_extension_new),
+        )
+        .route(
+            "/web/admin/extension/:extension",
+            post(self::post::admin_extension_set).delete(self::delete::admin_extension_delete),
+        )
+        .route("/web/admin/context", post(self::post::admin_context_new))
+        .route("/web/admin/export", get(self::get::admin_export))
+        .route("/web/admin/import", post(self::post::admin_import_check))
+        .route(
+            "/web/admin/import/apply",
+            post(self::post::admin_import_apply),
+        )
+        .route(
+            "/web/admin/context/:context",
+            post(self::post::admin_context_set).delete(self::delete::admin_context_delete),
         )
 }
 
@@ -46,12 +95,136 @@ pub(crate) fn create_protected_router() -> Router {
 struct SingleCallForwardShowTemplate<'a> {
     fwd: CallForward<'a, HasId>,
     contexts: Vec<&'a Context>,
+    usage: ForwardUsage,
+    timezone: Tz,
+}
+
+/// How often the call forward `fwd_id` was applied.
+///
+/// The statistics are not worth failing a request for, so errors only show up in the log.
+async fn usage_of(config: &Config, fwd_id: i32) -> ForwardUsage {
+    match crate::db::get_call_forward_usage(config, Some(fwd_id)).await {
+        Ok(mut x) => x.remove(&fwd_id).unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!("Showing call forward {fwd_id} without usage: {e}");
+            ForwardUsage::default()
+        }
+    }
+}
+
+/// One extension on the PIN page
+struct PinRow {
+    extension: crate::types::Extension,
+    is_set: bool,
+    status: String,
+}
+impl PinRow {
+    /// Look up the PIN state of `extension`
+    async fn create(
+        config: &Config,
+        extension: crate::types::Extension,
+    ) -> Result<Self, crate::db::DBError> {
+        let pin = crate::db::get_extension_pin(config, &extension.extension).await?;
+        let status = match &pin {
+            None => "nicht gesetzt".to_string(),
+            Some(x) => match x.locked_until {
+                Some(until) if x.is_locked_at(&chrono::Utc::now()) => format!(
+                    "gesperrt bis {}",
+                    until
+                        .with_timezone(&config.default_timezone)
+                        .format("%d.%m.%Y %H:%M")
+                ),
+                _ => "gesetzt".to_string(),
+            },
+        };
+        Ok(PinRow {
+            extension,
+            is_set: pin.is_some(),
+            status,
+        })
+    }
+}
+
+#[derive(Template)]
+#[template(path = "pin_row.html")]
+struct PinRowTemplate {
+    row: PinRow,
+}
+
+/// The extension `extension` if it is owned by `username`
+fn owned_extension(
+    config: &Config,
+    username: &str,
+    extension: &str,
+) -> Option<crate::types::Extension> {
+    config
+        .registry
+        .extension(extension)
+        .filter(|x| x.owner.as_deref() == Some(username))
+}
+
+#[derive(Template)]
+#[template(path = "admin_extension_row.html")]
+struct AdminExtensionRowTemplate {
+    extension: crate::types::Extension,
+}
+
+#[derive(Template)]
+#[template(path = "admin_context_row.html")]
+struct AdminContextRowTemplate {
+    context: Context,
+}
+
+/// The answer to users who may not manage extensions and contexts
+fn not_an_admin() -> (axum::http::StatusCode, String) {
+    (
+        axum::http::StatusCode::FORBIDDEN,
+        error_display("Nur Administratoren dürfen Anschlüsse, Kontexte und Importe verwalten."),
+    )
+}
+
+/// The answer to a change on the admin page that failed with `e`
+fn admin_error_response(e: crate::db::DBError) -> askama_axum::Response {
+    use crate::db::DBError;
+    use askama_axum::IntoResponse;
+    use axum::http::StatusCode;
+
+    let message = match &e {
+        DBError::ExtensionExists(x) => format!("Den Anschluss {x} gibt es schon."),
+        DBError::ExtensionDoesNotExist(x) => format!("Den Anschluss {x} gibt es nicht mehr."),
+        DBError::ContextExists(x) => format!("Den Kontext {x} gibt es schon."),
+        DBError::ContextDoesNotExist(x) => format!("Den Kontext {x} gibt es nicht mehr."),
+        DBError::ContextInUse(x) => {
+            format!("Der Kontext {x} wird noch von Weiterleitungen verwendet, auch von gelöschten.")
+        }
+        _ => {
+            let error_uuid = Uuid::new_v4();
+            tracing::warn!("Sending internal server error because there was a problem changing extensions or contexts.");
+            tracing::warn!("DBError: {e}, Error-UUID: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                crate::web_server::InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        }
+    };
+    (StatusCode::BAD_REQUEST, error_display(&message)).into_response()
+}
+
+/// Show a change on the admin page everywhere right away, instead of on the next reload of the cache
+async fn refresh_registry(config: &Config) {
+    if let Err(e) = crate::registry::load(config).await {
+        tracing::warn!("Could not refresh the extensions and contexts: {e}");
+    };
 }
 
 pub(super) mod get {
     use crate::{
-        db::{get_all_call_forwards, get_call_forward_by_id},
-        types::{CallForward, Context, HasId},
+        db::{
+            get_all_call_forwards, get_audit_entries, get_call_forward_by_id,
+            get_call_forward_usage, get_call_lookups, get_deleted_call_forwards,
+        },
+        types::{AuditEntry, CallForward, Context, DeletedCallForward, HasId, Validity},
         web_server::{login::AuthSession, InternalServerErrorTemplate},
     };
 
@@ -59,16 +232,36 @@ pub(super) mod get {
 
     use askama::Template;
     use askama_axum::IntoResponse;
-    use axum::{extract::Path, http::StatusCode};
-    use tracing::{warn, Level};
+    use axum::{
+        extract::Path,
+        http::{header, StatusCode},
+    };
+    use tracing::{info, warn, Level};
     use uuid::Uuid;
 
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    use super::post::{mark_string_at_positions, string_fuzzy_match, SearchResultTemplate};
+    use crate::bulk::{self, FileFormat};
+
+    use crate::{
+        pin::{MAX_PIN_LENGTH, MIN_PIN_LENGTH},
+        types::{CallLookup, ScheduleWindow},
+    };
+
     #[derive(Template)]
     #[template(path = "landing.html")]
     struct LandingTemplate<'a> {
         username: String,
-        existing_forwards: Vec<CallForward<'a, HasId>>,
+        /// the call forwards that are valid now, whether switched on or not
+        existing_forwards: Vec<(CallForward<'a, HasId>, ForwardUsage)>,
+        upcoming_forwards: Vec<(CallForward<'a, HasId>, ForwardUsage)>,
+        expired_forwards: Vec<(CallForward<'a, HasId>, ForwardUsage)>,
         contexts: Vec<&'a Context>,
+        timezone: Tz,
+        expired_forward_retention_days: i64,
+        is_admin: bool,
     }
 
     pub(super) async fn root(
@@ -89,13 +282,39 @@ pub(super) mod get {
         let call_forward_res = get_all_call_forwards(&config).await;
         match call_forward_res {
             Ok(forwards) => {
-                let mut contexts = config.contexts.values().collect::<Vec<_>>();
-                contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
+                let contexts = config.registry.contexts();
+                // the statistics are not worth failing the whole page for
+                let mut usage = get_call_forward_usage(&config, None)
+                    .await
+                    .unwrap_or_else(|e| {
+                        warn!("Showing the call forwards without usage: {e}");
+                        HashMap::new()
+                    });
+                let now = chrono::Utc::now();
+                let mut existing_forwards = vec![];
+                let mut upcoming_forwards = vec![];
+                let mut expired_forwards = vec![];
+                for fwd in forwards {
+                    let fwd_usage = usage.remove(&fwd.fwd_id.into()).unwrap_or_default();
+                    match fwd.validity_at(&now) {
+                        Validity::Valid => existing_forwards.push((fwd, fwd_usage)),
+                        Validity::Upcoming => upcoming_forwards.push((fwd, fwd_usage)),
+                        Validity::Expired => expired_forwards.push((fwd, fwd_usage)),
+                    };
+                }
+                // the next to start and the last to expire first
+                upcoming_forwards.sort_by_key(|(fwd, _)| fwd.valid_from);
+                expired_forwards.sort_by_key(|(fwd, _)| std::cmp::Reverse(fwd.valid_until));
 
                 LandingTemplate {
+                    is_admin: config.is_admin(&user.username),
                     username: user.username,
-                    existing_forwards: forwards,
+                    existing_forwards,
+                    upcoming_forwards,
+                    expired_forwards,
                     contexts,
+                    timezone: config.default_timezone,
+                    expired_forward_retention_days: config.expired_forward_retention.num_days(),
                 }
                 .into_response()
             }
@@ -107,248 +326,1200 @@ pub(super) mod get {
                     StatusCode::INTERNAL_SERVER_ERROR,
                     InternalServerErrorTemplate { error_uuid },
                 )
-                    .into_response()
+                    .into_response()
+            }
+        }
+    }
+
+    #[derive(Template)]
+    #[template(path = "pin.html")]
+    struct PinTemplate {
+        username: String,
+        rows: Vec<PinRow>,
+        min_pin_length: usize,
+        max_pin_length: usize,
+    }
+
+    /// The PINs of all extensions owned by the logged in user
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn pin_overview(
+        auth_session: AuthSession,
+        Extension(config): Extension<Arc<Config>>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there is no user in the auth session. uuid: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        };
+        let owned = config
+            .registry
+            .extensions()
+            .into_iter()
+            .filter(|x| x.owner.as_deref() == Some(user.username.as_str()))
+            .collect::<Vec<_>>();
+
+        let mut rows = vec![];
+        for ext in owned {
+            match PinRow::create(&config, ext).await {
+                Ok(x) => rows.push(x),
+                Err(e) => {
+                    let error_uuid = Uuid::new_v4();
+                    warn!(
+                        "Sending internal server error because there was a problem getting a PIN."
+                    );
+                    warn!("DBError: {e} Error-UUID: {error_uuid}");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        InternalServerErrorTemplate { error_uuid },
+                    )
+                        .into_response();
+                }
+            };
+        }
+        PinTemplate {
+            username: user.username,
+            rows,
+            min_pin_length: MIN_PIN_LENGTH,
+            max_pin_length: MAX_PIN_LENGTH,
+        }
+        .into_response()
+    }
+
+    #[derive(Template)]
+    #[template(path = "admin.html")]
+    struct AdminTemplate {
+        username: String,
+        extensions: Vec<crate::types::Extension>,
+        contexts: Vec<&'static Context>,
+    }
+
+    /// All known extensions and contexts, for admins to manage
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn admin_overview(
+        auth_session: AuthSession,
+        Extension(config): Extension<Arc<Config>>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there is no user in the auth session. uuid: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        };
+        if !config.is_admin(&user.username) {
+            warn!(
+                "{} opened the admin page without being an admin.",
+                user.username
+            );
+            return not_an_admin().into_response();
+        };
+        AdminTemplate {
+            username: user.username,
+            extensions: config.registry.extensions(),
+            contexts: config.registry.contexts(),
+        }
+        .into_response()
+    }
+
+    #[derive(serde::Deserialize, Debug)]
+    pub(super) struct ExportQuery {
+        format: FileFormat,
+    }
+
+    /// All call forwards as a file to download
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn admin_export(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        axum::extract::Query(query): axum::extract::Query<ExportQuery>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to export the call forwards without being an admin.");
+            return not_an_admin().into_response();
+        };
+        let file = match get_all_call_forwards(&config).await {
+            Ok(forwards) => bulk::export(&forwards, query.format),
+            Err(e) => Err(e.into()),
+        };
+        match file {
+            Ok(file) => {
+                info!("{username} Exported the call forwards.");
+                let file_name = format!(
+                    "asterconf-{}.{}",
+                    chrono::Utc::now().format("%Y-%m-%d"),
+                    query.format.extension()
+                );
+                (
+                    [
+                        (
+                            header::CONTENT_TYPE,
+                            query.format.content_type().to_string(),
+                        ),
+                        (
+                            header::CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{file_name}\""),
+                        ),
+                    ],
+                    file,
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because the call forwards could not be exported: {e}, Error-UUID: {error_uuid}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    /// How many lookups are shown on the lookups page
+    const LOOKUPS_PER_PAGE: i64 = 100;
+
+    #[derive(Template)]
+    #[template(path = "lookups.html")]
+    struct LookupsTemplate {
+        username: String,
+        extension: crate::types::Extension,
+        /// the lookups with the display names of their contexts
+        lookups: Vec<(CallLookup, String)>,
+        timezone: Tz,
+    }
+
+    /// The latest lookups for calls to one extension
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn lookups(
+        auth_session: AuthSession,
+        Extension(config): Extension<Arc<Config>>,
+        Path(extension): Path<String>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there is no user in the auth session. uuid: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        };
+        match get_call_lookups(&config, &extension, LOOKUPS_PER_PAGE).await {
+            Ok(lookups) => {
+                let lookups = lookups
+                    .into_iter()
+                    .map(|x| {
+                        let context = config
+                            .registry
+                            .context(&x.context)
+                            .map(|ctx| ctx.display_name.clone())
+                            .unwrap_or_else(|| x.context.clone());
+                        (x, context)
+                    })
+                    .collect();
+                LookupsTemplate {
+                    username: user.username,
+                    extension: crate::types::Extension::create_from_name(&config, extension),
+                    lookups,
+                    timezone: config.default_timezone,
+                }
+                .into_response()
+            }
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because there was a problem getting call lookups.");
+                warn!("DBError: {e} Error-UUID: {error_uuid}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    /// How many changes are shown on the history page
+    const HISTORY_ENTRIES_PER_PAGE: i64 = 200;
+
+    #[derive(serde::Deserialize, Debug)]
+    pub(super) struct HistoryFilter {
+        /// only changes to call forwards from or to this extension
+        #[serde(default)]
+        extension: String,
+        /// only changes made by this user
+        #[serde(default)]
+        user: String,
+    }
+
+    #[derive(Template)]
+    #[template(path = "history.html")]
+    struct HistoryTemplate {
+        username: String,
+        extension: String,
+        user: String,
+        entries: Vec<AuditEntry>,
+        timezone: Tz,
+    }
+
+    /// The latest changes to call forwards, optionally filtered by extension and user
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn history(
+        auth_session: AuthSession,
+        Extension(config): Extension<Arc<Config>>,
+        axum::extract::Query(filter): axum::extract::Query<HistoryFilter>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there is no user in the auth session. uuid: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        };
+        let extension = filter.extension.trim();
+        let actor = filter.user.trim();
+        match get_audit_entries(
+            &config,
+            Some(extension).filter(|x| !x.is_empty()),
+            Some(actor).filter(|x| !x.is_empty()),
+            HISTORY_ENTRIES_PER_PAGE,
+        )
+        .await
+        {
+            Ok(entries) => HistoryTemplate {
+                username: user.username,
+                extension: extension.to_string(),
+                user: actor.to_string(),
+                entries,
+                timezone: config.default_timezone,
+            }
+            .into_response(),
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because there was a problem getting the audit log.");
+                warn!("DBError: {e} Error-UUID: {error_uuid}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    #[derive(Template)]
+    #[template(path = "deleted.html")]
+    struct DeletedTemplate<'a> {
+        username: String,
+        forwards: Vec<DeletedCallForward<'a>>,
+        deleted_forward_retention_days: i64,
+        timezone: Tz,
+    }
+
+    /// The deleted call forwards, which can still be restored
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn deleted_call_forwards(
+        auth_session: AuthSession,
+        Extension(config): Extension<Arc<Config>>,
+    ) -> impl IntoResponse {
+        let Some(user) = auth_session.user else {
+            let error_uuid = Uuid::new_v4();
+            warn!("Sending internal server error because there is no user in the auth session. uuid: {error_uuid}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                InternalServerErrorTemplate { error_uuid },
+            )
+                .into_response();
+        };
+        match get_deleted_call_forwards(&config).await {
+            Ok(forwards) => DeletedTemplate {
+                username: user.username,
+                forwards,
+                deleted_forward_retention_days: config.deleted_forward_retention.num_days(),
+                timezone: config.default_timezone,
+            }
+            .into_response(),
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because there was a problem getting the deleted call forwards.");
+                warn!("DBError: {e} Error-UUID: {error_uuid}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward(
+        Extension(config): Extension<Arc<Config>>,
+        Path(fwdid): Path<i32>,
+    ) -> impl IntoResponse {
+        let fwd_res = get_call_forward_by_id(&config, fwdid).await;
+        match fwd_res {
+            Ok(fwd) => {
+                let contexts = config.registry.contexts();
+                let usage = usage_of(&config, fwdid).await;
+                SingleCallForwardShowTemplate {
+                    fwd,
+                    contexts,
+                    usage,
+                    timezone: config.default_timezone,
+                }
+                .into_response()
+            }
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because there was a problem getting a call forward.");
+                warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    #[derive(Template)]
+    #[template(path = "call_forward_edit.html")]
+    struct SingleCallForwardEditTemplate<'a> {
+        current_forward: Option<CallForward<'a, HasId>>,
+        contexts: Vec<&'a Context>,
+        default_timezone: String,
+        /// the validity is entered in this timezone
+        timezone: Tz,
+    }
+
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward_edit(
+        Extension(config): Extension<Arc<Config>>,
+        Path(fwdid): Path<i32>,
+    ) -> impl IntoResponse {
+        // Get call forward by id
+        let fwd_res = get_call_forward_by_id(&config, fwdid).await;
+        match fwd_res {
+            Ok(current_forward) => {
+                let contexts = config.registry.contexts();
+                SingleCallForwardEditTemplate {
+                    current_forward: Some(current_forward),
+                    contexts,
+                    default_timezone: config.default_timezone.to_string(),
+                    timezone: config.default_timezone,
+                }
+            }
+            .into_response(),
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because there was a problem getting a call forward.");
+                warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward_new(
+        Extension(config): Extension<Arc<Config>>,
+    ) -> impl IntoResponse {
+        // Get call forward by id
+        let contexts = config.registry.contexts();
+        SingleCallForwardEditTemplate {
+            current_forward: None,
+            contexts,
+            default_timezone: config.default_timezone.to_string(),
+            timezone: config.default_timezone,
+        }
+        .into_response()
+    }
+
+    #[derive(Template)]
+    #[template(path = "destination_input.html")]
+    struct DestinationInputTemplate {
+        destination: String,
+    }
+
+    /// A new, empty input for another destination in the edit form
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn destination() -> impl IntoResponse {
+        DestinationInputTemplate {
+            destination: String::new(),
+        }
+        .into_response()
+    }
+
+    #[derive(Template)]
+    #[template(path = "schedule_window.html")]
+    struct ScheduleWindowTemplate {
+        window: ScheduleWindow,
+    }
+
+    /// A new, empty row for a schedule window in the edit form
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn schedule_window() -> impl IntoResponse {
+        ScheduleWindowTemplate {
+            window: ScheduleWindow::default(),
+        }
+        .into_response()
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct ToExtensionSearchForm {
+        to: String,
+    }
+
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn to_search_extension(
+        Extension(config): Extension<Arc<Config>>,
+        axum::extract::Query(search_form): axum::extract::Query<ToExtensionSearchForm>,
+    ) -> impl IntoResponse {
+        let relevant_extensions = config
+            .registry
+            .extensions()
+            .into_iter()
+            .filter_map(|extension| {
+                let ext_hr_string = extension.to_string();
+                let fuzzy_match = string_fuzzy_match(&search_form.to, &ext_hr_string);
+                if let Some(y) = fuzzy_match {
+                    Some((
+                        mark_string_at_positions(&ext_hr_string, y)?,
+                        extension.extension,
+                    ))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        SearchResultTemplate {
+            results: relevant_extensions,
+            target: "to".to_owned(),
+        }
+    }
+}
+
+pub(super) mod post {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use askama_axum::IntoResponse;
+    use axum::{extract::Path, http::StatusCode, Extension};
+    use serde::Deserialize;
+    use tracing::{info, warn, Level};
+
+    use crate::{
+        bulk::{self, DiffLine, FileFormat},
+        db::{
+            get_call_forward_by_id, insert_context, insert_extension, new_call_forward,
+            restore_call_forward, set_call_forward_active, set_extension_pin, update_call_forward,
+            update_context, update_extension, DBError,
+        },
+        forward_input::{ForwardInput, WindowInput},
+        pin::{hash_pin, is_valid_pin, MAX_PIN_LENGTH, MIN_PIN_LENGTH},
+        types::Config,
+        web_server::{login::AuthSession, InternalServerErrorTemplate},
+    };
+
+    #[derive(Deserialize, Debug)]
+    pub struct ForwardFormData {
+        from: String,
+        to: Option<Vec<String>>,
+        ctx_checkboxes: Option<Vec<String>>,
+        schedule_timezone: Option<String>,
+        // the n-th entry of each of these belongs to the n-th schedule window
+        window_first_day: Option<Vec<u8>>,
+        window_last_day: Option<Vec<u8>>,
+        window_start: Option<Vec<String>>,
+        window_end: Option<Vec<String>>,
+        condition: Option<String>,
+        no_answer_timeout: Option<String>,
+        /// separated by commas or whitespace
+        caller_ids: Option<String>,
+        /// as sent by `<input type="datetime-local">`, in the default timezone
+        valid_from: Option<String>,
+        valid_until: Option<String>,
+        /// the version of the call forward the edit form was made from
+        version: Option<i32>,
+    }
+
+    impl ForwardFormData {
+        /// The call forward as entered, to be checked with [`ForwardInput::check`]
+        ///
+        /// Returns the message to display to the user if the schedule is incomplete.
+        fn into_input(self) -> Result<ForwardInput, String> {
+            let first_days = self.window_first_day.unwrap_or_default();
+            let last_days = self.window_last_day.unwrap_or_default();
+            let starts = self.window_start.unwrap_or_default();
+            let ends = self.window_end.unwrap_or_default();
+            if first_days.len() != last_days.len()
+                || first_days.len() != starts.len()
+                || first_days.len() != ends.len()
+            {
+                return Err("Der Zeitplan ist unvollständig.".to_string());
+            };
+            let windows = first_days
+                .into_iter()
+                .zip(last_days)
+                .zip(starts.into_iter().zip(ends))
+                .map(|((first_day, last_day), (start, end))| WindowInput {
+                    first_day,
+                    last_day,
+                    start,
+                    end,
+                })
+                .collect();
+            Ok(ForwardInput {
+                from: self.from,
+                to: self.to.unwrap_or_default(),
+                contexts: self.ctx_checkboxes.unwrap_or_default(),
+                schedule_timezone: self.schedule_timezone,
+                windows,
+                condition: self.condition,
+                no_answer_timeout: self.no_answer_timeout,
+                caller_ids: self.caller_ids,
+                valid_from: self.valid_from,
+                valid_until: self.valid_until,
+                // editing does not switch the call forward on or off
+                active: true,
+            })
+        }
+    }
+
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward_new(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        axum_extra::extract::Form(forward_form): axum_extra::extract::Form<ForwardFormData>,
+    ) -> impl IntoResponse {
+        let forward = match forward_form.into_input().and_then(|x| x.check(&config)) {
+            Ok(x) => x,
+            Err(msg) => {
+                return (StatusCode::BAD_REQUEST, error_display(&msg)).into_response();
+            }
+        };
+
+        let username = session.user.expect("route should be protected").username;
+        let res = new_call_forward(&config, forward, &username).await;
+
+        match res {
+            Ok(x) => {
+                let contexts = config.registry.contexts();
+                info!(
+                    "{username} Inserted a new call forward: {}->{:?}@{:?}",
+                    x.from.extension,
+                    x.destination_extensions(),
+                    x.in_contexts
+                );
+                SingleCallForwardShowTemplate {
+                    fwd: x,
+                    contexts,
+                    usage: ForwardUsage::default(),
+                    timezone: config.default_timezone,
+                }
+                .into_response()
+            }
+            Err(e) => match forward_conflict_message(&e) {
+                Some(message) => (StatusCode::BAD_REQUEST, error_display(&message)).into_response(),
+                None => {
+                    let error_uuid = Uuid::new_v4();
+                    warn!("Sending internal server error because there was a problem INSERTing a call forward to the db.");
+                    warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        InternalServerErrorTemplate { error_uuid },
+                    )
+                        .into_response();
+                }
+            },
+        }
+    }
+
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward_edit(
+        Extension(config): Extension<Arc<Config>>,
+        axum::Extension(session): axum::Extension<AuthSession>,
+        Path(fwdid): Path<i32>,
+        axum_extra::extract::Form(forward_form): axum_extra::extract::Form<ForwardFormData>,
+    ) -> impl IntoResponse {
+        // a form without a version is as outdated as it gets
+        let version = forward_form.version.unwrap_or_default();
+        let forward = match forward_form.into_input().and_then(|x| x.check(&config)) {
+            Ok(x) => x.set_id(fwdid).set_version(version),
+            Err(msg) => {
+                return (StatusCode::BAD_REQUEST, error_display(&msg)).into_response();
+            }
+        };
+        let username = session.user.expect("route should be protected").username;
+        let update_res = update_call_forward(&config, &forward, &username).await;
+
+        match update_res {
+            Ok(()) => {
+                let contexts = config.registry.contexts();
+                info!("{username} Updated a call forward. Is now: {}->{:?}@{:?}.", forward.from.extension, forward.destination_extensions(), forward.in_contexts);
+                // show whether it is switched on as stored
+                let forward = match get_call_forward_by_id(&config, fwdid).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        warn!("Showing the updated call forward {fwdid} as entered: {e}");
+                        forward
+                    }
+                };
+                let usage = usage_of(&config, forward.fwd_id.into()).await;
+                SingleCallForwardShowTemplate {
+                    fwd: forward,
+                    contexts,
+                    usage,
+                    timezone: config.default_timezone,
+                }
+                .into_response()
+            }
+            Err(DBError::StaleCallForward(_)) => {
+                // show what the other change left, instead of overwriting it
+                let current = match get_call_forward_by_id(&config, fwdid).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let error_uuid = Uuid::new_v4();
+                        warn!("Sending internal server error because the call forward {fwdid} changed during an edit and could not be read again.");
+                        warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                        return (StatusCode::INTERNAL_SERVER_ERROR, InternalServerErrorTemplate { error_uuid }).into_response();
+                    }
+                };
+                info!("{username} Tried to update call forward {fwdid}, which was changed in the meantime.");
+                let contexts = config.registry.contexts();
+                let usage = usage_of(&config, fwdid).await;
+                let row = SingleCallForwardShowTemplate {
+                    fwd: current,
+                    contexts,
+                    usage,
+                    timezone: config.default_timezone,
+                };
+                match row.render() {
+                    Ok(row) => axum::response::Html(format!(
+                        "{row}{}",
+                        error_display_oob("Diese Weiterleitung wurde in der Zwischenzeit geändert, deine Änderung wurde nicht gespeichert. Hier ist der aktuelle Stand.")
+                    ))
+                    .into_response(),
+                    Err(e) => {
+                        let error_uuid = Uuid::new_v4();
+                        warn!("Sending internal server error because a call forward could not be rendered: {e}, Error-UUID: {error_uuid}");
+                        (StatusCode::INTERNAL_SERVER_ERROR, InternalServerErrorTemplate { error_uuid }).into_response()
+                    }
+                }
+            }
+            Err(DBError::CannotSelectCallForward(_)) => (
+                StatusCode::BAD_REQUEST,
+                error_display("Diese Weiterleitung existiert nicht mehr. Bitte Seite neu laden und erneut versuchen."),
+            )
+                .into_response(),
+            Err(DBError::CannotSelectContexts(_)) => (
+                StatusCode::BAD_REQUEST,
+                error_display("Kontext existiert nicht mehr. Bitte Seite neu laden und erneut versuchen."),
+            )
+                .into_response(),
+            Err(e) => match forward_conflict_message(&e) {
+                Some(message) => (StatusCode::BAD_REQUEST, error_display(&message)).into_response(),
+                None => {
+                    let error_uuid = Uuid::new_v4();
+                    warn!("Sending internal server error because there was a problem UPATEing a call forward to the db.");
+                    warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                    return (StatusCode::INTERNAL_SERVER_ERROR, InternalServerErrorTemplate { error_uuid }).into_response();
+                }
+            },
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct ActiveFormData {
+        active: bool,
+    }
+
+    /// Switch a call forward on or off without losing its id and statistics
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward_active(
+        Extension(config): Extension<Arc<Config>>,
+        axum::Extension(session): axum::Extension<AuthSession>,
+        Path(fwdid): Path<i32>,
+        axum::Form(active_form): axum::Form<ActiveFormData>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        let res = match set_call_forward_active(&config, fwdid, active_form.active, &username).await
+        {
+            Ok(()) => get_call_forward_by_id(&config, fwdid).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(forward) => {
+                let contexts = config.registry.contexts();
+                info!(
+                    "{username} Switched call forward {fwdid} {}.",
+                    if forward.active { "on" } else { "off" }
+                );
+                let usage = usage_of(&config, fwdid).await;
+                SingleCallForwardShowTemplate {
+                    fwd: forward,
+                    contexts,
+                    usage,
+                    timezone: config.default_timezone,
+                }
+                .into_response()
+            }
+            Err(DBError::CannotSelectCallForward(_)) => (
+                StatusCode::BAD_REQUEST,
+                error_display("Diese Weiterleitung existiert nicht mehr. Bitte Seite neu laden und erneut versuchen."),
+            )
+                .into_response(),
+            Err(e) => match forward_conflict_message(&e) {
+                Some(message) => (StatusCode::BAD_REQUEST, error_display(&message)).into_response(),
+                None => {
+                    let error_uuid = Uuid::new_v4();
+                    warn!("Sending internal server error because there was a problem switching a call forward on or off.");
+                    warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        InternalServerErrorTemplate { error_uuid },
+                    )
+                        .into_response()
+                }
+            },
+        }
+    }
+
+    /// Bring back a deleted call forward, unless it clashes with the current ones
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn single_call_forward_restore(
+        Extension(config): Extension<Arc<Config>>,
+        axum::Extension(session): axum::Extension<AuthSession>,
+        Path(fwdid): Path<i32>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        match restore_call_forward(&config, fwdid, &username).await {
+            Ok(()) => {
+                info!("{username} Restored call forward {fwdid}.");
+                // it is no longer deleted, so it leaves the list
+                "".into_response()
+            }
+            Err(DBError::CannotSelectCallForward(_)) => (
+                StatusCode::BAD_REQUEST,
+                error_display("Diese Weiterleitung ist nicht mehr gelöscht oder wurde endgültig gelöscht. Bitte Seite neu laden."),
+            )
+                .into_response(),
+            Err(e) => match forward_conflict_message(&e) {
+                Some(message) => (StatusCode::BAD_REQUEST, error_display(&message)).into_response(),
+                None => {
+                    let error_uuid = Uuid::new_v4();
+                    warn!("Sending internal server error because there was a problem restoring a call forward.");
+                    warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        InternalServerErrorTemplate { error_uuid },
+                    )
+                        .into_response()
+                }
+            },
+        }
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct PinFormData {
+        pin: String,
+        pin_repeat: String,
+    }
+
+    /// Set the PIN of an extension owned by the logged in user
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn pin_set(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        Path(extension): Path<String>,
+        axum_extra::extract::Form(pin_form): axum_extra::extract::Form<PinFormData>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        let Some(owned) = owned_extension(&config, &username, &extension) else {
+            warn!("{username} tried to set the PIN of {extension}, which they do not own.");
+            return (
+                StatusCode::FORBIDDEN,
+                error_display("Dieser Anschluss gehört dir nicht."),
+            )
+                .into_response();
+        };
+        if pin_form.pin != pin_form.pin_repeat {
+            return (
+                StatusCode::BAD_REQUEST,
+                error_display("Die beiden PINs stimmen nicht überein."),
+            )
+                .into_response();
+        };
+        if !is_valid_pin(&pin_form.pin) {
+            return (
+                StatusCode::BAD_REQUEST,
+                error_display(&format!(
+                    "Die PIN muss aus {MIN_PIN_LENGTH} bis {MAX_PIN_LENGTH} Ziffern bestehen."
+                )),
+            )
+                .into_response();
+        };
+        let pin_hash = match hash_pin(&pin_form.pin) {
+            Ok(x) => x,
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because a PIN could not be hashed.");
+                warn!("Error: {e}, Error-UUID: {error_uuid}");
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response();
             }
-        }
-    }
-
-    #[tracing::instrument(level=Level::DEBUG,skip_all)]
-    pub(super) async fn single_call_forward(
-        Extension(config): Extension<Arc<Config>>,
-        Path(fwdid): Path<i32>,
-    ) -> impl IntoResponse {
-        let fwd_res = get_call_forward_by_id(&config, fwdid).await;
-        match fwd_res {
-            Ok(fwd) => {
-                let mut contexts = config.contexts.values().collect::<Vec<_>>();
-                contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
-                SingleCallForwardShowTemplate { fwd, contexts }
+        };
+        let row_res = match set_extension_pin(&config, &extension, &pin_hash).await {
+            Ok(()) => PinRow::create(&config, owned).await,
+            Err(e) => Err(e),
+        };
+        match row_res {
+            Ok(row) => {
+                info!("{username} Set the PIN of {extension}.");
+                PinRowTemplate { row }.into_response()
             }
-            .into_response(),
             Err(e) => {
                 let error_uuid = Uuid::new_v4();
-                warn!("Sending internal server error because there was a problem getting a call forward.");
+                warn!("Sending internal server error because there was a problem setting a PIN.");
                 warn!("DBError: {e}, Error-UUID: {error_uuid}");
-                return (
+                (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     InternalServerErrorTemplate { error_uuid },
                 )
-                    .into_response();
+                    .into_response()
             }
         }
     }
 
-    #[derive(Template)]
-    #[template(path = "call_forward_edit.html")]
-    struct SingleCallForwardEditTemplate<'a> {
-        current_forward: Option<CallForward<'a, HasId>>,
-        contexts: Vec<&'a Context>,
+    #[derive(Deserialize)]
+    pub(super) struct ExtensionFormData {
+        /// only sent when adding an extension. Afterwards it is part of the path
+        extension: Option<String>,
+        name: String,
+        /// the LDAP uid of the owner, empty if nobody owns the extension
+        owner: String,
+    }
+    impl ExtensionFormData {
+        fn into_extension(self, extension: String) -> Result<crate::types::Extension, String> {
+            let extension = extension.trim().to_string();
+            let name = self.name.trim().to_string();
+            if extension.is_empty() || name.is_empty() {
+                return Err("Nummer und Name dürfen nicht leer sein.".to_string());
+            };
+            let owner = Some(self.owner.trim().to_string()).filter(|x| !x.is_empty());
+            Ok(crate::types::Extension::new(extension, name, owner))
+        }
     }
 
+    /// Add a new extension
     #[tracing::instrument(level=Level::DEBUG,skip_all)]
-    pub(super) async fn single_call_forward_edit(
+    pub(super) async fn admin_extension_new(
         Extension(config): Extension<Arc<Config>>,
-        Path(fwdid): Path<i32>,
+        Extension(session): Extension<AuthSession>,
+        axum_extra::extract::Form(extension_form): axum_extra::extract::Form<ExtensionFormData>,
     ) -> impl IntoResponse {
-        // Get call forward by id
-        let fwd_res = get_call_forward_by_id(&config, fwdid).await;
-        match fwd_res {
-            Ok(current_forward) => {
-                let mut contexts = config.contexts.values().collect::<Vec<_>>();
-                contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
-                SingleCallForwardEditTemplate {
-                    current_forward: Some(current_forward),
-                    contexts,
-                }
-            }
-            .into_response(),
-            Err(e) => {
-                let error_uuid = Uuid::new_v4();
-                warn!("Sending internal server error because there was a problem getting a call forward.");
-                warn!("DBError: {e}, Error-UUID: {error_uuid}");
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    InternalServerErrorTemplate { error_uuid },
-                )
-                    .into_response();
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to add an extension without being an admin.");
+            return not_an_admin().into_response();
+        };
+        let extension = extension_form.extension.clone().unwrap_or_default();
+        let extension = match extension_form.into_extension(extension) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, error_display(&e)).into_response(),
+        };
+        match insert_extension(&config, &extension).await {
+            Ok(()) => {
+                info!("{username} Added the extension {extension}.");
+                refresh_registry(&config).await;
+                AdminExtensionRowTemplate { extension }.into_response()
             }
+            Err(e) => admin_error_response(e),
         }
     }
 
+    /// Change the name and owner of an extension
     #[tracing::instrument(level=Level::DEBUG,skip_all)]
-    pub(super) async fn single_call_forward_new(
+    pub(super) async fn admin_extension_set(
         Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        Path(extension): Path<String>,
+        axum_extra::extract::Form(extension_form): axum_extra::extract::Form<ExtensionFormData>,
     ) -> impl IntoResponse {
-        // Get call forward by id
-        let mut contexts = config.contexts.values().collect::<Vec<_>>();
-        contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
-        SingleCallForwardEditTemplate {
-            current_forward: None,
-            contexts,
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to change the extension {extension} without being an admin.");
+            return not_an_admin().into_response();
+        };
+        let extension = match extension_form.into_extension(extension) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, error_display(&e)).into_response(),
+        };
+        match update_extension(&config, &extension).await {
+            Ok(()) => {
+                info!("{username} Changed the extension {extension}.");
+                refresh_registry(&config).await;
+                AdminExtensionRowTemplate { extension }.into_response()
+            }
+            Err(e) => admin_error_response(e),
         }
-        .into_response()
     }
-}
-
-pub(super) mod post {
-    use super::*;
 
-    use std::sync::Arc;
-
-    use askama_axum::IntoResponse;
-    use axum::{extract::Path, http::StatusCode, Extension};
-    use serde::Deserialize;
-    use tracing::{info, warn, Level};
+    #[derive(Deserialize)]
+    pub(super) struct ContextFormData {
+        /// only sent when adding a context. Afterwards it is part of the path
+        asterisk_name: Option<String>,
+        display_name: String,
+    }
+    impl ContextFormData {
+        fn into_context(self, asterisk_name: String) -> Result<Context, String> {
+            let asterisk_name = asterisk_name.trim().to_string();
+            let display_name = self.display_name.trim().to_string();
+            if asterisk_name.is_empty() || display_name.is_empty() {
+                return Err("Asterisk-Name und Anzeigename dürfen nicht leer sein.".to_string());
+            };
+            Ok(Context {
+                display_name,
+                asterisk_name,
+            })
+        }
+    }
 
-    use crate::{
-        db::{new_call_forward, update_call_forward, DBError},
-        types::{CallForward, Config, HasId, NoId},
-        web_server::{login::AuthSession, InternalServerErrorTemplate},
-    };
+    #[derive(Template)]
+    #[template(path = "admin_import_result.html")]
+    struct ImportResultTemplate {
+        /// why the file cannot be imported. Nothing is changed if there are any
+        problems: Vec<String>,
+        diff: Vec<DiffLine>,
+        unchanged: usize,
+        /// were the changes made, or is this a dry run?
+        applied: bool,
+        /// the file to import when the changes are confirmed
+        content: String,
+        format: &'static str,
+        replace: bool,
+        /// the stored call forwards the changes were shown for, see [`bulk::stored_versions`]
+        stored_versions: String,
+    }
 
-    #[derive(Deserialize, Debug)]
-    pub struct ForwardFormData {
-        from: String,
-        to: String,
-        ctx_checkboxes: Option<Vec<String>>,
+    /// Import `content` as `username`, showing the changes or why they cannot be made.
+    ///
+    /// Without `previewed`, the changes are only checked. Otherwise, they are made if the stored
+    /// call forwards are still the ones they were checked for.
+    async fn import_response(
+        config: &Config,
+        username: &str,
+        content: String,
+        format: FileFormat,
+        replace: bool,
+        previewed: Option<&str>,
+    ) -> askama_axum::Response {
+        let records = match bulk::parse(&content, format) {
+            Ok(x) => x,
+            Err(msg) => return (StatusCode::BAD_REQUEST, error_display(&msg)).into_response(),
+        };
+        let commit = previewed.is_some();
+        let (problems, diff, unchanged, stored_versions) =
+            match bulk::import(config, &records, replace, commit, username, previewed).await {
+                Ok(plan) => {
+                    if commit {
+                        info!(
+                            "{username} Imported {} changes to the call forwards.",
+                            plan.changes.len()
+                        );
+                    };
+                    (
+                        vec![],
+                        plan.diff(&config.default_timezone),
+                        plan.unchanged,
+                        plan.stored_versions,
+                    )
+                }
+                Err(problems) => (
+                    problems.iter().map(|x| x.to_string()).collect(),
+                    vec![],
+                    0,
+                    String::new(),
+                ),
+            };
+        ImportResultTemplate {
+            problems,
+            diff,
+            unchanged,
+            applied: commit,
+            content,
+            format: format.extension(),
+            replace,
+            stored_versions,
+        }
+        .into_response()
     }
 
+    /// Show what importing the uploaded file changes, without changing anything
     #[tracing::instrument(level=Level::DEBUG,skip_all)]
-    pub(super) async fn single_call_forward_new(
+    pub(super) async fn admin_import_check(
         Extension(config): Extension<Arc<Config>>,
         Extension(session): Extension<AuthSession>,
-        axum_extra::extract::Form(forward_form): axum_extra::extract::Form<ForwardFormData>,
+        mut multipart: axum::extract::Multipart,
     ) -> impl IntoResponse {
-        let Some(from_ext) = config.extensions.get(&forward_form.from) else {
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to import call forwards without being an admin.");
+            return not_an_admin().into_response();
+        };
+        let mut file = None;
+        let mut replace = false;
+        loop {
+            let field = match multipart.next_field().await {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Could not read an uploaded import: {e}");
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        error_display("Die Datei konnte nicht hochgeladen werden."),
+                    )
+                        .into_response();
+                }
+            };
+            match field.name() {
+                Some("file") => {
+                    let format = field.file_name().and_then(FileFormat::from_file_name);
+                    match field.text().await {
+                        Ok(x) => file = Some((x, format)),
+                        Err(e) => {
+                            warn!("Could not read an uploaded import: {e}");
+                            return (
+                                StatusCode::BAD_REQUEST,
+                                error_display("Die Datei konnte nicht hochgeladen werden."),
+                            )
+                                .into_response();
+                        }
+                    };
+                }
+                Some("replace") => replace = true,
+                _ => {}
+            };
+        }
+        let Some((content, format)) = file else {
             return (
                 StatusCode::BAD_REQUEST,
-                error_display("Das 'Anruf für &darr;'-Feld muss eine bekannte Nummer sein."),
+                error_display("Bitte eine Datei auswählen."),
             )
                 .into_response();
         };
-        let to_ext = crate::types::Extension::create_from_name(&config, forward_form.to);
-
-        let mut contexts = vec![];
-        let Some(ctx_checkboxes) = forward_form.ctx_checkboxes else {
+        let Some(format) = format else {
             return (
                 StatusCode::BAD_REQUEST,
-                error_display("Eine Weiterleitung muss mindestens einen Kontext enthalten."),
+                error_display(
+                    "Nur Dateien mit der Endung .csv oder .json können importiert werden.",
+                ),
             )
                 .into_response();
         };
-        for ctx in ctx_checkboxes {
-            let Some(this_ctx) = config.contexts.get(&ctx) else {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    error_display(&format!("Konnte den Kontext {ctx} nicht finden.")),
-                )
-                    .into_response();
-            };
-            contexts.push(this_ctx);
-        }
-
-        let forward = CallForward {
-            fwd_id: NoId {},
-            from: from_ext.clone(),
-            to: to_ext.clone(),
-            in_contexts: contexts,
-        };
-
-        let res = new_call_forward(&config, forward).await;
+        import_response(&config, &username, content, format, replace, None).await
+    }
 
-        match res {
-            Ok(x) => {
-                let mut contexts = config.contexts.values().collect::<Vec<_>>();
-                contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
-                info!("{} Inserted a new call forward: {}->{}@{:?}", session.user.expect("route should be protected").username, x.from.extension, x.to.extension, x.in_contexts);
-                SingleCallForwardShowTemplate { fwd: x, contexts }.into_response()
-            }
-            Err(DBError::OverlappingCallForwards(x, y)) => (
-                StatusCode::BAD_REQUEST,
-                error_display(&format!(
-                    "Anrufe an die Nummer {x} werden bereits weitergeleitet wenn sie von {y} kommen."
-                )),
-            )
-                .into_response(),
-            Err(e) => {
-                let error_uuid = Uuid::new_v4();
-                warn!("Sending internal server error because there was a problem INSERTing a call forward to the db.");
-                warn!("DBError: {e}, Error-UUID: {error_uuid}");
-                return (StatusCode::INTERNAL_SERVER_ERROR, InternalServerErrorTemplate { error_uuid }).into_response();
-            },
-        }
+    #[derive(Deserialize)]
+    pub(super) struct ImportFormData {
+        content: String,
+        format: FileFormat,
+        #[serde(default)]
+        replace: bool,
+        stored_versions: String,
     }
 
+    /// Import the file checked before, making all changes or none
     #[tracing::instrument(level=Level::DEBUG,skip_all)]
-    pub(super) async fn single_call_forward_edit(
+    pub(super) async fn admin_import_apply(
         Extension(config): Extension<Arc<Config>>,
-        axum::Extension(session): axum::Extension<AuthSession>,
-        Path(fwdid): Path<i32>,
-        axum_extra::extract::Form(forward_form): axum_extra::extract::Form<ForwardFormData>,
+        Extension(session): Extension<AuthSession>,
+        axum::Form(import_form): axum::Form<ImportFormData>,
     ) -> impl IntoResponse {
-        let Some(from_ext) = config.extensions.get(&forward_form.from) else {
-            return (
-                StatusCode::BAD_REQUEST,
-                error_display("Das 'Anruf für &darr;'-Feld muss eine bekannte Nummer sein."),
-            )
-                .into_response();
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to import call forwards without being an admin.");
+            return not_an_admin().into_response();
         };
-        let to_ext = crate::types::Extension::create_from_name(&config, forward_form.to);
+        import_response(
+            &config,
+            &username,
+            import_form.content,
+            import_form.format,
+            import_form.replace,
+            Some(&import_form.stored_versions),
+        )
+        .await
+    }
 
-        let mut contexts = vec![];
-        let Some(ctx_checkboxes) = forward_form.ctx_checkboxes else {
-            return (
-                StatusCode::BAD_REQUEST,
-                error_display("Eine Weiterleitung muss mindestens einen Kontext enthalten dessen Anrufe weitergeleitet werden."),
-            )
-                .into_response();
+    /// Add a new context
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn admin_context_new(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        axum_extra::extract::Form(context_form): axum_extra::extract::Form<ContextFormData>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to add a context without being an admin.");
+            return not_an_admin().into_response();
         };
-        for ctx in ctx_checkboxes {
-            let Some(this_ctx) = config.contexts.get(&ctx) else {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    error_display(&format!("Kontext {ctx} konnte nicht gefunden werden.")),
-                )
-                    .into_response();
-            };
-            contexts.push(this_ctx);
+        let asterisk_name = context_form.asterisk_name.clone().unwrap_or_default();
+        let context = match context_form.into_context(asterisk_name) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, error_display(&e)).into_response(),
+        };
+        match insert_context(&config, &context).await {
+            Ok(()) => {
+                info!("{username} Added the context {}.", context.asterisk_name);
+                refresh_registry(&config).await;
+                AdminContextRowTemplate { context }.into_response()
+            }
+            Err(e) => admin_error_response(e),
         }
+    }
 
-        let forward = CallForward {
-            fwd_id: HasId::new(fwdid),
-            from: from_ext.clone(),
-            to: to_ext.clone(),
-            in_contexts: contexts,
+    /// Change the display name of a context
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn admin_context_set(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        Path(asterisk_name): Path<String>,
+        axum_extra::extract::Form(context_form): axum_extra::extract::Form<ContextFormData>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to change the context {asterisk_name} without being an admin.");
+            return not_an_admin().into_response();
         };
-        let update_res = update_call_forward(&config, &forward).await;
-
-        match update_res {
+        let context = match context_form.into_context(asterisk_name) {
+            Ok(x) => x,
+            Err(e) => return (StatusCode::BAD_REQUEST, error_display(&e)).into_response(),
+        };
+        match update_context(&config, &context).await {
             Ok(()) => {
-                let mut contexts = config.contexts.values().collect::<Vec<_>>();
-                contexts.sort_unstable_by(|a, b| a.display_name.cmp(&b.display_name));
-                info!("{} Updated a call forward. Is now: {}->{}@{:?}.", session.user.expect("route should be protected").username, forward.from.extension, forward.to.extension, forward.in_contexts);
-                SingleCallForwardShowTemplate {
-                    fwd: forward,
-                    contexts,
-                }
-                .into_response()
-            }
-            Err(DBError::CannotSelectCallForward(_)) => (
-                StatusCode::BAD_REQUEST,
-                error_display("Diese Weiterleitung existiert nicht mehr. Bitte Seite neu laden und erneut versuchen."),
-            )
-                .into_response(),
-            Err(DBError::CannotSelectContexts(_)) => (
-                StatusCode::BAD_REQUEST,
-                error_display("Kontext existiert nicht mehr. Bitte Seite neu laden und erneut versuchen."),
-            )
-                .into_response(),
-            Err(e) => {
-                let error_uuid = Uuid::new_v4();
-                warn!("Sending internal server error because there was a problem UPATEing a call forward to the db.");
-                warn!("DBError: {e}, Error-UUID: {error_uuid}");
-                return (StatusCode::INTERNAL_SERVER_ERROR, InternalServerErrorTemplate { error_uuid }).into_response();
+                info!("{username} Changed the context {}.", context.asterisk_name);
+                refresh_registry(&config).await;
+                AdminContextRowTemplate { context }.into_response()
             }
+            Err(e) => admin_error_response(e),
         }
     }
 
@@ -356,16 +1527,12 @@ pub(super) mod post {
     pub(super) struct FromExtensionSearchForm {
         from: String,
     }
-    #[derive(Deserialize)]
-    pub(super) struct ToExtensionSearchForm {
-        to: String,
-    }
 
     /// Find the characters of `search` in `term`, in order
     /// Returns
     /// - None, if no match
     /// - Some([indices-in-term-where-the-chars-from-search-are]) if match
-    fn string_fuzzy_match(search: &str, term: &str) -> Option<Vec<usize>> {
+    pub(super) fn string_fuzzy_match(search: &str, term: &str) -> Option<Vec<usize>> {
         let our_search = search.to_lowercase();
         let our_term = term.to_lowercase();
 
@@ -382,7 +1549,7 @@ pub(super) mod post {
         Some(pos_vec)
     }
 
-    fn mark_string_at_positions(s: &str, positions: Vec<usize>) -> Option<String> {
+    pub(super) fn mark_string_at_positions(s: &str, positions: Vec<usize>) -> Option<String> {
         let mut res = String::new();
         // the last char of s copied over
         let mut last = None;
@@ -422,8 +1589,8 @@ pub(super) mod post {
     #[derive(Template)]
     #[template(path = "search_results.html")]
     pub(super) struct SearchResultTemplate {
-        results: Vec<(String, String)>,
-        target: String,
+        pub(super) results: Vec<(String, String)>,
+        pub(super) target: String,
     }
 
     #[tracing::instrument(level=Level::DEBUG,skip_all)]
@@ -432,15 +1599,16 @@ pub(super) mod post {
         axum_extra::extract::Form(search_form): axum_extra::extract::Form<FromExtensionSearchForm>,
     ) -> impl IntoResponse {
         let relevant_extensions = config
-            .extensions
-            .iter()
-            .filter_map(|(ext_name, extension)| {
+            .registry
+            .extensions()
+            .into_iter()
+            .filter_map(|extension| {
                 let ext_hr_string = extension.to_string();
                 let fuzzy_match = string_fuzzy_match(&search_form.from, &ext_hr_string);
                 if let Some(y) = fuzzy_match {
                     Some((
                         mark_string_at_positions(&ext_hr_string, y)?,
-                        ext_name.to_owned(),
+                        extension.extension,
                     ))
                 } else {
                     None
@@ -453,33 +1621,6 @@ pub(super) mod post {
         }
     }
 
-    #[tracing::instrument(level=Level::DEBUG,skip_all)]
-    pub(super) async fn to_search_extension(
-        Extension(config): Extension<Arc<Config>>,
-        axum_extra::extract::Form(search_form): axum_extra::extract::Form<ToExtensionSearchForm>,
-    ) -> impl IntoResponse {
-        let relevant_extensions = config
-            .extensions
-            .iter()
-            .filter_map(|(ext_name, extension)| {
-                let ext_hr_string = extension.to_string();
-                let fuzzy_match = string_fuzzy_match(&search_form.to, &ext_hr_string);
-                if let Some(y) = fuzzy_match {
-                    Some((
-                        mark_string_at_positions(&ext_hr_string, y)?,
-                        ext_name.to_owned(),
-                    ))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
-        SearchResultTemplate {
-            results: relevant_extensions,
-            target: "to".to_owned(),
-        }
-    }
-
     #[cfg(test)]
     mod test {
         use crate::web_server::protected::post::mark_string_at_positions;
@@ -544,7 +1685,7 @@ pub(super) mod delete {
     use tracing::{info, warn, Level};
 
     use crate::{
-        db::delete_call_forward_by_id,
+        db::{delete_call_forward_by_id, delete_context, delete_extension, delete_extension_pin},
         types::Config,
         web_server::{login::AuthSession, InternalServerErrorTemplate},
     };
@@ -555,13 +1696,11 @@ pub(super) mod delete {
         Extension(session): Extension<AuthSession>,
         Path(fwdid): Path<i32>,
     ) -> impl IntoResponse {
-        let fwd_res = delete_call_forward_by_id(&config, fwdid).await;
+        let username = session.user.expect("route should be protected").username;
+        let fwd_res = delete_call_forward_by_id(&config, fwdid, &username).await;
         match fwd_res {
             Ok(()) => {
-                info!(
-                    "{} Deleted a call forward.",
-                    session.user.expect("route should be protected").username
-                );
+                info!("{username} Deleted call forward {fwdid}.");
                 "".into_response()
             }
             Err(e) => {
@@ -576,4 +1715,86 @@ pub(super) mod delete {
             }
         }
     }
+
+    /// Remove the PIN of an extension owned by the logged in user, disabling remote access
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn pin_delete(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        Path(extension): Path<String>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        let Some(owned) = owned_extension(&config, &username, &extension) else {
+            warn!("{username} tried to remove the PIN of {extension}, which they do not own.");
+            return (
+                StatusCode::FORBIDDEN,
+                error_display("Dieser Anschluss gehört dir nicht."),
+            )
+                .into_response();
+        };
+        let row_res = match delete_extension_pin(&config, &extension).await {
+            Ok(()) => PinRow::create(&config, owned).await,
+            Err(e) => Err(e),
+        };
+        match row_res {
+            Ok(row) => {
+                info!("{username} Removed the PIN of {extension}.");
+                PinRowTemplate { row }.into_response()
+            }
+            Err(e) => {
+                let error_uuid = Uuid::new_v4();
+                warn!("Sending internal server error because there was a problem removing a PIN.");
+                warn!("DBError: {e}, Error-UUID: {error_uuid}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    InternalServerErrorTemplate { error_uuid },
+                )
+                    .into_response()
+            }
+        }
+    }
+
+    /// Forget an extension. Its call forwards and its PIN stay.
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn admin_extension_delete(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        Path(extension): Path<String>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to remove the extension {extension} without being an admin.");
+            return not_an_admin().into_response();
+        };
+        match delete_extension(&config, &extension).await {
+            Ok(()) => {
+                info!("{username} Removed the extension {extension}.");
+                refresh_registry(&config).await;
+                "".into_response()
+            }
+            Err(e) => admin_error_response(e),
+        }
+    }
+
+    /// Forget a context that no call forward is set in
+    #[tracing::instrument(level=Level::DEBUG,skip_all)]
+    pub(super) async fn admin_context_delete(
+        Extension(config): Extension<Arc<Config>>,
+        Extension(session): Extension<AuthSession>,
+        Path(asterisk_name): Path<String>,
+    ) -> impl IntoResponse {
+        let username = session.user.expect("route should be protected").username;
+        if !config.is_admin(&username) {
+            warn!("{username} tried to remove the context {asterisk_name} without being an admin.");
+            return not_an_admin().into_response();
+        };
+        match delete_context(&config, &asterisk_name).await {
+            Ok(()) => {
+                info!("{username} Removed the context {asterisk_name}.");
+                refresh_registry(&config).await;
+                "".into_response()
+            }
+            Err(e) => admin_error_response(e),
+        }
+    }
 }